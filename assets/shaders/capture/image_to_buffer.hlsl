@@ -0,0 +1,15 @@
+[[vk::binding(0)]] Texture2D<float4> input_tex;
+[[vk::binding(1)]] RWStructuredBuffer<float4> output_buffer;
+[[vk::binding(2)]] cbuffer _ {
+    float4 input_tex_size;
+};
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    const uint2 extent = uint2(input_tex_size.xy);
+    if (any(px >= extent)) {
+        return;
+    }
+
+    output_buffer[px.y * extent.x + px.x] = input_tex[px];
+}
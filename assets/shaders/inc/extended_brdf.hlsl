@@ -0,0 +1,224 @@
+#include "layered_brdf.hlsl"
+#include "material_extensions.hlsl"
+
+static const float CLEARCOAT_F0 = 0.04;
+
+// "Production Friendly Microfacet Sheen BRDF", Estevez and Kulla, 2017,
+// with the visibility term from "Crafting a Next-Gen Material Pipeline for The Order: 1886", Neubelt and Pettineo, 2013.
+struct SheenBrdf {
+    float3 color;
+    float roughness;
+
+    static float charlie_ndf(float alpha, float cos_theta) {
+        const float inv_alpha = 1.0 / alpha;
+        const float sin2_theta = max(0.0, 1.0 - cos_theta * cos_theta);
+        return (2.0 + inv_alpha) * pow(sin2_theta, 0.5 * inv_alpha) / M_TAU;
+    }
+
+    static float neubelt_visibility(float ndotv, float ndotl) {
+        return 1.0 / (4.0 * (ndotl + ndotv - ndotl * ndotv));
+    }
+
+    float alpha() {
+        return max(1e-3, roughness * roughness);
+    }
+
+    // With a white `color`
+    float evaluate_unit(float3 wo, float3 wi) {
+        const float3 m = normalize(wo + wi);
+        return charlie_ndf(alpha(), m.z) * neubelt_visibility(wo.z, wi.z);
+    }
+
+    float3 evaluate(float3 wo, float3 wi) {
+        if (wo.z <= 0 || wi.z <= 0) {
+            return 0;
+        }
+
+        return color * evaluate_unit(wo, wi);
+    }
+
+    // Fraction of light reflected by the lobe with a white `color`, used to darken the layers below.
+    // There's no LUT for it, so it's integrated with a small fixed set of cosine-distributed directions.
+    float directional_albedo(float ndotv) {
+        static const uint GRID_SIZE = 4;
+
+        const float3 wo = float3(sqrt(max(0.0, 1.0 - ndotv * ndotv)), 0.0, ndotv);
+
+        float sum = 0.0;
+        for (uint y = 0; y < GRID_SIZE; ++y) {
+            for (uint x = 0; x < GRID_SIZE; ++x) {
+                const float2 urand = (float2(x, y) + 0.5) / GRID_SIZE;
+
+                DiffuseBrdf cosine_lobe;
+                cosine_lobe.albedo = 1.0;
+                const float3 wi = cosine_lobe.sample(wo, urand).wi;
+
+                // Divided by the pdf of cosine-distributed directions.
+                sum += evaluate_unit(wo, wi) * M_PI;
+            }
+        }
+
+        return saturate(sum / (GRID_SIZE * GRID_SIZE));
+    }
+
+    BrdfSample sample(float3 wo, float2 urand) {
+        DiffuseBrdf cosine_lobe;
+        cosine_lobe.albedo = 1.0;
+
+        BrdfSample res = cosine_lobe.sample(wo, urand);
+        res.value = evaluate(wo, res.wi);
+        res.value_over_pdf = res.value / res.pdf;
+        res.approx_roughness = roughness;
+        return res;
+    }
+};
+
+struct ExtendedBrdfSample {
+    // `wi` points below the surface for transmission.
+    BrdfSample brdf_sample;
+    bool is_valid;
+};
+
+// `LayeredBrdf` extended with the `KHR_materials_*` glTF extensions:
+// a clearcoat on top of sheen, on top of the base layer, in which transmission
+// replaces a fraction of the diffuse lobe. IOR sets the base's dielectric reflectance.
+//
+// Transmitting surfaces are thin-walled: the transmitted lobe is the specular lobe
+// mirrored below the surface, without refraction, tinted by the base color.
+struct ExtendedBrdf {
+    LayeredBrdf base;
+    SpecularBrdf transmission_brdf;
+    float3 transmission_albedo;
+
+    SheenBrdf sheen_brdf;
+    float sheen_transmission;
+
+    SpecularBrdf clearcoat_brdf;
+    float clearcoat;
+    float clearcoat_transmission;
+
+    static ExtendedBrdf from_gbuffer_ndotv(
+        GbufferData gbuffer,
+        MaterialExtensions ext,
+        float ndotv
+    ) {
+        ExtendedBrdf res;
+        res.base = LayeredBrdf::from_gbuffer_ndotv_dielectric_f0(gbuffer, ndotv, ext.dielectric_f0());
+
+        // The diffuse albedo already excludes metals.
+        const float3 diffuse_albedo = res.base.diffuse_brdf.albedo;
+        res.base.diffuse_brdf.albedo = diffuse_albedo * (1.0 - ext.transmission);
+        res.transmission_albedo =
+            diffuse_albedo * ext.transmission
+            * res.base.energy_preservation.preintegrated_transmission_fraction;
+        res.transmission_brdf.albedo = 1.0;
+        res.transmission_brdf.roughness = gbuffer.roughness;
+
+        res.sheen_brdf.color = ext.sheen_color;
+        res.sheen_brdf.roughness = ext.sheen_roughness;
+        res.sheen_transmission = select(
+            any(ext.sheen_color > 0.0),
+            1.0 - max3(ext.sheen_color.r, ext.sheen_color.g, ext.sheen_color.b) * res.sheen_brdf.directional_albedo(ndotv),
+            1.0
+        );
+
+        res.clearcoat_brdf.albedo = CLEARCOAT_F0;
+        res.clearcoat_brdf.roughness = clamp(perceptual_roughness_to_roughness(ext.clearcoat_roughness), 1e-4, 1.0);
+        res.clearcoat = ext.clearcoat;
+        res.clearcoat_transmission = 1.0 - ext.clearcoat * eval_fresnel_schlick(CLEARCOAT_F0, 1.0, ndotv).x;
+
+        return res;
+    }
+
+    bool has_transmission() {
+        return any(transmission_albedo > 0.0);
+    }
+
+    // See `FIREFLY_SUPPRESSION` in the reference path tracer.
+    void bias_roughness(float roughness_bias) {
+        base.specular_brdf.roughness = lerp(base.specular_brdf.roughness, 1.0, roughness_bias);
+        transmission_brdf.roughness = lerp(transmission_brdf.roughness, 1.0, roughness_bias);
+        clearcoat_brdf.roughness = lerp(clearcoat_brdf.roughness, 1.0, roughness_bias);
+    }
+
+    float3 evaluate_layers(float3 wo, float3 wi, float3 base_value) {
+        if (wo.z <= 0 || wi.z == 0) {
+            return 0;
+        }
+
+        if (wi.z < 0) {
+            const float3 transmission_value =
+                transmission_albedo * transmission_brdf.evaluate(wo, float3(wi.xy, -wi.z)).value;
+            return clearcoat_transmission * sheen_transmission * transmission_value;
+        }
+
+        const float3 below_clearcoat = sheen_brdf.evaluate(wo, wi) + sheen_transmission * base_value;
+        return clearcoat * clearcoat_brdf.evaluate(wo, wi).value + clearcoat_transmission * below_clearcoat;
+    }
+
+    // `wi` may point below the surface for transmission.
+    float3 evaluate(float3 wo, float3 wi) {
+        return evaluate_layers(wo, wi, base.evaluate(wo, wi));
+    }
+
+    float3 evaluate_directional_light(float3 wo, float3 wi) {
+        return evaluate_layers(wo, wi, base.evaluate_directional_light(wo, wi));
+    }
+
+    ExtendedBrdfSample sample(float3 wo, float3 urand) {
+        // Layers are picked with `urand.z`, which is then remapped to [0, 1) for the next choice.
+        float lobe_xi = urand.z;
+
+        ExtendedBrdfSample res;
+
+        // The clearcoat is sampled in proportion to its Fresnel reflectance, and the layers below
+        // with the complement; that cancels out `clearcoat_transmission` in their throughput.
+        const float clearcoat_p = 1.0 - clearcoat_transmission;
+        if (lobe_xi < clearcoat_p) {
+            res.brdf_sample = clearcoat_brdf.sample(wo, urand.xy);
+            res.brdf_sample.value_over_pdf *= clearcoat / clearcoat_p;
+            res.brdf_sample.value *= clearcoat;
+            res.brdf_sample.pdf *= clearcoat_p;
+            res.is_valid = res.brdf_sample.is_valid();
+            return res;
+        }
+        lobe_xi = (lobe_xi - clearcoat_p) / (1.0 - clearcoat_p);
+
+        const float sheen_p = 0.5 * saturate(max3(sheen_brdf.color.r, sheen_brdf.color.g, sheen_brdf.color.b));
+        if (lobe_xi < sheen_p) {
+            res.brdf_sample = sheen_brdf.sample(wo, urand.xy);
+            res.brdf_sample.value_over_pdf /= sheen_p;
+            res.brdf_sample.pdf *= sheen_p;
+            res.is_valid = res.brdf_sample.is_valid();
+            return res;
+        }
+        lobe_xi = (lobe_xi - sheen_p) / (1.0 - sheen_p);
+
+        const float base_mult = sheen_transmission / (1.0 - sheen_p);
+
+        // Weights of the base layer's lobes, like in `LayeredBrdf::sample`
+        const float spec_wt = sRGB_to_luminance(base.energy_preservation.preintegrated_reflection);
+        const float diffuse_wt = sRGB_to_luminance(base.energy_preservation.preintegrated_transmission_fraction * base.diffuse_brdf.albedo);
+        const float transmission_wt = sRGB_to_luminance(transmission_albedo);
+        const float transmission_p = transmission_wt / max(1e-8, spec_wt + diffuse_wt + transmission_wt);
+
+        if (lobe_xi < transmission_p) {
+            res.brdf_sample = transmission_brdf.sample(wo, urand.xy);
+            res.is_valid = res.brdf_sample.is_valid();
+
+            res.brdf_sample.wi.z = -res.brdf_sample.wi.z;
+            res.brdf_sample.value_over_pdf *= transmission_albedo * base_mult / transmission_p;
+            res.brdf_sample.value *= transmission_albedo * sheen_transmission;
+            res.brdf_sample.pdf *= transmission_p * (1.0 - sheen_p);
+            return res;
+        }
+        lobe_xi = (lobe_xi - transmission_p) / (1.0 - transmission_p);
+
+        res.brdf_sample = base.sample(wo, float3(urand.xy, lobe_xi));
+        res.brdf_sample.value_over_pdf *= base_mult / (1.0 - transmission_p);
+        res.brdf_sample.value *= sheen_transmission;
+        res.brdf_sample.pdf *= (1.0 - transmission_p) * (1.0 - sheen_p);
+        res.is_valid = res.brdf_sample.is_valid();
+        return res;
+    }
+};
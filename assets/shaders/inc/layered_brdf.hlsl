@@ -45,9 +45,17 @@ struct LayeredBrdf {
     static LayeredBrdf from_gbuffer_ndotv(
         GbufferData gbuffer,
         float ndotv
+    ) {
+        return from_gbuffer_ndotv_dielectric_f0(gbuffer, ndotv, 0.04);
+    }
+
+    static LayeredBrdf from_gbuffer_ndotv_dielectric_f0(
+        GbufferData gbuffer,
+        float ndotv,
+        float dielectric_f0
     ) {
         SpecularBrdf specular_brdf;
-        specular_brdf.albedo = 0.04;
+        specular_brdf.albedo = dielectric_f0;
         specular_brdf.roughness = gbuffer.roughness;
 
         DiffuseBrdf diffuse_brdf;
@@ -0,0 +1,56 @@
+#ifndef MATERIAL_EXTENSIONS_HLSL
+#define MATERIAL_EXTENSIONS_HLSL
+
+#include "pack_unpack.hlsl"
+
+struct MaterialExtensions;
+
+struct MaterialExtensionsPacked {
+    uint4 data0;
+
+    MaterialExtensions unpack();
+};
+
+// Shading parameters from the `KHR_materials_*` glTF extensions.
+// Only the reference path tracer uses them; see `extended_brdf.hlsl`.
+struct MaterialExtensions {
+    float clearcoat;
+    float clearcoat_roughness;
+    float transmission;
+    float ior;
+    float3 sheen_color;
+    float sheen_roughness;
+
+    // Specular reflectance at normal incidence of a dielectric interface with air
+    float dielectric_f0() {
+        const float r = (ior - 1.0) / (ior + 1.0);
+        return r * r;
+    }
+
+    MaterialExtensionsPacked pack();
+};
+
+MaterialExtensionsPacked MaterialExtensions::pack() {
+    MaterialExtensionsPacked packed;
+    packed.data0.x = pack_2x16f_uint(float2(clearcoat, clearcoat_roughness));
+    packed.data0.y = pack_2x16f_uint(float2(transmission, ior));
+    packed.data0.z = pack_color_888(sheen_color);
+    packed.data0.w = asuint(sheen_roughness);
+    return packed;
+}
+
+MaterialExtensions MaterialExtensionsPacked::unpack() {
+    const float2 clearcoat_roughness = unpack_2x16f_uint(data0.x);
+    const float2 transmission_ior = unpack_2x16f_uint(data0.y);
+
+    MaterialExtensions res;
+    res.clearcoat = clearcoat_roughness.x;
+    res.clearcoat_roughness = clearcoat_roughness.y;
+    res.transmission = transmission_ior.x;
+    res.ior = transmission_ior.y;
+    res.sheen_color = unpack_color_888(data0.z);
+    res.sheen_roughness = asfloat(data0.w);
+    return res;
+}
+
+#endif
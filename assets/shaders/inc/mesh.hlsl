@@ -15,6 +15,7 @@ struct Mesh {
     uint vertex_tangent_offset;
     uint mat_data_offset;
     uint index_offset;
+    uint alpha_tested_first_triangle;
 };
 
 struct Vertex {
@@ -46,6 +47,17 @@ VertexPacked pack_vertex(Vertex v) {
 }
 
 static const uint MESH_MATERIAL_FLAG_EMISSIVE_USED_AS_LIGHT = 1;
+static const uint MESH_MATERIAL_FLAG_ALPHA_MASK = 2;
+static const uint MESH_MATERIAL_FLAG_ALPHA_BLEND = 4;
+
+struct MeshMaterialExtensions {
+    float clearcoat;
+    float clearcoat_roughness;
+    float transmission;
+    float ior;
+    float sheen_color[3];
+    float sheen_roughness;
+};
 
 struct MeshMaterial {
     float base_color_mult[4];
@@ -58,6 +70,8 @@ struct MeshMaterial {
     float emissive[3];
     uint flags;
     float map_transforms[6 * 4];
+    float alpha_cutoff;
+    MeshMaterialExtensions extensions;
 };
 
 float2 transform_material_uv(MeshMaterial mat, float2 uv, uint map_idx) {
@@ -67,5 +81,17 @@ float2 transform_material_uv(MeshMaterial mat, float2 uv, uint map_idx) {
     return mul(rot_scl, uv) + offset;
 }
 
+// Returns false if the surface should be discarded.
+// Blended materials are stochastically transparent: `noise` is a uniform random number in [0, 1).
+bool mesh_material_alpha_test(MeshMaterial mat, float alpha, float noise) {
+    if (mat.flags & MESH_MATERIAL_FLAG_ALPHA_MASK) {
+        return alpha >= mat.alpha_cutoff;
+    } else if (mat.flags & MESH_MATERIAL_FLAG_ALPHA_BLEND) {
+        return alpha > noise;
+    } else {
+        return true;
+    }
+}
+
 
 #endif
\ No newline at end of file
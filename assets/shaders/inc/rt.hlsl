@@ -3,10 +3,21 @@
 
 #include "math_const.hlsl"
 #include "gbuffer.hlsl"
+#include "mesh.hlsl"
+#include "material_extensions.hlsl"
 #include "ray_cone.hlsl"
 
+// Only the reference path tracer uses material extensions, so other passes keep
+// their payloads smaller. Must be defined for all shaders of a pipeline, or none.
+#ifndef RT_PAYLOAD_MATERIAL_EXTENSIONS
+    #define RT_PAYLOAD_MATERIAL_EXTENSIONS 0
+#endif
+
 struct GbufferRayPayload {
     GbufferDataPacked gbuffer_packed;
+#if RT_PAYLOAD_MATERIAL_EXTENSIONS
+    MaterialExtensionsPacked material_ext_packed;
+#endif
     float t;
     RayCone ray_cone;
     uint path_length;
@@ -46,6 +57,18 @@ struct ShadowRayPayload {
     }
 };
 
+// Index of the hit group and miss shader of `rt_is_shadowed` rays. Passes tracing
+// nothing but shadow rays define it as 0, and need only one hit group and miss shader.
+#ifndef RT_SHADOW_RAY_INDEX
+    #define RT_SHADOW_RAY_INDEX 1
+#endif
+
+// Index of the hit triangle in the mesh's ray tracing indices. Opaque triangles are in
+// the first geometry of the BLAS, and alpha-tested ones in the second one.
+uint rt_mesh_triangle_index(Mesh mesh) {
+    return PrimitiveIndex() + (GeometryIndex() == 0 ? 0 : mesh.alpha_tested_first_triangle);
+}
+
 RayDesc new_ray(float3 origin, float3 direction, float tmin, float tmax) {
     RayDesc ray;
     ray.Origin = origin;
@@ -60,10 +83,11 @@ bool rt_is_shadowed(
     RayDesc ray
 ) {
     ShadowRayPayload shadow_payload = ShadowRayPayload::new_hit();
+
     TraceRay(
         acceleration_structure,
         RAY_FLAG_ACCEPT_FIRST_HIT_AND_END_SEARCH | RAY_FLAG_SKIP_CLOSEST_HIT_SHADER,
-        0xff, 0, 0, 1, ray, shadow_payload
+        0xff, RT_SHADOW_RAY_INDEX, 0, RT_SHADOW_RAY_INDEX, ray, shadow_payload
     );
 
     return shadow_payload.is_shadowed;
@@ -72,6 +96,9 @@ bool rt_is_shadowed(
 struct GbufferPathVertex {
     bool is_hit;
     GbufferDataPacked gbuffer_packed;
+#if RT_PAYLOAD_MATERIAL_EXTENSIONS
+    MaterialExtensionsPacked material_ext_packed;
+#endif
     float3 position;
     float ray_t;
 };
@@ -126,6 +153,9 @@ struct GbufferRaytrace {
             res.is_hit = true;
             res.position = ray.Origin + ray.Direction * payload.t;
             res.gbuffer_packed = payload.gbuffer_packed;
+#if RT_PAYLOAD_MATERIAL_EXTENSIONS
+            res.material_ext_packed = payload.material_ext_packed;
+#endif
             res.ray_t = payload.t;
             return res;
         } else {
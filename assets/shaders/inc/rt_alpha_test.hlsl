@@ -0,0 +1,56 @@
+#ifndef RT_ALPHA_TEST_HLSL
+#define RT_ALPHA_TEST_HLSL
+
+#include "samplers.hlsl"
+#include "mesh.hlsl"
+#include "hash.hlsl"
+#include "frame_constants.hlsl"
+#include "rt.hlsl"
+
+// Any-hit shader alpha test of the current candidate triangle. Expects `bindless.hlsl` to be included.
+// Textures are sampled at the top mip, since there's no ray cone to derive a LOD from.
+bool rt_alpha_test_candidate_hit(float2 bary) {
+    const float3 barycentrics = float3(1.0 - bary.x - bary.y, bary.x, bary.y);
+
+    Mesh mesh = meshes[InstanceID()];
+    const uint triangle_index = rt_mesh_triangle_index(mesh);
+
+    // Indices of the triangle
+    uint3 ind = uint3(
+        vertices.Load((triangle_index * 3 + 0) * sizeof(uint) + mesh.index_offset),
+        vertices.Load((triangle_index * 3 + 1) * sizeof(uint) + mesh.index_offset),
+        vertices.Load((triangle_index * 3 + 2) * sizeof(uint) + mesh.index_offset)
+    );
+
+    uint material_id = vertices.Load(ind.x * sizeof(uint) + mesh.vertex_mat_offset);
+    MeshMaterial material = vertices.Load<MeshMaterial>(mesh.mat_data_offset + material_id * sizeof(MeshMaterial));
+
+    if (0 == (material.flags & (MESH_MATERIAL_FLAG_ALPHA_MASK | MESH_MATERIAL_FLAG_ALPHA_BLEND))) {
+        return true;
+    }
+
+    float v_alpha = 1.0;
+    if (mesh.vertex_aux_offset != 0) {
+        float4 vc0 = asfloat(vertices.Load4(ind.x * sizeof(float4) + mesh.vertex_aux_offset));
+        float4 vc1 = asfloat(vertices.Load4(ind.y * sizeof(float4) + mesh.vertex_aux_offset));
+        float4 vc2 = asfloat(vertices.Load4(ind.z * sizeof(float4) + mesh.vertex_aux_offset));
+        v_alpha = vc0.a * barycentrics.x + vc1.a * barycentrics.y + vc2.a * barycentrics.z;
+    }
+
+    float2 uv0 = asfloat(vertices.Load2(ind.x * sizeof(float2) + mesh.vertex_uv_offset));
+    float2 uv1 = asfloat(vertices.Load2(ind.y * sizeof(float2) + mesh.vertex_uv_offset));
+    float2 uv2 = asfloat(vertices.Load2(ind.z * sizeof(float2) + mesh.vertex_uv_offset));
+    float2 uv = uv0 * barycentrics.x + uv1 * barycentrics.y + uv2 * barycentrics.z;
+
+    float2 albedo_uv = transform_material_uv(material, uv, 0);
+    Texture2D albedo_tex = bindless_textures[NonUniformResourceIndex(material.albedo_map)];
+    const float alpha =
+        albedo_tex.SampleLevel(sampler_llr, albedo_uv, 0).a
+        * material.base_color_mult[3]
+        * v_alpha;
+
+    const uint rng = hash4(uint4(DispatchRaysIndex().xy, triangle_index, frame_constants.frame_index));
+    return mesh_material_alpha_test(material, alpha, uint_to_u01_float(rng));
+}
+
+#endif
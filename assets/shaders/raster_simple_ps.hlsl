@@ -5,6 +5,7 @@
 #include "inc/pack_unpack.hlsl"
 #include "inc/bindless.hlsl"
 #include "inc/gbuffer.hlsl"
+#include "inc/hash.hlsl"
 
 struct PsIn {
     [[vk::location(0)]] float4 color: TEXCOORD0;
@@ -36,7 +37,7 @@ struct PsOut {
     float4 velocity: SV_TARGET2;
 };
 
-PsOut main(PsIn ps) {
+PsOut main(PsIn ps, float4 sv_position: SV_Position) {
     Mesh mesh = meshes[push_constants.mesh_index];
     MeshMaterial material = vertices.Load<MeshMaterial>(mesh.mat_data_offset + ps.material_id * sizeof(MeshMaterial));
 
@@ -45,7 +46,10 @@ PsOut main(PsIn ps) {
     float2 albedo_uv = transform_material_uv(material, ps.uv, 0);
     Texture2D albedo_tex = bindless_textures[NonUniformResourceIndex(material.albedo_map)];
     float4 albedo_texel = albedo_tex.SampleBias(sampler_llr, albedo_uv, lod_bias);
-    if (albedo_texel.a < 0.5) {
+
+    const float alpha = albedo_texel.a * material.base_color_mult[3] * ps.color.a;
+    const uint alpha_rng = hash3(uint3(uint2(sv_position.xy), frame_constants.frame_index));
+    if (!mesh_material_alpha_test(material, alpha, uint_to_u01_float(alpha_rng))) {
         discard;
     }
 
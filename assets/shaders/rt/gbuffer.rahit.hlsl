@@ -0,0 +1,16 @@
+#include "../inc/math.hlsl"
+#include "../inc/frame_constants.hlsl"
+#include "../inc/bindless.hlsl"
+#include "../inc/rt.hlsl"
+#include "../inc/rt_alpha_test.hlsl"
+
+struct RayHitAttrib {
+    float2 bary;
+};
+
+[shader("anyhit")]
+void main(inout GbufferRayPayload payload: SV_RayPayload, in RayHitAttrib attrib: SV_IntersectionAttributes) {
+    if (!rt_alpha_test_candidate_hit(attrib.bary)) {
+        IgnoreHit();
+    }
+}
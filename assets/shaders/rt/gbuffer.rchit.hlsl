@@ -52,12 +52,13 @@ void main(inout GbufferRayPayload payload: SV_RayPayload, in RayHitAttrib attrib
 
     //Mesh mesh = meshes[InstanceIndex() / 2];
     Mesh mesh = meshes[InstanceID()];
+    const uint triangle_index = rt_mesh_triangle_index(mesh);
 
     // Indices of the triangle
     uint3 ind = uint3(
-        vertices.Load((PrimitiveIndex() * 3 + 0) * sizeof(uint) + mesh.index_offset),
-        vertices.Load((PrimitiveIndex() * 3 + 1) * sizeof(uint) + mesh.index_offset),
-        vertices.Load((PrimitiveIndex() * 3 + 2) * sizeof(uint) + mesh.index_offset)
+        vertices.Load((triangle_index * 3 + 0) * sizeof(uint) + mesh.index_offset),
+        vertices.Load((triangle_index * 3 + 1) * sizeof(uint) + mesh.index_offset),
+        vertices.Load((triangle_index * 3 + 2) * sizeof(uint) + mesh.index_offset)
     );
 
     Vertex v0 = unpack_vertex(VertexPacked(asfloat(vertices.Load4(ind.x * sizeof(float4) + mesh.vertex_core_offset))));
@@ -197,6 +198,17 @@ void main(inout GbufferRayPayload payload: SV_RayPayload, in RayHitAttrib attrib
 
     //gbuffer.albedo = float3(0.966653, 0.802156, 0.323968); // Au from Mitsuba
 
+#if RT_PAYLOAD_MATERIAL_EXTENSIONS
+    MaterialExtensions material_ext;
+    material_ext.clearcoat = material.extensions.clearcoat;
+    material_ext.clearcoat_roughness = material.extensions.clearcoat_roughness;
+    material_ext.transmission = material.extensions.transmission;
+    material_ext.ior = material.extensions.ior;
+    material_ext.sheen_color = float3(material.extensions.sheen_color);
+    material_ext.sheen_roughness = material.extensions.sheen_roughness;
+    payload.material_ext_packed = material_ext.pack();
+#endif
+
     payload.gbuffer_packed = gbuffer.pack();
     payload.t = RayTCurrent();
 }
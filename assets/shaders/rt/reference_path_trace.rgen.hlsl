@@ -4,7 +4,7 @@
 #include "../inc/gbuffer.hlsl"
 #include "../inc/brdf.hlsl"
 #include "../inc/brdf_lut.hlsl"
-#include "../inc/layered_brdf.hlsl"
+#include "../inc/extended_brdf.hlsl"
 #include "../inc/rt.hlsl"
 #include "../inc/quasi_random.hlsl"
 #include "../inc/bindless_textures.hlsl"
@@ -168,6 +168,7 @@ void main() {
                         ));
 
                     GbufferData gbuffer = primary_hit.gbuffer_packed.unpack();
+                    const MaterialExtensions material_ext = primary_hit.material_ext_packed.unpack();
 
 
                     if (SHOW_ALBEDO) {
@@ -236,20 +237,21 @@ void main() {
                         wo = normalize(wo);
                     }
 
-                    LayeredBrdf brdf = LayeredBrdf::from_gbuffer_ndotv(gbuffer, wo.z);
+                    ExtendedBrdf brdf = ExtendedBrdf::from_gbuffer_ndotv(gbuffer, material_ext, wo.z);
 
                     if (FIREFLY_SUPPRESSION) {
-                        brdf.specular_brdf.roughness = lerp(brdf.specular_brdf.roughness, 1.0, roughness_bias);
+                        brdf.bias_roughness(roughness_bias);
                     }
 
                     if (FURNACE_TEST && FURNACE_TEST_EXCLUDE_DIFFUSE) {
-                        brdf.diffuse_brdf.albedo = 0.0.xxx;
+                        brdf.base.diffuse_brdf.albedo = 0.0.xxx;
                     }
 
                     if (!FURNACE_TEST && !(ONLY_SPECULAR_FIRST_BOUNCE && path_length == 0)) {
                         const float3 brdf_value = brdf.evaluate_directional_light(wo, wi);
                         const float3 light_radiance = select(is_shadowed, 0.0, SUN_COLOR);
-                        total_radiance += throughput * brdf_value * light_radiance * max(0.0, wi.z);
+                        // The BRDF is zero below the surface unless it transmits.
+                        total_radiance += throughput * brdf_value * light_radiance * abs(wi.z);
 
                         if (USE_EMISSIVE) {
                             total_radiance += gbuffer.emissive * throughput;
@@ -273,8 +275,9 @@ void main() {
                                 const float dist_to_light2 = dot(to_light_ws, to_light_ws);
                                 const float3 to_light_norm_ws = to_light_ws * rsqrt(dist_to_light2);
 
+                                const float light_cos = dot(to_light_norm_ws, gbuffer.normal);
                                 const float to_psa_metric =
-                                    max(0.0, dot(to_light_norm_ws, gbuffer.normal))
+                                    max(0.0, select(brdf.has_transmission(), abs(light_cos), light_cos))
                                     * max(0.0, dot(to_light_norm_ws, -light_sample.normal))
                                     / dist_to_light2;
 
@@ -300,7 +303,6 @@ void main() {
                     }
 
                     float3 urand;
-                    BrdfSample brdf_sample = BrdfSample::invalid();
 
                     #if 0
                     if (path_length == 0) {
@@ -324,9 +326,10 @@ void main() {
                             uint_to_u01_float(hash1_mut(rng)));
                     }
 
-                    brdf_sample = brdf.sample(wo, urand);
+                    const ExtendedBrdfSample extended_sample = brdf.sample(wo, urand);
+                    const BrdfSample brdf_sample = extended_sample.brdf_sample;
 
-                    if (brdf_sample.is_valid()) {
+                    if (extended_sample.is_valid) {
                         if (FIREFLY_SUPPRESSION) {
                             roughness_bias = lerp(roughness_bias, 1.0, 0.5 * brdf_sample.approx_roughness);
                         }
@@ -0,0 +1,67 @@
+#include "inc/mesh.hlsl"
+
+// Rows of a 3x4 affine joint transform: `joint * inverse_bind`
+struct SkinningMatrix {
+    float4 rows[3];
+};
+
+[[vk::binding(0)]] RWByteAddressBuffer vertices;
+[[vk::binding(1)]] StructuredBuffer<SkinningMatrix> skinning_matrices;
+[[vk::binding(2)]] cbuffer _ {
+    uint vertex_count;
+    uint src_core_offset;
+    uint src_tangent_offset;
+    uint joint_offset;
+    uint weight_offset;
+    uint dst_core_offset;
+    uint dst_tangent_offset;
+};
+
+float3x4 load_skinning_matrix(uint joint) {
+    SkinningMatrix m = skinning_matrices[joint];
+    return float3x4(m.rows[0], m.rows[1], m.rows[2]);
+}
+
+// Inverse transpose of the linear part of `xform`, up to a positive scale;
+// for transforming normals under non-uniform scaling.
+float3x3 normal_matrix(float3x4 xform) {
+    const float3 r0 = xform[0].xyz;
+    const float3 r1 = xform[1].xyz;
+    const float3 r2 = xform[2].xyz;
+    const float3x3 cofactors = float3x3(cross(r1, r2), cross(r2, r0), cross(r0, r1));
+    return dot(r0, cross(r1, r2)) < 0.0 ? -cofactors : cofactors;
+}
+
+[numthreads(64, 1, 1)]
+void main(uint vertex_idx: SV_DispatchThreadID) {
+    if (vertex_idx >= vertex_count) {
+        return;
+    }
+
+    Vertex v = unpack_vertex(VertexPacked(asfloat(vertices.Load4(vertex_idx * sizeof(float4) + src_core_offset))));
+    float4 tangent = asfloat(vertices.Load4(vertex_idx * sizeof(float4) + src_tangent_offset));
+
+    // Four 16-bit joint indices
+    const uint2 joints_packed = vertices.Load2(vertex_idx * sizeof(uint2) + joint_offset);
+    const uint4 joints = uint4(
+        joints_packed.x & 0xffff, joints_packed.x >> 16,
+        joints_packed.y & 0xffff, joints_packed.y >> 16
+    );
+    const float4 weights = asfloat(vertices.Load4(vertex_idx * sizeof(float4) + weight_offset));
+
+    // Vertices without any weights are not attached to the skeleton
+    if (dot(weights, 1.0.xxxx) > 0.0) {
+        const float3x4 xform =
+            load_skinning_matrix(joints.x) * weights.x
+            + load_skinning_matrix(joints.y) * weights.y
+            + load_skinning_matrix(joints.z) * weights.z
+            + load_skinning_matrix(joints.w) * weights.w;
+
+        v.position = mul(xform, float4(v.position, 1.0));
+        v.normal = normalize(mul(normal_matrix(xform), v.normal));
+        tangent.xyz = normalize(mul(xform, float4(tangent.xyz, 0.0)));
+    }
+
+    vertices.Store4(vertex_idx * sizeof(float4) + dst_core_offset, asuint(pack_vertex(v).data0));
+    vertices.Store4(vertex_idx * sizeof(float4) + dst_tangent_offset, asuint(tangent));
+}
@@ -1,19 +1,163 @@
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use glam::{EulerRot, Quat};
 use kajiya_asset_pipe::*;
-use std::path::PathBuf;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "bake", about = "Kanelbullar")]
 struct Opt {
+    /// A single glTF scene to bake; requires `-o`
     #[structopt(long, parse(from_os_str))]
-    scene: PathBuf,
+    scene: Option<PathBuf>,
 
     #[structopt(long, default_value = "1.0")]
     scale: f32,
 
     #[structopt(short = "o")]
-    output_name: String,
+    output_name: Option<String>,
+
+    /// A RON file listing many assets to bake in parallel
+    #[structopt(long, parse(from_os_str))]
+    manifest: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str), default_value = DEFAULT_OUTPUT_DIR)]
+    output_dir: PathBuf,
+}
+
+/// The `--manifest` file, e.g.:
+///
+/// ```ron
+/// (
+///     assets: [
+///         (
+///             path: "meshes/336_lrm/scene.gltf",
+///             name: "336_lrm",
+///             scale: 0.01,
+///             rotation: (0, 90, 0),
+///             textures: (
+///                 max_size: 1024,
+///                 albedo: (compression: Some(Bc1)),
+///                 emissive: (compression: Some(Bc6h)),
+///                 overrides: {
+///                     "decal_albedo.png": (compression: Some(Bc7), max_size: Some(4096)),
+///                 },
+///             ),
+///             lods: (count: 2, reduction: 0.25),
+///             meshlets: true,
+///         ),
+///     ],
+/// )
+/// ```
+#[derive(Deserialize)]
+struct BakeManifest {
+    assets: Vec<BakeManifestAsset>,
+}
+
+fn default_asset_scale() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+struct BakeManifestAsset {
+    /// Relative to the directory of the manifest
+    path: PathBuf,
+    name: String,
+    #[serde(default = "default_asset_scale")]
+    scale: f32,
+    /// Euler angles in degrees, like in scene files
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default)]
+    textures: TexSettings,
+    #[serde(default)]
+    lods: LodSettings,
+    #[serde(default)]
+    meshlets: bool,
+}
+
+impl BakeManifestAsset {
+    fn into_process_params(self, manifest_dir: &Path) -> MeshAssetProcessParams {
+        MeshAssetProcessParams {
+            scale: self.scale,
+            rotation: Quat::from_euler(
+                EulerRot::YXZ,
+                self.rotation[1].to_radians(),
+                self.rotation[0].to_radians(),
+                self.rotation[2].to_radians(),
+            ),
+            textures: self.textures,
+            lods: self.lods,
+            meshlets: self.meshlets,
+            ..MeshAssetProcessParams::new(manifest_dir.join(self.path), self.name)
+        }
+    }
+}
+
+fn load_manifest(path: &Path) -> Result<Vec<MeshAssetProcessParams>> {
+    let manifest: BakeManifest = ron::de::from_reader(
+        File::open(path).with_context(|| format!("Opening the bake manifest {:?}", path))?,
+    )
+    .with_context(|| format!("Parsing the bake manifest {:?}", path))?;
+
+    let manifest_dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    Ok(manifest
+        .assets
+        .into_iter()
+        .map(|asset| asset.into_process_params(manifest_dir))
+        .collect())
+}
+
+fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+fn print_summary(
+    assets: &[MeshAssetProcessParams],
+    reports: &[Result<MeshAssetBakeReport>],
+) -> usize {
+    println!();
+    println!(
+        "{:<32} {:<10} {:>12} {:>8} {:>12} {:>10} {:>14} {:>9}",
+        "asset", "status", "mesh", "images", "image data", "min PSNR", "ACMR", "time"
+    );
+
+    let mut failed_count = 0;
+    for (asset, report) in assets.iter().zip(reports) {
+        match report {
+            Ok(report) => println!(
+                "{:<32} {:<10} {:>12} {:>8} {:>12} {:>10} {:>14} {:>8.2}s",
+                asset.output_name,
+                if report.up_to_date {
+                    "up to date"
+                } else {
+                    "baked"
+                },
+                format_bytes(report.mesh_bytes),
+                report.images_baked,
+                format_bytes(report.image_bytes),
+                report
+                    .min_image_psnr
+                    .map_or("-".to_owned(), |psnr| format!("{:.1} dB", psnr)),
+                report.vertex_cache.map_or("-".to_owned(), |stats| format!(
+                    "{:.2} -> {:.2}",
+                    stats.acmr_before, stats.acmr_after
+                )),
+                report.duration.as_secs_f32()
+            ),
+            Err(err) => {
+                failed_count += 1;
+                println!("{:<32} {:<10} {:#}", asset.output_name, "failed", err);
+            }
+        }
+    }
+
+    failed_count
 }
 
 fn main() -> Result<()> {
@@ -21,9 +165,28 @@ fn main() -> Result<()> {
 
     let opt = Opt::from_args();
 
-    process_mesh_asset(MeshAssetProcessParams {
-        path: opt.scene,
-        output_name: opt.output_name,
-        scale: opt.scale,
-    })
+    let assets = match (&opt.manifest, &opt.scene) {
+        (Some(manifest), None) => load_manifest(manifest)?,
+        (None, Some(scene)) => {
+            let output_name = opt
+                .output_name
+                .clone()
+                .context("`--scene` requires an output name (`-o`)")?;
+
+            vec![MeshAssetProcessParams {
+                scale: opt.scale,
+                ..MeshAssetProcessParams::new(scene.clone(), output_name)
+            }]
+        }
+        _ => anyhow::bail!("Specify either `--scene` or `--manifest`"),
+    };
+
+    let reports = process_mesh_assets(assets.clone(), &opt.output_dir)?;
+
+    let failed_count = print_summary(&assets, &reports);
+    if failed_count > 0 {
+        anyhow::bail!("Failed to bake {} of {} assets", failed_count, assets.len());
+    }
+
+    Ok(())
 }
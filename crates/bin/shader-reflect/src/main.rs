@@ -0,0 +1,55 @@
+use anyhow::Result;
+use kajiya_backend::{
+    shader_compiler::CompileShader, shader_reflection::dump_shader_interface,
+    vulkan::shader::ShaderDefine,
+};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use turbosloth::*;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "shader-reflect",
+    about = "Prints the descriptor bindings of a shader, as the renderer sees them"
+)]
+struct Opt {
+    /// The shader, e.g. `/shaders/final_blit.hlsl`, or compiled `.spv`
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+
+    /// `cs`, `vs` or `ps`; `lib` for ray tracing shaders
+    #[structopt(long, default_value = "cs")]
+    profile: String,
+
+    /// Preprocessor defines, as `NAME` or `NAME=VALUE`
+    #[structopt(short = "D", number_of_values = 1)]
+    defines: Vec<String>,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+
+    let defines = opt
+        .defines
+        .iter()
+        .map(|define| match define.split_once('=') {
+            Some((name, value)) => ShaderDefine::new(name, value),
+            None => ShaderDefine::flag(define.as_str()),
+        })
+        .collect();
+
+    let lazy_cache = LazyCache::create();
+    let compiled = smol::block_on(
+        CompileShader {
+            path: opt.path,
+            profile: opt.profile,
+            defines,
+        }
+        .into_lazy()
+        .eval(&lazy_cache),
+    )?;
+
+    print!("{}", dump_shader_interface(&compiled.spirv)?);
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use kajiya::{
+    asset::animation::{Animation, NodeHierarchy},
+    world_renderer::{InstanceHandle, MeshHandle, WorldRenderer},
+};
+use kajiya_asset_pipe::GltfHierarchyAsset;
+use kajiya_simple::{Affine3A, Mat4};
+
+struct NodeInstance {
+    node: u32,
+    instance: InstanceHandle,
+    rest_transform: Mat4,
+
+    // Empty unless the mesh is skinned
+    joint_nodes: Vec<u32>,
+}
+
+/// Instances of a glTF scene imported with its node hierarchy, posed by one of its animations.
+/// Nodes referencing the same glTF mesh share a `MeshHandle`.
+pub struct AnimatedHierarchy {
+    hierarchy: NodeHierarchy,
+    animations: Vec<Animation>,
+    root_transform: Mat4,
+    instances: Vec<NodeInstance>,
+    pub active_animation: Option<usize>,
+}
+
+impl AnimatedHierarchy {
+    /// `meshes` are the handles of `asset.meshes`.
+    pub fn new(
+        asset: GltfHierarchyAsset,
+        meshes: &[MeshHandle],
+        world_renderer: &mut WorldRenderer,
+    ) -> anyhow::Result<Self> {
+        if asset.instances.is_empty() {
+            anyhow::bail!("The scene has no meshes");
+        }
+
+        let instances = asset
+            .instances
+            .iter()
+            .map(|inst| NodeInstance {
+                node: inst.node,
+                instance: world_renderer.add_instance(
+                    meshes[inst.mesh as usize],
+                    Affine3A::from_mat4(inst.transform),
+                ),
+                rest_transform: inst.transform,
+                joint_nodes: asset.mesh_joint_nodes[inst.mesh as usize].clone(),
+            })
+            .collect();
+
+        Ok(Self {
+            active_animation: (!asset.animations.is_empty()).then_some(0),
+            hierarchy: asset.hierarchy,
+            animations: asset.animations,
+            root_transform: asset.root_transform,
+            instances,
+        })
+    }
+
+    /// The instance which identifies the hierarchy in the persisted scene.
+    pub fn root_instance(&self) -> InstanceHandle {
+        self.instances[0].instance
+    }
+
+    pub fn instances(&self) -> impl Iterator<Item = InstanceHandle> + '_ {
+        self.instances.iter().map(|inst| inst.instance)
+    }
+
+    pub fn animation_names(&self) -> impl Iterator<Item = String> + '_ {
+        self.animations.iter().enumerate().map(|(i, anim)| {
+            anim.name
+                .clone()
+                .unwrap_or_else(|| format!("Animation {}", i))
+        })
+    }
+
+    /// Poses the instances at time `t` of the active animation, looping it.
+    pub fn update(&self, world_renderer: &mut WorldRenderer, transform: Affine3A, t: f32) {
+        let anim = if let Some(anim) = self.active_animation.and_then(|i| self.animations.get(i)) {
+            anim
+        } else {
+            // Static scenes keep their rest pose
+            for inst in &self.instances {
+                world_renderer.set_instance_transform(
+                    inst.instance,
+                    transform * Affine3A::from_mat4(inst.rest_transform),
+                );
+            }
+            return;
+        };
+
+        let mut local = self.hierarchy.rest_transforms();
+
+        let duration = anim.duration();
+        let t = if duration > 0.0 {
+            t.rem_euclid(duration)
+        } else {
+            0.0
+        };
+        anim.apply(t, &mut local);
+
+        let world = self.hierarchy.world_transforms(&local, Mat4::IDENTITY);
+        let root = Mat4::from(transform) * self.root_transform;
+
+        for inst in &self.instances {
+            if inst.joint_nodes.is_empty() {
+                world_renderer.set_instance_transform(
+                    inst.instance,
+                    Affine3A::from_mat4(root * world[inst.node as usize]),
+                );
+            } else {
+                // Skinned meshes are in the space of the scene, and posed by their joints.
+                world_renderer.set_instance_transform(inst.instance, Affine3A::from_mat4(root));
+
+                let joint_matrices: Vec<Mat4> = inst
+                    .joint_nodes
+                    .iter()
+                    .map(|&node| world[node as usize])
+                    .collect();
+                world_renderer.set_instance_joint_matrices(inst.instance, &joint_matrices);
+            }
+        }
+    }
+
+    pub fn remove(self, world_renderer: &mut WorldRenderer) {
+        for inst in self.instances {
+            world_renderer.remove_instance(inst.instance);
+        }
+    }
+}
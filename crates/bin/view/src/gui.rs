@@ -1,10 +1,13 @@
 use imgui::im_str;
-use kajiya::RenderOverrideFlags;
+use kajiya::{
+    frame_capture::{CaptureSource, FrameCaptureRequest},
+    RenderOverrideFlags,
+};
 use kajiya_simple::*;
 
 use crate::{
     runtime::{RuntimeState, MAX_FPS_LIMIT},
-    PersistedState,
+    PersistedState, FRAME_CAPTURE_DIR, RENDER_GRAPH_DUMP_DIR,
 };
 
 impl RuntimeState {
@@ -15,8 +18,12 @@ impl RuntimeState {
 
         ctx.world_renderer.rg_debug_hook = self.locked_rg_debug_hook.clone();
 
+        let pipeline_failures = ctx.pipeline_failures;
+
         if self.show_gui {
             ctx.imgui.take().unwrap().frame(|ui| {
+                pipeline_failure_overlay(ui, pipeline_failures);
+
                 if imgui::CollapsingHeader::new(im_str!("Tweaks"))
                     .default_open(true)
                     .build(ui)
@@ -174,6 +181,21 @@ impl RuntimeState {
                         let id_token = ui.push_id(idx as i32);
                         ui.text(im_str!("{:?}", elem.source));
 
+                        if let Some(hierarchy) = self.element_hierarchy_mut(elem.instance) {
+                            let names: Vec<imgui::ImString> = hierarchy
+                                .animation_names()
+                                .map(imgui::ImString::from)
+                                .collect();
+
+                            if let Some(active) = hierarchy.active_animation.as_mut() {
+                                let names: Vec<&imgui::ImString> = names.iter().collect();
+
+                                ui.set_next_item_width(200.0);
+                                imgui::ComboBox::new(im_str!("animation"))
+                                    .build_simple_string(ui, active, &names);
+                            }
+                        }
+
                         {
                             ui.set_next_item_width(200.0);
 
@@ -242,7 +264,7 @@ impl RuntimeState {
 
                     if let Some(idx) = element_to_remove {
                         let elem = persisted.scene.elements.remove(idx);
-                        ctx.world_renderer.remove_instance(elem.instance);
+                        self.remove_element_instances(ctx.world_renderer, elem.instance);
                     }
                 }
 
@@ -277,6 +299,11 @@ impl RuntimeState {
                             ui,
                             &mut ctx.world_renderer.render_overrides.material_roughness_scale,
                         );
+
+                    imgui::Drag::<f32>::new(im_str!("LOD error (px)"))
+                        .range(0.0..=16.0)
+                        .speed(0.01)
+                        .build(ui, &mut ctx.world_renderer.lod_error_threshold_px);
                 }
 
                 if imgui::CollapsingHeader::new(im_str!("Sequence"))
@@ -310,6 +337,13 @@ impl RuntimeState {
                         }
                     }
 
+                    // Follows the sequence while it plays
+                    ui.set_next_item_width(100.0);
+                    imgui::Drag::<f32>::new(im_str!("Animation time"))
+                        .range(0.0..=f32::MAX)
+                        .speed(0.01)
+                        .build(ui, &mut self.animation_time);
+
                     enum Cmd {
                         JumpToKey(usize),
                         DeleteKey(usize),
@@ -408,6 +442,75 @@ impl RuntimeState {
                     ui.checkbox(im_str!("Allow pass overlap"), unsafe {
                         &mut kajiya::rg::RG_ALLOW_PASS_OVERLAP
                     });
+
+                    ui.checkbox(im_str!("Alias transient images"), unsafe {
+                        &mut kajiya::rg::RG_ALIAS_TRANSIENT_RESOURCES
+                    });
+
+                    ui.checkbox(im_str!("Cull unused passes"), unsafe {
+                        &mut kajiya::rg::RG_CULL_PASSES
+                    });
+
+                    ui.checkbox(im_str!("Async compute"), unsafe {
+                        &mut kajiya::rg::RG_ASYNC_COMPUTE
+                    });
+
+                    let rg_stats = kajiya::rg::last_frame_stats();
+                    ui.text(format!(
+                        "Render graph passes: {} ({} culled)",
+                        rg_stats.pass_count, rg_stats.culled_pass_count
+                    ));
+                    ui.text(format!(
+                        "Transient images: {} ({:.1} MB)",
+                        rg_stats.transient_image_count,
+                        rg_stats.transient_image_bytes as f64 / (1024.0 * 1024.0)
+                    ));
+                    ui.text(format!(
+                        "Aliased: {} in {} blocks, saving {:.1} MB",
+                        rg_stats.aliased_image_count,
+                        rg_stats.aliased_memory_block_count,
+                        rg_stats.alias_bytes_saved as f64 / (1024.0 * 1024.0)
+                    ));
+                    ui.text(format!(
+                        "Async compute passes: {} ({} submissions)",
+                        rg_stats.async_compute_pass_count, rg_stats.queue_submission_count
+                    ));
+                    ui.text(format!(
+                        "Barriers: {} in {} batches ({} elided, {} merged)",
+                        rg_stats.barrier_count,
+                        rg_stats.barrier_batch_count,
+                        rg_stats.elided_barrier_count,
+                        rg_stats.merged_barrier_count
+                    ));
+
+                    if ui.button(im_str!("Dump render graph"), [0.0, 0.0]) {
+                        kajiya::rg::request_render_graph_dump(RENDER_GRAPH_DUMP_DIR);
+                    }
+
+                    for (label, source) in [
+                        (im_str!("Capture final"), CaptureSource::Final),
+                        (im_str!("Capture HDR"), CaptureSource::HdrAccumulation),
+                        (im_str!("Capture debug target"), CaptureSource::DebugTarget),
+                    ] {
+                        if ui.button(label, [0.0, 0.0]) {
+                            let timestamp = std::time::SystemTime::now()
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .map(|t| t.as_secs())
+                                .unwrap_or_default();
+
+                            if let Err(err) = std::fs::create_dir_all(FRAME_CAPTURE_DIR) {
+                                log::error!("Failed to create {}: {}", FRAME_CAPTURE_DIR, err);
+                            } else {
+                                ctx.world_renderer
+                                    .request_frame_capture(FrameCaptureRequest::new(
+                                        source,
+                                        format!("{}/capture_{}", FRAME_CAPTURE_DIR, timestamp),
+                                    ));
+                            }
+                        }
+                        ui.same_line(0.0);
+                    }
+                    ui.new_line();
                 }
 
                 if imgui::CollapsingHeader::new(im_str!("GPU passes"))
@@ -468,6 +571,31 @@ impl RuntimeState {
                     }
                 }
             });
+        } else if !pipeline_failures.is_empty() {
+            ctx.imgui
+                .take()
+                .unwrap()
+                .frame(|ui| pipeline_failure_overlay(ui, pipeline_failures));
         }
     }
 }
+
+/// Lists shaders which failed to build, even with the rest of the GUI hidden.
+fn pipeline_failure_overlay(ui: &imgui::Ui, failures: &[shader_compiler::ShaderDiagnostic]) {
+    if failures.is_empty() {
+        return;
+    }
+
+    imgui::Window::new(im_str!("Shader errors"))
+        .position(
+            [ui.io().display_size[0] * 0.5, 10.0],
+            imgui::Condition::FirstUseEver,
+        )
+        .always_auto_resize(true)
+        .build(ui, || {
+            ui.text("Rendering with the last working version of:");
+            for failure in failures {
+                ui.text_colored([1.0, 0.4, 0.3, 1.0], failure.to_string());
+            }
+        });
+}
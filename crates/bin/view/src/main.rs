@@ -1,8 +1,10 @@
+mod animation;
 mod gui;
 mod keymap;
 mod misc;
 mod opt;
 mod persisted;
+mod render_sequence;
 mod runtime;
 mod scene;
 mod sequence;
@@ -60,10 +62,16 @@ impl AppState {
     }
 
     fn add_standalone_mesh(&mut self, path: PathBuf, mesh_scale: f32) -> anyhow::Result<()> {
+        let source = if self.runtime.import_gltf_hierarchy {
+            MeshSource::Hierarchy(path)
+        } else {
+            MeshSource::File(path)
+        };
+
         self.runtime.add_mesh_instance(
             &mut self.persisted,
             &mut self.kajiya.world_renderer,
-            MeshSource::File(path),
+            source,
             SceneElementTransform {
                 position: Vec3::ZERO,
                 rotation_euler_degrees: Vec3::ZERO,
@@ -86,6 +94,8 @@ impl AppState {
 }
 
 const APP_STATE_CONFIG_FILE_PATH: &str = "view_state.ron";
+pub const RENDER_GRAPH_DUMP_DIR: &str = "render_graph_dump";
+pub const FRAME_CAPTURE_DIR: &str = "captures";
 
 fn main() -> anyhow::Result<()> {
     set_vfs_mount_point("/meshes", "assets/meshes");
@@ -102,6 +112,14 @@ fn main() -> anyhow::Result<()> {
         persisted.scene = SceneState::default();
     }
 
+    if let Some(dir) = opt.dump_render_graph.as_ref() {
+        kajiya::rg::request_render_graph_dump(dir);
+    }
+
+    if let Some(out_dir) = opt.render_sequence.as_ref() {
+        return render_sequence::render_sequence(persisted, &opt, out_dir);
+    }
+
     let mut state = AppState::new(persisted, &opt)?;
 
     if let Some(scene) = opt.scene.as_ref() {
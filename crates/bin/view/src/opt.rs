@@ -23,6 +23,10 @@ pub struct Opt {
     #[structopt(long, default_value = "1.0")]
     pub mesh_scale: f32,
 
+    /// Import glTF files with their node hierarchy and animations, instead of merging them into one mesh
+    #[structopt(long)]
+    pub gltf_hierarchy: bool,
+
     #[structopt(long)]
     pub no_vsync: bool,
 
@@ -40,4 +44,25 @@ pub struct Opt {
 
     #[structopt(long)]
     pub keymap: Option<PathBuf>,
+
+    /// Write the first frame's render graph as DOT and JSON into this directory
+    #[structopt(long)]
+    pub dump_render_graph: Option<PathBuf>,
+
+    /// Render the persisted camera sequence offline, writing numbered PNGs into this directory
+    #[structopt(long)]
+    pub render_sequence: Option<PathBuf>,
+
+    /// Frame rate of the rendered sequence
+    #[structopt(long, default_value = "60")]
+    pub fps: f32,
+
+    /// Number of frames rendered and averaged for each sequence frame
+    #[structopt(long, default_value = "1")]
+    pub accumulate_frames: u32,
+
+    /// Render the sequence with the reference path tracer, taking this many samples per frame.
+    /// Overrides `--accumulate-frames`
+    #[structopt(long)]
+    pub reference_samples: Option<u32>,
 }
@@ -286,6 +286,9 @@ impl SceneElementTransform {
 pub enum MeshSource {
     File(PathBuf),
     Cache(PathBuf),
+
+    // glTF file imported with its node hierarchy and animations
+    Hierarchy(PathBuf),
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize, PartialEq)]
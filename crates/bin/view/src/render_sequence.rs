@@ -0,0 +1,215 @@
+use anyhow::Context;
+use kajiya_simple::*;
+use std::path::Path;
+
+use crate::{
+    opt::Opt,
+    persisted::{MeshSource, SceneElementTransform},
+    runtime::RuntimeState,
+    PersistedState,
+};
+
+/// Renders the persisted camera sequence without a window, stepping it at a fixed
+/// time step, and writes every frame as `frame_00000.png` etc. into `out_dir`.
+pub fn render_sequence(
+    mut persisted: PersistedState,
+    opt: &Opt,
+    out_dir: &Path,
+) -> anyhow::Result<()> {
+    if opt.fps <= 0.0 {
+        anyhow::bail!("--fps must be positive");
+    }
+
+    let mut kajiya = SimpleMainLoop::builder()
+        .resolution([opt.width, opt.height])
+        .graphics_debugging(opt.graphics_debugging)
+        .physical_device_index(opt.physical_device_index)
+        .temporal_upsampling(opt.temporal_upsampling)
+        .default_log_level(log::LevelFilter::Info)
+        .build_headless()?;
+
+    let mut runtime = RuntimeState::new(&mut persisted, &mut kajiya.world_renderer, opt);
+
+    if let Some(scene) = opt.scene.as_ref() {
+        runtime.load_scene(&mut persisted, &mut kajiya.world_renderer, scene)?;
+    } else if let Some(mesh) = opt.mesh.as_ref() {
+        runtime.add_mesh_instance(
+            &mut persisted,
+            &mut kajiya.world_renderer,
+            if opt.gltf_hierarchy {
+                MeshSource::Hierarchy(mesh.clone())
+            } else {
+                MeshSource::File(mesh.clone())
+            },
+            SceneElementTransform {
+                position: Vec3::ZERO,
+                rotation_euler_degrees: Vec3::ZERO,
+                scale: Vec3::splat(opt.mesh_scale),
+            },
+        )?;
+    }
+
+    if persisted.sequence.get_item(0).is_none() {
+        anyhow::bail!("The camera sequence is empty; add keyframes to it in the viewer first");
+    }
+
+    let mut sequence = persisted.sequence.to_playback();
+    let duration = sequence.duration();
+    let output_frame_count = (duration * opt.fps).floor() as usize + 1;
+
+    // Each output frame comes from `frames_per_output` frames rendered with the same camera.
+    // The path tracer accumulates its samples itself, so only its last frame is written;
+    // otherwise, the frames are averaged.
+    let average_frames = opt.reference_samples.is_none();
+    let frames_per_output = if let Some(samples) = opt.reference_samples {
+        kajiya.world_renderer.render_mode = RenderMode::Reference;
+        samples
+    } else {
+        kajiya.world_renderer.render_mode = RenderMode::Standard;
+        opt.accumulate_frames
+    }
+    .max(1) as usize;
+
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Creating output directory {:?}", out_dir))?;
+
+    log::info!(
+        "Rendering {} frames ({:.2}s at {} fps, {} frames each) into {:?}",
+        output_frame_count,
+        duration,
+        opt.fps,
+        frames_per_output,
+        out_dir
+    );
+
+    let aspect_ratio = kajiya.aspect_ratio();
+    let mut average = FrameAverage::new();
+
+    kajiya
+        .frame_dt(1.0 / (opt.fps * frames_per_output as f32))
+        .run(
+            output_frame_count * frames_per_output,
+            |ctx| {
+                let output_frame = ctx.frame_index / frames_per_output;
+                let t = (output_frame as f32 / opt.fps).min(duration);
+                let value = sequence
+                    .sample(t)
+                    .expect("sequence sampled within its duration");
+
+                if ctx.frame_index % frames_per_output == 0 {
+                    ctx.world_renderer.reset_reference_accumulation = true;
+                }
+
+                runtime.sequence_frame(
+                    &mut persisted,
+                    ctx.world_renderer,
+                    t,
+                    &value,
+                    ctx.render_extent,
+                    aspect_ratio,
+                )
+            },
+            |frame| {
+                let is_last_of_output =
+                    frame.frame_index % frames_per_output == frames_per_output - 1;
+
+                let pixels = if average_frames {
+                    average.add(&frame.pixels);
+                    if !is_last_of_output {
+                        return Ok(());
+                    }
+                    average.take()
+                } else if is_last_of_output {
+                    frame.pixels
+                } else {
+                    return Ok(());
+                };
+
+                let output_frame = frame.frame_index / frames_per_output;
+                let path = out_dir.join(format!("frame_{:05}.png", output_frame));
+
+                image::RgbaImage::from_raw(frame.extent[0], frame.extent[1], pixels)
+                    .context("Unexpected frame size")?
+                    .save(&path)
+                    .with_context(|| format!("Writing {:?}", path))?;
+
+                log::info!(
+                    "Wrote {:?} ({}/{})",
+                    path,
+                    output_frame + 1,
+                    output_frame_count
+                );
+
+                Ok(())
+            },
+        )
+}
+
+/// Running average of the frames rendered for one output frame.
+///
+/// The frames share the camera, so averaging only smooths out what differs between them,
+/// such as noise and sub-pixel jitter. That's done after tonemapping on purpose: only the final
+/// image is read back, and averaging it keeps the output looking like the viewer, with the same
+/// exposure and tone curve. The sRGB encoding is undone first though, so that the average is
+/// taken in linear light.
+struct FrameAverage {
+    mean: Vec<f32>,
+    count: u32,
+
+    /// Linear values of the 8-bit sRGB ones
+    srgb_to_linear: Vec<f32>,
+}
+
+impl FrameAverage {
+    fn new() -> Self {
+        Self {
+            mean: Vec::new(),
+            count: 0,
+            srgb_to_linear: (0..=255u8)
+                .map(|v| srgb_to_linear(v as f32 / 255.0))
+                .collect(),
+        }
+    }
+
+    fn add(&mut self, pixels: &[u8]) {
+        let srgb_to_linear = &self.srgb_to_linear;
+
+        if self.count == 0 {
+            self.mean.clear();
+            self.mean
+                .extend(pixels.iter().map(|&p| srgb_to_linear[p as usize]));
+        } else {
+            let weight = 1.0 / (self.count + 1) as f32;
+            for (mean, &p) in self.mean.iter_mut().zip(pixels) {
+                *mean += (srgb_to_linear[p as usize] - *mean) * weight;
+            }
+        }
+
+        self.count += 1;
+    }
+
+    /// The average of the frames added since the last call, encoded as sRGB again
+    fn take(&mut self) -> Vec<u8> {
+        self.count = 0;
+        self.mean
+            .iter()
+            .map(|&mean| (linear_to_srgb(mean) * 255.0).round() as u8)
+            .collect()
+    }
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
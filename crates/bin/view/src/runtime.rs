@@ -5,15 +5,16 @@ use anyhow::Context;
 use dolly::prelude::*;
 use kajiya::{
     rg::GraphDebugHook,
-    world_renderer::{AddMeshOptions, MeshHandle, WorldRenderer},
+    world_renderer::{AddMeshOptions, InstanceHandle, MeshHandle, WorldRenderer},
 };
 use kajiya_simple::*;
 
 use crate::{
+    animation::AnimatedHierarchy,
     opt::Opt,
     persisted::{MeshSource, SceneElement, SceneElementTransform, ShouldResetPathTracer as _},
     scene::SceneDesc,
-    sequence::{CameraPlaybackSequence, MemOption, SequenceValue},
+    sequence::{CameraPlaybackSequence, MemOption, SequenceFullValue, SequenceValue},
     PersistedState,
 };
 
@@ -50,6 +51,13 @@ pub struct RuntimeState {
     pub sequence_playback_speed: f32,
 
     known_meshes: HashMap<PathBuf, MeshHandle>,
+
+    // Scene elements loaded from `MeshSource::Hierarchy`, keyed by `SceneElement::instance`
+    hierarchies: HashMap<InstanceHandle, AnimatedHierarchy>,
+
+    // Time of the animations; follows the sequence while it plays
+    pub animation_time: f32,
+    pub import_gltf_hierarchy: bool,
 }
 
 enum SequencePlaybackState {
@@ -110,14 +118,21 @@ impl RuntimeState {
             sequence_playback_speed: 1.0,
 
             known_meshes: Default::default(),
+            hierarchies: Default::default(),
+
+            animation_time: 0.0,
+            import_gltf_hierarchy: opt.gltf_hierarchy,
         };
 
         // Load meshes that the persisted scene was referring to
         persisted.scene.elements.retain_mut(|elem| {
-            match res.load_mesh(world_renderer, &elem.source) {
-                Ok(mesh) => {
-                    elem.instance =
-                        world_renderer.add_instance(mesh, elem.transform.affine_transform());
+            match res.instantiate_mesh_source(
+                world_renderer,
+                &elem.source,
+                elem.transform.affine_transform(),
+            ) {
+                Ok(instance) => {
+                    elem.instance = instance;
                     true
                 }
                 Err(err) => {
@@ -143,10 +158,29 @@ impl RuntimeState {
         world_renderer: &mut WorldRenderer,
     ) {
         for elem in persisted.scene.elements.drain(..) {
-            world_renderer.remove_instance(elem.instance);
+            self.remove_element_instances(world_renderer, elem.instance);
         }
     }
 
+    pub fn remove_element_instances(
+        &mut self,
+        world_renderer: &mut WorldRenderer,
+        instance: InstanceHandle,
+    ) {
+        if let Some(hierarchy) = self.hierarchies.remove(&instance) {
+            hierarchy.remove(world_renderer);
+        } else {
+            world_renderer.remove_instance(instance);
+        }
+    }
+
+    pub fn element_hierarchy_mut(
+        &mut self,
+        instance: InstanceHandle,
+    ) -> Option<&mut AnimatedHierarchy> {
+        self.hierarchies.get_mut(&instance)
+    }
+
     pub fn load_scene(
         &mut self,
         persisted: &mut PersistedState,
@@ -166,10 +200,19 @@ impl RuntimeState {
                 .with_context(|| format!("Mesh path: {:?}", instance.mesh))
                 .expect("valid mesh path");
 
-            let mesh = self
-                .load_mesh(world_renderer, &MeshSource::File(mesh_path.clone()))
-                .with_context(|| format!("Mesh path: {:?}", instance.mesh))
-                .expect("valid mesh");
+            let is_gltf = mesh_path
+                .extension()
+                .map_or(false, |ext| ext == "gltf" || ext == "glb");
+
+            let source = if is_gltf
+                && instance
+                    .keep_hierarchy
+                    .unwrap_or(self.import_gltf_hierarchy)
+            {
+                MeshSource::Hierarchy(mesh_path)
+            } else {
+                MeshSource::File(mesh_path)
+            };
 
             let transform = SceneElementTransform {
                 position: instance.position.into(),
@@ -177,10 +220,13 @@ impl RuntimeState {
                 scale: instance.scale.into(),
             };
 
-            let render_instance = world_renderer.add_instance(mesh, transform.affine_transform());
+            let render_instance = self
+                .instantiate_mesh_source(world_renderer, &source, transform.affine_transform())
+                .with_context(|| format!("Mesh path: {:?}", instance.mesh))
+                .expect("valid mesh");
 
             persisted.scene.elements.push(SceneElement {
-                source: MeshSource::File(mesh_path),
+                source,
                 instance: render_instance,
                 transform,
             });
@@ -399,22 +445,53 @@ impl RuntimeState {
         }*/
     }
 
-    fn update_objects(&mut self, persisted: &mut PersistedState, ctx: &mut FrameContext) {
+    fn update_objects(&mut self, persisted: &PersistedState, world_renderer: &mut WorldRenderer) {
         let emissive_toggle_mult = if persisted.light.enable_emissive {
             1.0
         } else {
             0.0
         };
 
+        if let SequencePlaybackState::Playing { t, .. } = &self.sequence_playback_state {
+            self.animation_time = t.max(0.0);
+        }
+
         for elem in persisted.scene.elements.iter() {
-            ctx.world_renderer
-                .get_instance_dynamic_parameters_mut(elem.instance)
-                .emissive_multiplier = persisted.light.emissive_multiplier * emissive_toggle_mult;
-            ctx.world_renderer
-                .set_instance_transform(elem.instance, elem.transform.affine_transform());
+            let emissive_multiplier = persisted.light.emissive_multiplier * emissive_toggle_mult;
+
+            if let Some(hierarchy) = self.hierarchies.get(&elem.instance) {
+                for instance in hierarchy.instances() {
+                    world_renderer
+                        .get_instance_dynamic_parameters_mut(instance)
+                        .emissive_multiplier = emissive_multiplier;
+                }
+
+                hierarchy.update(
+                    world_renderer,
+                    elem.transform.affine_transform(),
+                    self.animation_time,
+                );
+            } else {
+                world_renderer
+                    .get_instance_dynamic_parameters_mut(elem.instance)
+                    .emissive_multiplier = emissive_multiplier;
+                world_renderer
+                    .set_instance_transform(elem.instance, elem.transform.affine_transform());
+            }
         }
     }
 
+    fn update_exposure(persisted: &PersistedState, world_renderer: &mut WorldRenderer) {
+        world_renderer.ev_shift = persisted.exposure.ev_shift;
+        world_renderer.contrast = persisted.exposure.contrast;
+        world_renderer.dynamic_exposure.enabled = persisted.exposure.use_dynamic_adaptation;
+        world_renderer.dynamic_exposure.speed_log2 = persisted.exposure.dynamic_adaptation_speed;
+        world_renderer.dynamic_exposure.histogram_clipping.low =
+            persisted.exposure.dynamic_adaptation_low_clip;
+        world_renderer.dynamic_exposure.histogram_clipping.high =
+            persisted.exposure.dynamic_adaptation_high_clip;
+    }
+
     pub fn frame(
         &mut self,
         mut ctx: FrameContext,
@@ -436,7 +513,7 @@ impl RuntimeState {
 
         self.do_gui(persisted, &mut ctx);
         self.update_lights(persisted, &mut ctx);
-        self.update_objects(persisted, &mut ctx);
+        self.update_objects(persisted, ctx.world_renderer);
         self.update_sun(persisted, &mut ctx);
 
         self.update_camera(persisted, &ctx);
@@ -463,15 +540,7 @@ impl RuntimeState {
             };
         }
 
-        ctx.world_renderer.ev_shift = persisted.exposure.ev_shift;
-        ctx.world_renderer.contrast = persisted.exposure.contrast;
-        ctx.world_renderer.dynamic_exposure.enabled = persisted.exposure.use_dynamic_adaptation;
-        ctx.world_renderer.dynamic_exposure.speed_log2 =
-            persisted.exposure.dynamic_adaptation_speed;
-        ctx.world_renderer.dynamic_exposure.histogram_clipping.low =
-            persisted.exposure.dynamic_adaptation_low_clip;
-        ctx.world_renderer.dynamic_exposure.histogram_clipping.high =
-            persisted.exposure.dynamic_adaptation_high_clip;
+        Self::update_exposure(persisted, ctx.world_renderer);
 
         if persisted.should_reset_path_tracer(&orig_persisted_state)
             || ctx.world_renderer.render_overrides != orig_render_overrides
@@ -507,6 +576,47 @@ impl RuntimeState {
         }
     }
 
+    /// Poses the camera, the sun and animations at time `t` of a sequence, without any smoothing.
+    /// Used when rendering sequences offline, where there's no user input to respond to.
+    pub fn sequence_frame(
+        &mut self,
+        persisted: &mut PersistedState,
+        world_renderer: &mut WorldRenderer,
+        t: f32,
+        value: &SequenceFullValue,
+        render_extent: [u32; 2],
+        aspect_ratio: f32,
+    ) -> WorldFrameDesc {
+        self.animation_time = t;
+
+        persisted.camera.position = value.camera_position;
+        persisted.camera.rotation =
+            dolly::util::look_at::<dolly::handedness::RightHanded>(value.camera_direction);
+        persisted
+            .light
+            .sun
+            .controller
+            .set_towards_sun(value.towards_sun);
+
+        self.sun_direction_interp = persisted.light.sun.controller.towards_sun();
+        world_renderer.sun_size_multiplier = persisted.light.sun.size_multiplier;
+
+        self.update_objects(persisted, world_renderer);
+        Self::update_exposure(persisted, world_renderer);
+
+        let lens = CameraLens {
+            aspect_ratio,
+            vertical_fov: persisted.camera.vertical_fov,
+            ..Default::default()
+        };
+
+        WorldFrameDesc {
+            camera_matrices: (persisted.camera.position, persisted.camera.rotation).through(&lens),
+            render_extent,
+            sun_direction: self.sun_direction_interp,
+        }
+    }
+
     pub fn is_sequence_playing(&self) -> bool {
         matches!(
             &self.sequence_playback_state,
@@ -576,6 +686,8 @@ impl RuntimeState {
                 .sun
                 .controller
                 .set_towards_sun(exact_item.value.towards_sun.unwrap_or(value.towards_sun));
+
+            self.animation_time = exact_item.t.max(0.0);
         }
 
         self.active_camera_key = Some(idx);
@@ -609,40 +721,76 @@ impl RuntimeState {
 
         let path = match source {
             MeshSource::File(path) => {
-                fn calculate_hash(t: &PathBuf) -> u64 {
-                    let mut s = DefaultHasher::new();
-                    t.hash(&mut s);
-                    s.finish()
-                }
-
-                let path_hash = match path.canonicalize() {
-                    Ok(canonical) => calculate_hash(&canonical),
-                    Err(_) => calculate_hash(path),
-                };
-
-                let cached_mesh_name = format!("{:8.8x}", path_hash);
+                let cached_mesh_name = cached_mesh_name(path);
                 let cached_mesh_path = PathBuf::from(format!("/cache/{}.mesh", cached_mesh_name));
 
-                if !canonical_path_from_vfs(&cached_mesh_path).map_or(false, |path| path.exists()) {
-                    kajiya_asset_pipe::process_mesh_asset(
-                        kajiya_asset_pipe::MeshAssetProcessParams {
-                            path: path.clone(),
-                            output_name: cached_mesh_name,
-                            scale: 1.0,
-                        },
-                    )?;
-                }
+                // Re-bakes the mesh if it's missing from the cache, or if its sources changed.
+                kajiya_asset_pipe::process_mesh_asset(
+                    kajiya_asset_pipe::MeshAssetProcessParams::new(path.clone(), cached_mesh_name),
+                )?;
 
                 cached_mesh_path
             }
             MeshSource::Cache(path) => path.clone(),
+            MeshSource::Hierarchy(_) => {
+                anyhow::bail!("{:?} does not refer to a single mesh", source)
+            }
         };
 
-        Ok(*self.known_meshes.entry(path.clone()).or_insert_with(|| {
+        Ok(self.load_baked_mesh(world_renderer, path))
+    }
+
+    fn load_baked_mesh(&mut self, world_renderer: &mut WorldRenderer, path: PathBuf) -> MeshHandle {
+        *self.known_meshes.entry(path.clone()).or_insert_with(|| {
             world_renderer
                 .add_baked_mesh(path, AddMeshOptions::new())
                 .unwrap()
-        }))
+        })
+    }
+
+    fn load_hierarchy(
+        &mut self,
+        world_renderer: &mut WorldRenderer,
+        path: &PathBuf,
+    ) -> anyhow::Result<AnimatedHierarchy> {
+        log::info!("Loading a glTF hierarchy from {:?}", path);
+
+        let asset = kajiya_asset_pipe::process_gltf_hierarchy_asset(
+            kajiya_asset_pipe::MeshAssetProcessParams::new(path.clone(), cached_mesh_name(path)),
+        )?;
+
+        let meshes: Vec<MeshHandle> = asset
+            .meshes
+            .iter()
+            .map(|name| {
+                self.load_baked_mesh(
+                    world_renderer,
+                    PathBuf::from(format!("/cache/{}.mesh", name)),
+                )
+            })
+            .collect();
+
+        AnimatedHierarchy::new(asset, &meshes, world_renderer)
+    }
+
+    /// Creates the render instances for a scene element, returning the one to identify it with.
+    pub(crate) fn instantiate_mesh_source(
+        &mut self,
+        world_renderer: &mut WorldRenderer,
+        source: &MeshSource,
+        transform: Affine3A,
+    ) -> anyhow::Result<InstanceHandle> {
+        if let MeshSource::Hierarchy(path) = source {
+            let hierarchy = self.load_hierarchy(world_renderer, path)?;
+            let instance = hierarchy.root_instance();
+            hierarchy.update(world_renderer, transform, self.animation_time);
+            self.hierarchies.insert(instance, hierarchy);
+
+            Ok(instance)
+        } else {
+            let mesh = self.load_mesh(world_renderer, source)?;
+            Ok(world_renderer.add_instance(mesh, transform))
+        }
     }
 
     pub(crate) fn add_mesh_instance(
@@ -652,8 +800,8 @@ impl RuntimeState {
         source: MeshSource,
         transform: SceneElementTransform,
     ) -> anyhow::Result<()> {
-        let mesh = self.load_mesh(world_renderer, &source)?;
-        let inst = world_renderer.add_instance(mesh, transform.affine_transform());
+        let inst =
+            self.instantiate_mesh_source(world_renderer, &source, transform.affine_transform())?;
 
         persisted.scene.elements.push(SceneElement {
             source,
@@ -700,10 +848,16 @@ impl RuntimeState {
                         }
                         "gltf" | "glb" => {
                             // Mesh
+                            let source = if self.import_gltf_hierarchy {
+                                MeshSource::Hierarchy(path.clone())
+                            } else {
+                                MeshSource::File(path.clone())
+                            };
+
                             if let Err(err) = self.add_mesh_instance(
                                 persisted,
                                 world_renderer,
-                                MeshSource::File(path.clone()),
+                                source,
                                 SceneElementTransform::IDENTITY,
                             ) {
                                 log::error!("{:#}", err);
@@ -718,6 +872,21 @@ impl RuntimeState {
     }
 }
 
+fn cached_mesh_name(path: &PathBuf) -> String {
+    fn calculate_hash(t: &PathBuf) -> u64 {
+        let mut s = DefaultHasher::new();
+        t.hash(&mut s);
+        s.finish()
+    }
+
+    let path_hash = match path.canonicalize() {
+        Ok(canonical) => calculate_hash(&canonical),
+        Err(_) => calculate_hash(path),
+    };
+
+    format!("{:8.8x}", path_hash)
+}
+
 #[derive(PartialEq, Eq)]
 pub enum LeftClickEditMode {
     MoveSun,
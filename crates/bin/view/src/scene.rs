@@ -15,4 +15,9 @@ pub struct SceneInstanceDesc {
     #[serde(default)]
     pub rotation: [f32; 3],
     pub mesh: String,
+
+    /// Instance the nodes of a glTF file separately, sharing meshes between them,
+    /// rather than merging it into one mesh. Defaults to `--gltf-hierarchy`.
+    #[serde(default)]
+    pub keep_hierarchy: Option<bool>,
 }
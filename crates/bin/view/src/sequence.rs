@@ -170,6 +170,10 @@ pub struct CameraPlaybackSequence {
 }
 
 impl CameraPlaybackSequence {
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+
     pub fn sample(&mut self, t: f32) -> Option<SequenceFullValue> {
         if t > self.duration {
             return None;
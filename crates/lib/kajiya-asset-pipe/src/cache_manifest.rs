@@ -0,0 +1,250 @@
+use kajiya_asset::mesh::FLAT_ASSET_FORMAT_VERSION;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::{Context as _, Result};
+
+/// Name of the manifest file in the output directory of the bake pipeline
+pub const CACHE_MANIFEST_FILE_NAME: &str = "manifest.ron";
+
+/// A source file of a baked artifact, with a hash of its contents.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SourceHash {
+    /// `None` for data embedded in another source, such as images inside a `.glb`.
+    pub path: Option<PathBuf>,
+    pub hash: u64,
+}
+
+impl SourceHash {
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let bytes = std::fs::read(&path).with_context(|| format!("Reading {:?}", path))?;
+
+        Ok(Self {
+            hash: content_hash(&bytes),
+            path: Some(path),
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            path: None,
+            hash: content_hash(bytes),
+        }
+    }
+}
+
+fn content_hash(bytes: &[u8]) -> u64 {
+    wyhash::wyhash(bytes, 0)
+}
+
+/// The content hash of a source file, valid for as long as its size and modification time match.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+struct SourceStamp {
+    len: u64,
+    modified: SystemTime,
+    hash: u64,
+}
+
+/// Everything a baked artifact depends on. The artifact is re-baked when any of it changes.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct BakeInputs {
+    /// Changes to the baked file format make every artifact in the cache stale
+    pub format_version: u32,
+    pub sources: Vec<SourceHash>,
+
+    /// Import parameters, such as the scale of meshes or `TexParams` of images
+    pub params: String,
+}
+
+impl BakeInputs {
+    pub fn new(sources: Vec<SourceHash>, params: String) -> Self {
+        Self {
+            format_version: FLAT_ASSET_FORMAT_VERSION,
+            sources,
+            params,
+        }
+    }
+}
+
+/// Records the inputs of every artifact in a cache directory, keyed by the artifact's file name.
+#[derive(Serialize, Deserialize, Default)]
+pub struct CacheManifest {
+    #[serde(skip)]
+    path: PathBuf,
+
+    /// Whether anything was recorded since the manifest was loaded
+    #[serde(skip)]
+    dirty: bool,
+
+    artifacts: BTreeMap<String, BakeInputs>,
+
+    /// Hashes of the source files read so far, so that unchanged ones aren't read again
+    #[serde(default)]
+    sources: BTreeMap<PathBuf, SourceStamp>,
+}
+
+impl CacheManifest {
+    /// Loads the manifest of `cache_dir`. A missing or unreadable manifest
+    /// is treated as empty, so everything gets re-baked.
+    pub fn load(cache_dir: &Path) -> Self {
+        let path = cache_dir.join(CACHE_MANIFEST_FILE_NAME);
+
+        let manifest = File::open(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Ok(ron::de::from_reader(file)?));
+
+        let manifest = match manifest {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                if path.exists() {
+                    log::warn!("Ignoring the asset cache manifest {:?}: {:#}", path, err);
+                }
+                Self::default()
+            }
+        };
+
+        Self { path, ..manifest }
+    }
+
+    /// Writes the manifest back, unless nothing changed since it was loaded.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        // Written to a temporary file first, so that an interrupted save doesn't corrupt the manifest.
+        let tmp_path = self.path.with_extension("ron.tmp");
+        ron::ser::to_writer_pretty(File::create(&tmp_path)?, self, Default::default())?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Hash of the source file at `path`, if it had the same size and modification time
+    /// when it was hashed.
+    fn source_hash(&self, path: &Path, len: u64, modified: SystemTime) -> Option<u64> {
+        self.sources
+            .get(path)
+            .filter(|stamp| stamp.len == len && stamp.modified == modified)
+            .map(|stamp| stamp.hash)
+    }
+
+    fn record_source(&mut self, path: PathBuf, stamp: SourceStamp) {
+        self.sources.insert(path, stamp);
+        self.dirty = true;
+    }
+
+    /// Returns true if `artifact` exists and was baked from `inputs`.
+    pub fn is_up_to_date(&self, artifact: &Path, inputs: &BakeInputs) -> bool {
+        artifact.exists() && self.artifacts.get(&Self::artifact_key(artifact)) == Some(inputs)
+    }
+
+    pub fn record(&mut self, artifact: &Path, inputs: BakeInputs) {
+        self.artifacts.insert(Self::artifact_key(artifact), inputs);
+        self.dirty = true;
+    }
+
+    fn artifact_key(artifact: &Path) -> String {
+        artifact
+            .file_name()
+            .unwrap_or(artifact.as_os_str())
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Like `SourceHash::from_file`, but only reads and hashes the file if its size or
+/// modification time differ from when it was last hashed. The manifest is only locked
+/// to look up and record the hash, and not while the file is read.
+pub fn hash_source_file(
+    manifest: &Mutex<CacheManifest>,
+    path: impl Into<PathBuf>,
+) -> Result<SourceHash> {
+    let path = path.into();
+    let metadata = std::fs::metadata(&path).with_context(|| format!("Reading {:?}", path))?;
+
+    // Not available on every platform, in which case the file is always hashed
+    let modified = if let Ok(modified) = metadata.modified() {
+        modified
+    } else {
+        return SourceHash::from_file(path);
+    };
+
+    let cached_hash = manifest
+        .lock()
+        .unwrap()
+        .source_hash(&path, metadata.len(), modified);
+
+    if let Some(hash) = cached_hash {
+        return Ok(SourceHash {
+            hash,
+            path: Some(path),
+        });
+    }
+
+    let source = SourceHash::from_file(&path)?;
+    manifest.lock().unwrap().record_source(
+        path,
+        SourceStamp {
+            len: metadata.len(),
+            modified,
+            hash: source.hash,
+        },
+    );
+
+    Ok(source)
+}
+
+#[test]
+fn test_changed_inputs_are_stale() {
+    let dir = std::env::temp_dir().join(format!("kajiya-cache-manifest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let artifact = dir.join("test.mesh");
+    File::create(&artifact).unwrap();
+
+    let inputs = BakeInputs::new(vec![SourceHash::from_bytes(b"v1")], "scale: 1.0".into());
+
+    let mut manifest = CacheManifest::default();
+    assert!(!manifest.is_up_to_date(&artifact, &inputs));
+
+    manifest.record(&artifact, inputs.clone());
+    assert!(manifest.is_up_to_date(&artifact, &inputs));
+
+    let edited = BakeInputs::new(vec![SourceHash::from_bytes(b"v2")], "scale: 1.0".into());
+    assert!(!manifest.is_up_to_date(&artifact, &edited));
+
+    let rescaled = BakeInputs::new(vec![SourceHash::from_bytes(b"v1")], "scale: 2.0".into());
+    assert!(!manifest.is_up_to_date(&artifact, &rescaled));
+
+    // Unchanged sources are not read again
+    let manifest = Mutex::new(manifest);
+    let source = dir.join("test.gltf");
+    std::fs::write(&source, b"v1").unwrap();
+    let hash = hash_source_file(&manifest, &source).unwrap();
+    assert_eq!(hash, SourceHash::from_file(&source).unwrap());
+
+    manifest
+        .lock()
+        .unwrap()
+        .sources
+        .get_mut(&source)
+        .unwrap()
+        .hash = 0;
+    assert_eq!(hash_source_file(&manifest, &source).unwrap().hash, 0);
+
+    std::fs::write(&source, b"v2 edited").unwrap();
+    assert_eq!(
+        hash_source_file(&manifest, &source).unwrap(),
+        SourceHash::from_file(&source).unwrap()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,101 +1,714 @@
 use async_channel::unbounded;
 use async_executor::Executor;
 use easy_parallel::Parallel;
-use glam::Quat;
-use kajiya_asset::mesh::{pack_triangle_mesh, GpuImage, LoadGltfScene, PackedTriMesh};
+use glam::{Mat4, Quat};
+use kajiya_asset::{
+    animation::{Animation, NodeHierarchy},
+    image::ImageSource,
+    mesh::{
+        pack_triangle_mesh, GltfMeshInstance, GpuImage, ImportWarning, LoadGltfHierarchy,
+        LoadGltfScene, MeshMaterialMap, MeshPackParams, PackedTriMesh, TexCompressionMode,
+        TexMapKind, TexParams, TriangleMesh, VertexCacheStats, DEFAULT_TEX_MAX_SIZE,
+    },
+    simplify::LodChainParams,
+};
+use kajiya_backend::write_atomically;
+use serde::{Deserialize, Serialize};
 use smol::future;
-use std::{collections::HashSet, fs::File, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    io::Write as _,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use turbosloth::*;
 
 use anyhow::Result;
 
+pub mod cache_manifest;
+use cache_manifest::{hash_source_file, BakeInputs, CacheManifest, SourceHash};
+
+/// Where baked assets go unless specified otherwise. The runtime loads them from `/cache`.
+pub const DEFAULT_OUTPUT_DIR: &str = "cache";
+
+/// Overrides of the texture parameters chosen by the importer.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TexSettings {
+    /// Block-compress the textures which the importer deems compressible
+    pub compress: bool,
+
+    /// Generate mip chains for the textures which the importer deems mipmapped
+    pub mips: bool,
+
+    /// Limit of the width and height of textures; larger ones are downsampled
+    pub max_size: u32,
+
+    /// Per map type
+    pub albedo: TexOverride,
+    pub normal: TexOverride,
+    pub spec: TexOverride,
+    pub emissive: TexOverride,
+
+    /// Per texture, by file name, e.g. `"wood_albedo.png"`. Take precedence over the above.
+    pub overrides: BTreeMap<String, TexOverride>,
+}
+
+impl Default for TexSettings {
+    fn default() -> Self {
+        Self {
+            compress: true,
+            mips: true,
+            max_size: DEFAULT_TEX_MAX_SIZE,
+            albedo: Default::default(),
+            normal: Default::default(),
+            spec: Default::default(),
+            emissive: Default::default(),
+            overrides: Default::default(),
+        }
+    }
+}
+
+impl TexSettings {
+    fn apply(&self, params: TexParams, kind: TexMapKind, source: &ImageSource) -> TexParams {
+        let mut params = TexParams {
+            compression: if self.compress {
+                params.compression
+            } else {
+                TexCompressionMode::None
+            },
+            use_mips: params.use_mips && self.mips,
+            max_size: self.max_size,
+            ..params
+        };
+
+        let kind_override = match kind {
+            TexMapKind::Albedo => &self.albedo,
+            TexMapKind::Normal => &self.normal,
+            TexMapKind::Spec => &self.spec,
+            TexMapKind::Emissive => &self.emissive,
+        };
+        kind_override.apply(&mut params);
+
+        let file_name = match source {
+            ImageSource::File(path) => path.file_name().and_then(|name| name.to_str()),
+            ImageSource::Memory(_) => None,
+        };
+        if let Some(file_override) = file_name.and_then(|name| self.overrides.get(name)) {
+            file_override.apply(&mut params);
+        }
+
+        params
+    }
+}
+
+/// Texture parameters to use instead of those of the importer and `TexSettings`.
+/// Fields left unset don't override anything.
+#[derive(Clone, Copy, Default, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TexOverride {
+    pub compression: Option<TexCompression>,
+    pub max_size: Option<u32>,
+    pub mips: Option<bool>,
+}
+
+impl TexOverride {
+    fn apply(&self, params: &mut TexParams) {
+        if let Some(compression) = self.compression {
+            params.compression = compression.mode();
+        }
+        if let Some(max_size) = self.max_size {
+            params.max_size = max_size;
+        }
+        if let Some(mips) = self.mips {
+            params.use_mips = mips;
+        }
+    }
+}
+
+/// Block compression formats; `Bc4` keeps only the red channel, and `Bc5` red and green.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum TexCompression {
+    None,
+    Bc1,
+    Bc3,
+    Bc4,
+    Bc5,
+    /// For high dynamic range; LDR images are converted to linear values
+    Bc6h,
+    Bc7,
+}
+
+impl TexCompression {
+    fn mode(self) -> TexCompressionMode {
+        match self {
+            TexCompression::None => TexCompressionMode::None,
+            TexCompression::Bc1 => TexCompressionMode::Rgb,
+            TexCompression::Bc3 => TexCompressionMode::RgbaFast,
+            TexCompression::Bc4 => TexCompressionMode::R,
+            TexCompression::Bc5 => TexCompressionMode::Rg,
+            TexCompression::Bc6h => TexCompressionMode::RgbHdr,
+            TexCompression::Bc7 => TexCompressionMode::Rgba,
+        }
+    }
+}
+
+/// The simplified levels of detail generated for each mesh.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LodSettings {
+    /// Number of levels in addition to the full-detail mesh; zero disables LODs
+    pub count: usize,
+
+    /// Index count of each level relative to the previous one
+    pub reduction: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        let params = LodChainParams::default();
+        Self {
+            count: params.max_lods,
+            reduction: params.reduction,
+        }
+    }
+}
+
+impl LodSettings {
+    fn chain_params(&self) -> LodChainParams {
+        LodChainParams {
+            max_lods: self.count,
+            reduction: self.reduction,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct MeshAssetProcessParams {
     pub path: PathBuf,
     pub output_name: String,
     pub scale: f32,
+    pub rotation: Quat,
+    pub textures: TexSettings,
+    pub lods: LodSettings,
+
+    /// Partition meshes into meshlets, for GPU culling
+    pub meshlets: bool,
+}
+
+impl MeshAssetProcessParams {
+    pub fn new(path: impl Into<PathBuf>, output_name: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            output_name: output_name.into(),
+            scale: 1.0,
+            rotation: Quat::IDENTITY,
+            textures: Default::default(),
+            lods: Default::default(),
+            meshlets: false,
+        }
+    }
+
+    fn pack_params(&self) -> MeshPackParams {
+        MeshPackParams {
+            lods: self.lods.chain_params(),
+            meshlets: self.meshlets,
+        }
+    }
+}
+
+/// What baking a mesh asset produced.
+#[derive(Clone, Debug)]
+pub struct MeshAssetBakeReport {
+    /// The mesh was found up to date in the cache, and nothing was baked
+    pub up_to_date: bool,
+
+    /// Size of the baked `.mesh` file
+    pub mesh_bytes: u64,
+
+    /// Number and total size of the `.image` files baked for this mesh.
+    /// Images which were up to date, or baked for a previous mesh in the batch, are not included.
+    pub images_baked: usize,
+    pub image_bytes: u64,
+
+    /// Lowest PSNR of the images baked, in decibels; `None` if none was compressed
+    pub min_image_psnr: Option<f32>,
+
+    /// `None` if the mesh was up to date
+    pub vertex_cache: Option<VertexCacheStats>,
+
+    pub duration: Duration,
 }
 
+/// State shared by the assets baked together.
+#[derive(Clone)]
+struct BakeContext {
+    executor: Arc<Executor<'static>>,
+    lazy_cache: Arc<LazyCache>,
+    manifest: Arc<Mutex<CacheManifest>>,
+    output_dir: Arc<PathBuf>,
+}
+
+impl BakeContext {
+    fn new(output_dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(output_dir)?;
+
+        Ok(Self {
+            executor: Arc::new(Executor::new()),
+            lazy_cache: LazyCache::create(),
+            manifest: Arc::new(Mutex::new(CacheManifest::load(output_dir))),
+            output_dir: Arc::new(output_dir.to_owned()),
+        })
+    }
+
+    /// Runs `future` to completion, processing the tasks spawned onto the executor on all cores.
+    fn run<T>(&self, future: impl Future<Output = T>) -> T {
+        let (signal, shutdown) = unbounded::<()>();
+
+        let (_, res) = Parallel::new()
+            .each(0..num_cpus::get(), |_| {
+                future::block_on(self.executor.run(shutdown.recv()))
+            })
+            .finish(|| {
+                let res = future::block_on(future);
+                drop(signal);
+                res
+            });
+
+        res
+    }
+
+    fn save_manifest(&self) -> Result<()> {
+        self.manifest.lock().unwrap().save()
+    }
+
+    fn mesh_path(&self, name: &str) -> PathBuf {
+        self.output_dir.join(format!("{}.mesh", name))
+    }
+
+    fn hierarchy_path(&self, name: &str) -> PathBuf {
+        self.output_dir.join(format!("{}.hierarchy", name))
+    }
+
+    fn image_path(&self, img: &Lazy<GpuImage::Proto>) -> PathBuf {
+        self.output_dir
+            .join(format!("{:8.8x}.image", img.identity()))
+    }
+}
+
+/// Bakes the glTF scene at `opt.path` into `cache/{output_name}.mesh` and its images.
+/// Nothing is done if the cache manifest shows the baked mesh to be up to date.
 pub fn process_mesh_asset(opt: MeshAssetProcessParams) -> Result<()> {
-    let lazy_cache = LazyCache::create();
+    let ctx = BakeContext::new(Path::new(DEFAULT_OUTPUT_DIR))?;
+    let report = ctx.run(bake_mesh_asset(ctx.clone(), opt))?;
+
+    if report.up_to_date {
+        println!("Up to date.");
+    } else {
+        println!("Done.");
+    }
+
+    // Even if up to date, the hashes of sources may have been refreshed
+    ctx.save_manifest()?;
+
+    Ok(())
+}
+
+/// Bakes many mesh assets into `output_dir` in parallel. Returns a report per asset,
+/// in the order of `assets`. A failure to bake one asset doesn't stop the others.
+pub fn process_mesh_assets(
+    assets: Vec<MeshAssetProcessParams>,
+    output_dir: &Path,
+) -> Result<Vec<Result<MeshAssetBakeReport>>> {
+    let ctx = BakeContext::new(output_dir)?;
 
-    std::fs::create_dir_all("cache")?;
+    let tasks: Vec<_> = assets
+        .into_iter()
+        .map(|opt| ctx.executor.spawn(bake_mesh_asset(ctx.clone(), opt)))
+        .collect();
 
+    let reports = ctx.run(futures::future::join_all(tasks));
+
+    // Artifacts of assets which failed half-way are recorded too; they're valid on their own.
+    ctx.save_manifest()?;
+
+    Ok(reports)
+}
+
+async fn bake_mesh_asset(
+    ctx: BakeContext,
+    opt: MeshAssetProcessParams,
+) -> Result<MeshAssetBakeReport> {
+    let start_time = Instant::now();
+
+    let mesh_path = ctx.mesh_path(&opt.output_name);
+    let mesh_inputs = gltf_bake_inputs(&opt, &ctx.manifest)?;
+
+    if ctx
+        .manifest
+        .lock()
+        .unwrap()
+        .is_up_to_date(&mesh_path, &mesh_inputs)
     {
-        println!("Loading {:?}...", opt.path);
+        return Ok(MeshAssetBakeReport {
+            up_to_date: true,
+            mesh_bytes: std::fs::metadata(&mesh_path)?.len(),
+            images_baked: 0,
+            image_bytes: 0,
+            min_image_psnr: None,
+            vertex_cache: None,
+            duration: start_time.elapsed(),
+        });
+    }
 
-        let mesh = LoadGltfScene {
-            path: opt.path,
-            scale: opt.scale,
-            //rotation: Quat::from_rotation_y(std::f32::consts::FRAC_PI_2),
-            rotation: Quat::IDENTITY,
+    println!("Loading {:?}...", opt.path);
+
+    let mesh = LoadGltfScene {
+        path: opt.path.clone(),
+        scale: opt.scale,
+        rotation: opt.rotation,
+    }
+    .into_lazy();
+
+    let mesh = mesh.eval(&ctx.lazy_cache).await?;
+    report_import_warnings(&opt.path, &mesh.import_warnings);
+
+    println!("Packing {:?}...", opt.path);
+    let (mesh, vertex_cache) = prepare_mesh(&mesh, &opt);
+    let packed: PackedTriMesh::Proto = pack_triangle_mesh(&mesh, &opt.pack_params());
+    write_atomically(&mesh_path, |file| {
+        packed.flatten_into(file);
+        Ok(())
+    })?;
+
+    let images = mesh_images(&mesh, packed.maps, &ctx.manifest)?;
+    let images = process_images(&ctx, images).await?;
+
+    ctx.manifest.lock().unwrap().record(&mesh_path, mesh_inputs);
+
+    Ok(MeshAssetBakeReport {
+        up_to_date: false,
+        mesh_bytes: std::fs::metadata(&mesh_path)?.len(),
+        images_baked: images.count,
+        image_bytes: images.bytes,
+        min_image_psnr: images.min_psnr,
+        vertex_cache: Some(vertex_cache),
+        duration: start_time.elapsed(),
+    })
+}
+
+/// A glTF scene baked with its node hierarchy preserved. Stored as `cache/{output_name}.hierarchy`,
+/// so that loading an unchanged scene doesn't need to import the glTF again.
+#[derive(Serialize, Deserialize)]
+pub struct GltfHierarchyAsset {
+    /// Names of the baked meshes, to be loaded from `cache/{name}.mesh`.
+    /// Indexed by `GltfMeshInstance::mesh`.
+    pub meshes: Vec<String>,
+    pub instances: Vec<GltfMeshInstance>,
+    pub hierarchy: NodeHierarchy,
+    pub animations: Vec<Animation>,
+    pub root_transform: Mat4,
+
+    /// Joint nodes of skinned meshes, indexed like `meshes`.
+    pub mesh_joint_nodes: Vec<Vec<u32>>,
+}
+
+/// Loads a glTF scene keeping its hierarchy, and bakes each unique mesh in it
+/// as `cache/{output_name}_mesh{index}.mesh`. Meshes which the cache manifest shows
+/// to be up to date are not re-baked, and if the baked hierarchy is up to date too,
+/// the glTF isn't imported at all.
+pub fn process_gltf_hierarchy_asset(opt: MeshAssetProcessParams) -> Result<GltfHierarchyAsset> {
+    let ctx = BakeContext::new(Path::new(DEFAULT_OUTPUT_DIR))?;
+
+    let hierarchy_path = ctx.hierarchy_path(&opt.output_name);
+    let mesh_inputs = gltf_bake_inputs(&opt, &ctx.manifest)?;
+
+    if let Some(asset) = load_baked_hierarchy(&ctx, &hierarchy_path, &mesh_inputs) {
+        // Even if up to date, the hashes of sources may have been refreshed
+        ctx.save_manifest()?;
+        return Ok(asset);
+    }
+
+    println!("Loading {:?}...", opt.path);
+
+    let scene = LoadGltfHierarchy {
+        path: opt.path.clone(),
+        scale: opt.scale,
+        rotation: opt.rotation,
+    }
+    .into_lazy();
+
+    let scene = smol::block_on(scene.eval(&ctx.lazy_cache))?;
+    report_import_warnings(&opt.path, &scene.import_warnings);
+
+    let mut mesh_names = Vec::with_capacity(scene.meshes.len());
+    let mut baked_mesh_paths = Vec::new();
+    let mut maps = Vec::new();
+
+    for (i, mesh) in scene.meshes.iter().enumerate() {
+        let name = format!("{}_mesh{}", opt.output_name, i);
+        let path = ctx.mesh_path(&name);
+
+        let is_up_to_date = ctx
+            .manifest
+            .lock()
+            .unwrap()
+            .is_up_to_date(&path, &mesh_inputs);
+
+        if !is_up_to_date {
+            let (mesh, _) = prepare_mesh(mesh, &opt);
+            let packed: PackedTriMesh::Proto = pack_triangle_mesh(&mesh, &opt.pack_params());
+            write_atomically(&path, |file| {
+                packed.flatten_into(file);
+                Ok(())
+            })?;
+            maps.extend(mesh_images(&mesh, packed.maps, &ctx.manifest)?);
+            baked_mesh_paths.push(path);
         }
-        .into_lazy();
 
-        let mesh = &*smol::block_on(mesh.eval(&lazy_cache))?;
+        mesh_names.push(name);
+    }
+
+    println!(
+        "{} unique meshes in {} instances",
+        scene.meshes.len(),
+        scene.instances.len()
+    );
 
-        println!("Packing the mesh...");
-        let mesh: PackedTriMesh::Proto = pack_triangle_mesh(mesh);
+    if !maps.is_empty() {
+        ctx.run(process_images(&ctx, maps))?;
+    }
 
-        mesh.flatten_into(&mut File::create(format!(
-            "cache/{}.mesh",
-            opt.output_name
-        ))?);
-        let unique_images: Vec<Lazy<GpuImage::Proto>> = mesh
-            .maps
-            .into_iter()
-            .collect::<HashSet<_>>()
+    let asset = GltfHierarchyAsset {
+        meshes: mesh_names,
+        instances: scene.instances.clone(),
+        hierarchy: scene.hierarchy.clone(),
+        animations: scene.animations.clone(),
+        root_transform: scene.root_transform,
+        mesh_joint_nodes: scene
+            .meshes
+            .iter()
+            .map(|mesh| mesh.joint_nodes.clone())
+            .collect(),
+    };
+
+    let serialized = ron::ser::to_string(&asset)?;
+    write_atomically(&hierarchy_path, |file| {
+        file.write_all(serialized.as_bytes())
+    })?;
+
+    let mut manifest = ctx.manifest.lock().unwrap();
+    for path in baked_mesh_paths {
+        manifest.record(&path, mesh_inputs.clone());
+    }
+    manifest.record(&hierarchy_path, mesh_inputs);
+    manifest.save()?;
+
+    Ok(asset)
+}
+
+/// The hierarchy baked at `path`, if it and all of its meshes were baked from `inputs`.
+fn load_baked_hierarchy(
+    ctx: &BakeContext,
+    path: &Path,
+    inputs: &BakeInputs,
+) -> Option<GltfHierarchyAsset> {
+    if !ctx.manifest.lock().unwrap().is_up_to_date(path, inputs) {
+        return None;
+    }
+
+    let asset: GltfHierarchyAsset = match std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|serialized| Ok(ron::de::from_str(&serialized)?))
+    {
+        Ok(asset) => asset,
+        Err(err) => {
+            log::warn!("Ignoring the baked hierarchy {:?}: {:#}", path, err);
+            return None;
+        }
+    };
+
+    let manifest = ctx.manifest.lock().unwrap();
+    if asset
+        .meshes
+        .iter()
+        .all(|name| manifest.is_up_to_date(&ctx.mesh_path(name), inputs))
+    {
+        Some(asset)
+    } else {
+        None
+    }
+}
+
+fn report_import_warnings(path: &Path, warnings: &[ImportWarning]) {
+    for warning in warnings {
+        log::warn!("{:?}: {}", path, warning);
+    }
+}
+
+/// Applies the texture settings, and optimizes the vertex order of a copy of `mesh`.
+fn prepare_mesh(
+    mesh: &TriangleMesh,
+    opt: &MeshAssetProcessParams,
+) -> (TriangleMesh, VertexCacheStats) {
+    let mut mesh = mesh.clone();
+    for map in &mut mesh.maps {
+        if let MeshMaterialMap::Image {
+            source,
+            params,
+            kind,
+        } = map
+        {
+            *params = opt.textures.apply(*params, *kind, source);
+        }
+    }
+
+    let vertex_cache = mesh.optimize_vertex_order();
+    println!(
+        "Vertex cache ACMR: {:.3} -> {:.3}",
+        vertex_cache.acmr_before, vertex_cache.acmr_after
+    );
+
+    (mesh, vertex_cache)
+}
+
+/// The glTF file and everything it references, with the import parameters.
+fn gltf_bake_inputs(
+    opt: &MeshAssetProcessParams,
+    manifest: &Mutex<CacheManifest>,
+) -> Result<BakeInputs> {
+    let mut sources = vec![hash_source_file(manifest, &opt.path)?];
+    for path in kajiya_asset::gltf_referenced_files(&opt.path)? {
+        sources.push(hash_source_file(manifest, path)?);
+    }
+
+    Ok(BakeInputs::new(
+        sources,
+        format!(
+            "scale: {:?}, rotation: {:?}, textures: {:?}, lods: {:?}, meshlets: {}",
+            opt.scale, opt.rotation, opt.textures, opt.lods, opt.meshlets
+        ),
+    ))
+}
+
+/// Pairs the images of a packed mesh with their bake inputs.
+/// `packed_maps` are the result of `pack_triangle_mesh`, parallel to `mesh.maps`.
+fn mesh_images(
+    mesh: &TriangleMesh,
+    packed_maps: Vec<Lazy<GpuImage::Proto>>,
+    manifest: &Mutex<CacheManifest>,
+) -> Result<Vec<(Lazy<GpuImage::Proto>, BakeInputs)>> {
+    mesh.maps
+        .iter()
+        .zip(packed_maps)
+        .map(|(map, packed)| Ok((packed, image_bake_inputs(map, manifest)?)))
+        .collect()
+}
+
+fn image_bake_inputs(map: &MeshMaterialMap, manifest: &Mutex<CacheManifest>) -> Result<BakeInputs> {
+    Ok(match map {
+        MeshMaterialMap::Image { source, params, .. } => {
+            let source = match source {
+                ImageSource::File(path) => hash_source_file(manifest, path)?,
+                ImageSource::Memory(bytes) => SourceHash::from_bytes(bytes),
+            };
+            BakeInputs::new(vec![source], format!("{:?}", params))
+        }
+        MeshMaterialMap::Placeholder(values) => {
+            BakeInputs::new(Vec::new(), format!("placeholder: {:?}", values))
+        }
+    })
+}
+
+/// What `process_images` baked.
+#[derive(Default)]
+struct BakedImages {
+    count: usize,
+    bytes: u64,
+    min_psnr: Option<f32>,
+}
+
+fn format_psnr(psnr: f32) -> String {
+    if psnr.is_finite() {
+        format!("{:.1} dB", psnr)
+    } else {
+        "lossless".to_owned()
+    }
+}
+
+/// Bakes the images which the cache manifest doesn't show to be up to date, and records them in it.
+async fn process_images(
+    ctx: &BakeContext,
+    maps: Vec<(Lazy<GpuImage::Proto>, BakeInputs)>,
+) -> Result<BakedImages> {
+    let unique_images: Vec<(Lazy<GpuImage::Proto>, BakeInputs)> = {
+        let manifest = ctx.manifest.lock().unwrap();
+        maps.into_iter()
+            .collect::<HashMap<_, _>>()
             .into_iter()
-            .collect::<Vec<_>>();
+            .filter(|(img, inputs)| !manifest.is_up_to_date(&ctx.image_path(img), inputs))
+            .collect::<Vec<_>>()
+    };
 
-        let ex = &Executor::new();
-        let (signal, shutdown) = unbounded::<()>();
+    if unique_images.is_empty() {
+        return Ok(BakedImages::default());
+    }
+
+    println!("Processing {} images...", unique_images.len());
+
+    // Spawn tasks for processing all images onto the executor
+    let images = unique_images.iter().map(|(img, _)| {
+        let img = img.clone();
+        let lazy_cache = ctx.lazy_cache.clone();
+        let img_dst = ctx.image_path(&img);
+
+        ctx.executor.spawn(async move {
+            let loaded = img.eval(&lazy_cache).await?;
+
+            println!(
+                "{:?}: {:?} {}x{}, PSNR: {}",
+                img_dst.file_name().unwrap_or_default(),
+                loaded.format,
+                loaded.extent[0],
+                loaded.extent[1],
+                format_psnr(loaded.compression_psnr)
+            );
 
-        // Prepare tasks for processing all images
-        let lazy_cache = &lazy_cache;
-        let images = unique_images.iter().cloned().map(|img| async move {
-            let loaded = img.eval(lazy_cache).await?;
-            let img_dst = PathBuf::from(format!("cache/{:8.8x}.image", img.identity()));
-
-            match File::create(&img_dst) {
-                Ok(mut file) => loaded.flatten_into(&mut file),
-                Err(err) => {
-                    if img_dst.exists() {
-                        log::info!("Could not create {:?}; ignoring", img_dst);
-                    } else {
-                        anyhow::anyhow!(err);
-                    }
+            if let Err(err) = write_atomically(&img_dst, |file| {
+                loaded.flatten_into(file);
+                Ok(())
+            }) {
+                if img_dst.exists() {
+                    log::info!("Could not replace {:?}; ignoring", img_dst);
+                } else {
+                    return Err(anyhow::anyhow!(err));
                 }
-            };
+            }
 
-            anyhow::Result::<()>::Ok(())
-        });
+            anyhow::Result::<(u64, f32)>::Ok((
+                std::fs::metadata(&img_dst)?.len(),
+                loaded.compression_psnr,
+            ))
+        })
+    });
 
-        // Now spawn them onto the executor
-        let images = images.map(|task| ex.spawn(task));
-        let image_count = images.len();
-
-        if image_count > 0 {
-            // A task to join them all
-            let all_images = futures::future::try_join_all(images);
-
-            println!("Processing {} images...", image_count);
-
-            // Now spawn threads for the executor and run it to completion
-            Parallel::new()
-                .each(0..num_cpus::get(), |_| {
-                    future::block_on(ex.run(shutdown.recv()))
-                })
-                .finish(|| {
-                    future::block_on(async {
-                        all_images.await.expect("Failed to load mesh images");
-                        drop(signal);
-                    })
-                });
-        }
+    let baked: Vec<(u64, f32)> = futures::future::try_join_all(images).await?;
 
-        println!("Done.");
+    let mut manifest = ctx.manifest.lock().unwrap();
+    for (img, inputs) in unique_images {
+        manifest.record(&ctx.image_path(&img), inputs);
     }
 
-    Ok(())
+    Ok(BakedImages {
+        count: baked.len(),
+        bytes: baked.iter().map(|(bytes, _)| bytes).sum(),
+        min_psnr: baked
+            .iter()
+            .map(|(_, psnr)| *psnr)
+            .filter(|psnr| psnr.is_finite())
+            .reduce(f32::min),
+    })
 }
@@ -0,0 +1,283 @@
+use glam::{Mat4, Quat, Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnimatedProperty {
+    Translation,
+    Rotation,
+    Scale,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnimationChannel {
+    pub node: u32,
+    pub property: AnimatedProperty,
+    pub interpolation: Interpolation,
+
+    // Key times in seconds, ascending
+    pub times: Vec<f32>,
+
+    // `xyz` for translation and scale, `xyzw` quaternions for rotation.
+    // Cubic splines store three values per key: in-tangent, value, out-tangent.
+    pub values: Vec<Vec4>,
+}
+
+impl AnimationChannel {
+    pub fn duration(&self) -> f32 {
+        self.times.last().copied().unwrap_or(0.0)
+    }
+
+    fn key_value(&self, key: usize) -> Vec4 {
+        match self.interpolation {
+            Interpolation::CubicSpline => self.values[key * 3 + 1],
+            Interpolation::Step | Interpolation::Linear => self.values[key],
+        }
+    }
+
+    /// Samples the channel at time `t`, clamping outside of the keyed range.
+    pub fn sample(&self, t: f32) -> Vec4 {
+        let key_count = self.times.len();
+        assert!(key_count > 0, "animation channel has no keys");
+
+        // Index of the first key after `t`
+        let next = self.times.partition_point(|&key_t| key_t <= t);
+
+        if next == 0 {
+            return self.key_value(0);
+        } else if next == key_count {
+            return self.key_value(key_count - 1);
+        }
+
+        let prev = next - 1;
+        let dt = self.times[next] - self.times[prev];
+        let s = if dt > 0.0 {
+            (t - self.times[prev]) / dt
+        } else {
+            0.0
+        };
+
+        let res = match self.interpolation {
+            Interpolation::Step => return self.key_value(prev),
+            Interpolation::Linear => {
+                if self.property == AnimatedProperty::Rotation {
+                    let a = Quat::from_vec4(self.values[prev]);
+                    let b = Quat::from_vec4(self.values[next]);
+                    return Vec4::from(a.slerp(b, s));
+                }
+
+                self.values[prev].lerp(self.values[next], s)
+            }
+            Interpolation::CubicSpline => {
+                let p0 = self.values[prev * 3 + 1];
+                let m0 = self.values[prev * 3 + 2] * dt;
+                let m1 = self.values[next * 3] * dt;
+                let p1 = self.values[next * 3 + 1];
+
+                let s2 = s * s;
+                let s3 = s2 * s;
+
+                p0 * (2.0 * s3 - 3.0 * s2 + 1.0)
+                    + m0 * (s3 - 2.0 * s2 + s)
+                    + p1 * (-2.0 * s3 + 3.0 * s2)
+                    + m1 * (s3 - s2)
+            }
+        };
+
+        if self.property == AnimatedProperty::Rotation {
+            res.normalize()
+        } else {
+            res
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Animation {
+    pub name: Option<String>,
+    pub channels: Vec<AnimationChannel>,
+}
+
+impl Animation {
+    pub fn duration(&self) -> f32 {
+        self.channels
+            .iter()
+            .map(AnimationChannel::duration)
+            .fold(0.0, f32::max)
+    }
+
+    /// Overrides the animated properties of `nodes` with their values at time `t`.
+    pub fn apply(&self, t: f32, nodes: &mut [NodeTransform]) {
+        for channel in &self.channels {
+            let node = &mut nodes[channel.node as usize];
+            let value = channel.sample(t);
+
+            match channel.property {
+                AnimatedProperty::Translation => node.translation = value.truncate(),
+                AnimatedProperty::Rotation => node.rotation = Quat::from_vec4(value),
+                AnimatedProperty::Scale => node.scale = value.truncate(),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeTransform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl NodeTransform {
+    pub const IDENTITY: NodeTransform = NodeTransform {
+        translation: Vec3::ZERO,
+        rotation: Quat::IDENTITY,
+        scale: Vec3::ONE,
+    };
+
+    pub fn to_mat4(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HierarchyNode {
+    pub name: Option<String>,
+    pub children: Vec<u32>,
+
+    // The local transform when not animated
+    pub rest_transform: NodeTransform,
+}
+
+/// Node tree of a glTF scene; node indices match those in the source document.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NodeHierarchy {
+    pub nodes: Vec<HierarchyNode>,
+    pub roots: Vec<u32>,
+}
+
+impl NodeHierarchy {
+    pub fn rest_transforms(&self) -> Vec<NodeTransform> {
+        self.nodes.iter().map(|node| node.rest_transform).collect()
+    }
+
+    /// Accumulates `local` transforms down the hierarchy. Nodes not reachable
+    /// from the roots get the identity.
+    pub fn world_transforms(&self, local: &[NodeTransform], root: Mat4) -> Vec<Mat4> {
+        fn visit(
+            hierarchy: &NodeHierarchy,
+            local: &[NodeTransform],
+            node: u32,
+            parent_xform: Mat4,
+            res: &mut [Mat4],
+        ) {
+            let xform = parent_xform * local[node as usize].to_mat4();
+            res[node as usize] = xform;
+
+            for &child in &hierarchy.nodes[node as usize].children {
+                visit(hierarchy, local, child, xform, res);
+            }
+        }
+
+        let mut res = vec![Mat4::IDENTITY; self.nodes.len()];
+        for &node in &self.roots {
+            visit(self, local, node, root, &mut res);
+        }
+
+        res
+    }
+}
+
+#[test]
+fn test_channel_sampling() {
+    let channel = |interpolation, values: Vec<Vec4>| AnimationChannel {
+        node: 0,
+        property: AnimatedProperty::Translation,
+        interpolation,
+        times: vec![1.0, 3.0],
+        values,
+    };
+
+    let a = Vec4::new(0.0, 0.0, 0.0, 0.0);
+    let b = Vec4::new(2.0, 4.0, 0.0, 0.0);
+
+    let step = channel(Interpolation::Step, vec![a, b]);
+    assert_eq!(step.sample(0.0), a);
+    assert_eq!(step.sample(2.9), a);
+    assert_eq!(step.sample(3.0), b);
+    assert_eq!(step.sample(10.0), b);
+
+    let linear = channel(Interpolation::Linear, vec![a, b]);
+    assert_eq!(linear.sample(2.0), Vec4::new(1.0, 2.0, 0.0, 0.0));
+    assert_eq!(linear.duration(), 3.0);
+
+    // With zero tangents, the spline is a smoothstep between the keys
+    let cubic = channel(
+        Interpolation::CubicSpline,
+        vec![Vec4::ZERO, a, Vec4::ZERO, Vec4::ZERO, b, Vec4::ZERO],
+    );
+    assert_eq!(cubic.sample(1.0), a);
+    assert_eq!(cubic.sample(2.0), Vec4::new(1.0, 2.0, 0.0, 0.0));
+    assert_eq!(cubic.sample(2.5), b * 0.84375);
+    assert_eq!(cubic.sample(3.0), b);
+}
+
+#[test]
+fn test_rotation_sampling() {
+    let channel = AnimationChannel {
+        node: 0,
+        property: AnimatedProperty::Rotation,
+        interpolation: Interpolation::Linear,
+        times: vec![0.0, 1.0],
+        values: vec![
+            Vec4::from(Quat::IDENTITY),
+            Vec4::from(Quat::from_rotation_y(std::f32::consts::FRAC_PI_2)),
+        ],
+    };
+
+    let q = Quat::from_vec4(channel.sample(0.5));
+    assert!(q.abs_diff_eq(Quat::from_rotation_y(std::f32::consts::FRAC_PI_4), 1e-5));
+}
+
+#[test]
+fn test_world_transforms() {
+    let node = |children: Vec<u32>, translation: Vec3| HierarchyNode {
+        name: None,
+        children,
+        rest_transform: NodeTransform {
+            translation,
+            ..NodeTransform::IDENTITY
+        },
+    };
+
+    // Node 1 is the parent of node 0; node 2 is unreachable.
+    let hierarchy = NodeHierarchy {
+        nodes: vec![
+            node(vec![], Vec3::X),
+            node(vec![0], Vec3::Y),
+            node(vec![], Vec3::Z),
+        ],
+        roots: vec![1],
+    };
+
+    let world = hierarchy.world_transforms(
+        &hierarchy.rest_transforms(),
+        Mat4::from_scale(Vec3::splat(2.0)),
+    );
+
+    assert_eq!(
+        world[0].transform_point3(Vec3::ZERO),
+        Vec3::new(2.0, 2.0, 0.0)
+    );
+    assert_eq!(
+        world[1].transform_point3(Vec3::ZERO),
+        Vec3::new(0.0, 2.0, 0.0)
+    );
+    assert_eq!(world[2], Mat4::IDENTITY);
+}
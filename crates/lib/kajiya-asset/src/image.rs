@@ -2,11 +2,11 @@ use std::path::PathBuf;
 
 use bytes::Bytes;
 use image::{imageops::FilterType, DynamicImage, GenericImageView as _, ImageBuffer, Rgba};
-use intel_tex_2::{bc5, bc7};
+use intel_tex_2::{bc1, bc3, bc4, bc5, bc6h, bc7};
 use kajiya_backend::{ash::vk, file::LoadFile, ImageDesc};
 use turbosloth::*;
 
-use crate::mesh::TexCompressionMode;
+use crate::mesh::{TexCompressionMode, TexGamma};
 
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub enum ImageSource {
@@ -14,14 +14,35 @@ pub enum ImageSource {
     Memory(Bytes),
 }
 
+impl ImageSource {
+    /// Whether the image is in Radiance HDR format, and loads as `RawImage::RgbaF32`.
+    pub fn is_hdr(&self) -> bool {
+        match self {
+            ImageSource::File(path) => path
+                .extension()
+                .map_or(false, |ext| ext.eq_ignore_ascii_case("hdr")),
+            ImageSource::Memory(bytes) => {
+                image::guess_format(bytes).ok() == Some(image::ImageFormat::Hdr)
+            }
+        }
+    }
+}
+
 pub struct RawRgba8Image {
     pub data: Bytes,
     pub dimensions: [u32; 2],
 }
 
+/// Linear, high dynamic range
+pub struct RawRgbaF32Image {
+    pub data: Vec<f32>,
+    pub dimensions: [u32; 2],
+}
+
 #[allow(clippy::large_enum_variant)]
 pub enum RawImage {
     Rgba8(RawRgba8Image),
+    RgbaF32(RawRgbaF32Image),
     Dds(ddsfile::Dds),
 }
 
@@ -33,6 +54,9 @@ pub enum LoadImage {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum BcMode {
+    Bc1,
+    Bc3,
+    Bc4,
     Bc5,
     Bc7,
 }
@@ -40,10 +64,230 @@ enum BcMode {
 impl BcMode {
     fn block_bytes(self) -> usize {
         match self {
+            BcMode::Bc1 => 8,
+            BcMode::Bc3 => 16,
+            BcMode::Bc4 => 8,
             BcMode::Bc5 => 16,
             BcMode::Bc7 => 16,
         }
     }
+
+    fn format(self, gamma: TexGamma) -> anyhow::Result<vk::Format> {
+        Ok(match (self, gamma) {
+            (BcMode::Bc1, TexGamma::Linear) => vk::Format::BC1_RGB_UNORM_BLOCK,
+            (BcMode::Bc1, TexGamma::Srgb) => vk::Format::BC1_RGB_SRGB_BLOCK,
+            (BcMode::Bc3, TexGamma::Linear) => vk::Format::BC3_UNORM_BLOCK,
+            (BcMode::Bc3, TexGamma::Srgb) => vk::Format::BC3_SRGB_BLOCK,
+            (BcMode::Bc4, TexGamma::Linear) => vk::Format::BC4_UNORM_BLOCK,
+            (BcMode::Bc5, TexGamma::Linear) => vk::Format::BC5_UNORM_BLOCK,
+            (BcMode::Bc7, TexGamma::Linear) => vk::Format::BC7_UNORM_BLOCK,
+            (BcMode::Bc7, TexGamma::Srgb) => vk::Format::BC7_SRGB_BLOCK,
+            (BcMode::Bc4 | BcMode::Bc5, TexGamma::Srgb) => {
+                anyhow::bail!("{:?} has no sRGB format", self)
+            }
+        })
+    }
+
+    /// Number of leading channels stored
+    fn channel_count(self, needs_alpha: bool) -> usize {
+        match self {
+            BcMode::Bc1 => 3,
+            BcMode::Bc3 | BcMode::Bc7 => 3 + needs_alpha as usize,
+            BcMode::Bc4 => 1,
+            BcMode::Bc5 => 2,
+        }
+    }
+
+    /// Decodes a block to 4x4 texels in row-major order. Channels which aren't stored are zero.
+    fn decode(self, block: &[u8]) -> [[u8; 4]; 16] {
+        let mut decoded = [0u8; 64];
+        let decoded_channels = match self {
+            BcMode::Bc1 => {
+                bcdec_rs::bc1(block, &mut decoded, 4 * 4);
+                4
+            }
+            BcMode::Bc3 => {
+                bcdec_rs::bc3(block, &mut decoded, 4 * 4);
+                4
+            }
+            BcMode::Bc4 => {
+                bcdec_rs::bc4(block, &mut decoded, 4, false);
+                1
+            }
+            BcMode::Bc5 => {
+                bcdec_rs::bc5(block, &mut decoded, 4 * 2, false);
+                2
+            }
+            BcMode::Bc7 => {
+                bcdec_rs::bc7(block, &mut decoded, 4 * 4);
+                4
+            }
+        };
+
+        let mut texels = [[0u8; 4]; 16];
+        for (texel, decoded) in texels
+            .iter_mut()
+            .zip(decoded.chunks_exact(decoded_channels))
+        {
+            texel[..decoded_channels].copy_from_slice(decoded);
+        }
+        texels
+    }
+}
+
+type RgbaF32Buffer = ImageBuffer<Rgba<f32>, Vec<f32>>;
+
+/// Coordinates of the texels of block `block_index` in an image `width` texels wide,
+/// in the order they're decoded in.
+fn block_texels(width: u32, block_index: usize) -> impl Iterator<Item = (u32, u32)> {
+    let blocks_per_row = (width / 4) as usize;
+    let x0 = (block_index % blocks_per_row) as u32 * 4;
+    let y0 = (block_index / blocks_per_row) as u32 * 4;
+    (0..16).map(move |i| (x0 + i % 4, y0 + i / 4))
+}
+
+fn psnr(squared_error_sum: f64, sample_count: usize, peak: f64) -> f32 {
+    if squared_error_sum == 0.0 || peak == 0.0 {
+        f32::INFINITY
+    } else {
+        let mse = squared_error_sum / sample_count as f64;
+        (10.0 * (peak * peak / mse).log10()) as f32
+    }
+}
+
+/// Peak signal-to-noise ratio, in decibels, of the compressed blocks of `src`,
+/// over the channels stored by `bc_mode`.
+fn ldr_compression_psnr(
+    src: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    compressed: &[u8],
+    bc_mode: BcMode,
+    needs_alpha: bool,
+) -> f32 {
+    let channel_count = bc_mode.channel_count(needs_alpha);
+    let mut squared_error_sum = 0.0f64;
+
+    for (block_index, block) in compressed.chunks_exact(bc_mode.block_bytes()).enumerate() {
+        let decoded = bc_mode.decode(block);
+        for (texel, (x, y)) in decoded.iter().zip(block_texels(src.width(), block_index)) {
+            let expected = src.get_pixel(x, y).0;
+            for c in 0..channel_count {
+                let diff = texel[c] as f64 - expected[c] as f64;
+                squared_error_sum += diff * diff;
+            }
+        }
+    }
+
+    let sample_count = (src.width() * src.height()) as usize * channel_count;
+    psnr(squared_error_sum, sample_count, 255.0)
+}
+
+/// Like `ldr_compression_psnr`, but for BC6H, with the peak signal at the brightest texel.
+fn hdr_compression_psnr(src: &RgbaF32Buffer, compressed: &[u8]) -> f32 {
+    let peak = src
+        .pixels()
+        .flat_map(|px| [px.0[0], px.0[1], px.0[2]])
+        .fold(0.0f32, f32::max);
+
+    let mut squared_error_sum = 0.0f64;
+    for (block_index, block) in compressed.chunks_exact(16).enumerate() {
+        let mut decoded = [0.0f32; 4 * 4 * 3];
+        bcdec_rs::bc6h_float(block, &mut decoded, 4 * 3, false);
+
+        for (texel, (x, y)) in decoded
+            .chunks_exact(3)
+            .zip(block_texels(src.width(), block_index))
+        {
+            let expected = src.get_pixel(x, y).0;
+            for c in 0..3 {
+                let diff = texel[c] as f64 - expected[c] as f64;
+                squared_error_sum += diff * diff;
+            }
+        }
+    }
+
+    let sample_count = (src.width() * src.height()) as usize * 3;
+    psnr(squared_error_sum, sample_count, peak as f64)
+}
+
+/// Triangle-filtered resize. Unlike `image::imageops::resize`, keeps values outside of [0, 1].
+fn resize_rgba_f32(src: &RgbaF32Buffer, width: u32, height: u32) -> RgbaF32Buffer {
+    // Source texels and their weights for each destination texel along an axis
+    let filter_taps = |src_len: u32, dst_len: u32| -> Vec<Vec<(u32, f32)>> {
+        let scale = src_len as f32 / dst_len as f32;
+        let radius = scale.max(1.0);
+
+        (0..dst_len)
+            .map(|i| {
+                let center = (i as f32 + 0.5) * scale - 0.5;
+                let first = (center - radius).floor().max(0.0) as u32;
+                let last = ((center + radius).ceil() as u32).min(src_len - 1);
+
+                let mut taps: Vec<(u32, f32)> = (first..=last)
+                    .map(|j| (j, 1.0 - (j as f32 - center).abs() / radius))
+                    .filter(|(_, weight)| *weight > 0.0)
+                    .collect();
+
+                let weight_sum: f32 = taps.iter().map(|(_, weight)| weight).sum();
+                for (_, weight) in &mut taps {
+                    *weight /= weight_sum;
+                }
+
+                taps
+            })
+            .collect()
+    };
+
+    let horizontal_taps = filter_taps(src.width(), width);
+    let horizontal = RgbaF32Buffer::from_fn(width, src.height(), |x, y| {
+        let mut res = [0.0f32; 4];
+        for &(sx, weight) in &horizontal_taps[x as usize] {
+            let px = src.get_pixel(sx, y).0;
+            for c in 0..4 {
+                res[c] += px[c] * weight;
+            }
+        }
+        Rgba(res)
+    });
+
+    let vertical_taps = filter_taps(src.height(), height);
+    RgbaF32Buffer::from_fn(width, height, |x, y| {
+        let mut res = [0.0f32; 4];
+        for &(sy, weight) in &vertical_taps[y as usize] {
+            let px = horizontal.get_pixel(x, sy).0;
+            for c in 0..4 {
+                res[c] += px[c] * weight;
+            }
+        }
+        Rgba(res)
+    })
+}
+
+/// Converts to linear values for processing in high dynamic range.
+fn rgba8_to_linear_f32(src: &RawRgba8Image, gamma: TexGamma) -> RawRgbaF32Image {
+    let to_linear = |v: u8| -> f32 {
+        let v = v as f32 / 255.0;
+        match gamma {
+            TexGamma::Linear => v,
+            TexGamma::Srgb if v <= 0.04045 => v / 12.92,
+            TexGamma::Srgb => ((v + 0.055) / 1.055).powf(2.4),
+        }
+    };
+
+    RawRgbaF32Image {
+        data: src
+            .data
+            .chunks_exact(4)
+            .flat_map(|px| {
+                [
+                    to_linear(px[0]),
+                    to_linear(px[1]),
+                    to_linear(px[2]),
+                    px[3] as f32 / 255.0,
+                ]
+            })
+            .collect(),
+        dimensions: src.dimensions,
+    }
 }
 
 impl LoadImage {
@@ -83,6 +327,21 @@ impl LazyWorker for LoadImage {
             );
 
             Ok(RawImage::Dds(dds))
+        } else if image::guess_format(&bytes).ok() == Some(image::ImageFormat::Hdr) {
+            // Decoded via `HdrDecoder` directly, as `image::load_from_memory` tone-maps to 8 bits
+            let decoder = image::codecs::hdr::HdrDecoder::new(std::io::Cursor::new(&bytes))?;
+            let metadata = decoder.metadata();
+            log::info!("Loaded HDR image: {}x{}", metadata.width, metadata.height);
+
+            let pixels = decoder.read_image_hdr()?;
+
+            Ok(RawImage::RgbaF32(RawRgbaF32Image {
+                data: pixels
+                    .iter()
+                    .flat_map(|px| [px.0[0], px.0[1], px.0[2], 1.0])
+                    .collect(),
+                dimensions: [metadata.width, metadata.height],
+            }))
         } else {
             let image = image::load_from_memory(&bytes)?;
             let image_dimensions = image.dimensions();
@@ -130,8 +389,8 @@ pub struct CreateGpuImage {
 impl CreateGpuImage {
     fn process_rgba8(&self, src: &RawRgba8Image) -> anyhow::Result<super::mesh::GpuImage::Proto> {
         let mut format = match self.params.gamma {
-            crate::mesh::TexGamma::Linear => vk::Format::R8G8B8A8_UNORM,
-            crate::mesh::TexGamma::Srgb => vk::Format::R8G8B8A8_SRGB,
+            TexGamma::Linear => vk::Format::R8G8B8A8_UNORM,
+            TexGamma::Srgb => vk::Format::R8G8B8A8_SRGB,
         };
 
         let mut image = image::DynamicImage::ImageRgba8(
@@ -143,16 +402,28 @@ impl CreateGpuImage {
             .unwrap(),
         );
 
-        let should_compress = self.params.compression != TexCompressionMode::None
-            && image.width() >= 4
-            && image.height() >= 4;
+        let bc_mode = match self.params.compression {
+            TexCompressionMode::None => None,
+            TexCompressionMode::Rgba => Some(BcMode::Bc7),
+            TexCompressionMode::RgbaFast => Some(BcMode::Bc3),
+            TexCompressionMode::Rgb => Some(BcMode::Bc1),
+            TexCompressionMode::Rg => Some(BcMode::Bc5),
+            TexCompressionMode::R => Some(BcMode::Bc4),
+            TexCompressionMode::RgbHdr => unreachable!("processed by `process_hdr`"),
+        };
+
+        let bc_mode = bc_mode.filter(|_| image.width() >= 4 && image.height() >= 4);
+        let should_compress = bc_mode.is_some();
 
-        const MAX_SIZE: u32 = 2048;
+        if let Some(bc_mode) = bc_mode {
+            format = bc_mode.format(self.params.gamma)?;
+        }
 
-        if image.dimensions().0 > MAX_SIZE || image.dimensions().1 > MAX_SIZE {
+        let max_size = self.params.max_size;
+        if image.dimensions().0 > max_size || image.dimensions().1 > max_size {
             image = image.resize_exact(
-                image.dimensions().0.min(MAX_SIZE),
-                image.dimensions().1.min(MAX_SIZE),
+                image.dimensions().0.min(max_size),
+                image.dimensions().1.min(max_size),
                 FilterType::Lanczos3,
             );
         }
@@ -160,18 +431,14 @@ impl CreateGpuImage {
         let mut desc = ImageDesc::new_2d(format, [image.dimensions().0, image.dimensions().1])
             .usage(vk::ImageUsageFlags::SAMPLED);
 
-        let mut compress = |mip: ImageBuffer<Rgba<u8>, Vec<u8>>| -> Vec<u8> {
+        let mut compression_psnr: Option<f32> = None;
+
+        let mut compress = |mip: ImageBuffer<Rgba<u8>, Vec<u8>>, bc_mode: BcMode| -> Vec<u8> {
             let block_count = intel_tex_2::divide_up_by_multiple(mip.width() * mip.height(), 16);
 
             let needs_alpha =
                 self.params.compression.supports_alpha() && mip.pixels().any(|px| px.0[3] != 255);
 
-            let bc_mode = match self.params.compression {
-                TexCompressionMode::None => unreachable!(),
-                TexCompressionMode::Rgba => BcMode::Bc7,
-                TexCompressionMode::Rg => BcMode::Bc5,
-            };
-
             let block_bytes = bc_mode.block_bytes();
 
             let surface = intel_tex_2::RgbaSurface {
@@ -185,20 +452,11 @@ impl CreateGpuImage {
 
             log::info!("Compressing to {:?}...", bc_mode);
             match bc_mode {
-                BcMode::Bc5 => {
-                    format = match self.params.gamma {
-                        crate::mesh::TexGamma::Linear => vk::Format::BC5_UNORM_BLOCK,
-                        crate::mesh::TexGamma::Srgb => unimplemented!(),
-                    };
-
-                    bc5::compress_blocks_into(&surface, &mut compressed_bytes)
-                }
+                BcMode::Bc1 => bc1::compress_blocks_into(&surface, &mut compressed_bytes),
+                BcMode::Bc3 => bc3::compress_blocks_into(&surface, &mut compressed_bytes),
+                BcMode::Bc4 => bc4::compress_blocks_into(&surface, &mut compressed_bytes),
+                BcMode::Bc5 => bc5::compress_blocks_into(&surface, &mut compressed_bytes),
                 BcMode::Bc7 => {
-                    format = match self.params.gamma {
-                        crate::mesh::TexGamma::Linear => vk::Format::BC7_UNORM_BLOCK,
-                        crate::mesh::TexGamma::Srgb => vk::Format::BC7_SRGB_BLOCK,
-                    };
-
                     let settings = if needs_alpha {
                         bc7::alpha_basic_settings()
                     } else {
@@ -209,6 +467,11 @@ impl CreateGpuImage {
                 }
             }
 
+            // Only measured for the top mip
+            compression_psnr.get_or_insert_with(|| {
+                ldr_compression_psnr(&mip, &compressed_bytes, bc_mode, needs_alpha)
+            });
+
             compressed_bytes
         };
 
@@ -242,8 +505,8 @@ impl CreateGpuImage {
 
             swizzle(&mut mip);
 
-            if should_compress {
-                compress(mip)
+            if let Some(bc_mode) = bc_mode {
+                compress(mip, bc_mode)
             } else {
                 mip.into_raw()
             }
@@ -283,6 +546,130 @@ impl CreateGpuImage {
             format,
             extent: desc.extent,
             mips,
+            compression_psnr: compression_psnr.unwrap_or(f32::INFINITY),
+        })
+    }
+
+    /// Compresses to BC6H if compression is requested at all, and stores half floats otherwise.
+    /// Like BC6H, the latter keeps no alpha.
+    fn process_hdr(&self, src: &RawRgbaF32Image) -> anyhow::Result<super::mesh::GpuImage::Proto> {
+        let mut image =
+            RgbaF32Buffer::from_raw(src.dimensions[0], src.dimensions[1], src.data.clone())
+                .unwrap();
+
+        if let Some(swizzle) = self.params.channel_swizzle {
+            for px in image.pixels_mut() {
+                let values = px.0;
+                px.0 = [
+                    values[swizzle[0]],
+                    values[swizzle[1]],
+                    values[swizzle[2]],
+                    values[swizzle[3]],
+                ];
+            }
+        }
+
+        let should_compress = self.params.compression != TexCompressionMode::None
+            && image.width() >= 4
+            && image.height() >= 4;
+
+        let format = if should_compress {
+            vk::Format::BC6H_UFLOAT_BLOCK
+        } else {
+            vk::Format::R16G16B16A16_SFLOAT
+        };
+
+        let max_size = self.params.max_size;
+        if image.width() > max_size || image.height() > max_size {
+            image = resize_rgba_f32(
+                &image,
+                image.width().min(max_size),
+                image.height().min(max_size),
+            );
+        }
+
+        let mut desc = ImageDesc::new_2d(format, [image.width(), image.height()])
+            .usage(vk::ImageUsageFlags::SAMPLED);
+
+        let min_img_dim = if should_compress { 4 } else { 1 };
+
+        let round_up_to_block = |x: u32| -> u32 {
+            (((x + min_img_dim - 1) / min_img_dim) * min_img_dim).max(min_img_dim)
+        };
+
+        let mut compression_psnr: Option<f32> = None;
+
+        let mut process_mip = |mip: RgbaF32Buffer| -> Vec<u8> {
+            let mut mip = if mip.width() % min_img_dim != 0 || mip.height() % min_img_dim != 0 {
+                resize_rgba_f32(
+                    &mip,
+                    round_up_to_block(mip.width()),
+                    round_up_to_block(mip.height()),
+                )
+            } else {
+                mip
+            };
+
+            // The range of unsigned half floats
+            for v in mip.iter_mut() {
+                *v = if v.is_nan() {
+                    0.0
+                } else {
+                    v.clamp(0.0, 65504.0)
+                };
+            }
+
+            let halfs: Vec<u8> = mip
+                .iter()
+                .flat_map(|&v| half::f16::from_f32(v).to_bits().to_ne_bytes())
+                .collect();
+
+            if !should_compress {
+                return halfs;
+            }
+
+            let block_count = intel_tex_2::divide_up_by_multiple(mip.width() * mip.height(), 16);
+            let surface = intel_tex_2::RgbaSurface {
+                width: mip.width(),
+                height: mip.height(),
+                stride: mip.width() * 8,
+                data: &halfs,
+            };
+
+            let mut compressed_bytes = vec![0u8; block_count as usize * 16];
+
+            log::info!("Compressing to BC6H...");
+            bc6h::compress_blocks_into(&bc6h::basic_settings(), &surface, &mut compressed_bytes);
+
+            compression_psnr.get_or_insert_with(|| hdr_compression_psnr(&mip, &compressed_bytes));
+
+            compressed_bytes
+        };
+
+        let mips: Vec<Vec<u8>> = if self.params.use_mips {
+            desc = desc.all_mip_levels();
+
+            let mut mips = Vec::with_capacity(desc.mip_levels as usize);
+            for _ in 0..desc.mip_levels {
+                let next = resize_rgba_f32(
+                    &image,
+                    round_up_to_block(image.width() / 2),
+                    round_up_to_block(image.height() / 2),
+                );
+                let mip = std::mem::replace(&mut image, next);
+                mips.push(process_mip(mip));
+            }
+
+            mips
+        } else {
+            vec![process_mip(image)]
+        };
+
+        Ok(super::mesh::GpuImage::Proto {
+            format,
+            extent: desc.extent,
+            mips,
+            compression_psnr: compression_psnr.unwrap_or(f32::INFINITY),
         })
     }
 
@@ -332,6 +719,8 @@ impl CreateGpuImage {
             format,
             extent: [dds.get_width(), dds.get_height(), dds.get_depth()],
             mips,
+            // Stored as is
+            compression_psnr: f32::INFINITY,
         })
     }
 }
@@ -367,8 +756,28 @@ impl LazyWorker for CreateGpuImage {
         let src = self.image.eval(&ctx).await?;
 
         match &*src {
+            RawImage::Rgba8(src) if self.params.compression == TexCompressionMode::RgbHdr => {
+                self.process_hdr(&rgba8_to_linear_f32(src, self.params.gamma))
+            }
             RawImage::Rgba8(src) => self.process_rgba8(src),
+            RawImage::RgbaF32(src) => self.process_hdr(src),
             RawImage::Dds(src) => self.process_dds(src),
         }
     }
 }
+
+#[test]
+fn test_compression_psnr() {
+    // BC4 block with every texel at the first endpoint
+    let block = [200u8, 100, 0, 0, 0, 0, 0, 0];
+    let mut src = ImageBuffer::from_pixel(4, 4, Rgba([200u8, 0, 0, 255]));
+    assert_eq!(
+        ldr_compression_psnr(&src, &block, BcMode::Bc4, false),
+        f32::INFINITY
+    );
+
+    // One texel off by 16, for a mean squared error of 16
+    src.put_pixel(1, 2, Rgba([216, 0, 0, 255]));
+    let expected = 10.0 * (255.0f32 * 255.0 / 16.0).log10();
+    assert!((ldr_compression_psnr(&src, &block, BcMode::Bc4, false) - expected).abs() < 1e-3);
+}
@@ -1,15 +1,24 @@
 // Based on `import.rs` in the `gltf` crate, but modified not to load images (we do that separately).
 
 use bytes::Bytes;
-use gltf::{buffer, image, Document, Error, Gltf, Result};
-use std::{fs, io, path::Path};
+use gltf::{buffer, image, Document, Error, Glb, Gltf, Result};
+use serde_json::Value;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 
-use crate::image::ImageSource;
+use crate::{image::ImageSource, mesh::MeshMaterialExtensions};
 
 type BufferBytes = Bytes;
 
 /// Return type of `import`.
-type Import = (Document, Vec<BufferBytes>, Vec<ImageSource>);
+type Import = (
+    Document,
+    Vec<BufferBytes>,
+    Vec<ImageSource>,
+    Vec<GltfMaterialExtensions>,
+);
 
 /// Represents the set of URI schemes the importer supports.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -154,18 +163,33 @@ pub fn import_image_data(
     Ok(images)
 }
 
-fn import_impl(Gltf { document, blob }: Gltf, base: Option<&Path>) -> Result<Import> {
+fn import_impl(
+    Gltf { document, blob }: Gltf,
+    material_extensions: Vec<GltfMaterialExtensions>,
+    base: Option<&Path>,
+) -> Result<Import> {
     let buffer_data = import_buffer_data(&document, base, blob)?;
     let image_data = import_image_data(&document, base, &buffer_data)?;
-    let import = (document, buffer_data, image_data);
+    let import = (document, buffer_data, image_data, material_extensions);
     Ok(import)
 }
 
 fn import_path(path: &Path) -> Result<Import> {
     let base = path.parent().unwrap_or_else(|| Path::new("./"));
-    let file = fs::File::open(path).map_err(Error::Io)?;
-    let reader = io::BufReader::new(file);
-    import_impl(Gltf::from_reader_without_validation(reader)?, Some(base))
+    let data = read_to_end(path)?;
+
+    // The `gltf` crate doesn't know about most material extensions, so they're read from the JSON directly.
+    let material_extensions = if data.starts_with(b"glTF") {
+        parse_material_extensions(&Glb::from_slice(&data)?.json)
+    } else {
+        parse_material_extensions(&data)
+    };
+
+    import_impl(
+        Gltf::from_slice_without_validation(&data)?,
+        material_extensions,
+        Some(base),
+    )
 }
 
 /// Import some glTF 2.0 from the file system.
@@ -175,3 +199,141 @@ where
 {
     import_path(path.as_ref())
 }
+
+/// Returns the external files referenced by the glTF document at `path`:
+/// its buffers and images, without `path` itself. Embedded data is skipped.
+pub fn referenced_files<P>(path: P) -> Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let base = path.parent().unwrap_or_else(|| Path::new("./"));
+    let Gltf { document, .. } = Gltf::from_slice_without_validation(&read_to_end(path)?)?;
+
+    let mut files = Vec::new();
+    let mut push_uri = |uri: &str| match Scheme::parse(uri) {
+        Scheme::File(path) => files.push(PathBuf::from(path)),
+        Scheme::Relative => files.push(base.join(uri)),
+        Scheme::Data(..) | Scheme::Unsupported => {}
+    };
+
+    for buffer in document.buffers() {
+        if let buffer::Source::Uri(uri) = buffer.source() {
+            push_uri(uri);
+        }
+    }
+
+    for image in document.images() {
+        if let image::Source::Uri { uri, .. } = image.source() {
+            let uri = urlencoding::decode(uri).map_err(|_| Error::UnsupportedScheme)?;
+            push_uri(uri.as_ref());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Material parameters from `KHR_materials_*` extensions not exposed by the `gltf` crate.
+#[derive(Clone, Copy)]
+pub struct GltfMaterialExtensions {
+    /// `KHR_materials_emissive_strength`; scales the emissive factor
+    pub emissive_strength: f32,
+
+    /// `KHR_materials_clearcoat`, `KHR_materials_transmission`, `KHR_materials_ior` and `KHR_materials_sheen`
+    pub shading: MeshMaterialExtensions,
+}
+
+impl Default for GltfMaterialExtensions {
+    fn default() -> Self {
+        Self {
+            emissive_strength: 1.0,
+            shading: Default::default(),
+        }
+    }
+}
+
+/// Returns the extensions of each material in the document JSON, in document order.
+/// Malformed extensions are ignored, leaving the defaults.
+fn parse_material_extensions(json: &[u8]) -> Vec<GltfMaterialExtensions> {
+    let root: Value = match serde_json::from_slice(json) {
+        Ok(root) => root,
+        Err(err) => {
+            log::warn!("Failed to parse glTF material extensions: {}", err);
+            return Vec::new();
+        }
+    };
+
+    let materials = root["materials"].as_array().map_or(&[][..], Vec::as_slice);
+    materials
+        .iter()
+        .map(|material| parse_single_material_extensions(&material["extensions"]))
+        .collect()
+}
+
+fn parse_single_material_extensions(extensions: &Value) -> GltfMaterialExtensions {
+    fn factor(ext: &Value, name: &str, default: f32) -> f32 {
+        ext[name].as_f64().map_or(default, |v| v as f32)
+    }
+
+    let mut res = GltfMaterialExtensions::default();
+
+    let ext = &extensions["KHR_materials_emissive_strength"];
+    res.emissive_strength = factor(ext, "emissiveStrength", res.emissive_strength);
+
+    let shading = &mut res.shading;
+
+    let ext = &extensions["KHR_materials_clearcoat"];
+    shading.clearcoat = factor(ext, "clearcoatFactor", shading.clearcoat);
+    shading.clearcoat_roughness =
+        factor(ext, "clearcoatRoughnessFactor", shading.clearcoat_roughness);
+
+    let ext = &extensions["KHR_materials_transmission"];
+    shading.transmission = factor(ext, "transmissionFactor", shading.transmission);
+
+    let ext = &extensions["KHR_materials_ior"];
+    shading.ior = factor(ext, "ior", shading.ior);
+
+    let ext = &extensions["KHR_materials_sheen"];
+    if let Some(color) = ext["sheenColorFactor"].as_array() {
+        for (dst, src) in shading.sheen_color.iter_mut().zip(color) {
+            *dst = src.as_f64().unwrap_or(0.0) as f32;
+        }
+    }
+    shading.sheen_roughness = factor(ext, "sheenRoughnessFactor", shading.sheen_roughness);
+
+    res
+}
+
+#[test]
+fn test_parse_material_extensions() {
+    let json = br#"{
+        "materials": [
+            {},
+            {
+                "extensions": {
+                    "KHR_materials_emissive_strength": { "emissiveStrength": 5.0 },
+                    "KHR_materials_clearcoat": { "clearcoatFactor": 1.0, "clearcoatRoughnessFactor": 0.25 },
+                    "KHR_materials_transmission": { "transmissionFactor": 0.5 },
+                    "KHR_materials_ior": { "ior": 1.33 },
+                    "KHR_materials_sheen": { "sheenColorFactor": [1.0, 0.5, 0.25] }
+                }
+            }
+        ]
+    }"#;
+
+    let materials = parse_material_extensions(json);
+    assert_eq!(materials.len(), 2);
+
+    assert_eq!(materials[0].emissive_strength, 1.0);
+    assert_eq!(materials[0].shading.ior, 1.5);
+    assert_eq!(materials[0].shading.transmission, 0.0);
+
+    let ext = &materials[1];
+    assert_eq!(ext.emissive_strength, 5.0);
+    assert_eq!(ext.shading.clearcoat, 1.0);
+    assert_eq!(ext.shading.clearcoat_roughness, 0.25);
+    assert_eq!(ext.shading.transmission, 0.5);
+    assert_eq!(ext.shading.ior, 1.33);
+    assert_eq!(ext.shading.sheen_color, [1.0, 0.5, 0.25]);
+    assert_eq!(ext.shading.sheen_roughness, 0.0);
+}
@@ -1,4 +1,9 @@
+pub mod animation;
 pub mod image;
 pub mod mesh;
+pub mod optimize;
+pub mod simplify;
 
 mod import_gltf;
+
+pub use import_gltf::referenced_files as gltf_referenced_files;
@@ -16,14 +16,28 @@ use kajiya_backend::bytes::into_byte_vec;
     },
 };*/
 use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     hash::Hash,
     mem::size_of,
     path::{Path, PathBuf},
 };
 use turbosloth::*;
 
-use crate::image::ImageSource;
+use crate::{
+    animation::{
+        AnimatedProperty, Animation, AnimationChannel, HierarchyNode, Interpolation, NodeHierarchy,
+        NodeTransform,
+    },
+    image::ImageSource,
+    import_gltf::GltfMaterialExtensions,
+    optimize::{
+        average_cache_miss_ratio, build_meshlets, optimize_vertex_cache, remap_vertices,
+        vertex_fetch_remap, Meshlet,
+    },
+    simplify::{build_lod_chain, LodChainParams},
+};
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum TexGamma {
@@ -34,8 +48,18 @@ pub enum TexGamma {
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum TexCompressionMode {
     None,
+    /// BC7
     Rgba,
+    /// BC3; faster to compress than BC7, at lower quality
+    RgbaFast,
+    /// BC1; half the size of BC7, without alpha
+    Rgb,
+    /// BC5
     Rg,
+    /// BC4
+    R,
+    /// BC6H, unsigned. LDR sources are converted to linear floats first.
+    RgbHdr,
 }
 
 impl TexCompressionMode {
@@ -43,17 +67,36 @@ impl TexCompressionMode {
         match self {
             TexCompressionMode::None => true,
             TexCompressionMode::Rgba => true,
+            TexCompressionMode::RgbaFast => true,
+            TexCompressionMode::Rgb => false,
             TexCompressionMode::Rg => false,
+            TexCompressionMode::R => false,
+            TexCompressionMode::RgbHdr => false,
         }
     }
 }
 
+/// Images larger than this are downsampled unless `TexParams::max_size` says otherwise.
+pub const DEFAULT_TEX_MAX_SIZE: u32 = 2048;
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct TexParams {
     pub gamma: TexGamma,
     pub use_mips: bool,
     pub compression: TexCompressionMode,
     pub channel_swizzle: Option<[usize; 4]>,
+
+    /// Limit of the width and height of the top mip
+    pub max_size: u32,
+}
+
+/// Which material slot an image is used in.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum TexMapKind {
+    Albedo,
+    Normal,
+    Spec,
+    Emissive,
 }
 
 #[derive(Clone, Hash, PartialEq, Eq)]
@@ -61,6 +104,7 @@ pub enum MeshMaterialMap {
     Image {
         source: ImageSource,
         params: TexParams,
+        kind: TexMapKind,
     },
     Placeholder([u8; 4]),
 }
@@ -68,6 +112,10 @@ pub enum MeshMaterialMap {
 pub struct MeshMaterialFlags;
 impl MeshMaterialFlags {
     pub const MESH_MATERIAL_FLAG_EMISSIVE_USED_AS_LIGHT: u32 = 1;
+
+    // glTF `alphaMode`; neither flag means opaque.
+    pub const MESH_MATERIAL_FLAG_ALPHA_MASK: u32 = 2;
+    pub const MESH_MATERIAL_FLAG_ALPHA_BLEND: u32 = 4;
 }
 
 #[derive(Clone, Copy)]
@@ -80,6 +128,43 @@ pub struct MeshMaterial {
     pub emissive: [f32; 3],
     pub flags: u32,
     pub map_transforms: [[f32; 6]; 4],
+    pub alpha_cutoff: f32,
+    pub extensions: MeshMaterialExtensions,
+}
+
+impl MeshMaterial {
+    /// Whether the material can make parts of the geometry transparent.
+    pub fn is_alpha_tested(&self) -> bool {
+        0 != self.flags
+            & (MeshMaterialFlags::MESH_MATERIAL_FLAG_ALPHA_MASK
+                | MeshMaterialFlags::MESH_MATERIAL_FLAG_ALPHA_BLEND)
+    }
+}
+
+/// Parameters of the `KHR_materials_*` glTF extensions. Factors only; extension textures
+/// are not imported. The defaults leave the material unchanged.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct MeshMaterialExtensions {
+    pub clearcoat: f32,
+    pub clearcoat_roughness: f32,
+    pub transmission: f32,
+    pub ior: f32,
+    pub sheen_color: [f32; 3],
+    pub sheen_roughness: f32,
+}
+
+impl Default for MeshMaterialExtensions {
+    fn default() -> Self {
+        Self {
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.0,
+            transmission: 0.0,
+            ior: 1.5,
+            sheen_color: [0.0; 3],
+            sheen_roughness: 0.0,
+        }
+    }
 }
 
 #[derive(Clone, Default)]
@@ -94,6 +179,139 @@ pub struct TriangleMesh {
     pub materials: Vec<MeshMaterial>, // global
     pub maps: Vec<MeshMaterialMap>,   // global
     pub images: Vec<ImageSource>,
+
+    // Skinning data; all empty unless the mesh has skins.
+    // Vertices with all-zero weights are not attached to any joint.
+    pub joints: Vec<[u16; 4]>,                 // per vertex
+    pub weights: Vec<[f32; 4]>,                // per vertex
+    pub inverse_bind_matrices: Vec<[f32; 16]>, // per joint, column-major
+    pub joint_rest_transforms: Vec<[f32; 16]>, // per joint, column-major; the scene's default pose
+    pub joint_nodes: Vec<u32>,                 // per joint, index of the glTF node
+
+    // Problems encountered while importing; not packed
+    pub import_warnings: Vec<ImportWarning>,
+}
+
+impl TriangleMesh {
+    pub fn is_skinned(&self) -> bool {
+        !self.joint_nodes.is_empty()
+    }
+
+    /// Reorders triangles for vertex cache locality, and then vertices in the order of their first use.
+    pub fn optimize_vertex_order(&mut self) -> VertexCacheStats {
+        let vertex_count = self.positions.len();
+        let acmr_before = average_cache_miss_ratio(&self.indices, vertex_count);
+
+        self.indices = optimize_vertex_cache(&self.indices, vertex_count);
+        let acmr_after = average_cache_miss_ratio(&self.indices, vertex_count);
+
+        let remap = vertex_fetch_remap(&self.indices, vertex_count);
+        for i in &mut self.indices {
+            *i = remap[*i as usize];
+        }
+
+        remap_vertices(&mut self.positions, &remap);
+        remap_vertices(&mut self.normals, &remap);
+        remap_vertices(&mut self.colors, &remap);
+        remap_vertices(&mut self.uvs, &remap);
+        remap_vertices(&mut self.tangents, &remap);
+        remap_vertices(&mut self.material_ids, &remap);
+        remap_vertices(&mut self.joints, &remap);
+        remap_vertices(&mut self.weights, &remap);
+
+        VertexCacheStats {
+            acmr_before,
+            acmr_after,
+        }
+    }
+}
+
+/// Average cache miss ratios of a mesh before and after `TriangleMesh::optimize_vertex_order`;
+/// see `average_cache_miss_ratio`.
+#[derive(Clone, Copy, Debug)]
+pub struct VertexCacheStats {
+    pub acmr_before: f32,
+    pub acmr_after: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImportWarning {
+    pub mesh: Option<String>,
+    pub primitive: usize,
+    pub kind: ImportWarningKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum ImportWarningKind {
+    /// Points and lines don't have a triangle representation; the primitive was skipped
+    UnsupportedPrimitiveMode(gltf::mesh::Mode),
+    /// The primitive was skipped
+    MissingPositions,
+    /// The primitive was skipped
+    MissingNormals,
+    /// Trailing indices which don't form a whole triangle were dropped
+    IncompleteTriangle,
+    /// Joint indices don't fit in 16 bits; the primitive was imported without skinning
+    TooManyJoints,
+}
+
+impl std::fmt::Display for ImportWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "mesh {:?}, primitive {}: ",
+            self.mesh.as_deref().unwrap_or("<unnamed>"),
+            self.primitive
+        )?;
+
+        match &self.kind {
+            ImportWarningKind::UnsupportedPrimitiveMode(mode) => {
+                write!(f, "primitive mode {:?} is not supported; skipped", mode)
+            }
+            ImportWarningKind::MissingPositions => write!(f, "no positions; skipped"),
+            ImportWarningKind::MissingNormals => write!(f, "no normals; skipped"),
+            ImportWarningKind::IncompleteTriangle => {
+                write!(f, "index count is not a multiple of three; truncated")
+            }
+            ImportWarningKind::TooManyJoints => {
+                write!(f, "too many joints; imported without skinning")
+            }
+        }
+    }
+}
+
+/// Converts the indices of a primitive to a triangle list. Returns `None` for modes
+/// which don't describe triangles.
+fn triangulate_primitive(mode: gltf::mesh::Mode, indices: Vec<u32>) -> Option<Vec<u32>> {
+    use gltf::mesh::Mode;
+
+    let is_degenerate = |tri: &[u32; 3]| tri[0] == tri[1] || tri[1] == tri[2] || tri[0] == tri[2];
+
+    match mode {
+        Mode::Triangles => Some(indices),
+        Mode::TriangleStrip => Some(
+            (0..indices.len().saturating_sub(2))
+                .map(|i| {
+                    // Every other triangle is flipped to keep the winding consistent
+                    if i % 2 == 0 {
+                        [indices[i], indices[i + 1], indices[i + 2]]
+                    } else {
+                        [indices[i + 1], indices[i], indices[i + 2]]
+                    }
+                })
+                .filter(|tri| !is_degenerate(tri))
+                .flatten()
+                .collect(),
+        ),
+        Mode::TriangleFan => Some(
+            (1..indices.len().saturating_sub(1))
+                .map(|i| [indices[i], indices[i + 1], indices[0]])
+                .filter(|tri| !is_degenerate(tri))
+                .flatten()
+                .collect(),
+        ),
+        Mode::Points | Mode::Lines | Mode::LineLoop | Mode::LineStrip => None,
+    }
 }
 
 fn iter_gltf_node_tree<F: FnMut(&gltf::scene::Node, Mat4)>(
@@ -119,6 +337,7 @@ fn get_gltf_texture_source(tex: gltf::texture::Texture) -> Option<String> {
 
 fn load_gltf_material(
     mat: &gltf::material::Material,
+    extensions: &GltfMaterialExtensions,
     document_images: &[ImageSource],
 ) -> (Vec<MeshMaterialMap>, MeshMaterial) {
     const DEFAULT_MAP_TRANSFORM: [f32; 6] = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
@@ -163,7 +382,9 @@ fn load_gltf_material(
                             use_mips: true,
                             compression: TexCompressionMode::Rgba,
                             channel_swizzle: None,
+                            max_size: DEFAULT_TEX_MAX_SIZE,
                         },
+                        kind: TexMapKind::Albedo,
                     },
                     transform,
                 )
@@ -183,10 +404,19 @@ fn load_gltf_material(
                         use_mips: true,
                         compression: TexCompressionMode::Rg,
                         channel_swizzle: None,
+                        max_size: DEFAULT_TEX_MAX_SIZE,
                     },
+                    kind: TexMapKind::Normal,
                 }
             });
 
+    // Without metalness, only the roughness channel is needed.
+    let spec_compression = if mat.pbr_metallic_roughness().metallic_factor() == 0.0 {
+        TexCompressionMode::R
+    } else {
+        TexCompressionMode::Rg
+    };
+
     let (spec_map, spec_map_transform) = mat
         .pbr_metallic_roughness()
         .metallic_roughness_texture()
@@ -206,9 +436,11 @@ fn load_gltf_material(
                         params: TexParams {
                             gamma: TexGamma::Linear,
                             use_mips: true,
-                            compression: TexCompressionMode::Rg,
+                            compression: spec_compression,
                             channel_swizzle: Some([1, 2, 0, 3]),
+                            max_size: DEFAULT_TEX_MAX_SIZE,
                         },
+                        kind: TexMapKind::Spec,
                     },
                     texture_transform_to_matrix(tex.texture_transform()),
                 )
@@ -220,23 +452,43 @@ fn load_gltf_material(
     let mut emissive_map = MeshMaterialMap::Placeholder([255, 255, 255, 255]);
     if let Some(tex) = mat.emissive_texture() {
         map_transforms[3] = texture_transform_to_matrix(tex.texture_transform());
+        let source = &document_images[tex.texture().source().index()];
+
+        // BC6H keeps the range of HDR emission
+        let compression = if source.is_hdr() {
+            TexCompressionMode::RgbHdr
+        } else {
+            TexCompressionMode::Rgba
+        };
+
         emissive_map = MeshMaterialMap::Image {
-            source: document_images[tex.texture().source().index()].clone(),
+            source: source.clone(),
             params: TexParams {
                 gamma: TexGamma::Srgb,
                 use_mips: true,
-                compression: TexCompressionMode::Rgba,
+                compression,
                 channel_swizzle: None,
+                max_size: DEFAULT_TEX_MAX_SIZE,
             },
+            kind: TexMapKind::Emissive,
         }
     }
 
-    let emissive = mat.emissive_factor();
+    let emissive = mat
+        .emissive_factor()
+        .map(|v| v * extensions.emissive_strength);
 
     let base_color_mult = mat.pbr_metallic_roughness().base_color_factor();
     let roughness_mult = mat.pbr_metallic_roughness().roughness_factor();
     let metalness_factor = mat.pbr_metallic_roughness().metallic_factor();
 
+    let flags = match mat.alpha_mode() {
+        gltf::material::AlphaMode::Opaque => 0,
+        gltf::material::AlphaMode::Mask => MeshMaterialFlags::MESH_MATERIAL_FLAG_ALPHA_MASK,
+        gltf::material::AlphaMode::Blend => MeshMaterialFlags::MESH_MATERIAL_FLAG_ALPHA_BLEND,
+    };
+    let alpha_cutoff = mat.alpha_cutoff().unwrap_or(0.5);
+
     //mata.normal_texture().and_then(|tex| tex.transform())
 
     (
@@ -247,8 +499,10 @@ fn load_gltf_material(
             roughness_mult,
             metalness_factor,
             emissive,
-            flags: 0,
+            flags,
             map_transforms,
+            alpha_cutoff,
+            extensions: extensions.shading,
         },
     )
 }
@@ -276,172 +530,575 @@ impl LazyWorker for LoadGltfScene {
     type Output = anyhow::Result<TriangleMesh>;
 
     async fn run(self, _ctx: RunContext) -> Self::Output {
-        let (gltf, buffers, imgs) = crate::import_gltf::import(&self.path)
+        let (gltf, buffers, imgs, material_extensions) = crate::import_gltf::import(&self.path)
             .with_context(|| format!("Loading GLTF scene from {:?}", self.path))?;
 
         if let Some(scene) = gltf.default_scene().or_else(|| gltf.scenes().next()) {
             let mut res: TriangleMesh = TriangleMesh::default();
 
-            let mut process_node = |node: &gltf::scene::Node, xform: Mat4| {
-                if let Some(mesh) = node.mesh() {
-                    let flip_winding_order = xform.determinant() < 0.0;
+            let root_xform = Mat4::from_scale_rotation_translation(
+                Vec3::splat(self.scale),
+                self.rotation,
+                Vec3::ZERO,
+            );
 
-                    for prim in mesh.primitives() {
-                        let reader = prim.reader(|buffer| Some(&buffers[buffer.index()]));
+            let loader = GltfMeshLoader {
+                path: &self.path,
+                buffers: &buffers,
+                images: &imgs,
+                material_extensions: &material_extensions,
+                node_xforms: gltf_node_xforms(&gltf, &scene, root_xform),
+                root_xform,
+            };
 
-                        let res_material_index = res.materials.len() as u32;
+            // Index of the first joint of each skin in `res.joint_nodes`
+            let mut skin_joint_base: HashMap<usize, usize> = HashMap::new();
 
-                        {
-                            let (mut maps, mut material) =
-                                load_gltf_material(&prim.material(), imgs.as_slice());
+            for node in scene.nodes() {
+                iter_gltf_node_tree(
+                    &node,
+                    root_xform,
+                    &mut |node: &gltf::scene::Node, node_xform: Mat4| {
+                        loader.append_node_mesh(&mut res, &mut skin_joint_base, node, node_xform);
+                    },
+                );
+            }
 
-                            let map_base = res.maps.len() as u32;
-                            for id in material.maps.iter_mut() {
-                                *id += map_base;
-                            }
+            res.finish_skinning();
 
-                            res.materials.push(material);
-                            res.maps.append(&mut maps);
-                        }
+            Ok(res)
+        } else {
+            Err(anyhow::anyhow!("No default scene found in gltf"))
+        }
+    }
+}
 
-                        // Collect positions (required)
-                        let positions = if let Some(iter) = reader.read_positions() {
-                            iter.collect::<Vec<_>>()
-                        } else {
-                            return;
-                        };
-
-                        // Collect normals (required)
-                        let normals = if let Some(iter) = reader.read_normals() {
-                            iter.collect::<Vec<_>>()
-                        } else {
-                            return;
-                        };
-
-                        // Collect tangents (optional)
-                        let (mut tangents, tangents_found) =
-                            if let Some(iter) = reader.read_tangents() {
-                                (iter.collect::<Vec<_>>(), true)
-                            } else {
-                                (vec![[1.0, 0.0, 0.0, 0.0]; positions.len()], false)
-                            };
-
-                        // Collect uvs (optional)
-                        let (mut uvs, uvs_found) = if let Some(iter) = reader.read_tex_coords(0) {
-                            (iter.into_f32().collect::<Vec<_>>(), true)
-                        } else {
-                            (vec![[0.0, 0.0]; positions.len()], false)
-                        };
-
-                        // Collect colors (optional)
-                        let mut colors = if let Some(iter) = reader.read_colors(0) {
-                            iter.into_rgba_f32().collect::<Vec<_>>()
-                        } else {
-                            vec![[1.0, 1.0, 1.0, 1.0]; positions.len()]
-                        };
-
-                        // Collect material ids
-                        let mut material_ids = vec![res_material_index; positions.len()];
-
-                        // Collect indices
-                        let mut indices: Vec<u32>;
-                        {
-                            if let Some(indices_reader) = reader.read_indices() {
-                                indices = indices_reader.into_u32().collect();
-                            } else {
-                                if positions.is_empty() {
-                                    return;
-                                }
+/// Loads a glTF scene without flattening its node hierarchy: every unique glTF mesh becomes
+/// a `TriangleMesh` in its local space, and every node referencing one becomes an instance.
+/// Skinned meshes are in the space of the scene instead, since their node transforms don't apply.
+#[derive(Clone)]
+pub struct LoadGltfHierarchy {
+    pub path: PathBuf,
+    pub scale: f32,
+    pub rotation: Quat,
+}
 
-                                match prim.mode() {
-                                    gltf::mesh::Mode::Triangles => {
-                                        indices = (0..positions.len() as u32).collect();
-                                    }
-                                    _ => {
-                                        panic!(
-                                            "Primitive mode {:?} not supported yet",
-                                            prim.mode()
-                                        );
-                                    }
-                                }
-                            }
+impl Hash for LoadGltfHierarchy {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+        self.scale.to_ne_bytes().hash(state);
+        self.rotation.x.to_ne_bytes().hash(state);
+        self.rotation.y.to_ne_bytes().hash(state);
+        self.rotation.z.to_ne_bytes().hash(state);
+        self.rotation.w.to_ne_bytes().hash(state);
+    }
+}
 
-                            if flip_winding_order {
-                                for tri in indices.chunks_exact_mut(3) {
-                                    tri.swap(0, 2);
-                                }
-                            }
-                        }
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct GltfMeshInstance {
+    pub node: u32,
+    pub mesh: u32,
+
+    // Placement of the mesh when the scene is not animated, including the import transform
+    pub transform: Mat4,
+}
+
+#[derive(Clone)]
+pub struct GltfHierarchy {
+    pub hierarchy: NodeHierarchy,
+    pub animations: Vec<Animation>,
+    pub meshes: Vec<TriangleMesh>,
+    pub instances: Vec<GltfMeshInstance>,
+    pub import_warnings: Vec<ImportWarning>,
+
+    // Scale and rotation requested at import time, applied above the scene's roots
+    pub root_transform: Mat4,
+}
+
+#[async_trait]
+impl LazyWorker for LoadGltfHierarchy {
+    type Output = anyhow::Result<GltfHierarchy>;
+
+    async fn run(self, _ctx: RunContext) -> Self::Output {
+        let (gltf, buffers, imgs, material_extensions) = crate::import_gltf::import(&self.path)
+            .with_context(|| format!("Loading GLTF scene from {:?}", self.path))?;
+
+        let scene = gltf
+            .default_scene()
+            .or_else(|| gltf.scenes().next())
+            .context("No default scene found in gltf")?;
+
+        // Meshes are loaded in the space of their nodes; the import transform is
+        // applied by the instances.
+        let loader = GltfMeshLoader {
+            path: &self.path,
+            buffers: &buffers,
+            images: &imgs,
+            material_extensions: &material_extensions,
+            node_xforms: gltf_node_xforms(&gltf, &scene, Mat4::IDENTITY),
+            root_xform: Mat4::IDENTITY,
+        };
+
+        let mut scene_nodes = Vec::new();
+        for node in scene.nodes() {
+            iter_gltf_node_tree(&node, Mat4::IDENTITY, &mut |node: &gltf::scene::Node, _| {
+                scene_nodes.push(node.clone())
+            });
+        }
+
+        let root_transform = Mat4::from_scale_rotation_translation(
+            Vec3::splat(self.scale),
+            self.rotation,
+            Vec3::ZERO,
+        );
+
+        let mut meshes = Vec::new();
+        let mut instances = Vec::new();
+        let mut import_warnings = Vec::new();
+
+        // Nodes sharing a glTF mesh share the `TriangleMesh` too, unless they skin it differently.
+        let mut loaded_meshes: HashMap<(usize, Option<usize>), Option<u32>> = HashMap::new();
+
+        for node in scene_nodes {
+            let gltf_mesh = if let Some(mesh) = node.mesh() {
+                mesh
+            } else {
+                continue;
+            };
+
+            let skin = node.skin().map(|skin| skin.index());
+            let mesh_idx = *loaded_meshes
+                .entry((gltf_mesh.index(), skin))
+                .or_insert_with(|| {
+                    let mut mesh = TriangleMesh::default();
+                    loader.append_node_mesh(&mut mesh, &mut HashMap::new(), &node, Mat4::IDENTITY);
+                    mesh.finish_skinning();
+                    import_warnings.append(&mut mesh.import_warnings);
+
+                    if mesh.indices.is_empty() {
+                        return None;
+                    }
+
+                    meshes.push(mesh);
+                    Some(meshes.len() as u32 - 1)
+                });
+
+            if let Some(mesh_idx) = mesh_idx {
+                let transform = if meshes[mesh_idx as usize].is_skinned() {
+                    root_transform
+                } else {
+                    root_transform * loader.node_xforms[node.index()]
+                };
+
+                instances.push(GltfMeshInstance {
+                    node: node.index() as u32,
+                    mesh: mesh_idx,
+                    transform,
+                });
+            }
+        }
+
+        let hierarchy = NodeHierarchy {
+            nodes: gltf
+                .nodes()
+                .map(|node| {
+                    let (translation, rotation, scale) = node.transform().decomposed();
+                    HierarchyNode {
+                        name: node.name().map(str::to_owned),
+                        children: node.children().map(|child| child.index() as u32).collect(),
+                        rest_transform: NodeTransform {
+                            translation: Vec3::from(translation),
+                            rotation: Quat::from_array(rotation),
+                            scale: Vec3::from(scale),
+                        },
+                    }
+                })
+                .collect(),
+            roots: scene.nodes().map(|node| node.index() as u32).collect(),
+        };
+
+        let animations = gltf
+            .animations()
+            .map(|anim| load_gltf_animation(&anim, &buffers))
+            .collect();
+
+        Ok(GltfHierarchy {
+            hierarchy,
+            animations,
+            meshes,
+            instances,
+            import_warnings,
+            root_transform,
+        })
+    }
+}
+
+fn load_gltf_animation(anim: &gltf::Animation, buffers: &[bytes::Bytes]) -> Animation {
+    use gltf::animation::util::ReadOutputs;
+
+    let channels = anim
+        .channels()
+        .filter_map(|channel| {
+            let reader = channel.reader(|buffer| Some(&buffers[buffer.index()][..]));
+            let times: Vec<f32> = reader.read_inputs()?.collect();
+
+            let (property, values): (AnimatedProperty, Vec<Vec4>) = match reader.read_outputs()? {
+                ReadOutputs::Translations(iter) => (
+                    AnimatedProperty::Translation,
+                    iter.map(|v| Vec3::from(v).extend(0.0)).collect(),
+                ),
+                ReadOutputs::Rotations(iter) => (
+                    AnimatedProperty::Rotation,
+                    iter.into_f32().map(Vec4::from).collect(),
+                ),
+                ReadOutputs::Scales(iter) => (
+                    AnimatedProperty::Scale,
+                    iter.map(|v| Vec3::from(v).extend(0.0)).collect(),
+                ),
+                ReadOutputs::MorphTargetWeights(_) => {
+                    log::warn!(
+                        "Morph target animation in {:?} is not supported; ignoring",
+                        anim.name()
+                    );
+                    return None;
+                }
+            };
+
+            let interpolation = match channel.sampler().interpolation() {
+                gltf::animation::Interpolation::Step => Interpolation::Step,
+                gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+            };
+
+            let values_per_key = if interpolation == Interpolation::CubicSpline {
+                3
+            } else {
+                1
+            };
+
+            if times.is_empty() || values.len() != times.len() * values_per_key {
+                log::warn!("Malformed animation channel in {:?}; ignoring", anim.name());
+                return None;
+            }
+
+            Some(AnimationChannel {
+                node: channel.target().node().index() as u32,
+                property,
+                interpolation,
+                times,
+                values,
+            })
+        })
+        .collect();
 
-                        if !tangents_found && uvs_found {
-                            log::trace!(
-                                "Mesh had UVs but no tangents. Calculating the tangents..."
-                            );
-
-                            mikktspace::generate_tangents(&mut TangentCalcContext {
-                                indices: indices.as_slice(),
-                                positions: positions.as_slice(),
-                                normals: normals.as_slice(),
-                                uvs: uvs.as_slice(),
-                                tangents: tangents.as_mut_slice(),
+    Animation {
+        name: anim.name().map(str::to_owned),
+        channels,
+    }
+}
+
+// Skins refer to joints anywhere in the scene, so we need all the transforms up front.
+fn gltf_node_xforms(gltf: &gltf::Document, scene: &gltf::Scene, root_xform: Mat4) -> Vec<Mat4> {
+    let mut node_xforms = vec![Mat4::IDENTITY; gltf.nodes().count()];
+    for node in scene.nodes() {
+        iter_gltf_node_tree(
+            &node,
+            root_xform,
+            &mut |node: &gltf::scene::Node, xform: Mat4| {
+                node_xforms[node.index()] = xform;
+            },
+        );
+    }
+    node_xforms
+}
+
+impl TriangleMesh {
+    // Vertices after the last skinned primitive don't move either.
+    fn finish_skinning(&mut self) {
+        if self.is_skinned() {
+            self.joints.resize(self.positions.len(), [0; 4]);
+            self.weights.resize(self.positions.len(), [0.0; 4]);
+        }
+    }
+}
+
+struct GltfMeshLoader<'a> {
+    path: &'a Path,
+    buffers: &'a [bytes::Bytes],
+    images: &'a [ImageSource],
+    material_extensions: &'a [GltfMaterialExtensions],
+    node_xforms: Vec<Mat4>,
+    root_xform: Mat4,
+}
+
+impl<'a> GltfMeshLoader<'a> {
+    /// Appends the primitives of the mesh referenced by `node` (if any) to `res`, transformed by `node_xform`.
+    fn append_node_mesh(
+        &self,
+        res: &mut TriangleMesh,
+        skin_joint_base: &mut HashMap<usize, usize>,
+        node: &gltf::scene::Node,
+        node_xform: Mat4,
+    ) {
+        let buffers = self.buffers;
+        let root_xform = self.root_xform;
+
+        if let Some(mesh) = node.mesh() {
+            for prim in mesh.primitives() {
+                let warn = |res: &mut TriangleMesh, kind: ImportWarningKind| {
+                    res.import_warnings.push(ImportWarning {
+                        mesh: mesh.name().map(str::to_owned),
+                        primitive: prim.index(),
+                        kind,
+                    });
+                };
+
+                if matches!(
+                    prim.mode(),
+                    gltf::mesh::Mode::Points
+                        | gltf::mesh::Mode::Lines
+                        | gltf::mesh::Mode::LineLoop
+                        | gltf::mesh::Mode::LineStrip
+                ) {
+                    warn(
+                        res,
+                        ImportWarningKind::UnsupportedPrimitiveMode(prim.mode()),
+                    );
+                    continue;
+                }
+
+                let reader = prim.reader(|buffer| Some(&buffers[buffer.index()]));
+
+                // Skinned vertices are in the skeleton's bind space, and the transform
+                // of the node which references the mesh is ignored, as per the spec.
+                let skin_joints = node
+                    .skin()
+                    .and_then(|skin| Some((skin, reader.read_joints(0)?)))
+                    .and_then(|(skin, joints)| {
+                        let joint_base =
+                            *skin_joint_base.entry(skin.index()).or_insert_with(|| {
+                                append_gltf_skin(res, &skin, buffers, &self.node_xforms, root_xform)
                             });
+
+                        let joints = joints
+                            .into_u16()
+                            .map(|joints| {
+                                let mut packed = [0u16; 4];
+                                for (dst, src) in packed.iter_mut().zip(joints) {
+                                    *dst = u16::try_from(joint_base + src as usize).ok()?;
+                                }
+                                Some(packed)
+                            })
+                            .collect::<Option<Vec<_>>>();
+
+                        if joints.is_none() {
+                            warn(res, ImportWarningKind::TooManyJoints);
                         }
 
-                        // --------------------------------------------------------
-                        // Write it all to the output
+                        joints
+                    });
 
-                        {
-                            // log::info!("Loading a mesh with {} indices", indices.len());
-                            let base_index = res.positions.len() as u32;
-                            for i in &mut indices {
-                                *i += base_index;
-                            }
+                let xform = if skin_joints.is_some() {
+                    root_xform
+                } else {
+                    node_xform
+                };
+                let flip_winding_order = xform.determinant() < 0.0;
 
-                            res.indices.append(&mut indices);
-                            res.colors.append(&mut colors);
-                            res.material_ids.append(&mut material_ids);
-                        }
+                let res_material_index = res.materials.len() as u32;
 
-                        for v in positions {
-                            let pos = (xform * Vec3::from(v).extend(1.0)).truncate();
-                            res.positions.push(pos.into());
-                        }
+                {
+                    let material_extensions = prim
+                        .material()
+                        .index()
+                        .and_then(|idx| self.material_extensions.get(idx))
+                        .copied()
+                        .unwrap_or_default();
 
-                        for v in normals {
-                            let norm = (xform * Vec3::from(v).extend(0.0)).truncate().normalize();
-                            res.normals.push(norm.into());
-                        }
+                    let (mut maps, mut material) =
+                        load_gltf_material(&prim.material(), &material_extensions, self.images);
+
+                    let map_base = res.maps.len() as u32;
+                    for id in material.maps.iter_mut() {
+                        *id += map_base;
+                    }
+
+                    res.materials.push(material);
+                    res.maps.append(&mut maps);
+                }
+
+                // Collect positions (required)
+                let positions = if let Some(iter) = reader.read_positions() {
+                    iter.collect::<Vec<_>>()
+                } else {
+                    warn(res, ImportWarningKind::MissingPositions);
+                    continue;
+                };
+
+                // Collect normals (required)
+                let normals = if let Some(iter) = reader.read_normals() {
+                    iter.collect::<Vec<_>>()
+                } else {
+                    warn(res, ImportWarningKind::MissingNormals);
+                    continue;
+                };
+
+                // Collect tangents (optional)
+                let (mut tangents, tangents_found) = if let Some(iter) = reader.read_tangents() {
+                    (iter.collect::<Vec<_>>(), true)
+                } else {
+                    (vec![[1.0, 0.0, 0.0, 0.0]; positions.len()], false)
+                };
+
+                // Collect uvs (optional)
+                let (mut uvs, uvs_found) = if let Some(iter) = reader.read_tex_coords(0) {
+                    (iter.into_f32().collect::<Vec<_>>(), true)
+                } else {
+                    (vec![[0.0, 0.0]; positions.len()], false)
+                };
+
+                // Collect colors (optional)
+                let mut colors = if let Some(iter) = reader.read_colors(0) {
+                    iter.into_rgba_f32().collect::<Vec<_>>()
+                } else {
+                    vec![[1.0, 1.0, 1.0, 1.0]; positions.len()]
+                };
+
+                // Collect material ids
+                let mut material_ids = vec![res_material_index; positions.len()];
+
+                // Collect indices
+                let mut indices: Vec<u32>;
+                {
+                    indices = if let Some(indices_reader) = reader.read_indices() {
+                        indices_reader.into_u32().collect()
+                    } else {
+                        (0..positions.len() as u32).collect()
+                    };
+
+                    // Lines and points were rejected above
+                    indices = triangulate_primitive(prim.mode(), indices).unwrap_or_default();
 
-                        for v in tangents {
-                            let v = Vec4::from(v);
-                            let t = (xform * v.truncate().extend(0.0)).truncate().normalize();
-                            res.tangents.push(
-                                t.extend(v.w * if flip_winding_order { -1.0 } else { 1.0 })
-                                    .into(),
-                            );
+                    if indices.len() % 3 != 0 {
+                        warn(res, ImportWarningKind::IncompleteTriangle);
+                        indices.truncate(indices.len() - indices.len() % 3);
+                    }
+
+                    if flip_winding_order {
+                        for tri in indices.chunks_exact_mut(3) {
+                            tri.swap(0, 2);
                         }
+                    }
+                }
+
+                if !tangents_found && uvs_found {
+                    log::trace!("Mesh had UVs but no tangents. Calculating the tangents...");
 
-                        res.uvs.append(&mut uvs);
+                    mikktspace::generate_tangents(&mut TangentCalcContext {
+                        indices: indices.as_slice(),
+                        positions: positions.as_slice(),
+                        normals: normals.as_slice(),
+                        uvs: uvs.as_slice(),
+                        tangents: tangents.as_mut_slice(),
+                    });
+                }
+
+                // --------------------------------------------------------
+                // Write it all to the output
+
+                {
+                    // log::info!("Loading a mesh with {} indices", indices.len());
+                    let base_index = res.positions.len() as u32;
+                    for i in &mut indices {
+                        *i += base_index;
                     }
+
+                    res.indices.append(&mut indices);
+                    res.colors.append(&mut colors);
+                    res.material_ids.append(&mut material_ids);
                 }
-            };
 
-            let xform = Mat4::from_scale_rotation_translation(
-                Vec3::splat(self.scale),
-                self.rotation,
-                Vec3::ZERO,
-            );
-            for node in scene.nodes() {
-                iter_gltf_node_tree(&node, xform, &mut process_node);
-            }
+                for v in positions {
+                    let pos = (xform * Vec3::from(v).extend(1.0)).truncate();
+                    res.positions.push(pos.into());
+                }
 
-            Ok(res)
-        } else {
-            Err(anyhow::anyhow!("No default scene found in gltf"))
+                for v in normals {
+                    let norm = (xform * Vec3::from(v).extend(0.0)).truncate().normalize();
+                    res.normals.push(norm.into());
+                }
+
+                for v in tangents {
+                    let v = Vec4::from(v);
+                    let t = (xform * v.truncate().extend(0.0)).truncate().normalize();
+                    res.tangents.push(
+                        t.extend(v.w * if flip_winding_order { -1.0 } else { 1.0 })
+                            .into(),
+                    );
+                }
+
+                res.uvs.append(&mut uvs);
+
+                if let Some(mut joints) = skin_joints {
+                    let mut weights = if let Some(iter) = reader.read_weights(0) {
+                        iter.into_f32().collect::<Vec<_>>()
+                    } else {
+                        vec![[1.0, 0.0, 0.0, 0.0]; joints.len()]
+                    };
+
+                    for w in &mut weights {
+                        let sum: f32 = w.iter().sum();
+                        if sum > 0.0 {
+                            *w = w.map(|w| w / sum);
+                        }
+                    }
+
+                    // Vertices of any preceding static primitives don't move.
+                    let first_vertex = res.positions.len() - joints.len();
+                    res.joints.resize(first_vertex, [0; 4]);
+                    res.weights.resize(first_vertex, [0.0; 4]);
+
+                    res.joints.append(&mut joints);
+                    res.weights.append(&mut weights);
+                }
+            }
         }
     }
 }
 
+/// Appends the joints of `skin` to `res`, and returns the index of the first one.
+fn append_gltf_skin(
+    res: &mut TriangleMesh,
+    skin: &gltf::Skin,
+    buffers: &[bytes::Bytes],
+    node_xforms: &[Mat4],
+    root_xform: Mat4,
+) -> usize {
+    let joint_base = res.joint_nodes.len();
+    let joint_count = skin.joints().len();
+
+    let inverse_bind_matrices: Vec<Mat4> = skin
+        .reader(|buffer| Some(&buffers[buffer.index()][..]))
+        .read_inverse_bind_matrices()
+        .map_or_else(
+            || vec![Mat4::IDENTITY; joint_count],
+            |iter| iter.map(|m| Mat4::from_cols_array_2d(&m)).collect(),
+        );
+
+    // Vertices get `root_xform` baked in, so undo it before applying the bind matrices.
+    let inv_root_xform = root_xform.inverse();
+
+    for (joint, inverse_bind) in skin.joints().zip(inverse_bind_matrices) {
+        res.joint_nodes.push(joint.index() as u32);
+        res.inverse_bind_matrices
+            .push((inverse_bind * inv_root_xform).to_cols_array());
+        res.joint_rest_transforms
+            .push(node_xforms[joint.index()].to_cols_array());
+    }
+
+    joint_base
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct PackedVertex {
@@ -514,6 +1171,38 @@ impl<T> FlatVec<T> {
     pub fn iter(&self) -> impl Iterator<Item = &T> {
         self.as_slice().iter()
     }
+
+    /// Returns the elements after checking that both this header and the elements
+    /// lie within `payload`, the flattened asset data containing this vector.
+    fn validated_slice(&self, payload: &[u8]) -> anyhow::Result<&[T]> {
+        let len = self.len;
+        let offset = self.offset;
+
+        let field_addr = (std::ptr::addr_of!(self.offset) as usize)
+            .checked_sub(payload.as_ptr() as usize)
+            .filter(|&addr| addr + size_of::<u64>() <= payload.len())
+            .context("FlatVec header outside of the asset payload")?;
+
+        let data_end = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| field_addr.checked_add(offset))
+            .zip(
+                usize::try_from(len)
+                    .ok()
+                    .and_then(|len| len.checked_mul(size_of::<T>())),
+            )
+            .and_then(|(data_start, data_size)| data_start.checked_add(data_size));
+
+        anyhow::ensure!(
+            matches!(data_end, Some(end) if end <= payload.len()),
+            "FlatVec with {} elements at offset {} exceeds the asset payload of {} bytes",
+            len,
+            offset,
+            payload.len()
+        );
+
+        Ok(self.as_slice())
+    }
 }
 
 pub fn flatten_vec_header(writer: &mut Vec<u8>, len: usize) -> usize {
@@ -536,6 +1225,151 @@ pub fn flatten_bytes(writer: &mut impl std::io::Write, data: &[u8]) {
     writer.write_all(data).unwrap();
 }
 
+const FLAT_ASSET_MAGIC: [u8; 8] = *b"KJYASSET";
+const FLAT_ASSET_ENDIANNESS_MARKER: u32 = 0x01020304;
+const FLAT_ASSET_NAME_LEN: usize = 16;
+const FLAT_ASSET_SECTION_ALIGNMENT: usize = 16;
+
+/// Bumped whenever the flattened layout of any asset changes, including the plain types
+/// in them, such as `MeshMaterial`. Files of other versions are rejected, and need to be re-baked.
+pub const FLAT_ASSET_FORMAT_VERSION: u32 = 5;
+
+/// Starts every flattened asset file. Followed by `section_count` of `FlatSectionDesc`,
+/// and then the payload, at the start of which is the asset's `Flat` structure.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FlatAssetHeader {
+    magic: [u8; 8],
+    asset_name: [u8; FLAT_ASSET_NAME_LEN],
+    format_version: u32,
+    // Written in native endianness; only matches on machines of the same endianness
+    endianness_marker: u32,
+    payload_offset: u64,
+    payload_size: u64,
+    section_count: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FlatSectionDesc {
+    // Relative to the start of the payload
+    offset: u64,
+    size: u64,
+    checksum: u64,
+}
+
+fn flat_asset_name(name: &str) -> [u8; FLAT_ASSET_NAME_LEN] {
+    let mut res = [0u8; FLAT_ASSET_NAME_LEN];
+    let len = name.len().min(FLAT_ASSET_NAME_LEN);
+    res[..len].copy_from_slice(&name.as_bytes()[..len]);
+    res
+}
+
+fn flat_asset_checksum(bytes: &[u8]) -> u64 {
+    wyhash::wyhash(bytes, 0)
+}
+
+// `alignment` must be a power of two
+fn align_up(addr: usize, alignment: usize) -> usize {
+    (addr + alignment - 1) & !(alignment - 1)
+}
+
+fn read_plain<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    let bytes = bytes.get(offset..offset.checked_add(size_of::<T>())?)?;
+    Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Checks the header and section checksums of a flattened asset file,
+/// and returns its payload.
+fn validate_flat_asset_file<'a>(
+    bytes: &'a [u8],
+    asset_name: &str,
+    root_size: usize,
+) -> anyhow::Result<&'a [u8]> {
+    let header: FlatAssetHeader =
+        read_plain(bytes, 0).context("The file is too small for an asset header")?;
+
+    anyhow::ensure!(header.magic == FLAT_ASSET_MAGIC, "Not a baked asset file");
+    anyhow::ensure!(
+        header.endianness_marker == FLAT_ASSET_ENDIANNESS_MARKER,
+        "The asset was baked on a machine of different endianness"
+    );
+    anyhow::ensure!(
+        header.format_version == FLAT_ASSET_FORMAT_VERSION,
+        "The asset is of format version {}, but {} is expected; it needs to be re-baked",
+        header.format_version,
+        FLAT_ASSET_FORMAT_VERSION
+    );
+    anyhow::ensure!(
+        header.asset_name == flat_asset_name(asset_name),
+        "Expected a {} asset, but found {}",
+        asset_name,
+        String::from_utf8_lossy(&header.asset_name).trim_end_matches('\0')
+    );
+
+    let payload = usize::try_from(header.payload_offset)
+        .ok()
+        .zip(usize::try_from(header.payload_size).ok())
+        .and_then(|(offset, size)| bytes.get(offset..offset.checked_add(size)?))
+        .context("The asset payload exceeds the file size")?;
+
+    let section_table = usize::try_from(header.section_count)
+        .ok()
+        .and_then(|count| count.checked_mul(size_of::<FlatSectionDesc>()))
+        .and_then(|table_size| {
+            bytes.get(size_of::<FlatAssetHeader>()..size_of::<FlatAssetHeader>() + table_size)
+        })
+        .context("The asset section table exceeds the file size")?;
+
+    for (i, desc) in section_table
+        .chunks_exact(size_of::<FlatSectionDesc>())
+        .enumerate()
+    {
+        let desc: FlatSectionDesc = read_plain(desc, 0).unwrap();
+
+        let section = usize::try_from(desc.offset)
+            .ok()
+            .zip(usize::try_from(desc.size).ok())
+            .and_then(|(offset, size)| payload.get(offset..offset.checked_add(size)?))
+            .with_context(|| format!("Asset section {} exceeds the payload", i))?;
+
+        anyhow::ensure!(
+            flat_asset_checksum(section) == desc.checksum,
+            "Checksum mismatch in asset section {}",
+            i
+        );
+    }
+
+    anyhow::ensure!(
+        payload.len() >= root_size,
+        "The asset payload is too small for a {}",
+        asset_name
+    );
+
+    Ok(payload)
+}
+
+/// The `Flat` structure of a `def_asset!`, stored in a file by `Proto::flatten_into`.
+pub trait FlatAsset: Sized {
+    const ASSET_NAME: &'static str;
+
+    /// Checks the header, section checksums, and every `FlatVec` of a flattened asset file,
+    /// so that references into it can be handed out safely.
+    fn validate(file_bytes: &[u8]) -> anyhow::Result<()>;
+
+    /// # Safety
+    /// `file_bytes` must have passed `validate`.
+    unsafe fn from_validated_bytes(file_bytes: &[u8]) -> &Self {
+        let header: FlatAssetHeader = read_plain(file_bytes, 0).unwrap();
+        &*(file_bytes.as_ptr().add(header.payload_offset as usize) as *const Self)
+    }
+
+    fn from_bytes(file_bytes: &[u8]) -> anyhow::Result<&Self> {
+        Self::validate(file_bytes)?;
+        Ok(unsafe { Self::from_validated_bytes(file_bytes) })
+    }
+}
+
 pub struct DeferredBlob {
     pub fixup_addr: usize, // offset within parent
     pub nested: FlattenCtx,
@@ -549,21 +1383,26 @@ pub struct FlattenCtx {
 }
 
 impl FlattenCtx {
+    // Breadth-first, matching the order in which `finish` lays out the sections
     fn allocate_section_indices(&mut self) {
         let mut counter = 0;
-        self.allocate_section_indices_impl(&mut counter);
-    }
+        let mut ctx_list: Vec<&mut Self> = vec![self];
 
-    fn allocate_section_indices_impl(&mut self, counter: &mut usize) {
-        self.section_idx = Some(*counter);
-        *counter += 1;
+        while !ctx_list.is_empty() {
+            let mut next_ctx_list: Vec<&mut Self> = vec![];
+
+            for ctx in ctx_list {
+                ctx.section_idx = Some(counter);
+                counter += 1;
+
+                next_ctx_list.extend(ctx.deferred.iter_mut().map(|deferred| &mut deferred.nested));
+            }
 
-        for child in &mut self.deferred {
-            child.nested.allocate_section_indices_impl(counter);
+            ctx_list = next_ctx_list;
         }
     }
 
-    fn finish(mut self, writer: &mut impl std::io::Write) {
+    fn finish(mut self, asset_name: &str, writer: &mut impl std::io::Write) {
         self.allocate_section_indices();
 
         type FixupAddr = usize;
@@ -604,8 +1443,8 @@ impl FlattenCtx {
         let section_base_addr: Vec<usize> = sections
             .iter()
             .map(|s| {
-                let base_addr = total_bytes;
-                total_bytes += s.bytes.len();
+                let base_addr = align_up(total_bytes, FLAT_ASSET_SECTION_ALIGNMENT);
+                total_bytes = base_addr + s.bytes.len();
                 base_addr
             })
             .collect();
@@ -624,9 +1463,44 @@ impl FlattenCtx {
             }
         }
 
+        let section_table: Vec<FlatSectionDesc> = sections
+            .iter()
+            .zip(&section_base_addr)
+            .map(|(section, &section_addr)| FlatSectionDesc {
+                offset: section_addr as u64,
+                size: section.bytes.len() as u64,
+                checksum: flat_asset_checksum(&section.bytes),
+            })
+            .collect();
+
+        let header_size =
+            size_of::<FlatAssetHeader>() + section_table.len() * size_of::<FlatSectionDesc>();
+        let payload_offset = align_up(header_size, FLAT_ASSET_SECTION_ALIGNMENT);
+
+        // Write the header out
+        flatten_plain_field(
+            writer,
+            &FlatAssetHeader {
+                magic: FLAT_ASSET_MAGIC,
+                asset_name: flat_asset_name(asset_name),
+                format_version: FLAT_ASSET_FORMAT_VERSION,
+                endianness_marker: FLAT_ASSET_ENDIANNESS_MARKER,
+                payload_offset: payload_offset as u64,
+                payload_size: total_bytes as u64,
+                section_count: section_table.len() as u64,
+            },
+        );
+        for desc in &section_table {
+            flatten_plain_field(writer, desc);
+        }
+        flatten_bytes(writer, &vec![0u8; payload_offset - header_size]);
+
         // Write sections out
-        for section in sections {
+        let mut written_bytes = 0usize;
+        for (section, section_addr) in sections.into_iter().zip(section_base_addr) {
+            flatten_bytes(writer, &vec![0u8; section_addr - written_bytes]);
             writer.write_all(section.bytes.as_slice()).unwrap();
+            written_bytes = section_addr + section.bytes.len();
         }
     }
 }
@@ -650,6 +1524,15 @@ macro_rules! def_asset {
             nested,
         });
     };
+    (@validate $payload:expr; $field:expr; Vec($($type:tt)+)) => {
+        let _items = $field.validated_slice($payload)?;
+        def_asset!(@validate_items $payload; _items; $($type)+ );
+    };
+    (@validate_items $payload:expr; $items:expr; Vec($($type:tt)+)) => {
+        for item in $items.iter() {
+            def_asset!(@validate $payload; item; Vec($($type)+) );
+        }
+    };
 
     // Bytes
     (@proto_ty Bytes) => {
@@ -661,12 +1544,20 @@ macro_rules! def_asset {
     (@flatten $output:expr; $field:expr; Bytes) => {
         let fixup_addr = flatten_vec_header(&mut $output.bytes, $field.len());
         let mut nested = FlattenCtx::default();
-        flatten_bytes(&mut $output.bytes, $field);
+        flatten_bytes(&mut nested.bytes, $field);
         $output.deferred.push(DeferredBlob {
             fixup_addr,
             nested,
         });
     };
+    (@validate $payload:expr; $field:expr; Bytes) => {
+        $field.validated_slice($payload)?;
+    };
+    (@validate_items $payload:expr; $items:expr; Bytes) => {
+        for item in $items.iter() {
+            def_asset!(@validate $payload; item; Bytes);
+        }
+    };
 
     // Asset
     (@proto_ty Asset($($type:tt)+)) => {
@@ -682,6 +1573,8 @@ macro_rules! def_asset {
         };
         flatten_plain_field(&mut $output.bytes, &asset_ref)
     };
+    // Referenced assets are validated when they're loaded
+    (@validate $payload:expr; $field:expr; Asset($($type:tt)+)) => {};
 
 
     // Plain type
@@ -694,6 +1587,8 @@ macro_rules! def_asset {
     (@flatten $output:expr; $field:expr; $($type:tt)+) => {
         flatten_plain_field(&mut $output.bytes, $field)
     };
+    (@validate $payload:expr; $field:expr; $($type:tt)+) => {};
+    (@validate_items $payload:expr; $items:expr; $($type:tt)+) => {};
 
     (
         $(
@@ -735,7 +1630,26 @@ macro_rules! def_asset {
                         def_asset!(@flatten &mut output; &self.$name; $($type)+ );
                     )*
 
-                    output.finish(writer)
+                    output.finish(stringify!($struct_name), writer)
+                }
+            }
+
+            impl FlatAsset for Flat {
+                const ASSET_NAME: &'static str = stringify!($struct_name);
+
+                fn validate(file_bytes: &[u8]) -> anyhow::Result<()> {
+                    let payload = validate_flat_asset_file(
+                        file_bytes,
+                        Self::ASSET_NAME,
+                        std::mem::size_of::<Self>(),
+                    )?;
+                    let asset = unsafe { &*(payload.as_ptr() as *const Self) };
+
+                    $(
+                        def_asset!(@validate payload; asset.$name; $($type)+ );
+                    )*
+
+                    Ok(())
                 }
             }
         }
@@ -789,6 +1703,8 @@ def_asset! {
         format { kajiya_backend::ash::vk::Format }
         extent { [u32; 3] }
         mips { Vec(Vec(u8)) }
+        // Of the top mip relative to the source; infinite if stored losslessly
+        compression_psnr { f32 }
     }
 }
 
@@ -801,9 +1717,21 @@ def_asset! {
         tangents { Vec([f32; 4]) }
         colors { Vec([f32; 4]) }
         indices { Vec(u32) }
+        // Progressively coarser versions of `indices`, with their object-space errors
+        lod_indices { Vec(Vec(u32)) }
+        lod_errors { Vec(f32) }
+        // Partitioning of `indices` for GPU culling; empty unless requested in `MeshPackParams`
+        meshlets { Vec(Meshlet) }
+        meshlet_vertices { Vec(u32) }
+        meshlet_triangles { Vec(u8) }
         material_ids { Vec(u32) }
         materials { Vec(MeshMaterial) }
         maps { Vec(Asset(GpuImage)) }
+        joints { Vec([u16; 4]) }
+        weights { Vec([f32; 4]) }
+        inverse_bind_matrices { Vec([f32; 16]) }
+        joint_rest_transforms { Vec([f32; 16]) }
+        joint_nodes { Vec(u32) }
     }
 }
 
@@ -821,7 +1749,16 @@ pub struct PackedTriangleMesh {
 
 pub type PackedTriangleMesh = PackedTriMesh::Proto;
 
-pub fn pack_triangle_mesh(mesh: &TriangleMesh) -> PackedTriangleMesh {
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MeshPackParams {
+    pub lods: LodChainParams,
+
+    /// Partition the full-detail mesh into meshlets
+    pub meshlets: bool,
+}
+
+/// Best preceded by `TriangleMesh::optimize_vertex_order`.
+pub fn pack_triangle_mesh(mesh: &TriangleMesh, params: &MeshPackParams) -> PackedTriangleMesh {
     let mut verts: Vec<PackedVertex> = Vec::with_capacity(mesh.positions.len());
 
     for (i, pos) in mesh.positions.iter().enumerate() {
@@ -838,7 +1775,7 @@ pub fn pack_triangle_mesh(mesh: &TriangleMesh) -> PackedTriangleMesh {
         .iter()
         .map(|map| {
             let (image, params) = match map {
-                MeshMaterialMap::Image { source, params } => (
+                MeshMaterialMap::Image { source, params, .. } => (
                     super::image::LoadImage::new(source).unwrap().into_lazy(),
                     *params,
                 ),
@@ -849,6 +1786,7 @@ pub fn pack_triangle_mesh(mesh: &TriangleMesh) -> PackedTriangleMesh {
                         use_mips: false,
                         compression: TexCompressionMode::None,
                         channel_swizzle: None,
+                        max_size: DEFAULT_TEX_MAX_SIZE,
                     },
                 ),
             };
@@ -857,15 +1795,35 @@ pub fn pack_triangle_mesh(mesh: &TriangleMesh) -> PackedTriangleMesh {
         })
         .collect();
 
+    let lods = build_lod_chain(&mesh.positions, &mesh.indices, &params.lods);
+    let meshlets = if params.meshlets {
+        build_meshlets(&mesh.positions, &mesh.indices)
+    } else {
+        Default::default()
+    };
+
     PackedTriangleMesh {
         verts,
         uvs: mesh.uvs.clone(),
         tangents: mesh.tangents.clone(),
         colors: mesh.colors.clone(),
         indices: mesh.indices.clone(),
+        lod_errors: lods.iter().map(|lod| lod.error).collect(),
+        lod_indices: lods
+            .into_iter()
+            .map(|lod| optimize_vertex_cache(&lod.indices, mesh.positions.len()))
+            .collect(),
+        meshlets: meshlets.meshlets,
+        meshlet_vertices: meshlets.vertices,
+        meshlet_triangles: meshlets.triangles,
         material_ids: mesh.material_ids.clone(),
         materials: mesh.materials.clone(),
         maps,
+        joints: mesh.joints.clone(),
+        weights: mesh.weights.clone(),
+        inverse_bind_matrices: mesh.inverse_bind_matrices.clone(),
+        joint_rest_transforms: mesh.joint_rest_transforms.clone(),
+        joint_nodes: mesh.joint_nodes.clone(),
     }
 }
 
@@ -918,3 +1876,54 @@ impl<'a> mikktspace::Geometry for TangentCalcContext<'a> {
         self.tangents[self.indices[face * 3 + vert] as usize] = tangent;
     }
 }
+
+#[test]
+fn test_triangulate_primitive() {
+    use gltf::mesh::Mode;
+
+    assert_eq!(
+        triangulate_primitive(Mode::TriangleStrip, vec![0, 1, 2, 3, 4]),
+        Some(vec![0, 1, 2, 2, 1, 3, 2, 3, 4])
+    );
+
+    // Degenerate triangles stitching strips together are dropped
+    assert_eq!(
+        triangulate_primitive(Mode::TriangleStrip, vec![0, 1, 2, 2, 5, 5, 6, 7]),
+        Some(vec![0, 1, 2, 6, 5, 7])
+    );
+
+    assert_eq!(
+        triangulate_primitive(Mode::TriangleFan, vec![0, 1, 2, 3]),
+        Some(vec![1, 2, 0, 2, 3, 0])
+    );
+
+    assert_eq!(
+        triangulate_primitive(Mode::TriangleStrip, vec![0, 1]),
+        Some(vec![])
+    );
+    assert_eq!(triangulate_primitive(Mode::Lines, vec![0, 1]), None);
+}
+
+#[test]
+fn test_flat_asset_validation() {
+    let image = GpuImage::Proto {
+        format: kajiya_backend::ash::vk::Format::R8G8B8A8_UNORM,
+        extent: [2, 1, 1],
+        mips: vec![vec![0u8; 8], vec![1u8; 4]],
+        compression_psnr: f32::INFINITY,
+    };
+
+    let mut bytes = Vec::new();
+    image.flatten_into(&mut bytes);
+
+    let flat = GpuImage::Flat::from_bytes(&bytes).unwrap();
+    assert_eq!(flat.mips.len(), 2);
+    assert_eq!(flat.mips[1].as_slice(), &[1u8; 4]);
+
+    assert!(GpuImage::Flat::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(PackedTriMesh::Flat::from_bytes(&bytes).is_err());
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert!(GpuImage::Flat::from_bytes(&corrupted).is_err());
+}
@@ -0,0 +1,481 @@
+//! Quadric edge collapse simplification, after "Surface Simplification Using Quadric Error Metrics",
+//! Garland and Heckbert, 1997.
+//!
+//! Vertices are only ever collapsed onto their neighbors, so simplified index lists reference
+//! the original vertices, and all levels of detail of a mesh can share its vertex buffers.
+
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct LodChainParams {
+    /// Maximum number of levels of detail to generate, not counting the full-detail mesh
+    pub max_lods: usize,
+
+    /// Target index count of each level, relative to the previous one
+    pub reduction: f32,
+}
+
+impl Default for LodChainParams {
+    fn default() -> Self {
+        Self {
+            max_lods: 3,
+            reduction: 0.5,
+        }
+    }
+}
+
+pub struct SimplifiedMesh {
+    pub indices: Vec<u32>,
+
+    /// Approximate distance of the simplified surface from the original, in the units of `positions`
+    pub error: f32,
+}
+
+/// Simplifies the triangle list `indices` to about `target_index_count` indices.
+///
+/// Mesh boundaries and vertices split by attribute seams (vertices sharing a position) are kept
+/// in place, which preserves UV and material boundaries, but also limits how far meshes with many
+/// seams can be simplified.
+pub fn simplify_mesh(
+    positions: &[[f32; 3]],
+    indices: &[u32],
+    target_index_count: usize,
+) -> SimplifiedMesh {
+    Simplifier::new(positions, indices).simplify_to(target_index_count)
+}
+
+/// Simplifies the mesh into progressively coarser levels of detail, continuing from one to the next,
+/// with errors relative to the original mesh. Stops early once a level wouldn't be meaningfully
+/// smaller than the previous one.
+pub fn build_lod_chain(
+    positions: &[[f32; 3]],
+    indices: &[u32],
+    params: &LodChainParams,
+) -> Vec<SimplifiedMesh> {
+    let mut simplifier = Simplifier::new(positions, indices);
+    let mut lods: Vec<SimplifiedMesh> = Vec::new();
+    let mut prev_index_count = indices.len();
+
+    for _ in 0..params.max_lods {
+        let target_index_count = (prev_index_count as f32 * params.reduction) as usize;
+        if target_index_count < 3 {
+            break;
+        }
+
+        let lod = simplifier.simplify_to(target_index_count);
+
+        // Not worth the memory if it's barely coarser
+        if lod.indices.len() as f32 > prev_index_count as f32 * 0.95 {
+            break;
+        }
+
+        prev_index_count = lod.indices.len();
+        lods.push(lod);
+    }
+
+    lods
+}
+
+/// Sum of squared distances to planes; a symmetric 4x4 matrix stored as its upper triangle.
+#[derive(Clone, Copy, Default)]
+struct Quadric {
+    m: [f64; 10],
+    weight: f64,
+}
+
+impl Quadric {
+    fn from_triangle(p0: [f64; 3], p1: [f64; 3], p2: [f64; 3]) -> Self {
+        let n = cross(sub(p1, p0), sub(p2, p0));
+        let double_area = dot(n, n).sqrt();
+        if double_area == 0.0 {
+            return Self::default();
+        }
+
+        let [a, b, c] = n.map(|x| x / double_area);
+        let d = -dot([a, b, c], p0);
+
+        // Weighted by area, so that the error is the mean over the surface
+        let weight = double_area * 0.5;
+
+        Self {
+            m: [
+                a * a,
+                a * b,
+                a * c,
+                a * d,
+                b * b,
+                b * c,
+                b * d,
+                c * c,
+                c * d,
+                d * d,
+            ]
+            .map(|x| x * weight),
+            weight,
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (a, b) in self.m.iter_mut().zip(other.m.iter()) {
+            *a += b;
+        }
+        self.weight += other.weight;
+    }
+
+    /// Mean squared distance of `p` to the planes
+    fn error(&self, p: [f64; 3]) -> f64 {
+        if self.weight == 0.0 {
+            return 0.0;
+        }
+
+        let [x, y, z] = p;
+        let m = &self.m;
+        let e = m[0] * x * x
+            + 2.0 * m[1] * x * y
+            + 2.0 * m[2] * x * z
+            + 2.0 * m[3] * x
+            + m[4] * y * y
+            + 2.0 * m[5] * y * z
+            + 2.0 * m[6] * y
+            + m[7] * z * z
+            + 2.0 * m[8] * z
+            + m[9];
+
+        (e / self.weight).max(0.0)
+    }
+}
+
+#[derive(PartialEq)]
+struct Collapse {
+    cost: f64,
+    from: u32,
+    to: u32,
+}
+
+impl Eq for Collapse {}
+
+impl Ord for Collapse {
+    // Reversed, so that `BinaryHeap` pops the cheapest collapse first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .partial_cmp(&self.cost)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| (other.from, other.to).cmp(&(self.from, self.to)))
+    }
+}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Simplifier {
+    positions: Vec<[f64; 3]>,
+
+    // Index of the first vertex with the same position
+    position_ids: Vec<u32>,
+    locked: Vec<bool>,
+    collapsed: Vec<bool>,
+
+    // Per position
+    quadrics: Vec<Quadric>,
+
+    triangles: Vec<[u32; 3]>,
+    triangle_alive: Vec<bool>,
+    alive_triangle_count: usize,
+
+    // Triangles which were using the vertex at some point; some might not anymore.
+    vertex_triangles: Vec<Vec<u32>>,
+
+    collapses: BinaryHeap<Collapse>,
+
+    // Of the collapses done so far
+    max_cost: f64,
+}
+
+impl Simplifier {
+    fn new(positions: &[[f32; 3]], indices: &[u32]) -> Self {
+        let vertex_count = positions.len();
+
+        let mut position_ids = Vec::with_capacity(vertex_count);
+        let mut vertices_at_position = vec![0u32; vertex_count];
+        {
+            let mut first_vertex_at: HashMap<[u32; 3], u32> = HashMap::new();
+            for (i, p) in positions.iter().enumerate() {
+                let id = *first_vertex_at
+                    .entry(p.map(f32::to_bits))
+                    .or_insert(i as u32);
+                position_ids.push(id);
+                vertices_at_position[id as usize] += 1;
+            }
+        }
+
+        let triangles: Vec<[u32; 3]> = indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .collect();
+
+        // Edges between positions which aren't shared by exactly two triangles are boundaries.
+        let mut edge_use_count: HashMap<(u32, u32), u32> = HashMap::new();
+        for t in &triangles {
+            for i in 0..3 {
+                let a = position_ids[t[i] as usize];
+                let b = position_ids[t[(i + 1) % 3] as usize];
+                *edge_use_count.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+
+        let mut position_locked: Vec<bool> = vertices_at_position.iter().map(|&n| n > 1).collect();
+        for (&(a, b), &count) in &edge_use_count {
+            if count != 2 {
+                position_locked[a as usize] = true;
+                position_locked[b as usize] = true;
+            }
+        }
+
+        let positions: Vec<[f64; 3]> = positions.iter().map(|p| p.map(f64::from)).collect();
+
+        let mut quadrics = vec![Quadric::default(); vertex_count];
+        let mut vertex_triangles = vec![Vec::new(); vertex_count];
+        for (ti, t) in triangles.iter().enumerate() {
+            let q = Quadric::from_triangle(
+                positions[t[0] as usize],
+                positions[t[1] as usize],
+                positions[t[2] as usize],
+            );
+
+            for &v in t {
+                quadrics[position_ids[v as usize] as usize].add(&q);
+                vertex_triangles[v as usize].push(ti as u32);
+            }
+        }
+
+        let mut res = Self {
+            locked: position_ids
+                .iter()
+                .map(|&id| position_locked[id as usize])
+                .collect(),
+            positions,
+            position_ids,
+            collapsed: vec![false; vertex_count],
+            quadrics,
+            triangle_alive: vec![true; triangles.len()],
+            alive_triangle_count: triangles.len(),
+            triangles,
+            vertex_triangles,
+            collapses: BinaryHeap::new(),
+            max_cost: 0.0,
+        };
+
+        for ti in 0..res.triangles.len() {
+            res.push_triangle_collapses(ti as u32);
+        }
+
+        res
+    }
+
+    fn collapse_cost(&self, from: u32, to: u32) -> f64 {
+        let mut q = self.quadrics[self.position_ids[from as usize] as usize];
+        q.add(&self.quadrics[self.position_ids[to as usize] as usize]);
+        q.error(self.positions[to as usize])
+    }
+
+    fn push_collapse(&mut self, from: u32, to: u32) {
+        if !self.locked[from as usize] {
+            self.collapses.push(Collapse {
+                cost: self.collapse_cost(from, to),
+                from,
+                to,
+            });
+        }
+    }
+
+    fn push_triangle_collapses(&mut self, ti: u32) {
+        let t = self.triangles[ti as usize];
+        for i in 0..3 {
+            let (a, b) = (t[i], t[(i + 1) % 3]);
+            self.push_collapse(a, b);
+            self.push_collapse(b, a);
+        }
+    }
+
+    fn triangle_has_position(&self, t: [u32; 3], position_id: u32) -> bool {
+        t.iter()
+            .any(|&v| self.position_ids[v as usize] == position_id)
+    }
+
+    fn triangle_normal(&self, t: [u32; 3]) -> [f64; 3] {
+        let [p0, p1, p2] = t.map(|v| self.positions[v as usize]);
+        cross(sub(p1, p0), sub(p2, p0))
+    }
+
+    fn alive_triangles_of(&self, v: u32) -> impl Iterator<Item = u32> + '_ {
+        self.vertex_triangles[v as usize]
+            .iter()
+            .copied()
+            .filter(move |&ti| {
+                self.triangle_alive[ti as usize] && self.triangles[ti as usize].contains(&v)
+            })
+    }
+
+    // Collapsing must neither flip triangles, nor leave `from` without `to` as a neighbor.
+    fn can_collapse(&self, from: u32, to: u32) -> bool {
+        let to_position = self.position_ids[to as usize];
+        let mut is_neighbor = false;
+
+        for ti in self.alive_triangles_of(from) {
+            let t = self.triangles[ti as usize];
+            if self.triangle_has_position(t, to_position) {
+                is_neighbor = true;
+                continue;
+            }
+
+            let moved = t.map(|v| if v == from { to } else { v });
+            if dot(self.triangle_normal(t), self.triangle_normal(moved)) <= 0.0 {
+                return false;
+            }
+        }
+
+        is_neighbor
+    }
+
+    fn collapse(&mut self, from: u32, to: u32) {
+        let to_position = self.position_ids[to as usize];
+        let from_triangles: Vec<u32> = self.alive_triangles_of(from).collect();
+
+        for ti in from_triangles {
+            let t = &mut self.triangles[ti as usize];
+            for v in t.iter_mut() {
+                if *v == from {
+                    *v = to;
+                }
+            }
+
+            let t = *t;
+            if t[0] == t[1]
+                || t[1] == t[2]
+                || t[2] == t[0]
+                || t.iter()
+                    .filter(|&&v| self.position_ids[v as usize] == to_position)
+                    .count()
+                    > 1
+            {
+                self.triangle_alive[ti as usize] = false;
+                self.alive_triangle_count -= 1;
+            } else {
+                self.vertex_triangles[to as usize].push(ti);
+            }
+        }
+
+        self.collapsed[from as usize] = true;
+
+        let from_quadric = self.quadrics[self.position_ids[from as usize] as usize];
+        self.quadrics[to_position as usize].add(&from_quadric);
+
+        // Costs around `to` have changed
+        let to_triangles: Vec<u32> = self.alive_triangles_of(to).collect();
+        for ti in to_triangles {
+            self.push_triangle_collapses(ti);
+        }
+    }
+
+    fn simplify_to(&mut self, target_index_count: usize) -> SimplifiedMesh {
+        while self.alive_triangle_count * 3 > target_index_count {
+            let Collapse { cost, from, to } = match self.collapses.pop() {
+                Some(collapse) => collapse,
+                None => break,
+            };
+
+            if self.collapsed[from as usize] || self.collapsed[to as usize] {
+                continue;
+            }
+
+            // Quadrics only grow, so stale entries are cheaper than they should be.
+            let current_cost = self.collapse_cost(from, to);
+            if current_cost > cost {
+                self.collapses.push(Collapse {
+                    cost: current_cost,
+                    from,
+                    to,
+                });
+                continue;
+            }
+
+            if !self.can_collapse(from, to) {
+                continue;
+            }
+
+            self.collapse(from, to);
+            self.max_cost = self.max_cost.max(cost);
+        }
+
+        let indices = self
+            .triangles
+            .iter()
+            .zip(&self.triangle_alive)
+            .filter(|(_, &alive)| alive)
+            .flat_map(|(t, _)| t.iter().copied())
+            .collect();
+
+        SimplifiedMesh {
+            indices,
+            error: self.max_cost.sqrt() as f32,
+        }
+    }
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[test]
+fn test_simplify_mesh() {
+    // A bumpy grid; the border is a boundary, and stays in place.
+    const N: u32 = 16;
+    let positions: Vec<[f32; 3]> = (0..=N)
+        .flat_map(|y| (0..=N).map(move |x| [x as f32, y as f32, ((x * y) % 3) as f32 * 0.01]))
+        .collect();
+
+    let mut indices = Vec::new();
+    for y in 0..N {
+        for x in 0..N {
+            let v = y * (N + 1) + x;
+            indices.extend_from_slice(&[v, v + 1, v + N + 1, v + 1, v + N + 2, v + N + 1]);
+        }
+    }
+
+    let lod = simplify_mesh(&positions, &indices, indices.len() / 4);
+    assert!(lod.indices.len() <= indices.len() / 4);
+    assert!(lod.error > 0.0 && lod.error < 0.05);
+    assert!(lod.indices.iter().all(|&i| (i as usize) < positions.len()));
+
+    let boundary_vertex = N;
+    assert!(lod.indices.contains(&boundary_vertex));
+
+    // Nothing left to collapse in a single triangle
+    let triangle = simplify_mesh(&positions, &indices[..3], 0);
+    assert_eq!(triangle.indices, &indices[..3]);
+
+    let chain = build_lod_chain(&positions, &indices, &LodChainParams::default());
+    assert_eq!(chain.len(), 3);
+    assert!(chain
+        .windows(2)
+        .all(|w| w[1].indices.len() < w[0].indices.len() && w[1].error >= w[0].error));
+}
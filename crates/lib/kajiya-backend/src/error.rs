@@ -13,6 +13,9 @@ pub enum BackendError {
 
     #[error("Invalid resource access: {info:?}")]
     ResourceAccess { info: String },
+
+    #[error("Invalid shader binding in pass {pass:?}: {info}")]
+    ShaderBinding { pass: String, info: String },
 }
 
 impl From<ash::vk::Result> for BackendError {
@@ -4,7 +4,12 @@ use hotwatch::Hotwatch;
 use lazy_static::lazy_static;
 use normpath::PathExt;
 use parking_lot::Mutex;
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 use turbosloth::*;
 
 lazy_static! {
@@ -75,6 +80,28 @@ pub fn canonical_path_from_vfs(path: impl Into<PathBuf>) -> anyhow::Result<PathB
     Ok(path)
 }
 
+/// Resolves the mount point of `path` without touching the file system,
+/// so unlike the other variants, it works for files which don't exist yet.
+pub fn path_from_vfs(path: impl Into<PathBuf>) -> anyhow::Result<PathBuf> {
+    let path = path.into();
+
+    for (mount_point, mounted_path) in VFS_MOUNT_POINTS.lock().iter() {
+        if let Ok(rel_path) = path.strip_prefix(mount_point) {
+            return Ok(mounted_path.join(rel_path));
+        }
+    }
+
+    if path.strip_prefix("/").is_ok() {
+        anyhow::bail!(
+            "No vfs mount point for {:?}. Current mount points: {:#?}",
+            path,
+            VFS_MOUNT_POINTS.lock()
+        );
+    }
+
+    Ok(path)
+}
+
 pub fn normalized_path_from_vfs(path: impl Into<PathBuf>) -> anyhow::Result<PathBuf> {
     let path = path.into();
 
@@ -105,6 +132,33 @@ pub fn normalized_path_from_vfs(path: impl Into<PathBuf>) -> anyhow::Result<Path
     Ok(path)
 }
 
+/// Runs `write` on a temporary file next to `path`, and then moves it over `path`, so that
+/// the file at `path` is never seen half-written, even with concurrent writers. Memory-mapped
+/// files being replaced are also left intact under their existing mappings.
+pub fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    static TMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| write(&mut file))
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
 #[derive(Clone, Hash)]
 pub struct LoadFile {
     path: PathBuf,
@@ -144,3 +198,25 @@ impl LazyWorker for LoadFile {
         Some(format!("LoadFile({:?})", self.path).into())
     }
 }
+
+#[test]
+fn test_write_atomically() {
+    use std::io::Write as _;
+
+    let dir = std::env::temp_dir().join(format!("kajiya-write-atomically-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("0123456789abcdef.spv");
+
+    write_atomically(&path, |file| file.write_all(b"first")).unwrap();
+    write_atomically(&path, |file| file.write_all(b"second")).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"second");
+
+    // A failed write leaves the previous file in place
+    assert!(write_atomically(&path, |_| Err(std::io::ErrorKind::Other.into())).is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), b"second");
+
+    // No temporary files left behind
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -5,13 +5,18 @@ mod error;
 pub mod file;
 pub mod pipeline_cache;
 pub mod rust_shader_compiler;
+mod shader_cache;
 pub mod shader_compiler;
+pub mod shader_reflection;
 pub mod transient_resource_cache;
 pub mod vulkan;
 
 pub use ash;
 pub use error::BackendError;
-pub use file::{canonical_path_from_vfs, normalized_path_from_vfs, set_vfs_mount_point};
+pub use file::{
+    canonical_path_from_vfs, normalized_path_from_vfs, path_from_vfs, set_vfs_mount_point,
+    write_atomically,
+};
 pub use gpu_allocator;
 pub use gpu_profiler;
 pub use rspirv_reflect;
@@ -1,11 +1,12 @@
 use crate::{
     rust_shader_compiler::CompileRustShader,
-    shader_compiler::{CompileShader, CompiledShader},
+    shader_compiler::{CompileShader, CompiledShader, ShaderDiagnostic},
     vulkan::{
         ray_tracing::{create_ray_tracing_pipeline, RayTracingPipeline, RayTracingPipelineDesc},
         shader::*,
     },
 };
+use bytes::Bytes;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use std::{collections::HashMap, sync::Arc};
@@ -17,7 +18,10 @@ pub struct ComputePipelineHandle(usize);
 struct ComputePipelineCacheEntry {
     lazy_handle: Lazy<CompiledShader>,
     desc: ComputePipelineDesc,
+    /// The last pipeline which built successfully
     pipeline: Option<Arc<ComputePipeline>>,
+    needs_build: bool,
+    failure: Option<ShaderDiagnostic>,
 }
 
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
@@ -54,8 +58,10 @@ impl LazyWorker for CompilePipelineShaders {
                         ShaderPipelineStage::Pixel => "ps".to_owned(),
                         ShaderPipelineStage::RayGen
                         | ShaderPipelineStage::RayMiss
-                        | ShaderPipelineStage::RayClosestHit => "lib".to_owned(),
+                        | ShaderPipelineStage::RayClosestHit
+                        | ShaderPipelineStage::RayAnyHit => "lib".to_owned(),
                     },
+                    defines: desc.defines.clone(),
                 }
                 .into_lazy()
                 .eval(&ctx),
@@ -78,14 +84,22 @@ impl LazyWorker for CompilePipelineShaders {
 
 struct RasterPipelineCacheEntry {
     lazy_handle: Lazy<CompiledPipelineShaders>,
+    shaders: Vec<PipelineShaderDesc>,
     desc: RasterPipelineDesc,
+    /// The last pipeline which built successfully
     pipeline: Option<Arc<RasterPipeline>>,
+    needs_build: bool,
+    failure: Option<ShaderDiagnostic>,
 }
 
 struct RtPipelineCacheEntry {
     lazy_handle: Lazy<CompiledPipelineShaders>,
+    shaders: Vec<PipelineShaderDesc>,
     desc: RayTracingPipelineDesc,
+    /// The last pipeline which built successfully
     pipeline: Option<Arc<RayTracingPipeline>>,
+    needs_build: bool,
+    failure: Option<ShaderDiagnostic>,
 }
 
 pub struct PipelineCache {
@@ -95,7 +109,7 @@ pub struct PipelineCache {
     raster_entries: HashMap<RasterPipelineHandle, RasterPipelineCacheEntry>,
     rt_entries: HashMap<RtPipelineHandle, RtPipelineCacheEntry>,
 
-    compute_shader_to_handle: HashMap<ShaderSource, ComputePipelineHandle>,
+    compute_shader_to_handle: HashMap<(ShaderSource, Vec<ShaderDefine>), ComputePipelineHandle>,
     raster_shaders_to_handle: HashMap<Vec<PipelineShaderDesc>, RasterPipelineHandle>,
     rt_shaders_to_handle: HashMap<Vec<PipelineShaderDesc>, RtPipelineHandle>,
 }
@@ -118,7 +132,10 @@ impl PipelineCache {
 
     // TODO: should probably use the `desc` as key as well
     pub fn register_compute(&mut self, desc: &ComputePipelineDesc) -> ComputePipelineHandle {
-        match self.compute_shader_to_handle.entry(desc.source.clone()) {
+        match self
+            .compute_shader_to_handle
+            .entry((desc.source.clone(), desc.defines.clone()))
+        {
             std::collections::hash_map::Entry::Occupied(occupied) => *occupied.get(),
             std::collections::hash_map::Entry::Vacant(vacant) => {
                 let handle = ComputePipelineHandle(self.compute_entries.len());
@@ -130,6 +147,7 @@ impl PipelineCache {
                     ShaderSource::Hlsl { path } => CompileShader {
                         path: path.clone(),
                         profile: "cs".to_owned(),
+                        defines: desc.defines.clone(),
                     }
                     .into_lazy(),
                 };
@@ -140,6 +158,8 @@ impl PipelineCache {
                         lazy_handle: compile_task,
                         desc: desc.clone(),
                         pipeline: None,
+                        needs_build: true,
+                        failure: None,
                     },
                 );
                 vacant.insert(handle);
@@ -176,8 +196,11 @@ impl PipelineCache {
                     shader_descs: shaders.to_vec(),
                 }
                 .into_lazy(),
+                shaders: shaders.to_vec(),
                 desc: desc.clone(),
                 pipeline: None,
+                needs_build: true,
+                failure: None,
             },
         );
         handle
@@ -210,8 +233,11 @@ impl PipelineCache {
                     shader_descs: shaders.to_vec(),
                 }
                 .into_lazy(),
+                shaders: shaders.to_vec(),
                 desc: desc.clone(),
                 pipeline: None,
+                needs_build: true,
+                failure: None,
             },
         );
         handle
@@ -226,25 +252,48 @@ impl PipelineCache {
             .unwrap()
     }
 
+    /// Pipelines whose latest build failed. Those which built before keep being used
+    /// in their last good state until their shaders change again.
+    pub fn failures(&self) -> Vec<ShaderDiagnostic> {
+        let mut failures: Vec<ShaderDiagnostic> = self
+            .compute_entries
+            .values()
+            .filter_map(|entry| entry.failure.clone())
+            .chain(
+                self.raster_entries
+                    .values()
+                    .filter_map(|entry| entry.failure.clone()),
+            )
+            .chain(
+                self.rt_entries
+                    .values()
+                    .filter_map(|entry| entry.failure.clone()),
+            )
+            .collect();
+
+        failures.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+        failures.dedup();
+        failures
+    }
+
     fn invalidate_stale_pipelines(&mut self) {
+        // The pipelines stay in place until the new ones build successfully.
+        // Ones which never built are retried every frame.
         for entry in self.compute_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
+            if entry.pipeline.is_none() || entry.lazy_handle.is_stale() {
+                entry.needs_build = true;
             }
         }
 
         for entry in self.raster_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
+            if entry.pipeline.is_none() || entry.lazy_handle.is_stale() {
+                entry.needs_build = true;
             }
         }
 
         for entry in self.rt_entries.values_mut() {
-            if entry.pipeline.is_some() && entry.lazy_handle.is_stale() {
-                // TODO: release
-                entry.pipeline = None;
+            if entry.pipeline.is_none() || entry.lazy_handle.is_stale() {
+                entry.needs_build = true;
             }
         }
     }
@@ -255,33 +304,39 @@ impl PipelineCache {
     ) -> anyhow::Result<()> {
         // Prepare build tasks for compute
         let compute = self.compute_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
+            entry.needs_build.then(|| {
                 let task = entry.lazy_handle.eval(&self.lazy_cache);
                 smol::spawn(async move {
-                    task.await
-                        .map(|compiled| CompileTaskOutput::Compute { handle, compiled })
+                    CompileTaskOutput::Compute {
+                        handle,
+                        compiled: task.await,
+                    }
                 })
             })
         });
 
         // Prepare build tasks for raster
         let raster = self.raster_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
+            entry.needs_build.then(|| {
                 let task = entry.lazy_handle.eval(&self.lazy_cache);
                 smol::spawn(async move {
-                    task.await
-                        .map(|compiled| CompileTaskOutput::Raster { handle, compiled })
+                    CompileTaskOutput::Raster {
+                        handle,
+                        compiled: task.await,
+                    }
                 })
             })
         });
 
         // Prepare build tasks for rt
         let rt = self.rt_entries.iter().filter_map(|(&handle, entry)| {
-            entry.pipeline.is_none().then(|| {
+            entry.needs_build.then(|| {
                 let task = entry.lazy_handle.eval(&self.lazy_cache);
                 smol::spawn(async move {
-                    task.await
-                        .map(|compiled| CompileTaskOutput::Rt { handle, compiled })
+                    CompileTaskOutput::Rt {
+                        handle,
+                        compiled: task.await,
+                    }
                 })
             })
         });
@@ -292,26 +347,86 @@ impl PipelineCache {
         if !shader_tasks.is_empty() {
             // Compile all the things
             let compiled: Vec<CompileTaskOutput> =
-                smol::block_on(futures::future::try_join_all(shader_tasks))?;
+                smol::block_on(futures::future::join_all(shader_tasks));
 
-            // Build pipelines from all compiled shaders
-            for compiled in compiled {
-                match compiled {
-                    CompileTaskOutput::Compute { handle, compiled } => {
-                        let entry = self.compute_entries.get_mut(&handle).unwrap();
+            self.build_pipelines(device, compiled);
+        }
+
+        // Without a previous pipeline to fall back on, the frame can't be rendered
+        let unbuilt: Vec<String> = self
+            .compute_entries
+            .values()
+            .filter(|entry| entry.pipeline.is_none())
+            .filter_map(|entry| entry.failure.as_ref())
+            .chain(
+                self.raster_entries
+                    .values()
+                    .filter(|entry| entry.pipeline.is_none())
+                    .filter_map(|entry| entry.failure.as_ref()),
+            )
+            .chain(
+                self.rt_entries
+                    .values()
+                    .filter(|entry| entry.pipeline.is_none())
+                    .filter_map(|entry| entry.failure.as_ref()),
+            )
+            .map(ToString::to_string)
+            .collect();
+
+        if !unbuilt.is_empty() {
+            anyhow::bail!(
+                "Failed to build pipelines with nothing to fall back on:\n{}",
+                unbuilt.join("\n")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Creates pipelines from the compiled shaders, or records why they failed to build.
+    fn build_pipelines(
+        &mut self,
+        device: &Arc<crate::vulkan::device::Device>,
+        compiled: Vec<CompileTaskOutput>,
+    ) {
+        let mut built_any = false;
+
+        // Build pipelines from all compiled shaders
+        for compiled in compiled {
+            match compiled {
+                CompileTaskOutput::Compute { handle, compiled } => {
+                    let entry = self.compute_entries.get_mut(&handle).unwrap();
+                    entry.needs_build = false;
+
+                    let pipeline = compiled.and_then(|compiled| {
                         log::trace!(
                             "Creating compute pipeline {:?}:{:?}",
                             compiled.name,
                             entry.desc.source.entry(),
                         );
-                        entry.pipeline = Some(Arc::new(create_compute_pipeline(
-                            device.as_ref(),
-                            &compiled.spirv,
-                            &entry.desc,
-                        )));
+
+                        create_compute_pipeline(device.as_ref(), &compiled.spirv, &entry.desc)
+                    });
+
+                    match pipeline {
+                        Ok(pipeline) => {
+                            entry.pipeline = Some(Arc::new(pipeline));
+                            entry.failure = None;
+                            built_any = true;
+                        }
+                        Err(err) => {
+                            entry.failure = Some(build_failure(
+                                &err,
+                                std::slice::from_ref(&entry.desc.source),
+                            ));
+                        }
                     }
-                    CompileTaskOutput::Raster { handle, compiled } => {
-                        let entry = self.raster_entries.get_mut(&handle).unwrap();
+                }
+                CompileTaskOutput::Raster { handle, compiled } => {
+                    let entry = self.raster_entries.get_mut(&handle).unwrap();
+                    entry.needs_build = false;
+
+                    let pipeline = compiled.and_then(|compiled| {
                         log::trace!(
                             "Creating raster pipeline {}",
                             compiled
@@ -325,23 +440,30 @@ impl PipelineCache {
                                 .join(", ")
                         );
 
-                        let compiled_shaders = compiled
-                            .shaders
-                            .iter()
-                            .map(|shader| PipelineShader {
-                                code: shader.code.spirv.clone(),
-                                desc: shader.desc.clone(),
-                            })
-                            .collect::<Vec<_>>();
-
-                        // TODO: defer and handle the error
-                        entry.pipeline = Some(Arc::new(
-                            create_raster_pipeline(device.as_ref(), &compiled_shaders, &entry.desc)
-                                .expect("create_raster_pipeline"),
-                        ));
+                        create_raster_pipeline(
+                            device.as_ref(),
+                            &pipeline_shader_code(&compiled),
+                            &entry.desc,
+                        )
+                    });
+
+                    match pipeline {
+                        Ok(pipeline) => {
+                            entry.pipeline = Some(Arc::new(pipeline));
+                            entry.failure = None;
+                            built_any = true;
+                        }
+                        Err(err) => {
+                            entry.failure =
+                                Some(build_failure(&err, &shader_sources(&entry.shaders)));
+                        }
                     }
-                    CompileTaskOutput::Rt { handle, compiled } => {
-                        let entry = self.rt_entries.get_mut(&handle).unwrap();
+                }
+                CompileTaskOutput::Rt { handle, compiled } => {
+                    let entry = self.rt_entries.get_mut(&handle).unwrap();
+                    entry.needs_build = false;
+
+                    let pipeline = compiled.and_then(|compiled| {
                         log::trace!(
                             "Creating rt pipeline {}",
                             compiled
@@ -355,30 +477,34 @@ impl PipelineCache {
                                 .join(", ")
                         );
 
-                        let compiled_shaders = compiled
-                            .shaders
-                            .iter()
-                            .map(|shader| PipelineShader {
-                                code: shader.code.spirv.clone(),
-                                desc: shader.desc.clone(),
-                            })
-                            .collect::<Vec<_>>();
-
-                        // TODO: defer and handle the error
-                        entry.pipeline = Some(Arc::new(
-                            create_ray_tracing_pipeline(
-                                device.as_ref(),
-                                &compiled_shaders,
-                                &entry.desc,
-                            )
-                            .expect("create_ray_tracing_pipeline"),
-                        ));
+                        create_ray_tracing_pipeline(
+                            device.as_ref(),
+                            &pipeline_shader_code(&compiled),
+                            &entry.desc,
+                        )
+                    });
+
+                    match pipeline {
+                        Ok(pipeline) => {
+                            entry.pipeline = Some(Arc::new(pipeline));
+                            entry.failure = None;
+                            built_any = true;
+                        }
+                        Err(err) => {
+                            entry.failure =
+                                Some(build_failure(&err, &shader_sources(&entry.shaders)));
+                        }
                     }
                 }
             }
         }
 
-        Ok(())
+        if built_any {
+            // So that the next run can skip building the same pipelines
+            if let Err(err) = device.save_pipeline_cache() {
+                log::warn!("Failed to save the pipeline cache: {:#}", err);
+            }
+        }
     }
 
     pub fn prepare_frame(
@@ -392,17 +518,51 @@ impl PipelineCache {
     }
 }
 
+fn pipeline_shader_code(compiled: &CompiledPipelineShaders) -> Vec<PipelineShader<Bytes>> {
+    compiled
+        .shaders
+        .iter()
+        .map(|shader| PipelineShader {
+            code: shader.code.spirv.clone(),
+            desc: shader.desc.clone(),
+        })
+        .collect()
+}
+
+fn shader_sources(shaders: &[PipelineShaderDesc]) -> Vec<ShaderSource> {
+    shaders.iter().map(|shader| shader.source.clone()).collect()
+}
+
+/// Logs the error, and describes it by the first shader compiler diagnostic in its chain.
+/// Errors not coming from the compiler are attributed to the pipeline's shaders as a whole.
+fn build_failure(err: &anyhow::Error, sources: &[ShaderSource]) -> ShaderDiagnostic {
+    log::error!("{:#}", err);
+
+    err.chain()
+        .find_map(|err| err.downcast_ref::<ShaderDiagnostic>())
+        .cloned()
+        .unwrap_or_else(|| ShaderDiagnostic {
+            file: sources
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+            line: None,
+            message: err.to_string(),
+        })
+}
+
 enum CompileTaskOutput {
     Compute {
         handle: ComputePipelineHandle,
-        compiled: Arc<CompiledShader>,
+        compiled: anyhow::Result<Arc<CompiledShader>>,
     },
     Raster {
         handle: RasterPipelineHandle,
-        compiled: Arc<CompiledPipelineShaders>,
+        compiled: anyhow::Result<Arc<CompiledPipelineShaders>>,
     },
     Rt {
         handle: RtPipelineHandle,
-        compiled: Arc<CompiledPipelineShaders>,
+        compiled: anyhow::Result<Arc<CompiledPipelineShaders>>,
     },
 }
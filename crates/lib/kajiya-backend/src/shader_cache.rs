@@ -0,0 +1,198 @@
+//! Compilation results kept on disk between runs: SPIR-V of HLSL shaders,
+//! and the blob of the Vulkan pipeline cache.
+
+use crate::file::{path_from_vfs, write_atomically};
+use anyhow::Result;
+use ash::vk;
+use bytes::Bytes;
+use std::io::Write as _;
+
+const SPIRV_CACHE_DIR: &str = "/cache/shaders";
+const PIPELINE_CACHE_PATH: &str = "/cache/shaders/pipeline_cache.bin";
+
+/// Mixed into the keys of cached SPIR-V. Bump to invalidate all of it,
+/// e.g. after changing how shaders are compiled.
+const SPIRV_CACHE_VERSION: u64 = 1;
+
+/// Key of SPIR-V compiled from the preprocessed `source` with `compiler_args`.
+/// The arguments should identify the version of the compiler too.
+pub(crate) fn spirv_cache_key(source: &str, compiler_args: &[&str]) -> u64 {
+    let mut key = wyhash::wyhash(source.as_bytes(), SPIRV_CACHE_VERSION);
+    for arg in compiler_args {
+        key = wyhash::wyhash(arg.as_bytes(), key);
+    }
+    key
+}
+
+/// Loads the SPIR-V cached under `key`, or runs `compile` and caches its output.
+/// Failing to write the cache is not an error.
+pub(crate) fn cached_spirv(key: u64, compile: impl FnOnce() -> Result<Bytes>) -> Result<Bytes> {
+    cached_spirv_in(SPIRV_CACHE_DIR, key, compile)
+}
+
+/// `cached_spirv` with the cache in the VFS directory `cache_dir`
+fn cached_spirv_in(
+    cache_dir: &str,
+    key: u64,
+    compile: impl FnOnce() -> Result<Bytes>,
+) -> Result<Bytes> {
+    let path = path_from_vfs(format!("{}/{:016x}.spv", cache_dir, key))?;
+
+    if let Ok(spirv) = std::fs::read(&path) {
+        if is_spirv(&spirv) {
+            return Ok(spirv.into());
+        }
+    }
+
+    let spirv = compile()?;
+
+    if let Err(err) = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| write_atomically(&path, |file| file.write_all(&spirv)))
+    {
+        log::warn!("Could not cache SPIR-V at {:?}: {}", path, err);
+    }
+
+    Ok(spirv)
+}
+
+const SPIRV_MAGIC: u32 = 0x07230203;
+
+/// Size of the SPIR-V module header, in 32-bit words
+const SPIRV_HEADER_WORDS: usize = 5;
+
+/// Whether `data` looks like a whole SPIR-V module, rather than e.g. a truncated file.
+fn is_spirv(data: &[u8]) -> bool {
+    data.len() >= SPIRV_HEADER_WORDS * 4
+        && data.len() % 4 == 0
+        && u32::from_le_bytes([data[0], data[1], data[2], data[3]]) == SPIRV_MAGIC
+}
+
+/// Size of `VkPipelineCacheHeaderVersionOne`
+const PIPELINE_CACHE_HEADER_SIZE: usize = 32;
+
+/// Whether `data` starts with a header of a pipeline cache created by the device with `properties`.
+fn is_pipeline_cache_compatible(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    if data.len() < PIPELINE_CACHE_HEADER_SIZE {
+        return false;
+    }
+
+    let read_u32 = |offset: usize| {
+        u32::from_ne_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+
+    let header_size = read_u32(0);
+    let header_version = read_u32(4);
+
+    header_size as usize >= PIPELINE_CACHE_HEADER_SIZE
+        && header_version == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && read_u32(8) == properties.vendor_id
+        && read_u32(12) == properties.device_id
+        && data[16..32] == properties.pipeline_cache_uuid
+}
+
+/// The pipeline cache blob saved by a previous run on the same device and driver.
+/// Empty if there is none, or it's incompatible.
+pub(crate) fn load_pipeline_cache_data(properties: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+    let data = match path_from_vfs(PIPELINE_CACHE_PATH)
+        .and_then(|path| std::fs::read(&path).map_err(anyhow::Error::from))
+    {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+
+    if is_pipeline_cache_compatible(&data, properties) {
+        log::info!("Loaded {} bytes of pipeline cache", data.len());
+        data
+    } else {
+        log::info!("Discarding a pipeline cache saved for another device or driver");
+        Vec::new()
+    }
+}
+
+pub(crate) fn save_pipeline_cache_data(data: &[u8]) -> Result<()> {
+    let path = path_from_vfs(PIPELINE_CACHE_PATH)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    write_atomically(&path, |file| file.write_all(data))?;
+
+    Ok(())
+}
+
+#[test]
+fn test_pipeline_cache_validation() {
+    let properties = vk::PhysicalDeviceProperties {
+        vendor_id: 0x10de,
+        device_id: 0x2204,
+        pipeline_cache_uuid: [7; 16],
+        ..Default::default()
+    };
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&(PIPELINE_CACHE_HEADER_SIZE as u32).to_ne_bytes());
+    data.extend_from_slice(&(vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_ne_bytes());
+    data.extend_from_slice(&properties.vendor_id.to_ne_bytes());
+    data.extend_from_slice(&properties.device_id.to_ne_bytes());
+    data.extend_from_slice(&properties.pipeline_cache_uuid);
+    data.extend_from_slice(&[0; 64]);
+
+    assert!(is_pipeline_cache_compatible(&data, &properties));
+    assert!(!is_pipeline_cache_compatible(&data[..16], &properties));
+
+    let other_driver = vk::PhysicalDeviceProperties {
+        pipeline_cache_uuid: [8; 16],
+        ..properties
+    };
+    assert!(!is_pipeline_cache_compatible(&data, &other_driver));
+
+    assert_ne!(
+        spirv_cache_key("float4 main()", &["-spirv"]),
+        spirv_cache_key("float4 main()", &["-spirv", "-WX"])
+    );
+}
+
+#[cfg(test)]
+fn test_spirv_header() -> Vec<u8> {
+    let mut spirv = Vec::new();
+    for word in [SPIRV_MAGIC, 0x0001_0500, 0, 16, 0] {
+        spirv.extend_from_slice(&word.to_le_bytes());
+    }
+    spirv
+}
+
+#[test]
+fn test_spirv_validation() {
+    let spirv = test_spirv_header();
+
+    assert!(is_spirv(&spirv));
+    assert!(!is_spirv(&spirv[..spirv.len() - 2]));
+    assert!(!is_spirv(&spirv[..8]));
+    assert!(!is_spirv(&[0; 20]));
+}
+
+#[test]
+fn test_cached_spirv_from_empty_cache() {
+    let dir = std::env::temp_dir().join(format!("kajiya-empty-cache-{}", std::process::id()));
+    let mount_point = format!("/empty-cache-{}", std::process::id());
+    crate::file::set_vfs_mount_point(&mount_point, &dir);
+
+    // Neither the cache directory nor the file exist yet
+    let cache_dir = format!("{}/shaders", mount_point);
+    let spirv = Bytes::from(test_spirv_header());
+
+    let compiled = cached_spirv_in(&cache_dir, 1, || Ok(spirv.clone())).unwrap();
+    assert_eq!(compiled, spirv);
+
+    let cached = cached_spirv_in(&cache_dir, 1, || anyhow::bail!("compiled again")).unwrap();
+    assert_eq!(cached, spirv);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,10 +1,34 @@
-use crate::file::LoadFile;
+use crate::{file::LoadFile, shader_cache, vulkan::shader::ShaderDefine};
 use anyhow::{anyhow, bail, Context, Result};
 use bytes::Bytes;
+use lazy_static::lazy_static;
 use relative_path::RelativePathBuf;
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use turbosloth::*;
 
+/// Where and why a shader failed to compile
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShaderDiagnostic {
+    /// The file with the first error; an included one if that's where the error is
+    pub file: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ShaderDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ShaderDiagnostic {}
+
 pub struct CompiledShader {
     pub name: String,
     pub spirv: Bytes,
@@ -14,6 +38,7 @@ pub struct CompiledShader {
 pub struct CompileShader {
     pub path: PathBuf,
     pub profile: String,
+    pub defines: Vec<ShaderDefine>,
 }
 
 #[async_trait]
@@ -34,23 +59,26 @@ impl LazyWorker for CompileShader {
             .unwrap_or_else(|| "unknown".to_string());
 
         match ext.as_str() {
-            "glsl" => unimplemented!(),
             "spv" => {
                 let spirv = LoadFile::new(self.path.clone())?.run(ctx).await?;
                 Ok(CompiledShader { name, spirv })
             }
             "hlsl" => {
-                let file_path = self.path.to_str().unwrap().to_owned();
-                let source = shader_prepper::process_file(
-                    &file_path,
-                    &mut ShaderIncludeProvider { ctx },
-                    String::new(),
-                );
-                let source = source
-                    .map_err(|err| anyhow!("{}", err))
-                    .with_context(|| format!("shader path: {:?}", self.path))?;
+                let source = preprocess_shader_file(&self.path, ctx)?;
                 let target_profile = format!("{}_6_4", self.profile);
-                let spirv = compile_generic_shader_hlsl_impl(&name, &source, &target_profile)?;
+                let spirv = compile_generic_shader_hlsl_impl(
+                    &name,
+                    &source,
+                    &target_profile,
+                    &self.defines,
+                )?;
+
+                Ok(CompiledShader { name, spirv })
+            }
+            _ if is_glsl_extension(&ext) => {
+                let source = preprocess_shader_file(&self.path, ctx)?;
+                let spirv =
+                    compile_glsl_shader(&self.path, &name, &source, &self.profile, &self.defines)?;
 
                 Ok(CompiledShader { name, spirv })
             }
@@ -67,6 +95,7 @@ pub struct RayTracingShader {
 #[derive(Clone, Hash)]
 pub struct CompileRayTracingShader {
     pub path: PathBuf,
+    pub defines: Vec<ShaderDefine>,
 }
 
 #[async_trait]
@@ -74,13 +103,7 @@ impl LazyWorker for CompileRayTracingShader {
     type Output = Result<RayTracingShader>;
 
     async fn run(self, ctx: RunContext) -> Self::Output {
-        let file_path = self.path.to_str().unwrap().to_owned();
-        let source = shader_prepper::process_file(
-            &file_path,
-            &mut ShaderIncludeProvider { ctx },
-            String::new(),
-        );
-        let source = source.map_err(|err| anyhow!("{}", err))?;
+        let source = preprocess_shader_file(&self.path, ctx)?;
 
         let ext = self
             .path
@@ -95,10 +118,20 @@ impl LazyWorker for CompileRayTracingShader {
             .unwrap_or_else(|| "unknown".to_string());
 
         match ext.as_str() {
-            "glsl" => unimplemented!(),
             "hlsl" => {
-                let target_profile = "lib_6_4";
-                let spirv = compile_generic_shader_hlsl_impl(&name, &source, target_profile)?;
+                // 6.5 for `GeometryIndex()`
+                let target_profile = "lib_6_5";
+                let spirv = compile_generic_shader_hlsl_impl(
+                    &name,
+                    &source,
+                    target_profile,
+                    &self.defines,
+                )?;
+
+                Ok(RayTracingShader { name, spirv })
+            }
+            _ if is_glsl_extension(&ext) => {
+                let spirv = compile_glsl_shader(&self.path, &name, &source, "lib", &self.defines)?;
 
                 Ok(RayTracingShader { name, spirv })
             }
@@ -107,6 +140,21 @@ impl LazyWorker for CompileRayTracingShader {
     }
 }
 
+/// Resolves `#include`s over the VFS, and watches the included files for changes.
+fn preprocess_shader_file(
+    path: &Path,
+    ctx: RunContext,
+) -> Result<Vec<shader_prepper::SourceChunk>> {
+    let file_path = path.to_str().unwrap().to_owned();
+    shader_prepper::process_file(
+        &file_path,
+        &mut ShaderIncludeProvider { ctx },
+        String::new(),
+    )
+    .map_err(|err| anyhow!("{}", err))
+    .with_context(|| format!("shader path: {:?}", path))
+}
+
 struct ShaderIncludeProvider {
     ctx: RunContext,
 }
@@ -163,35 +211,378 @@ pub fn get_cs_local_size_from_spirv(spirv: &[u32]) -> Result<[u32; 3]> {
     Err(anyhow!("Could not find a ExecutionMode SPIR-V op"))
 }
 
+fn is_glsl_extension(ext: &str) -> bool {
+    matches!(
+        ext,
+        "glsl" | "comp" | "vert" | "frag" | "rgen" | "rmiss" | "rchit" | "rahit"
+    )
+}
+
+#[cfg(feature = "glsl")]
+fn compile_glsl_shader(
+    path: &Path,
+    name: &str,
+    source: &[shader_prepper::SourceChunk],
+    profile: &str,
+    defines: &[ShaderDefine],
+) -> Result<Bytes> {
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    let stage =
+        glsl_shader_kind(&ext, profile).with_context(|| format!("shader path: {:?}", path))?;
+
+    compile_generic_shader_glsl_impl(name, source, stage, defines)
+}
+
+#[cfg(not(feature = "glsl"))]
+fn compile_glsl_shader(
+    path: &Path,
+    _name: &str,
+    _source: &[shader_prepper::SourceChunk],
+    _profile: &str,
+    _defines: &[ShaderDefine],
+) -> Result<Bytes> {
+    bail!(
+        "Compiling GLSL shaders needs the `glsl` feature of kajiya-backend; shader path: {:?}",
+        path
+    )
+}
+
+/// The stage of a GLSL shader is told by its file extension. Generic `.glsl` files
+/// take it from the HLSL-style `profile` instead, which doesn't work for ray tracing.
+#[cfg(feature = "glsl")]
+fn glsl_shader_kind(ext: &str, profile: &str) -> Result<shaderc::ShaderKind> {
+    use shaderc::ShaderKind;
+
+    Ok(match (ext, profile) {
+        ("comp", _) | ("glsl", "cs") => ShaderKind::Compute,
+        ("vert", _) | ("glsl", "vs") => ShaderKind::Vertex,
+        ("frag", _) | ("glsl", "ps") => ShaderKind::Fragment,
+        ("rgen", _) => ShaderKind::RayGeneration,
+        ("rmiss", _) => ShaderKind::Miss,
+        ("rchit", _) => ShaderKind::ClosestHit,
+        ("rahit", _) => ShaderKind::AnyHit,
+        _ => bail!(
+            "Cannot tell the stage of a .{} shader with the {} profile; use an extension such as .rgen or .rchit",
+            ext,
+            profile
+        ),
+    })
+}
+
+/// The defines as `-D` compiler arguments, for the keys of cached SPIR-V
+fn define_args(defines: &[ShaderDefine]) -> Vec<String> {
+    defines
+        .iter()
+        .map(|define| match &define.value {
+            Some(value) => format!("-D{}={}", define.name, value),
+            None => format!("-D{}", define.name),
+        })
+        .collect()
+}
+
+#[cfg(feature = "glsl")]
+fn compile_generic_shader_glsl_impl(
+    name: &str,
+    source: &[shader_prepper::SourceChunk],
+    stage: shaderc::ShaderKind,
+    defines: &[ShaderDefine],
+) -> Result<Bytes> {
+    let mut source_text = String::new();
+    for s in source {
+        source_text += &s.source;
+    }
+
+    let (spirv_version, spirv_revision) = shaderc::get_spirv_version();
+    let compiler_version = format!("shaderc spirv {}.{}", spirv_version, spirv_revision);
+    let stage_arg = format!("{:?}", stage);
+    let define_args = define_args(defines);
+    let cache_key = shader_cache::spirv_cache_key(
+        &source_text,
+        &[
+            &[compiler_version.as_str(), stage_arg.as_str(), "-WX"][..],
+            &define_args.iter().map(String::as_str).collect::<Vec<_>>(),
+        ]
+        .concat(),
+    );
+
+    shader_cache::cached_spirv(cache_key, || {
+        let compiler = shaderc::Compiler::new().context("Failed to create the GLSL compiler")?;
+        let mut options =
+            shaderc::CompileOptions::new().context("Failed to create GLSL compile options")?;
+        options.set_target_env(
+            shaderc::TargetEnv::Vulkan,
+            shaderc::EnvVersion::Vulkan1_2 as u32,
+        );
+        options.set_warnings_as_errors();
+        for define in defines {
+            options.add_macro_definition(&define.name, define.value.as_deref());
+        }
+
+        let t0 = std::time::Instant::now();
+        let spirv = compiler
+            .compile_into_spirv(&source_text, stage, name, "main", Some(&options))
+            .map_err(|err| compile_error(&err.to_string(), source))?;
+
+        log::trace!("shaderc took {:?} for {}", t0.elapsed(), name,);
+
+        Ok(Bytes::copy_from_slice(spirv.as_binary_u8()))
+    })
+}
+
 fn compile_generic_shader_hlsl_impl(
     name: &str,
     source: &[shader_prepper::SourceChunk],
     target_profile: &str,
+    defines: &[ShaderDefine],
 ) -> Result<Bytes> {
     let mut source_text = String::new();
     for s in source {
         source_text += &s.source;
     }
 
-    let t0 = std::time::Instant::now();
-    let spirv = hassle_rs::compile_hlsl(
-        name,
+    let args = [
+        "-spirv",
+        //"-enable-16bit-types",
+        "-fspv-target-env=vulkan1.2",
+        "-WX",      // warnings as errors
+        "-Ges",     // strict mode
+        "-HV 2021", // HLSL version 2021
+    ];
+
+    let define_args = define_args(defines);
+    let cache_key = shader_cache::spirv_cache_key(
         &source_text,
-        "main",
-        target_profile,
         &[
-            "-spirv",
-            //"-enable-16bit-types",
-            "-fspv-target-env=vulkan1.2",
-            "-WX",      // warnings as errors
-            "-Ges",     // strict mode
-            "-HV 2021", // HLSL version 2021
-        ],
-        &[],
+            &[DXC_VERSION.as_str(), "main", target_profile][..],
+            &args[..],
+            &define_args.iter().map(String::as_str).collect::<Vec<_>>(),
+        ]
+        .concat(),
+    );
+
+    let dxc_defines: Vec<(&str, Option<&str>)> = defines
+        .iter()
+        .map(|define| (define.name.as_str(), define.value.as_deref()))
+        .collect();
+
+    shader_cache::cached_spirv(cache_key, || {
+        let t0 = std::time::Instant::now();
+        let spirv = hassle_rs::compile_hlsl(
+            name,
+            &source_text,
+            "main",
+            target_profile,
+            &args,
+            &dxc_defines,
+        )
+        .map_err(|err| compile_error(&err.to_string(), source))?;
+
+        log::trace!("dxc took {:?} for {}", t0.elapsed(), name,);
+
+        Ok(spirv.into())
+    })
+}
+
+lazy_static! {
+    /// Identifies the build of DXC, so that a compiler upgrade doesn't serve stale SPIR-V
+    static ref DXC_VERSION: String = dxc_version().unwrap_or_else(|err| {
+        log::warn!("Could not query the DXC version: {:#}", err);
+        "dxc".to_owned()
+    });
+}
+
+/// Same as the library `hassle_rs::compile_hlsl` loads
+#[cfg(windows)]
+const DXCOMPILER_LIB_NAME: &str = "dxcompiler.dll";
+#[cfg(target_os = "macos")]
+const DXCOMPILER_LIB_NAME: &str = "./libdxcompiler.dylib";
+#[cfg(not(any(windows, target_os = "macos")))]
+const DXCOMPILER_LIB_NAME: &str = "./libdxcompiler.so";
+
+/// The version of DXC, with the commit it was built from if it reports it.
+fn dxc_version() -> Result<String> {
+    use com_rs::ComPtr;
+    use hassle_rs::{
+        os::HRESULT, CLSID_DxcCompiler, DxcCreateInstanceProc, IDxcVersionInfo, IDxcVersionInfo2,
+        IID_IDxcVersionInfo, IID_IDxcVersionInfo2,
+    };
+
+    // Declared before the interfaces, so that it's unloaded after they're released
+    let lib = unsafe { libloading::Library::new(DXCOMPILER_LIB_NAME) }
+        .with_context(|| format!("Loading {}", DXCOMPILER_LIB_NAME))?;
+    let create_instance: libloading::Symbol<DxcCreateInstanceProc> =
+        unsafe { lib.get(b"DxcCreateInstance\0") }?;
+
+    let mut version_info: ComPtr<IDxcVersionInfo> = ComPtr::new();
+    create_instance(
+        &CLSID_DxcCompiler,
+        &IID_IDxcVersionInfo,
+        version_info.as_mut_ptr(),
+    )
+    .result()?;
+
+    let (mut major, mut minor) = (0, 0);
+    unsafe { version_info.get_version(&mut major, &mut minor) }.result()?;
+    let mut version = format!("dxc {}.{}", major, minor);
+
+    let mut version_info2: ComPtr<IDxcVersionInfo2> = ComPtr::new();
+    let has_commit_info = !HRESULT::from(unsafe {
+        version_info.query_interface(&IID_IDxcVersionInfo2, version_info2.as_mut_ptr())
+    })
+    .is_err();
+
+    if has_commit_info {
+        let mut commit_count = 0;
+        let mut commit_hash: *mut u8 = std::ptr::null_mut();
+        let result = unsafe { version_info2.get_commit_info(&mut commit_count, &mut commit_hash) };
+
+        // The hash string is allocated by DXC, and only a few bytes; it's not freed.
+        if !result.is_err() && !commit_hash.is_null() {
+            let commit_hash = unsafe { std::ffi::CStr::from_ptr(commit_hash as *const _) };
+            version += &format!(" ({} {})", commit_count, commit_hash.to_string_lossy());
+        }
+    }
+
+    Ok(version)
+}
+
+/// Turns the output of a failed compilation of the preprocessed `source`
+/// into a `ShaderDiagnostic`, with the full output as context.
+fn compile_error(output: &str, source: &[shader_prepper::SourceChunk]) -> anyhow::Error {
+    let root_file = source
+        .first()
+        .map(|chunk| chunk.file.clone())
+        .unwrap_or_default();
+
+    let diagnostic = match parse_compiler_error(output) {
+        Some((line, message)) => {
+            let (file, line) = source_location(source, line).unwrap_or((root_file, line));
+            ShaderDiagnostic {
+                file,
+                line: Some(line),
+                message,
+            }
+        }
+        None => ShaderDiagnostic {
+            file: root_file,
+            line: None,
+            message: output.lines().next().unwrap_or_default().to_owned(),
+        },
+    };
+
+    anyhow::Error::new(diagnostic).context(output.to_owned())
+}
+
+/// Line and message of the first error in compiler output, e.g.
+/// `name:12:5: error: ...` from dxc, or `name:12: error: ...` from shaderc.
+fn parse_compiler_error(output: &str) -> Option<(usize, String)> {
+    output.lines().find_map(|text| {
+        let (location, message) = text
+            .split_once(": error: ")
+            .or_else(|| text.split_once(": fatal error: "))?;
+
+        // Trailing numbers of the location are the line, and optionally the column
+        let numbers: Vec<usize> = location
+            .rsplit(':')
+            .map_while(|part| part.parse().ok())
+            .collect();
+
+        Some((*numbers.last()?, message.trim().to_owned()))
+    })
+}
+
+/// Maps a 1-based `line` of the concatenated `source` to the file and line it came from.
+fn source_location(source: &[shader_prepper::SourceChunk], line: usize) -> Option<(String, usize)> {
+    let mut chunk_start = 1;
+    for chunk in source.iter().filter(|chunk| !chunk.source.is_empty()) {
+        let newlines = chunk.source.matches('\n').count();
+        let chunk_end = chunk_start + newlines - usize::from(chunk.source.ends_with('\n'));
+        if line <= chunk_end {
+            return Some((
+                chunk.file.clone(),
+                chunk.line_offset + line - chunk_start + 1,
+            ));
+        }
+        chunk_start += newlines;
+    }
+
+    None
+}
+
+#[test]
+fn test_parse_compiler_error() {
+    let dxc = "sample_lights.rgen:42:17: error: use of undeclared identifier 'foo'\n    foo += 1;\n    ^\n";
+    assert_eq!(
+        parse_compiler_error(dxc),
+        Some((42, "use of undeclared identifier 'foo'".to_owned()))
+    );
+
+    let shaderc = "blur.comp:7: error: 'bar' : undeclared identifier\n1 error generated.";
+    assert_eq!(
+        parse_compiler_error(shaderc),
+        Some((7, "'bar' : undeclared identifier".to_owned()))
+    );
+
+    assert_eq!(parse_compiler_error("warning: something"), None);
+}
+
+#[cfg(feature = "glsl")]
+#[test]
+fn test_glsl_shader_kind() {
+    use shaderc::ShaderKind;
+
+    assert_eq!(glsl_shader_kind("comp", "cs").unwrap(), ShaderKind::Compute);
+    assert_eq!(glsl_shader_kind("glsl", "cs").unwrap(), ShaderKind::Compute);
+    assert_eq!(
+        glsl_shader_kind("glsl", "ps").unwrap(),
+        ShaderKind::Fragment
+    );
+    assert_eq!(glsl_shader_kind("vert", "vs").unwrap(), ShaderKind::Vertex);
+    assert_eq!(
+        glsl_shader_kind("rchit", "lib").unwrap(),
+        ShaderKind::ClosestHit
+    );
+    assert!(glsl_shader_kind("glsl", "lib").is_err());
+}
+
+#[cfg(feature = "glsl")]
+#[test]
+fn test_compile_glsl_compute_shader() {
+    let dir = std::env::temp_dir().join(format!("kajiya-glsl-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("common.glsl"), "#define GROUP_SIZE 8\n").unwrap();
+    std::fs::write(
+        dir.join("fill.comp"),
+        "#version 450\n\
+         #include \"common.glsl\"\n\
+         layout(local_size_x = GROUP_SIZE, local_size_y = GROUP_SIZE) in;\n\
+         layout(binding = 0, r32f) uniform image2D output_tex;\n\
+         void main() {\n\
+             imageStore(output_tex, ivec2(gl_GlobalInvocationID.xy), vec4(FILL_VALUE));\n\
+         }\n",
+    )
+    .unwrap();
+
+    crate::file::set_vfs_mount_point("/glsl-test", &dir);
+    crate::file::set_vfs_mount_point("/cache", dir.join("cache"));
+
+    let shader = smol::block_on(
+        CompileShader {
+            path: "/glsl-test/fill.comp".into(),
+            profile: "cs".to_owned(),
+            defines: vec![ShaderDefine::new("FILL_VALUE", 0.5)],
+        }
+        .into_lazy()
+        .eval(&LazyCache::create()),
     )
-    .map_err(|err| anyhow!("{}", err))?;
+    .unwrap();
 
-    log::trace!("dxc took {:?} for {}", t0.elapsed(), name,);
+    let spirv: Vec<u32> = shader
+        .spirv
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    assert_eq!(get_cs_local_size_from_spirv(&spirv).unwrap(), [8, 8, 1]);
 
-    Ok(spirv.into())
+    std::fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,324 @@
+//! Descriptor bindings of shaders as reflected from their SPIR-V: checked against
+//! what render passes bind, and dumped as text for inspection.
+
+use anyhow::{anyhow, Result};
+use ash::vk;
+use parking_lot::Mutex;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    fmt::Write as _,
+    hash::{Hash, Hasher},
+};
+
+/// A descriptor binding used by the shaders of a pipeline
+#[derive(Clone, Debug)]
+pub struct ReflectedBinding {
+    pub name: String,
+    pub ty: vk::DescriptorType,
+    /// `None` for runtime-sized arrays
+    pub count: Option<u32>,
+}
+
+/// The descriptor bindings used by the shaders of a pipeline
+#[derive(Debug, Default)]
+pub struct ShaderInterface {
+    /// Paths of the shader files, or entry points of Rust shaders
+    pub shaders: Vec<String>,
+    pub sets: BTreeMap<u32, BTreeMap<u32, ReflectedBinding>>,
+    /// Hashes of the sets and bound descriptors which passed `validate_set`
+    validated_sets: Mutex<HashSet<u64>>,
+}
+
+impl ShaderInterface {
+    /// `set_layout_info` has the descriptor types of the pipeline layout, which can
+    /// differ from the reflected ones, e.g. for dynamic uniform buffers.
+    ///
+    /// Samplers are left out: they're immutable in the layout, and never bound by passes.
+    pub(crate) fn new(
+        shaders: Vec<String>,
+        reflected_sets: &HashMap<u32, HashMap<u32, rspirv_reflect::DescriptorInfo>>,
+        set_layout_info: &[HashMap<u32, vk::DescriptorType>],
+    ) -> Self {
+        let sets = set_layout_info
+            .iter()
+            .enumerate()
+            .filter(|(_, set)| !set.is_empty())
+            .map(|(set_idx, set)| {
+                let set_idx = set_idx as u32;
+                let reflected_set = reflected_sets.get(&set_idx);
+
+                let bindings = set
+                    .iter()
+                    .filter(|(_, &ty)| ty != vk::DescriptorType::SAMPLER)
+                    .map(|(&binding_idx, &ty)| {
+                        // Absent if the layout was replaced with a predefined one
+                        let reflected = reflected_set.and_then(|set| set.get(&binding_idx));
+
+                        let binding = ReflectedBinding {
+                            name: reflected.map(|info| info.name.clone()).unwrap_or_default(),
+                            ty,
+                            count: reflected.map_or(Some(1), |info| {
+                                reflected_descriptor_count(&info.dimensionality)
+                            }),
+                        };
+
+                        (binding_idx, binding)
+                    })
+                    .collect();
+
+                (set_idx, bindings)
+            })
+            .collect();
+
+        Self {
+            shaders,
+            sets,
+            validated_sets: Default::default(),
+        }
+    }
+
+    /// Like `validate_set`, but remembers what passed, so that a pass binding the same
+    /// kinds of descriptors every frame only gets checked once per pipeline.
+    pub fn validate_set_once(
+        &self,
+        set_idx: u32,
+        bound: impl Iterator<Item = (vk::DescriptorType, u32)> + Clone,
+    ) -> std::result::Result<(), String> {
+        let mut hasher = DefaultHasher::new();
+        set_idx.hash(&mut hasher);
+        bound.clone().for_each(|bound| bound.hash(&mut hasher));
+        let key = hasher.finish();
+
+        if self.validated_sets.lock().contains(&key) {
+            return Ok(());
+        }
+
+        self.validate_set(set_idx, &bound.collect::<Vec<_>>())?;
+        self.validated_sets.lock().insert(key);
+
+        Ok(())
+    }
+
+    /// Checks the descriptors which a pass binds to `set_idx`, in binding order, against
+    /// what the shaders declare. Extra descriptors are fine, since the shader compiler
+    /// strips unused resources.
+    pub fn validate_set(
+        &self,
+        set_idx: u32,
+        bound: &[(vk::DescriptorType, u32)],
+    ) -> std::result::Result<(), String> {
+        let set = if let Some(set) = self.sets.get(&set_idx) {
+            set
+        } else {
+            return Ok(());
+        };
+
+        for (&binding_idx, binding) in set {
+            let describe_binding = || {
+                let name = if binding.name.is_empty() {
+                    String::new()
+                } else {
+                    format!(" `{}`", binding.name)
+                };
+
+                format!(
+                    "binding {}{} of set {} in {}",
+                    binding_idx,
+                    name,
+                    set_idx,
+                    self.shaders.join(", ")
+                )
+            };
+
+            let (ty, count) = if let Some(bound) = bound.get(binding_idx as usize) {
+                *bound
+            } else {
+                return Err(format!(
+                    "{} needs {}, but the pass binds only {} resources",
+                    describe_binding(),
+                    descriptor_type_name(binding.ty),
+                    bound.len()
+                ));
+            };
+
+            if ty != binding.ty {
+                return Err(format!(
+                    "{} is {}, but the pass binds {}",
+                    describe_binding(),
+                    descriptor_type_name(binding.ty),
+                    descriptor_type_name(ty)
+                ));
+            }
+
+            if let Some(expected_count) = binding.count {
+                if count > expected_count {
+                    return Err(format!(
+                        "{} has {} descriptors, but the pass binds {}",
+                        describe_binding(),
+                        expected_count,
+                        count
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn reflected_descriptor_count(
+    dimensionality: &rspirv_reflect::DescriptorDimensionality,
+) -> Option<u32> {
+    match dimensionality {
+        rspirv_reflect::DescriptorDimensionality::Single => Some(1),
+        rspirv_reflect::DescriptorDimensionality::Array(size) => Some(*size),
+        rspirv_reflect::DescriptorDimensionality::RuntimeArray => None,
+    }
+}
+
+/// In terms of what render passes bind
+fn descriptor_type_name(ty: vk::DescriptorType) -> String {
+    match ty {
+        vk::DescriptorType::SAMPLED_IMAGE => "a sampled image (`read`)".to_owned(),
+        vk::DescriptorType::STORAGE_IMAGE => "a storage image (`write`)".to_owned(),
+        vk::DescriptorType::STORAGE_BUFFER => "a storage buffer".to_owned(),
+        vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC => "constants (`constants`)".to_owned(),
+        vk::DescriptorType::STORAGE_BUFFER_DYNAMIC => {
+            "a constants storage buffer (`dynamic_storage_buffer`)".to_owned()
+        }
+        vk::DescriptorType::ACCELERATION_STRUCTURE_KHR => "an acceleration structure".to_owned(),
+        _ => format!("{:?}", ty),
+    }
+}
+
+/// The descriptor bindings of a SPIR-V module, one per line, grouped by set.
+pub fn dump_shader_interface(spirv: &[u8]) -> Result<String> {
+    let descriptor_sets = rspirv_reflect::Reflection::new_from_spirv(spirv)
+        .and_then(|reflection| reflection.get_descriptor_sets())
+        .map_err(|err| anyhow!("Failed to reflect SPIR-V: {:?}", err))?;
+
+    let mut sets: Vec<_> = descriptor_sets.iter().collect();
+    sets.sort_by_key(|(set_idx, _)| **set_idx);
+
+    let mut text = String::new();
+    for (set_idx, set) in sets {
+        writeln!(text, "set {}", set_idx)?;
+
+        let mut bindings: Vec<_> = set.iter().collect();
+        bindings.sort_by_key(|(binding_idx, _)| **binding_idx);
+
+        for (binding_idx, info) in bindings {
+            let array = match info.dimensionality {
+                rspirv_reflect::DescriptorDimensionality::Single => String::new(),
+                rspirv_reflect::DescriptorDimensionality::Array(size) => format!("[{}]", size),
+                rspirv_reflect::DescriptorDimensionality::RuntimeArray => "[]".to_owned(),
+            };
+
+            writeln!(
+                text,
+                "  binding {}: {}{}: {:?}",
+                binding_idx, info.name, array, info.ty
+            )?;
+        }
+    }
+
+    Ok(text)
+}
+
+#[test]
+fn test_validate_set() {
+    let binding = |name: &str, ty, count| ReflectedBinding {
+        name: name.to_owned(),
+        ty,
+        count,
+    };
+
+    let interface = ShaderInterface {
+        shaders: vec!["/shaders/blur.hlsl".to_owned()],
+        sets: [(
+            0,
+            [
+                (
+                    0,
+                    binding("input_tex", vk::DescriptorType::SAMPLED_IMAGE, Some(1)),
+                ),
+                (
+                    2,
+                    binding("output_tex", vk::DescriptorType::STORAGE_IMAGE, Some(1)),
+                ),
+                (
+                    3,
+                    binding(
+                        "constants",
+                        vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+                        Some(1),
+                    ),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        )]
+        .into_iter()
+        .collect(),
+        ..Default::default()
+    };
+
+    let sampled = (vk::DescriptorType::SAMPLED_IMAGE, 1);
+    let storage = (vk::DescriptorType::STORAGE_IMAGE, 1);
+    let constants = (vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, 1);
+
+    // Binding 1 is unused by the shader, so anything goes there
+    assert!(interface
+        .validate_set(0, &[sampled, storage, storage, constants])
+        .is_ok());
+    assert!(interface.validate_set(1, &[]).is_ok());
+
+    let err = interface
+        .validate_set(0, &[sampled, sampled, sampled, constants])
+        .unwrap_err();
+    assert!(err.contains("binding 2 `output_tex`"), "{}", err);
+    assert!(err.contains("/shaders/blur.hlsl"), "{}", err);
+
+    assert!(interface
+        .validate_set(0, &[sampled, storage, storage])
+        .is_err());
+    assert!(interface
+        .validate_set(
+            0,
+            &[
+                (vk::DescriptorType::SAMPLED_IMAGE, 4),
+                storage,
+                storage,
+                constants
+            ]
+        )
+        .is_err());
+
+    // Failures aren't remembered, so they keep getting reported
+    let mismatched = [sampled, sampled, sampled, constants];
+    for _ in 0..2 {
+        assert!(interface
+            .validate_set_once(0, mismatched.iter().copied())
+            .is_err());
+    }
+    assert!(interface
+        .validate_set_once(0, [sampled, storage, storage, constants].iter().copied())
+        .is_ok());
+
+    // Like in shaders including `samplers.hlsl`
+    let set_layout_info: HashMap<u32, vk::DescriptorType> = [
+        (0, vk::DescriptorType::SAMPLED_IMAGE),
+        (32, vk::DescriptorType::SAMPLER),
+        (33, vk::DescriptorType::SAMPLER),
+    ]
+    .into_iter()
+    .collect();
+
+    let interface = ShaderInterface::new(
+        vec!["/shaders/blur.hlsl".to_owned()],
+        &HashMap::new(),
+        &[set_layout_info],
+    );
+    assert!(interface.validate_set(0, &[sampled]).is_ok());
+    assert!(interface.validate_set(0, &[storage]).is_err());
+}
@@ -1,13 +1,57 @@
-use crate::vulkan::{
-    buffer::{Buffer, BufferDesc},
-    image::{Image, ImageDesc},
+use crate::{
+    vulkan::{
+        buffer::{Buffer, BufferDesc},
+        image::{Image, ImageDesc},
+    },
+    BackendError, Device,
 };
+use ash::vk;
+use gpu_allocator::{AllocationCreateDesc, MemoryLocation};
 use std::collections::HashMap;
 
+/// A chunk of device memory which the render graph places multiple transient images in.
+pub struct TransientMemoryBlock {
+    pub allocation: gpu_allocator::SubAllocation,
+    pub size: u64,
+    pub alignment: u64,
+    pub memory_type_bits: u32,
+}
+
+impl TransientMemoryBlock {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        unsafe { self.allocation.memory() }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.allocation.offset()
+    }
+
+    fn satisfies(&self, requirements: &vk::MemoryRequirements) -> bool {
+        // The allocator picked one of the block's memory types; make sure any of them
+        // would also be acceptable for the new requirements.
+        self.size >= requirements.size
+            && self.alignment >= requirements.alignment
+            && (self.memory_type_bits & requirements.memory_type_bits) == self.memory_type_bits
+    }
+}
+
+/// Number of frames a pooled memory block or placed image can go unused before it's freed.
+/// Must exceed the number of frames in flight, since resources are returned to the cache
+/// as soon as their frame is submitted.
+const MAX_UNUSED_FRAMES: u64 = 8;
+
 #[derive(Default)]
 pub struct TransientResourceCache {
     images: HashMap<ImageDesc, Vec<Image>>,
     buffers: HashMap<BufferDesc, Vec<Buffer>>,
+
+    image_memory_requirements: HashMap<ImageDesc, vk::MemoryRequirements>,
+
+    // Along with the frame they were last returned to the cache on
+    memory_blocks: Vec<(TransientMemoryBlock, u64)>,
+    placed_images: HashMap<(ImageDesc, vk::DeviceMemory, u64), Vec<(Image, u64)>>,
+
+    frame_idx: u64,
 }
 
 impl TransientResourceCache {
@@ -42,4 +86,120 @@ impl TransientResourceCache {
             self.buffers.insert(buffer.desc, vec![buffer]);
         }
     }
+
+    pub fn image_memory_requirements(
+        &mut self,
+        device: &Device,
+        desc: &ImageDesc,
+    ) -> Result<vk::MemoryRequirements, BackendError> {
+        if let Some(requirements) = self.image_memory_requirements.get(desc) {
+            return Ok(*requirements);
+        }
+
+        let requirements = device.image_memory_requirements(desc)?;
+        self.image_memory_requirements.insert(*desc, requirements);
+        Ok(requirements)
+    }
+
+    /// Returns a pooled memory block satisfying `requirements`, or allocates a new one.
+    pub fn get_memory_block(
+        &mut self,
+        device: &Device,
+        requirements: vk::MemoryRequirements,
+    ) -> Result<TransientMemoryBlock, BackendError> {
+        // Prefer the smallest block which fits
+        let best_fit = self
+            .memory_blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, (block, _))| block.satisfies(&requirements))
+            .min_by_key(|(_, (block, _))| block.size)
+            .map(|(idx, _)| idx);
+
+        if let Some(idx) = best_fit {
+            return Ok(self.memory_blocks.swap_remove(idx).0);
+        }
+
+        let allocation = device
+            .global_allocator
+            .lock()
+            .allocate(&AllocationCreateDesc {
+                name: "rg transient memory",
+                requirements,
+                location: MemoryLocation::GpuOnly,
+                linear: false,
+            })
+            .map_err(|err| BackendError::Allocation {
+                inner: err,
+                name: "rg transient memory".into(),
+            })?;
+
+        Ok(TransientMemoryBlock {
+            allocation,
+            size: requirements.size,
+            alignment: requirements.alignment,
+            memory_type_bits: requirements.memory_type_bits,
+        })
+    }
+
+    pub fn insert_memory_block(&mut self, block: TransientMemoryBlock) {
+        self.memory_blocks.push((block, self.frame_idx));
+    }
+
+    /// Returns an image bound to `offset` within `block`, reusing one from a previous frame
+    /// if the same placement was used before.
+    pub fn get_placed_image(
+        &mut self,
+        device: &Device,
+        desc: &ImageDesc,
+        block: &TransientMemoryBlock,
+        offset: u64,
+    ) -> Result<Image, BackendError> {
+        let key = (*desc, block.memory(), block.offset() + offset);
+
+        if let Some((image, _)) = self.placed_images.get_mut(&key).and_then(Vec::pop) {
+            Ok(image)
+        } else {
+            device.create_placed_image(*desc, key.1, key.2)
+        }
+    }
+
+    pub fn insert_placed_image(&mut self, image: Image, memory: vk::DeviceMemory, offset: u64) {
+        self.placed_images
+            .entry((image.desc, memory, offset))
+            .or_default()
+            .push((image, self.frame_idx));
+    }
+
+    /// Frees the memory blocks and placed images which haven't been used for a while,
+    /// e.g. after the render resolution changed.
+    pub fn advance_frame(&mut self, device: &Device) {
+        self.frame_idx += 1;
+        let frame_idx = self.frame_idx;
+        let is_stale = |last_used: u64| frame_idx - last_used > MAX_UNUSED_FRAMES;
+
+        for images in self.placed_images.values_mut() {
+            for (image, _) in drain_filter(images, |(_, last_used)| is_stale(*last_used)) {
+                device.immediate_destroy_placed_image(image);
+            }
+        }
+        self.placed_images.retain(|_, images| !images.is_empty());
+
+        for (block, _) in drain_filter(&mut self.memory_blocks, |(_, last_used)| {
+            is_stale(*last_used)
+        }) {
+            device
+                .global_allocator
+                .lock()
+                .free(block.allocation)
+                .expect("rg transient memory deallocated");
+        }
+    }
+}
+
+/// Removes and returns the elements of `items` matching `pred`.
+fn drain_filter<T>(items: &mut Vec<T>, pred: impl Fn(&T) -> bool) -> Vec<T> {
+    let (removed, kept) = std::mem::take(items).into_iter().partition(pred);
+    *items = kept;
+    removed
 }
@@ -66,8 +66,30 @@ pub struct DeviceFrame {
     pub presentation_command_buffer: CommandBuffer,
     pub pending_resource_releases: Mutex<PendingResourceReleases>,
     pub profiler_data: VkProfilerData,
+    submission_pool: Mutex<FrameSubmissionPool>,
 }
 
+/// Extra command buffers and semaphores for frames which are split into multiple
+/// queue submissions. Grown on demand, and recycled in `begin_frame`, once the GPU is done
+/// with the frame.
+#[derive(Default)]
+struct FrameSubmissionPool {
+    // Keyed by queue family index
+    command_buffers: HashMap<u32, (Vec<CommandBuffer>, usize)>,
+    semaphores: Vec<vk::Semaphore>,
+    used_semaphores: usize,
+}
+
+impl FrameSubmissionPool {
+    fn recycle(&mut self) {
+        for (_, used) in self.command_buffers.values_mut() {
+            *used = 0;
+        }
+        self.used_semaphores = 0;
+    }
+}
+
+#[derive(Clone)]
 pub struct CommandBuffer {
     pub raw: vk::CommandBuffer,
     pub submit_done_fence: vk::Fence,
@@ -130,6 +152,7 @@ impl DeviceFrame {
             main_command_buffer: CommandBuffer::new(device, queue_family).unwrap(),
             presentation_command_buffer: CommandBuffer::new(device, queue_family).unwrap(),
             pending_resource_releases: Default::default(),
+            submission_pool: Default::default(),
             profiler_data: VulkanProfilerFrame::new(
                 device,
                 ProfilerBackend::new(
@@ -147,10 +170,18 @@ pub struct Device {
     pub(crate) pdevice: Arc<PhysicalDevice>,
     pub(crate) instance: Arc<super::instance::Instance>,
     pub universal_queue: Queue,
+
+    /// Compute-only queue which can run alongside `universal_queue`.
+    /// `None` if the device doesn't have a dedicated compute queue family.
+    pub async_compute_queue: Option<Queue>,
+
     pub(crate) global_allocator: Arc<Mutex<VulkanAllocator>>,
     pub(crate) immutable_samplers: HashMap<SamplerDesc, vk::Sampler>,
     pub(crate) setup_cb: Mutex<CommandBuffer>,
 
+    /// Seeded from, and saved to disk by `save_pipeline_cache`
+    pub(crate) pipeline_cache: vk::PipelineCache,
+
     pub(crate) crash_tracking_buffer: Buffer,
     pub(crate) crash_marker_names: Mutex<CrashMarkerNames>,
 
@@ -275,10 +306,31 @@ impl Device {
             anyhow::bail!("No suitable render queue found");
         };
 
-        let universal_queue_info = [vk::DeviceQueueCreateInfo::builder()
-            .queue_family_index(universal_queue.index)
-            .queue_priorities(&priorities)
-            .build()];
+        // Queries are recorded from all queues, so the compute family needs timestamp support.
+        let async_compute_queue = pdevice
+            .queue_families
+            .iter()
+            .filter(|qf| {
+                qf.properties.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                    && !qf.properties.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                    && qf.properties.timestamp_valid_bits > 0
+            })
+            .copied()
+            .next();
+
+        if async_compute_queue.is_none() {
+            info!("No dedicated compute queue family found; async compute will be disabled");
+        }
+
+        let queue_infos: Vec<vk::DeviceQueueCreateInfo> = std::iter::once(universal_queue)
+            .chain(async_compute_queue)
+            .map(|queue_family| {
+                vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(queue_family.index)
+                    .queue_priorities(&priorities)
+                    .build()
+            })
+            .collect();
 
         let mut scalar_block = vk::PhysicalDeviceScalarBlockLayoutFeaturesEXT::default();
         let mut descriptor_indexing = vk::PhysicalDeviceDescriptorIndexingFeaturesEXT::default();
@@ -364,7 +416,7 @@ impl Device {
             }
 
             let device_create_info = vk::DeviceCreateInfo::builder()
-                .queue_create_infos(&universal_queue_info)
+                .queue_create_infos(&queue_infos)
                 .enabled_extension_names(&device_extension_names)
                 .push_next(&mut features2)
                 .build();
@@ -393,6 +445,11 @@ impl Device {
                 family: universal_queue,
             };
 
+            let async_compute_queue = async_compute_queue.map(|family| Queue {
+                raw: device.get_device_queue(family.index, 0),
+                family,
+            });
+
             let frame0 = DeviceFrame::new(
                 pdevice,
                 &device,
@@ -425,14 +482,25 @@ impl Device {
                 "crash tracking buffer",
             )?;
 
+            let pipeline_cache = {
+                let initial_data =
+                    crate::shader_cache::load_pipeline_cache_data(&pdevice.properties);
+                device.create_pipeline_cache(
+                    &vk::PipelineCacheCreateInfo::builder().initial_data(&initial_data),
+                    None,
+                )?
+            };
+
             Ok(Arc::new(Device {
                 pdevice: pdevice.clone(),
                 instance: pdevice.instance.clone(),
                 raw: device,
                 universal_queue,
+                async_compute_queue,
                 global_allocator: Arc::new(Mutex::new(global_allocator)),
                 immutable_samplers,
                 setup_cb: Mutex::new(setup_cb),
+                pipeline_cache,
                 crash_tracking_buffer,
                 crash_marker_names: Default::default(),
                 acceleration_structure_ext,
@@ -542,11 +610,44 @@ impl Device {
                 .pending_resource_releases
                 .get_mut()
                 .release_all(&self.raw);
+
+            frame0.submission_pool.get_mut().recycle();
         }
 
         frame0.clone()
     }
 
+    /// Returns a command buffer for an additional submission to `queue` within `frame`.
+    /// The command buffer is not in the recording state yet.
+    pub fn frame_command_buffer(&self, frame: &DeviceFrame, queue: &Queue) -> CommandBuffer {
+        let mut pool = frame.submission_pool.lock();
+        let (command_buffers, used) = pool.command_buffers.entry(queue.family.index).or_default();
+
+        if *used == command_buffers.len() {
+            command_buffers.push(CommandBuffer::new(&self.raw, &queue.family).unwrap());
+        }
+
+        *used += 1;
+        command_buffers[*used - 1].clone()
+    }
+
+    /// Returns a binary semaphore for synchronizing submissions within `frame`.
+    pub fn frame_semaphore(&self, frame: &DeviceFrame) -> vk::Semaphore {
+        let mut pool = frame.submission_pool.lock();
+
+        if pool.used_semaphores == pool.semaphores.len() {
+            let semaphore = unsafe {
+                self.raw
+                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
+                    .unwrap()
+            };
+            pool.semaphores.push(semaphore);
+        }
+
+        pool.used_semaphores += 1;
+        pool.semaphores[pool.used_semaphores - 1]
+    }
+
     pub fn defer_release(&self, resource: impl DeferredRelease) {
         resource.enqueue_release(&mut self.frames[0].lock().pending_resource_releases.lock());
     }
@@ -630,6 +731,12 @@ impl Device {
     pub fn ray_tracing_enabled(&self) -> bool {
         self.ray_tracing_enabled
     }
+
+    /// Writes the pipeline cache to disk, for the next run to start with.
+    pub fn save_pipeline_cache(&self) -> Result<()> {
+        let data = unsafe { self.raw.get_pipeline_cache_data(self.pipeline_cache) }?;
+        crate::shader_cache::save_pipeline_cache_data(&data)
+    }
 }
 
 impl Drop for Device {
@@ -637,6 +744,15 @@ impl Drop for Device {
         unsafe {
             log::trace!("device_wait_idle");
             let _ = self.raw.device_wait_idle();
+
+            for frame in &self.frames {
+                let frame = frame.lock();
+                for semaphore in frame.submission_pool.lock().semaphores.drain(..) {
+                    self.raw.destroy_semaphore(semaphore, None);
+                }
+            }
+
+            self.raw.destroy_pipeline_cache(self.pipeline_cache, None);
         }
     }
 }
@@ -305,8 +305,10 @@ impl Device {
                 vk::Format::BC1_RGB_SRGB_BLOCK => 8,
                 vk::Format::BC3_UNORM_BLOCK => 16,
                 vk::Format::BC3_SRGB_BLOCK => 16,
+                vk::Format::BC4_UNORM_BLOCK => 8,
                 vk::Format::BC5_UNORM_BLOCK => 16,
                 vk::Format::BC5_SNORM_BLOCK => 16,
+                vk::Format::BC6H_UFLOAT_BLOCK => 16,
                 vk::Format::BC7_UNORM_BLOCK => 16,
                 vk::Format::BC7_SRGB_BLOCK => 16,
                 _ => todo!("{:?}", desc.format),
@@ -410,6 +412,59 @@ impl Device {
         })
     }
 
+    /// Queries the memory requirements of an image with the given description
+    /// without allocating any memory for it.
+    pub fn image_memory_requirements(
+        &self,
+        desc: &ImageDesc,
+    ) -> Result<vk::MemoryRequirements, BackendError> {
+        let create_info = get_image_create_info(desc, false);
+
+        unsafe {
+            let image = self.raw.create_image(&create_info, None)?;
+            let requirements = self.raw.get_image_memory_requirements(image);
+            self.raw.destroy_image(image, None);
+            Ok(requirements)
+        }
+    }
+
+    /// Creates an image bound to an externally owned memory range. The caller is responsible
+    /// for keeping `memory` alive for as long as the image exists, and for synchronizing
+    /// with any other resources placed in the same range.
+    pub fn create_placed_image(
+        &self,
+        desc: ImageDesc,
+        memory: vk::DeviceMemory,
+        offset: u64,
+    ) -> Result<Image, BackendError> {
+        log::info!("Creating a placed image: {:?} @ {}", desc, offset);
+
+        let create_info = get_image_create_info(&desc, false);
+
+        let image = unsafe {
+            let image = self.raw.create_image(&create_info, None)?;
+            self.raw.bind_image_memory(image, memory, offset)?;
+            image
+        };
+
+        Ok(Image {
+            raw: image,
+            desc,
+            views: Default::default(),
+        })
+    }
+
+    /// Destroys an image created by `create_placed_image`, and its views.
+    /// The memory it's placed in is owned by the caller.
+    pub fn immediate_destroy_placed_image(&self, image: Image) {
+        unsafe {
+            for view in image.views.lock().values() {
+                self.raw.destroy_image_view(*view, None);
+            }
+            self.raw.destroy_image(image.raw, None);
+        }
+    }
+
     fn create_image_view(
         &self,
         desc: ImageViewDesc,
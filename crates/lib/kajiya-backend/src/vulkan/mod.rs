@@ -4,6 +4,7 @@ pub mod device;
 pub mod error;
 pub mod image;
 pub mod instance;
+pub mod offscreen;
 pub mod physical_device;
 mod profiler;
 pub mod ray_tracing;
@@ -32,8 +33,10 @@ fn select_surface_format(formats: Vec<vk::SurfaceFormatKHR>) -> Option<vk::Surfa
 
 pub struct RenderBackend {
     pub device: Arc<device::Device>,
-    pub surface: Arc<surface::Surface>,
-    pub swapchain: swapchain::Swapchain,
+
+    /// Both `None` for headless backends, which render into an `offscreen::OffscreenTarget`.
+    pub surface: Option<Arc<surface::Surface>>,
+    pub swapchain: Option<swapchain::Swapchain>,
 }
 
 #[derive(Clone, Copy)]
@@ -44,6 +47,46 @@ pub struct RenderBackendConfig {
     pub device_index: Option<usize>,
 }
 
+fn select_physical_device(
+    physical_devices: Vec<physical_device::PhysicalDevice>,
+    device_index: Option<usize>,
+) -> anyhow::Result<Arc<physical_device::PhysicalDevice>> {
+    info!(
+        "Available physical devices: {:#?}",
+        physical_devices
+            .iter()
+            .map(|dev| unsafe {
+                ::std::ffi::CStr::from_ptr(
+                    dev.properties.device_name.as_ptr() as *const std::os::raw::c_char
+                )
+            })
+            .collect::<Vec<_>>()
+    );
+
+    let physical_device = if let Some(device_index) = device_index {
+        physical_devices.into_iter().nth(device_index)
+    } else {
+        physical_devices
+            .into_iter()
+            // If there are multiple devices with the same score, `max_by_key` would choose the last,
+            // and we want to preserve the order of devices from `enumerate_physical_devices`.
+            .rev()
+            .max_by_key(|device| match device.properties.device_type {
+                vk::PhysicalDeviceType::INTEGRATED_GPU => 200,
+                vk::PhysicalDeviceType::DISCRETE_GPU => 1000,
+                vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
+                _ => 0,
+            })
+    };
+
+    let physical_device =
+        physical_device.ok_or_else(|| anyhow::anyhow!("No suitable physical device found"))?;
+
+    info!("Selected physical device: {:#?}", physical_device);
+
+    Ok(Arc::new(physical_device))
+}
+
 impl RenderBackend {
     pub fn new(
         window: &impl HasRawWindowHandle,
@@ -58,37 +101,7 @@ impl RenderBackend {
         use physical_device::*;
         let physical_devices =
             enumerate_physical_devices(&instance)?.with_presentation_support(&surface);
-
-        info!(
-            "Available physical devices: {:#?}",
-            physical_devices
-                .iter()
-                .map(|dev| unsafe {
-                    ::std::ffi::CStr::from_ptr(
-                        dev.properties.device_name.as_ptr() as *const std::os::raw::c_char
-                    )
-                })
-                .collect::<Vec<_>>()
-        );
-
-        let physical_device = Arc::new(if let Some(device_index) = config.device_index {
-            physical_devices.into_iter().nth(device_index).unwrap()
-        } else {
-            physical_devices
-                .into_iter()
-                // If there are multiple devices with the same score, `max_by_key` would choose the last,
-                // and we want to preserve the order of devices from `enumerate_physical_devices`.
-                .rev()
-                .max_by_key(|device| match device.properties.device_type {
-                    vk::PhysicalDeviceType::INTEGRATED_GPU => 200,
-                    vk::PhysicalDeviceType::DISCRETE_GPU => 1000,
-                    vk::PhysicalDeviceType::VIRTUAL_GPU => 1,
-                    _ => 0,
-                })
-                .unwrap()
-        });
-
-        info!("Selected physical device: {:#?}", *physical_device);
+        let physical_device = select_physical_device(physical_devices, config.device_index)?;
 
         let device = device::Device::create(&physical_device)?;
         let surface_formats = swapchain::Swapchain::enumerate_surface_formats(&device, &surface)?;
@@ -110,8 +123,37 @@ impl RenderBackend {
 
         Ok(Self {
             device,
-            surface,
-            swapchain,
+            surface: Some(surface),
+            swapchain: Some(swapchain),
+        })
+    }
+
+    /// Creates a backend without a window, surface, or swapchain. Works on devices without
+    /// presentation support, including software implementations such as lavapipe
+    /// (select it with the `VK_ICD_FILENAMES` environment variable).
+    ///
+    /// `swapchain_extent` and `vsync` in the `config` are ignored.
+    pub fn new_headless(config: RenderBackendConfig) -> anyhow::Result<Self> {
+        let instance = instance::Instance::builder()
+            .graphics_debugging(config.graphics_debugging)
+            .build()?;
+
+        // Devices without presentation support may lack the swapchain extension
+        let physical_devices = physical_device::enumerate_physical_devices(&instance)?
+            .into_iter()
+            .map(|mut pdevice| {
+                pdevice.presentation_requested = false;
+                pdevice
+            })
+            .collect();
+        let physical_device = select_physical_device(physical_devices, config.device_index)?;
+
+        let device = device::Device::create(&physical_device)?;
+
+        Ok(Self {
+            device,
+            surface: None,
+            swapchain: None,
         })
     }
 
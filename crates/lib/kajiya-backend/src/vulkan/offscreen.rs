@@ -0,0 +1,144 @@
+use super::{
+    barrier::{record_image_barrier, ImageBarrier},
+    buffer::{Buffer, BufferDesc},
+    device::{CommandBuffer, Device},
+};
+use crate::{Image, ImageDesc};
+use anyhow::Result;
+use ash::vk;
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Stands in for a swapchain when there's no display to present to.
+///
+/// Frames are rendered into `image`, and copied into a host-visible buffer at the end
+/// of each frame, from which `read_pixels` fetches them.
+pub struct OffscreenTarget {
+    pub image: Arc<Image>,
+    readback_buffer: Buffer,
+
+    /// Signaled once the last recorded readback is done
+    readback_fence: Mutex<Option<vk::Fence>>,
+    device: Arc<Device>,
+}
+
+impl OffscreenTarget {
+    pub const FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+    /// Access type `image` is left in after `record_readback`.
+    pub const ACCESS_AFTER_READBACK: vk_sync::AccessType = vk_sync::AccessType::TransferRead;
+
+    pub fn new(device: &Arc<Device>, extent: [u32; 2]) -> Result<Self> {
+        if extent[0] == 0 || extent[1] == 0 {
+            anyhow::bail!("Offscreen target resolution cannot be zero");
+        }
+
+        let image = device.create_image(
+            ImageDesc::new_2d(Self::FORMAT, extent)
+                .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC),
+            vec![],
+        )?;
+
+        let readback_buffer = device.create_buffer(
+            BufferDesc::new_gpu_to_cpu(
+                (extent[0] * extent[1] * 4) as usize,
+                vk::BufferUsageFlags::TRANSFER_DST,
+            ),
+            "offscreen readback buffer",
+            None,
+        )?;
+
+        Ok(Self {
+            image: Arc::new(image),
+            readback_buffer,
+            readback_fence: Mutex::new(None),
+            device: device.clone(),
+        })
+    }
+
+    pub fn extent(&self) -> [u32; 2] {
+        [self.image.desc.extent[0], self.image.desc.extent[1]]
+    }
+
+    /// Copies `image` into the readback buffer. The image must have last been written
+    /// by a compute shader, as the swapchain images are.
+    ///
+    /// `cb` must be submitted with its `submit_done_fence` before `read_pixels` is called.
+    pub fn record_readback(&self, cb: &CommandBuffer) {
+        let device = &*self.device;
+        *self.readback_fence.lock() = Some(cb.submit_done_fence);
+        let cb = cb.raw;
+
+        record_image_barrier(
+            device,
+            cb,
+            ImageBarrier::new(
+                self.image.raw,
+                vk_sync::AccessType::ComputeShaderWrite,
+                Self::ACCESS_AFTER_READBACK,
+                vk::ImageAspectFlags::COLOR,
+            ),
+        );
+
+        let [width, height] = self.extent();
+        let region = vk::BufferImageCopy::builder()
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_extent(vk::Extent3D {
+                width,
+                height,
+                depth: 1,
+            })
+            .build();
+
+        unsafe {
+            device.raw.cmd_copy_image_to_buffer(
+                cb,
+                self.image.raw,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                self.readback_buffer.raw,
+                &[region],
+            );
+        }
+
+        vk_sync::cmd::pipeline_barrier(
+            device.raw.fp_v1_0(),
+            cb,
+            None,
+            &[vk_sync::BufferBarrier {
+                previous_accesses: &[vk_sync::AccessType::TransferWrite],
+                next_accesses: &[vk_sync::AccessType::HostRead],
+                src_queue_family_index: device.universal_queue.family.index,
+                dst_queue_family_index: device.universal_queue.family.index,
+                buffer: self.readback_buffer.raw,
+                offset: 0,
+                size: self.readback_buffer.desc.size,
+            }],
+            &[],
+        );
+    }
+
+    /// Tightly packed RGBA8 pixels of the most recently rendered frame.
+    ///
+    /// Waits for the GPU to finish that frame, but not any other work.
+    pub fn read_pixels(&self) -> Result<Vec<u8>> {
+        if let Some(fence) = *self.readback_fence.lock() {
+            unsafe {
+                self.device
+                    .raw
+                    .wait_for_fences(&[fence], true, std::u64::MAX)?
+            };
+        }
+
+        Ok(self
+            .readback_buffer
+            .allocation
+            .mapped_slice()
+            .expect("readback buffer must be host-visible")
+            .to_vec())
+    }
+}
@@ -1,6 +1,9 @@
 use std::sync::Arc;
 
-use crate::{dynamic_constants::DynamicConstants, BackendError, MAX_DESCRIPTOR_SETS};
+use crate::{
+    dynamic_constants::DynamicConstants, shader_reflection::ShaderInterface, BackendError,
+    MAX_DESCRIPTOR_SETS,
+};
 
 use super::{
     device::Device,
@@ -35,6 +38,10 @@ pub struct RayTracingGeometryDesc {
     pub index_buffer: vk::DeviceAddress,
     pub vertex_format: vk::Format,
     pub vertex_stride: usize,
+
+    /// Non-opaque geometry invokes the any-hit shaders of the pipeline's hit groups
+    pub opaque: bool,
+
     pub parts: Vec<RayTracingGeometryPart>,
 }
 
@@ -54,6 +61,9 @@ pub struct RayTracingTopAccelerationDesc {
 #[derive(Clone, Debug)]
 pub struct RayTracingBottomAccelerationDesc {
     pub geometries: Vec<RayTracingGeometryDesc>,
+
+    /// Allows refitting with `update_ray_tracing_bottom_acceleration` when vertices move
+    pub allow_update: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -66,6 +76,14 @@ pub struct RayTracingShaderTableDesc {
 pub struct RayTracingAcceleration {
     pub raw: vk::AccelerationStructureKHR,
     backing_buffer: super::buffer::Buffer,
+    update_scratch_buffer: Option<super::buffer::Buffer>,
+}
+
+impl RayTracingAcceleration {
+    /// The buffer holding the acceleration structure, e.g. for queue ownership transfers
+    pub fn backing_buffer(&self) -> &super::buffer::Buffer {
+        &self.backing_buffer
+    }
 }
 
 #[derive(Clone)]
@@ -99,52 +117,12 @@ impl Device {
     ) -> Result<RayTracingAcceleration, BackendError> {
         //log::trace!("Creating ray tracing bottom acceleration: {:?}", desc);
 
-        let geometries: Result<Vec<ash::vk::AccelerationStructureGeometryKHR>, BackendError> = desc
-            .geometries
-            .iter()
-            .map(
-                |desc| -> Result<ash::vk::AccelerationStructureGeometryKHR, BackendError> {
-                    let part: RayTracingGeometryPart = desc.parts[0];
-
-                    let geometry = ash::vk::AccelerationStructureGeometryKHR::builder()
-                        .geometry_type(ash::vk::GeometryTypeKHR::TRIANGLES)
-                        .geometry(ash::vk::AccelerationStructureGeometryDataKHR {
-                            triangles:
-                                ash::vk::AccelerationStructureGeometryTrianglesDataKHR::builder()
-                                    .vertex_data(ash::vk::DeviceOrHostAddressConstKHR {
-                                        device_address: desc.vertex_buffer,
-                                    })
-                                    .vertex_stride(desc.vertex_stride as _)
-                                    .max_vertex(part.max_vertex)
-                                    .vertex_format(desc.vertex_format)
-                                    .index_data(ash::vk::DeviceOrHostAddressConstKHR {
-                                        device_address: desc.index_buffer,
-                                    })
-                                    .index_type(ash::vk::IndexType::UINT32) // TODO
-                                    .build(),
-                        })
-                        .flags(ash::vk::GeometryFlagsKHR::OPAQUE)
-                        .build();
-
-                    Ok(geometry)
-                },
-            )
-            .collect();
-        let geometries = geometries?;
-
-        let build_range_infos: Vec<ash::vk::AccelerationStructureBuildRangeInfoKHR> = desc
-            .geometries
-            .iter()
-            .map(|desc| {
-                ash::vk::AccelerationStructureBuildRangeInfoKHR::builder()
-                    .primitive_count(desc.parts[0].index_count as u32 / 3)
-                    .build()
-            })
-            .collect();
+        let geometries = bottom_acceleration_geometries(desc);
+        let build_range_infos = bottom_acceleration_build_ranges(desc);
 
         let geometry_info = ash::vk::AccelerationStructureBuildGeometryInfoKHR::builder()
             .ty(ash::vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
-            .flags(ash::vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .flags(bottom_acceleration_build_flags(desc))
             .geometries(geometries.as_slice())
             .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
             .build();
@@ -168,6 +146,63 @@ impl Device {
         )
     }
 
+    /// Refits `accel` to the current contents of the vertex buffers in `desc`, which must
+    /// be the same desc (with `allow_update` set) that the acceleration was created with.
+    pub fn update_ray_tracing_bottom_acceleration(
+        &self,
+        cb: vk::CommandBuffer,
+        desc: &RayTracingBottomAccelerationDesc,
+        accel: &RayTracingAcceleration,
+    ) {
+        let scratch_buffer = accel
+            .update_scratch_buffer
+            .as_ref()
+            .expect("acceleration structure was not created with `allow_update`");
+
+        let geometries = bottom_acceleration_geometries(desc);
+        let build_range_infos = bottom_acceleration_build_ranges(desc);
+
+        let geometry_info = ash::vk::AccelerationStructureBuildGeometryInfoKHR::builder()
+            .ty(ash::vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .flags(bottom_acceleration_build_flags(desc))
+            .geometries(geometries.as_slice())
+            .mode(vk::BuildAccelerationStructureModeKHR::UPDATE)
+            .src_acceleration_structure(accel.raw)
+            .dst_acceleration_structure(accel.raw)
+            .scratch_data(ash::vk::DeviceOrHostAddressKHR {
+                device_address: scratch_buffer.device_address(self),
+            })
+            .build();
+
+        unsafe {
+            self.acceleration_structure_ext
+                .cmd_build_acceleration_structures(
+                    cb,
+                    std::slice::from_ref(&geometry_info),
+                    std::slice::from_ref(&build_range_infos.as_slice()),
+                );
+
+            self.raw.cmd_pipeline_barrier(
+                cb,
+                ash::vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                ash::vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                ash::vk::DependencyFlags::empty(),
+                &[ash::vk::MemoryBarrier::builder()
+                    .src_access_mask(
+                        ash::vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR
+                            | ash::vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+                    )
+                    .dst_access_mask(
+                        ash::vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR
+                            | ash::vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+                    )
+                    .build()],
+                &[],
+                &[],
+            );
+        }
+    }
+
     pub fn create_ray_tracing_top_acceleration(
         &self,
         desc: &RayTracingTopAccelerationDesc,
@@ -212,7 +247,8 @@ impl Device {
                     0,
                     /*ash::vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE
                     | */
-                    ash::vk::GeometryInstanceFlagsKHR::FORCE_OPAQUE,
+                    // Opacity comes from the geometry flags of the BLAS
+                    ash::vk::GeometryInstanceFlagsKHR::empty(),
                     blas_address,
                 )
             })
@@ -317,6 +353,27 @@ impl Device {
             .size(backing_buffer_size as u64)
             .build();
 
+        let update_scratch_buffer = if geometry_info
+            .flags
+            .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE)
+        {
+            Some(
+                self.create_buffer(
+                    super::buffer::BufferDesc::new_gpu_only(
+                        memory_requirements.update_scratch_size as usize,
+                        vk::BufferUsageFlags::STORAGE_BUFFER
+                            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                    )
+                    // TODO: query minAccelerationStructureScratchOffsetAlignment
+                    .alignment(256),
+                    "Acceleration structure update scratch buffer",
+                    None,
+                )?,
+            )
+        } else {
+            None
+        };
+
         let mut tmp_scratch_buffer = None;
         let mut scratch_buffer_lock;
 
@@ -392,6 +449,7 @@ impl Device {
                 Ok(RayTracingAcceleration {
                     raw: accel_raw,
                     backing_buffer: accel_buffer,
+                    update_scratch_buffer,
                 })
             }
         };
@@ -444,7 +502,8 @@ impl Device {
                 0,
                 /*ash::vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE
                 | */
-                ash::vk::GeometryInstanceFlagsKHR::FORCE_OPAQUE,
+                // Opacity comes from the geometry flags of the BLAS
+                ash::vk::GeometryInstanceFlagsKHR::empty(),
                 blas_address,
             )
         }));
@@ -670,6 +729,64 @@ impl Device {
     }
 }
 
+fn bottom_acceleration_build_flags(
+    desc: &RayTracingBottomAccelerationDesc,
+) -> vk::BuildAccelerationStructureFlagsKHR {
+    if desc.allow_update {
+        vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+            | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE
+    } else {
+        vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+    }
+}
+
+fn bottom_acceleration_geometries(
+    desc: &RayTracingBottomAccelerationDesc,
+) -> Vec<vk::AccelerationStructureGeometryKHR> {
+    desc.geometries
+        .iter()
+        .map(|desc| {
+            let part: RayTracingGeometryPart = desc.parts[0];
+
+            ash::vk::AccelerationStructureGeometryKHR::builder()
+                .geometry_type(ash::vk::GeometryTypeKHR::TRIANGLES)
+                .geometry(ash::vk::AccelerationStructureGeometryDataKHR {
+                    triangles: ash::vk::AccelerationStructureGeometryTrianglesDataKHR::builder()
+                        .vertex_data(ash::vk::DeviceOrHostAddressConstKHR {
+                            device_address: desc.vertex_buffer,
+                        })
+                        .vertex_stride(desc.vertex_stride as _)
+                        .max_vertex(part.max_vertex)
+                        .vertex_format(desc.vertex_format)
+                        .index_data(ash::vk::DeviceOrHostAddressConstKHR {
+                            device_address: desc.index_buffer,
+                        })
+                        .index_type(ash::vk::IndexType::UINT32) // TODO
+                        .build(),
+                })
+                .flags(if desc.opaque {
+                    ash::vk::GeometryFlagsKHR::OPAQUE
+                } else {
+                    ash::vk::GeometryFlagsKHR::NO_DUPLICATE_ANY_HIT_INVOCATION
+                })
+                .build()
+        })
+        .collect()
+}
+
+fn bottom_acceleration_build_ranges(
+    desc: &RayTracingBottomAccelerationDesc,
+) -> Vec<vk::AccelerationStructureBuildRangeInfoKHR> {
+    desc.geometries
+        .iter()
+        .map(|desc| {
+            ash::vk::AccelerationStructureBuildRangeInfoKHR::builder()
+                .primitive_count(desc.parts[0].index_count as u32 / 3)
+                .build()
+        })
+        .collect()
+}
+
 pub struct RayTracingPipeline {
     pub common: ShaderPipelineCommon,
     pub sbt: RayTracingShaderTable,
@@ -725,14 +842,25 @@ pub fn create_ray_tracing_pipeline(
 
     //log::info!("{:#?}", stage_layouts);
 
+    let reflected_sets = merge_shader_stage_layouts(stage_layouts);
+
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
-        &merge_shader_stage_layouts(stage_layouts),
+        &reflected_sets,
         vk::ShaderStageFlags::ALL,
         //desc.descriptor_set_layout_flags.unwrap_or(&[]),  // TODO: merge flags
         &desc.descriptor_set_opts,
     );
 
+    let interface = ShaderInterface::new(
+        shaders
+            .iter()
+            .map(|shader| shader.desc.source.to_string())
+            .collect(),
+        &reflected_sets,
+        &set_layout_info,
+    );
+
     unsafe {
         let layout_create_info = vk::PipelineLayoutCreateInfo::builder()
             .set_layouts(&descriptor_set_layouts)
@@ -833,6 +961,7 @@ pub fn create_ray_tracing_pipeline(
                     assert!(
                         prev_stage == Some(ShaderPipelineStage::RayMiss)
                             || prev_stage == Some(ShaderPipelineStage::RayClosestHit)
+                            || prev_stage == Some(ShaderPipelineStage::RayAnyHit)
                     );
                     hit_entry_count += 1;
 
@@ -858,6 +987,44 @@ pub fn create_ray_tracing_pipeline(
                     shader_stages.push(stage);
                     shader_groups.push(group);
                 }
+                ShaderPipelineStage::RayAnyHit => {
+                    assert!(
+                        prev_stage == Some(ShaderPipelineStage::RayMiss)
+                            || prev_stage == Some(ShaderPipelineStage::RayClosestHit)
+                            || prev_stage == Some(ShaderPipelineStage::RayAnyHit)
+                    );
+
+                    let (module, entry_point) = create_shader_module(desc);
+
+                    entry_points.push(std::ffi::CString::new(entry_point).unwrap());
+                    let entry_point = &**entry_points.last().unwrap();
+
+                    let stage = ash::vk::PipelineShaderStageCreateInfo::builder()
+                        .stage(ash::vk::ShaderStageFlags::ANY_HIT_KHR)
+                        .module(module)
+                        .name(entry_point)
+                        .build();
+
+                    // An any-hit shader shares the hit group of the closest-hit shader
+                    // right before it; otherwise it's in a hit group of its own.
+                    if prev_stage == Some(ShaderPipelineStage::RayClosestHit) {
+                        shader_groups.last_mut().unwrap().any_hit_shader = group_idx as _;
+                    } else {
+                        hit_entry_count += 1;
+
+                        let group = ash::vk::RayTracingShaderGroupCreateInfoKHR::builder()
+                            .ty(ash::vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
+                            .general_shader(ash::vk::SHADER_UNUSED_KHR)
+                            .closest_hit_shader(ash::vk::SHADER_UNUSED_KHR)
+                            .any_hit_shader(group_idx as _)
+                            .intersection_shader(ash::vk::SHADER_UNUSED_KHR)
+                            .build();
+
+                        shader_groups.push(group);
+                    }
+
+                    shader_stages.push(stage);
+                }
                 _ => unimplemented!(),
             }
 
@@ -871,7 +1038,7 @@ pub fn create_ray_tracing_pipeline(
             .ray_tracing_pipeline_ext
             .create_ray_tracing_pipelines(
                 vk::DeferredOperationKHR::null(),
-                vk::PipelineCache::null(),
+                device.pipeline_cache,
                 &[ash::vk::RayTracingPipelineCreateInfoKHR::builder()
                     .stages(&shader_stages)
                     .groups(&shader_groups)
@@ -917,6 +1084,7 @@ pub fn create_ray_tracing_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::RAY_TRACING_KHR,
+                interface,
             },
             sbt,
         })
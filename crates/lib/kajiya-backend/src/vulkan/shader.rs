@@ -4,7 +4,10 @@ use super::{
     device::{Device, SamplerDesc},
     image::ImageDesc,
 };
-use crate::{chunky_list::TempList, shader_compiler::get_cs_local_size_from_spirv};
+use crate::{
+    chunky_list::TempList, shader_compiler::get_cs_local_size_from_spirv,
+    shader_reflection::ShaderInterface,
+};
 use arrayvec::ArrayVec;
 use ash::vk;
 use byte_slice_cast::AsSliceOf as _;
@@ -30,6 +33,7 @@ pub struct ShaderPipelineCommon {
     pub descriptor_pool_sizes: Vec<vk::DescriptorPoolSize>,
     pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
     pub pipeline_bind_point: vk::PipelineBindPoint,
+    pub interface: ShaderInterface,
 }
 pub struct ComputePipeline {
     pub common: ShaderPipelineCommon,
@@ -320,6 +324,7 @@ impl DescriptorSetLayoutOpts {
     }
 }
 
+/// Despite the name, `Hlsl` is also GLSL, by the file extension; see `CompileShader`
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
 pub enum ShaderSource {
     Rust { entry: String },
@@ -345,6 +350,39 @@ impl ShaderSource {
     }
 }
 
+impl std::fmt::Display for ShaderSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShaderSource::Rust { entry } => write!(f, "{}", entry),
+            ShaderSource::Hlsl { path } => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A preprocessor `#define` the shader is compiled with. Ignored by Rust shaders.
+#[derive(Clone, Hash, PartialEq, Eq, Debug)]
+pub struct ShaderDefine {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl ShaderDefine {
+    pub fn new(name: impl Into<String>, value: impl ToString) -> Self {
+        Self {
+            name: name.into(),
+            value: Some(value.to_string()),
+        }
+    }
+
+    /// `#define name` without a value
+    pub fn flag(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: None,
+        }
+    }
+}
+
 #[derive(Builder, Clone)]
 #[builder(pattern = "owned", derive(Clone))]
 pub struct ComputePipelineDesc {
@@ -353,6 +391,8 @@ pub struct ComputePipelineDesc {
     #[builder(default)]
     pub push_constants_bytes: usize,
     pub source: ShaderSource,
+    #[builder(default)]
+    pub defines: Vec<ShaderDefine>,
 }
 
 impl ComputePipelineDescBuilder {
@@ -388,17 +428,27 @@ pub fn create_compute_pipeline(
     device: &Device,
     spirv: &[u8],
     desc: &ComputePipelineDesc,
-) -> ComputePipeline {
+) -> anyhow::Result<ComputePipeline> {
+    let reflected_sets = rspirv_reflect::Reflection::new_from_spirv(spirv)
+        .and_then(|reflection| reflection.get_descriptor_sets())
+        .map_err(|err| anyhow::anyhow!("Failed to reflect SPIR-V: {:?}", err))?;
+
+    let spirv_words = spirv.as_slice_of::<u32>()?;
+    let group_size = get_cs_local_size_from_spirv(spirv_words)?;
+
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
-        &rspirv_reflect::Reflection::new_from_spirv(spirv)
-            .unwrap()
-            .get_descriptor_sets()
-            .unwrap(),
+        &reflected_sets,
         vk::ShaderStageFlags::COMPUTE,
         &desc.descriptor_set_opts,
     );
 
+    let interface = ShaderInterface::new(
+        vec![desc.source.to_string()],
+        &reflected_sets,
+        &set_layout_info,
+    );
+
     // dbg!(&set_layout_info);
 
     let mut layout_create_info =
@@ -416,15 +466,12 @@ pub fn create_compute_pipeline(
     }
 
     unsafe {
-        let shader_module = device
-            .raw
-            .create_shader_module(
-                &vk::ShaderModuleCreateInfo::builder().code(spirv.as_slice_of::<u32>().unwrap()),
-                None,
-            )
-            .unwrap();
+        let shader_module = device.raw.create_shader_module(
+            &vk::ShaderModuleCreateInfo::builder().code(spirv_words),
+            None,
+        )?;
 
-        let entry_name = CString::new(desc.source.entry()).unwrap();
+        let entry_name = CString::new(desc.source.entry())?;
         let stage_create_info = vk::PipelineShaderStageCreateInfo::builder()
             .module(shader_module)
             .stage(vk::ShaderStageFlags::COMPUTE)
@@ -432,8 +479,7 @@ pub fn create_compute_pipeline(
 
         let pipeline_layout = device
             .raw
-            .create_pipeline_layout(&layout_create_info, None)
-            .unwrap();
+            .create_pipeline_layout(&layout_create_info, None)?;
 
         let pipeline_info = vk::ComputePipelineCreateInfo::builder()
             .stage(stage_create_info.build())
@@ -441,9 +487,8 @@ pub fn create_compute_pipeline(
 
         let pipeline = device
             .raw
-            // TODO: pipeline cache
-            .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info.build()], None)
-            .expect("pipeline")[0];
+            .create_compute_pipelines(device.pipeline_cache, &[pipeline_info.build()], None)
+            .map_err(|(_, err)| err)?[0];
 
         let mut descriptor_pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
         for bindings in set_layout_info.iter() {
@@ -460,7 +505,7 @@ pub fn create_compute_pipeline(
             }
         }
 
-        ComputePipeline {
+        Ok(ComputePipeline {
             common: ShaderPipelineCommon {
                 pipeline_layout,
                 pipeline,
@@ -468,9 +513,10 @@ pub fn create_compute_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::COMPUTE,
+                interface,
             },
-            group_size: get_cs_local_size_from_spirv(spirv.as_slice_of::<u32>().unwrap()).unwrap(),
-        }
+            group_size,
+        })
     }
 }
 
@@ -481,6 +527,7 @@ pub enum ShaderPipelineStage {
     RayGen,
     RayMiss,
     RayClosestHit,
+    RayAnyHit,
 }
 
 #[derive(Builder, Hash, PartialEq, Eq, Clone, Debug)]
@@ -494,6 +541,8 @@ pub struct PipelineShaderDesc {
     #[builder(default = "\"main\".to_owned()")]
     pub entry: String,
     pub source: ShaderSource,
+    #[builder(default)]
+    pub defines: Vec<ShaderDefine>,
 }
 
 impl PipelineShaderDesc {
@@ -835,14 +884,25 @@ pub fn create_raster_pipeline(
         })
         .collect::<Vec<_>>();
 
+    let reflected_sets = merge_shader_stage_layouts(stage_layouts);
+
     let (descriptor_set_layouts, set_layout_info) = super::shader::create_descriptor_set_layouts(
         device,
-        &merge_shader_stage_layouts(stage_layouts),
+        &reflected_sets,
         vk::ShaderStageFlags::ALL_GRAPHICS,
         //desc.descriptor_set_layout_flags.unwrap_or(&[]),  // TODO: merge flags
         &desc.descriptor_set_opts,
     );
 
+    let interface = ShaderInterface::new(
+        shaders
+            .iter()
+            .map(|shader| shader.desc.source.to_string())
+            .collect(),
+        &reflected_sets,
+        &set_layout_info,
+    );
+
     unsafe {
         let mut layout_create_info =
             vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_set_layouts);
@@ -975,7 +1035,7 @@ pub fn create_raster_pipeline(
         let pipeline = device
             .raw
             .create_graphics_pipelines(
-                vk::PipelineCache::null(),
+                device.pipeline_cache,
                 &[graphic_pipeline_info.build()],
                 None,
             )
@@ -1005,6 +1065,7 @@ pub fn create_raster_pipeline(
                 descriptor_pool_sizes,
                 descriptor_set_layouts,
                 pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+                interface,
             },
         })
     }
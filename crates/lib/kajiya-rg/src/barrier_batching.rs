@@ -0,0 +1,590 @@
+//! Planning of the barriers a pass needs before it runs.
+//!
+//! The executor gathers every transition of a pass up front, and this module turns them into
+//! a list of barriers which can be recorded with a single `vkCmdPipelineBarrier`. Transitions
+//! which wouldn't do anything are dropped, and ones touching the same resource are merged.
+//!
+//! Everything here works on access types and subresource ranges only, without a device.
+
+use kajiya_backend::{ash::vk, vk_sync::AccessType, vulkan::barrier::get_access_info};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct SubresourceRange {
+    pub base_mip_level: u32,
+    pub level_count: u32,
+    pub base_array_layer: u32,
+    pub layer_count: u32,
+}
+
+impl SubresourceRange {
+    pub fn full(level_count: u32, layer_count: u32) -> Self {
+        Self {
+            base_mip_level: 0,
+            level_count,
+            base_array_layer: 0,
+            layer_count,
+        }
+    }
+
+    /// Union of two ranges, if it can be expressed as a single range.
+    fn union(&self, other: &Self) -> Option<Self> {
+        fn span_union(base0: u32, count0: u32, base1: u32, count1: u32) -> Option<(u32, u32)> {
+            let (end0, end1) = (base0 + count0, base1 + count1);
+            if base0 <= end1 && base1 <= end0 {
+                let base = base0.min(base1);
+                Some((base, end0.max(end1) - base))
+            } else {
+                None
+            }
+        }
+
+        if self.base_array_layer == other.base_array_layer && self.layer_count == other.layer_count
+        {
+            let (base_mip_level, level_count) = span_union(
+                self.base_mip_level,
+                self.level_count,
+                other.base_mip_level,
+                other.level_count,
+            )?;
+
+            Some(Self {
+                base_mip_level,
+                level_count,
+                ..*self
+            })
+        } else if self.base_mip_level == other.base_mip_level
+            && self.level_count == other.level_count
+        {
+            let (base_array_layer, layer_count) = span_union(
+                self.base_array_layer,
+                self.layer_count,
+                other.base_array_layer,
+                other.layer_count,
+            )?;
+
+            Some(Self {
+                base_array_layer,
+                layer_count,
+                ..*self
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum BarrierTarget {
+    Image {
+        aspect_mask: vk::ImageAspectFlags,
+        range: SubresourceRange,
+    },
+    Buffer,
+
+    // Access types are tracked, but no barriers are recorded (acceleration structures).
+    Untracked,
+}
+
+/// A resource access requested by a pass, along with what the resource was last used as.
+pub(crate) struct ResourceTransition {
+    pub resource: usize,
+    pub target: BarrierTarget,
+
+    /// More than one for images taking over memory from several aliased predecessors.
+    pub previous_accesses: Vec<AccessType>,
+    pub next_access: AccessType,
+
+    /// Don't synchronize if the resource is already in `next_access`.
+    pub skip_if_same: bool,
+    pub discard: bool,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct PlannedBarrier {
+    pub resource: usize,
+    pub target: BarrierTarget,
+    pub previous_accesses: Vec<AccessType>,
+    pub next_accesses: Vec<AccessType>,
+    pub discard: bool,
+}
+
+impl PlannedBarrier {
+    fn new(transition: &ResourceTransition) -> Self {
+        Self {
+            resource: transition.resource,
+            target: transition.target,
+            previous_accesses: transition.previous_accesses.clone(),
+            next_accesses: vec![transition.next_access],
+            discard: transition.discard,
+        }
+    }
+
+    /// Folds `other` into `self` if a single barrier can do the job of both.
+    fn try_merge(&mut self, other: &PlannedBarrier) -> bool {
+        if self.resource != other.resource
+            || self.discard != other.discard
+            || self.previous_accesses != other.previous_accesses
+        {
+            return false;
+        }
+
+        match (&mut self.target, &other.target) {
+            (BarrierTarget::Buffer, BarrierTarget::Buffer) => {
+                self.add_next_accesses(&other.next_accesses);
+                true
+            }
+            (
+                BarrierTarget::Image { aspect_mask, range },
+                BarrierTarget::Image {
+                    aspect_mask: other_aspect_mask,
+                    range: other_range,
+                },
+            ) if aspect_mask == other_aspect_mask => {
+                if range == other_range {
+                    self.add_next_accesses(&other.next_accesses);
+                    true
+                } else if self.next_accesses == other.next_accesses {
+                    if let Some(union) = range.union(other_range) {
+                        *range = union;
+                        true
+                    } else {
+                        false
+                    }
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn add_next_accesses(&mut self, next_accesses: &[AccessType]) {
+        // A subresource can only be in one layout. If the accesses disagree, the later one wins,
+        // which is also where back-to-back transitions would have left the resource.
+        let layout = |access: AccessType| get_access_info(access).image_layout;
+        if layout(self.next_accesses[0]) != layout(next_accesses[0]) {
+            self.next_accesses.clear();
+        }
+
+        for &access in next_accesses {
+            if !self.next_accesses.contains(&access) {
+                self.next_accesses.push(access);
+            }
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub(crate) struct PassBarrierPlan {
+    pub barriers: Vec<PlannedBarrier>,
+
+    /// Access type each synchronized resource is left in, in order of first appearance.
+    pub final_accesses: Vec<(usize, AccessType)>,
+
+    /// Transitions which don't need a barrier at all
+    pub elided_count: usize,
+
+    /// Transitions folded into a barrier of another transition of the same resource
+    pub merged_count: usize,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct BarrierStats {
+    pub batch_count: usize,
+    pub barrier_count: usize,
+    pub elided_count: usize,
+    pub merged_count: usize,
+}
+
+impl BarrierStats {
+    pub fn add_plan(&mut self, plan: &PassBarrierPlan) {
+        if !plan.barriers.is_empty() {
+            self.batch_count += 1;
+        }
+        self.barrier_count += plan.barriers.len();
+        self.elided_count += plan.elided_count;
+        self.merged_count += plan.merged_count;
+    }
+}
+
+fn is_read_only(access: AccessType) -> bool {
+    let write_mask = vk::AccessFlags::SHADER_WRITE
+        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
+        | vk::AccessFlags::TRANSFER_WRITE
+        | vk::AccessFlags::HOST_WRITE
+        | vk::AccessFlags::MEMORY_WRITE
+        | vk::AccessFlags::COMMAND_PREPROCESS_WRITE_NV
+        | vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR;
+
+    let access_mask = get_access_info(access).access_mask;
+    !access_mask.is_empty() && !access_mask.intersects(write_mask)
+}
+
+/// A read which keeps the layout needs no barrier, as long as the barrier which moved
+/// the resource into `previous` already made it visible to the stages of `next`.
+///
+/// The resource then stays in `previous`, so that later writes still wait for these reads.
+fn is_covered_read(previous: AccessType, next: AccessType) -> bool {
+    if !is_read_only(previous) || !is_read_only(next) {
+        return false;
+    }
+
+    let previous = get_access_info(previous);
+    let next = get_access_info(next);
+
+    previous.image_layout == next.image_layout
+        && (previous
+            .stage_mask
+            .contains(vk::PipelineStageFlags::ALL_COMMANDS)
+            || previous.stage_mask.contains(next.stage_mask))
+        && previous.access_mask.contains(next.access_mask)
+}
+
+impl ResourceTransition {
+    fn is_redundant(&self) -> bool {
+        if self.discard {
+            return false;
+        }
+
+        let previous = match self.previous_accesses.as_slice() {
+            [previous] => *previous,
+            _ => return false,
+        };
+
+        (self.skip_if_same && previous == self.next_access)
+            || is_covered_read(previous, self.next_access)
+    }
+}
+
+/// Turns the transitions of one pass into barriers which can be recorded in one go.
+///
+/// With `allow_elision` unset, every transition gets a barrier, although they're still merged.
+pub(crate) fn plan_pass_barriers(
+    transitions: &[ResourceTransition],
+    allow_elision: bool,
+) -> PassBarrierPlan {
+    let mut plan = PassBarrierPlan::default();
+
+    for transition in transitions {
+        if allow_elision && transition.is_redundant() {
+            plan.elided_count += 1;
+            continue;
+        }
+
+        match plan
+            .final_accesses
+            .iter_mut()
+            .find(|(resource, _)| *resource == transition.resource)
+        {
+            Some((_, access)) => *access = transition.next_access,
+            None => plan
+                .final_accesses
+                .push((transition.resource, transition.next_access)),
+        }
+
+        if transition.target == BarrierTarget::Untracked {
+            continue;
+        }
+
+        let barrier = PlannedBarrier::new(transition);
+        if plan
+            .barriers
+            .iter_mut()
+            .any(|existing| existing.try_merge(&barrier))
+        {
+            plan.merged_count += 1;
+        } else {
+            plan.barriers.push(barrier);
+        }
+    }
+
+    // Growing a range can make it adjacent to one which wasn't before.
+    'coalesce: loop {
+        for i in 0..plan.barriers.len() {
+            for j in i + 1..plan.barriers.len() {
+                let other = plan.barriers[j].clone();
+                if plan.barriers[i].try_merge(&other) {
+                    plan.barriers.remove(j);
+                    plan.merged_count += 1;
+                    continue 'coalesce;
+                }
+            }
+        }
+
+        break;
+    }
+
+    plan
+}
+
+#[cfg(test)]
+fn test_transition(
+    resource: usize,
+    range: SubresourceRange,
+    previous: AccessType,
+    next: AccessType,
+) -> ResourceTransition {
+    ResourceTransition {
+        resource,
+        target: BarrierTarget::Image {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            range,
+        },
+        previous_accesses: vec![previous],
+        next_access: next,
+        skip_if_same: true,
+        discard: false,
+    }
+}
+
+#[test]
+fn test_elide_redundant_transitions() {
+    let full = SubresourceRange::full(1, 1);
+
+    let plan = plan_pass_barriers(
+        &[
+            // Same access type as before
+            test_transition(
+                0,
+                full,
+                AccessType::ComputeShaderWrite,
+                AccessType::ComputeShaderWrite,
+            ),
+            // Read in a stage the previous read already covers
+            test_transition(
+                1,
+                full,
+                AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+            ),
+            // Read in a stage the previous read didn't make the resource visible to
+            test_transition(
+                2,
+                full,
+                AccessType::FragmentShaderReadSampledImageOrUniformTexelBuffer,
+                AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+            ),
+            // Read in another layout
+            test_transition(
+                3,
+                full,
+                AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                AccessType::ComputeShaderReadOther,
+            ),
+            // Write after read
+            test_transition(
+                4,
+                full,
+                AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+                AccessType::ComputeShaderWrite,
+            ),
+        ],
+        true,
+    );
+
+    assert_eq!(plan.elided_count, 2);
+    assert_eq!(
+        plan.barriers
+            .iter()
+            .map(|barrier| barrier.resource)
+            .collect::<Vec<_>>(),
+        vec![2, 3, 4]
+    );
+    assert_eq!(
+        plan.final_accesses,
+        vec![
+            (
+                2,
+                AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer
+            ),
+            (3, AccessType::ComputeShaderReadOther),
+            (4, AccessType::ComputeShaderWrite),
+        ]
+    );
+
+    let plan = plan_pass_barriers(
+        &[test_transition(
+            0,
+            full,
+            AccessType::ComputeShaderWrite,
+            AccessType::ComputeShaderWrite,
+        )],
+        false,
+    );
+    assert_eq!(plan.elided_count, 0);
+    assert_eq!(plan.barriers.len(), 1);
+}
+
+#[test]
+fn test_coalesce_subresource_ranges() {
+    let mip = |level: u32| SubresourceRange {
+        base_mip_level: level,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 6,
+    };
+    let layer = |layer: u32| SubresourceRange {
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: layer,
+        layer_count: 1,
+    };
+
+    let read = AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer;
+    let write = AccessType::ComputeShaderWrite;
+
+    // Mips out of order, with a gap which only the last one fills
+    let plan = plan_pass_barriers(
+        &[
+            test_transition(0, mip(0), write, read),
+            test_transition(0, mip(2), write, read),
+            test_transition(0, mip(1), write, read),
+            test_transition(1, layer(1), write, read),
+            test_transition(1, layer(0), write, read),
+            // Different access, so it can't share the barrier of the other layers
+            test_transition(1, layer(2), write, AccessType::TransferRead),
+        ],
+        true,
+    );
+
+    assert_eq!(plan.merged_count, 3);
+    assert_eq!(
+        plan.barriers
+            .iter()
+            .map(|barrier| barrier.target)
+            .collect::<Vec<_>>(),
+        vec![
+            BarrierTarget::Image {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                range: SubresourceRange {
+                    base_mip_level: 0,
+                    level_count: 3,
+                    base_array_layer: 0,
+                    layer_count: 6,
+                },
+            },
+            BarrierTarget::Image {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                range: SubresourceRange {
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 2,
+                },
+            },
+            BarrierTarget::Image {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                range: layer(2),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_keep_disjoint_subresource_ranges_apart() {
+    let range = |base_mip_level: u32, base_array_layer: u32| SubresourceRange {
+        base_mip_level,
+        level_count: 1,
+        base_array_layer,
+        layer_count: 1,
+    };
+
+    let read = AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer;
+    let write = AccessType::ComputeShaderWrite;
+
+    let plan = plan_pass_barriers(
+        &[
+            // A gap between mips 0 and 2
+            test_transition(0, range(0, 0), write, read),
+            test_transition(0, range(2, 0), write, read),
+            // Differs in both mip and layer, so the union isn't a single range
+            test_transition(1, range(0, 0), write, read),
+            test_transition(1, range(1, 1), write, read),
+            // Adjacent, but of another resource
+            test_transition(2, range(1, 0), write, read),
+        ],
+        true,
+    );
+
+    assert_eq!(plan.merged_count, 0);
+    assert_eq!(
+        plan.barriers
+            .iter()
+            .map(|barrier| (barrier.resource, barrier.target))
+            .collect::<Vec<_>>(),
+        [
+            (0, range(0, 0)),
+            (0, range(2, 0)),
+            (1, range(0, 0)),
+            (1, range(1, 1)),
+            (2, range(1, 0))
+        ]
+        .iter()
+        .map(|&(resource, range)| (
+            resource,
+            BarrierTarget::Image {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                range,
+            }
+        ))
+        .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_merge_accesses_of_same_resource() {
+    let full = SubresourceRange::full(4, 1);
+
+    let plan = plan_pass_barriers(
+        &[
+            test_transition(
+                0,
+                full,
+                AccessType::ColorAttachmentWrite,
+                AccessType::ComputeShaderReadOther,
+            ),
+            test_transition(
+                0,
+                full,
+                AccessType::ColorAttachmentWrite,
+                AccessType::ComputeShaderWrite,
+            ),
+            // Layout conflicts with the ones above; the last access wins
+            test_transition(
+                1,
+                full,
+                AccessType::ColorAttachmentWrite,
+                AccessType::ComputeShaderReadOther,
+            ),
+            test_transition(
+                1,
+                full,
+                AccessType::ColorAttachmentWrite,
+                AccessType::TransferWrite,
+            ),
+        ],
+        true,
+    );
+
+    assert_eq!(plan.merged_count, 2);
+    assert_eq!(plan.barriers.len(), 2);
+    assert_eq!(
+        plan.barriers[0].next_accesses,
+        vec![
+            AccessType::ComputeShaderReadOther,
+            AccessType::ComputeShaderWrite
+        ]
+    );
+    assert_eq!(
+        plan.barriers[1].next_accesses,
+        vec![AccessType::TransferWrite]
+    );
+    assert_eq!(
+        plan.final_accesses,
+        vec![
+            (0, AccessType::ComputeShaderWrite),
+            (1, AccessType::TransferWrite)
+        ]
+    );
+}
@@ -1,6 +1,16 @@
 #![allow(unused_imports)]
 
-use crate::{renderer::FrameConstantsLayout, resource_registry::PendingRenderResourceInfo};
+use crate::{
+    barrier_batching::{
+        plan_pass_barriers, BarrierStats, BarrierTarget, ResourceTransition, SubresourceRange,
+    },
+    queue_schedule::{
+        plan_queue_submissions, OwnershipTransfer, QueueAffinity, QueueSchedule, SchedulePass,
+    },
+    renderer::FrameConstantsLayout,
+    resource_aliasing::{pack_aliased_resources, AliasableResource, AliasedPlacement},
+    resource_registry::PendingRenderResourceInfo,
+};
 
 use super::{
     pass_builder::PassBuilder,
@@ -21,14 +31,11 @@ use kajiya_backend::{
         ComputePipelineHandle, PipelineCache, RasterPipelineHandle, RtPipelineHandle,
     },
     rspirv_reflect,
-    transient_resource_cache::TransientResourceCache,
+    transient_resource_cache::{TransientMemoryBlock, TransientResourceCache},
     vk_sync,
     vulkan::{
-        barrier::{
-            get_access_info, image_aspect_mask_from_access_type_and_format, record_image_barrier,
-            ImageBarrier,
-        },
-        device::{CommandBuffer, Device, VkProfilerData},
+        barrier::{get_access_info, image_aspect_mask_from_access_type_and_format},
+        device::{CommandBuffer, Device, DeviceFrame, Queue, VkProfilerData},
         image::ImageViewDesc,
         ray_tracing::{RayTracingAcceleration, RayTracingPipelineDesc},
         shader::{ComputePipelineDesc, PipelineShader, PipelineShaderDesc, RasterPipelineDesc},
@@ -37,7 +44,7 @@ use kajiya_backend::{
 };
 use parking_lot::Mutex;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     ffi::CString,
     hash::Hash,
     marker::PhantomData,
@@ -79,7 +86,7 @@ pub(crate) enum ExportableGraphResource {
 }
 
 impl ExportableGraphResource {
-    fn raw(&self) -> GraphRawResourceHandle {
+    pub(crate) fn raw(&self) -> GraphRawResourceHandle {
         match self {
             ExportableGraphResource::Image(h) => h.raw,
             ExportableGraphResource::Buffer(h) => h.raw,
@@ -132,9 +139,10 @@ pub struct GraphDebugHook {
 }
 
 pub struct RenderGraph {
-    passes: Vec<RecordedPass>,
-    resources: Vec<GraphResourceInfo>,
-    exported_resources: Vec<(ExportableGraphResource, vk_sync::AccessType)>,
+    pub(crate) passes: Vec<RecordedPass>,
+    pub(crate) resources: Vec<GraphResourceInfo>,
+    pub(crate) exported_resources: Vec<(ExportableGraphResource, vk_sync::AccessType)>,
+    pub(crate) temporal_resource_keys: HashMap<u32, String>,
     pub(crate) compute_pipelines: Vec<RgComputePipeline>,
     pub(crate) raster_pipelines: Vec<RgRasterPipeline>,
     pub(crate) rt_pipelines: Vec<RgRtPipeline>,
@@ -305,6 +313,7 @@ impl RenderGraph {
             passes: Vec::new(),
             resources: Vec::new(),
             exported_resources: Vec::new(),
+            temporal_resource_keys: HashMap::new(),
             compute_pipelines: Vec::new(),
             raster_pipelines: Vec::new(),
             rt_pipelines: Vec::new(),
@@ -381,15 +390,15 @@ impl RenderGraph {
 }
 
 #[derive(Debug)]
-struct ResourceLifetime {
-    //first_access: Option<usize>,
-    last_access: Option<usize>,
+pub(crate) struct ResourceLifetime {
+    pub first_access: Option<usize>,
+    pub last_access: Option<usize>,
 }
 
-struct ResourceInfo {
-    _lifetimes: Vec<ResourceLifetime>,
-    image_usage_flags: Vec<vk::ImageUsageFlags>,
-    buffer_usage_flags: Vec<vk::BufferUsageFlags>,
+pub(crate) struct ResourceInfo {
+    pub lifetimes: Vec<ResourceLifetime>,
+    pub image_usage_flags: Vec<vk::ImageUsageFlags>,
+    pub buffer_usage_flags: Vec<vk::BufferUsageFlags>,
 }
 
 pub struct RenderGraphExecutionParams<'a> {
@@ -407,9 +416,10 @@ pub struct RenderGraphPipelines {
 }
 
 pub struct CompiledRenderGraph {
-    rg: RenderGraph,
-    resource_info: ResourceInfo,
+    pub(crate) rg: RenderGraph,
+    pub(crate) resource_info: ResourceInfo,
     pipelines: RenderGraphPipelines,
+    stats: RenderGraphStats,
 }
 
 struct PendingDebugPass {
@@ -433,11 +443,11 @@ impl RenderGraph {
             .iter()
             .map(|res| match res {
                 GraphResourceInfo::Created(_) => ResourceLifetime {
-                    //first_access: None,
+                    first_access: None,
                     last_access: None,
                 },
                 GraphResourceInfo::Imported(_) => ResourceLifetime {
-                    //first_access: Some(0),
+                    first_access: Some(0),
                     last_access: Some(0),
                 },
             })
@@ -468,9 +478,14 @@ impl RenderGraph {
         }
 
         for (pass_idx, pass) in self.passes.iter().enumerate() {
+            if pass.culled {
+                continue;
+            }
+
             for res_access in pass.read.iter().chain(pass.write.iter()) {
                 let resource_index = res_access.handle.id as usize;
                 let res = &mut lifetimes[resource_index];
+                res.first_access.get_or_insert(pass_idx);
                 res.last_access = Some(
                     res.last_access
                         .map(|last_access| last_access.max(pass_idx))
@@ -541,24 +556,149 @@ impl RenderGraph {
         }
 
         ResourceInfo {
-            _lifetimes: lifetimes,
+            lifetimes,
             image_usage_flags,
             buffer_usage_flags,
         }
     }
 
-    pub fn compile(self, pipeline_cache: &mut PipelineCache) -> CompiledRenderGraph {
+    /// Finds passes which contribute to something observable outside of the graph: exported
+    /// resources, imported resources (including temporal ones and the swapchain), or anything
+    /// explicitly marked with `never_cull`. Walks the passes backwards, growing the set of
+    /// resources which live passes depend on.
+    fn find_live_passes(&self) -> Vec<bool> {
+        let mut live_resources: HashSet<u32> = self
+            .exported_resources
+            .iter()
+            .map(|(res, _)| res.raw().id)
+            .collect();
+
+        let mut live_passes = vec![false; self.passes.len()];
+
+        for (pass_idx, pass) in self.passes.iter().enumerate().rev() {
+            let is_live = pass.never_cull
+                || pass.write.iter().any(|res_ref| {
+                    live_resources.contains(&res_ref.handle.id)
+                        || matches!(
+                            self.resources[res_ref.handle.id as usize],
+                            GraphResourceInfo::Imported(_)
+                        )
+                });
+
+            if is_live {
+                live_passes[pass_idx] = true;
+
+                // Writes are included as well, since passes may only partially
+                // overwrite a resource, making them depend on its previous contents.
+                live_resources.extend(
+                    pass.read
+                        .iter()
+                        .chain(pass.write.iter())
+                        .map(|res_ref| res_ref.handle.id),
+                );
+            }
+        }
+
+        live_passes
+    }
+
+    /// Passes from this one onwards are recorded into the presentation command buffer.
+    fn first_presentation_pass(&self) -> usize {
+        self.passes
+            .iter()
+            .rposition(|pass| {
+                pass.write.iter().any(|res| {
+                    matches!(
+                        self.resources[res.handle.id as usize],
+                        GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage)
+                    )
+                })
+            })
+            .unwrap_or(self.passes.len())
+    }
+
+    /// Splits the passes before `first_presentation_pass` into per-queue submissions.
+    /// Everything is kept on the graphics queue unless `use_async_compute` is set.
+    fn plan_queue_submissions(
+        &self,
+        first_presentation_pass: usize,
+        use_async_compute: bool,
+    ) -> QueueSchedule {
+        let passes: Vec<SchedulePass> = self.passes[..first_presentation_pass]
+            .iter()
+            .map(|pass| {
+                if pass.culled {
+                    return SchedulePass {
+                        queue: QueueAffinity::Graphics,
+                        resources: Vec::new(),
+                    };
+                }
+
+                SchedulePass {
+                    queue: if use_async_compute {
+                        pass.queue
+                    } else {
+                        QueueAffinity::Graphics
+                    },
+                    resources: pass
+                        .read
+                        .iter()
+                        .chain(pass.write.iter())
+                        .map(|res_ref| res_ref.handle.id)
+                        .collect(),
+                }
+            })
+            .collect();
+
+        let initial_owners: Vec<Option<QueueAffinity>> = self
+            .resources
+            .iter()
+            .map(|res| match res {
+                GraphResourceInfo::Created(_) => None,
+                GraphResourceInfo::Imported(_) => Some(QueueAffinity::Graphics),
+            })
+            .collect();
+
+        // Imported and exported resources are handed back to the graphics queue, as are those
+        // used by the presentation passes.
+        let mut return_to_graphics: Vec<bool> =
+            initial_owners.iter().map(Option::is_some).collect();
+
+        for (res, _) in &self.exported_resources {
+            return_to_graphics[res.raw().id as usize] = true;
+        }
+
+        for pass in &self.passes[first_presentation_pass..] {
+            for res_ref in pass.read.iter().chain(pass.write.iter()) {
+                return_to_graphics[res_ref.handle.id as usize] = true;
+            }
+        }
+
+        plan_queue_submissions(&passes, &initial_owners, &return_to_graphics)
+    }
+
+    pub fn compile(mut self, pipeline_cache: &mut PipelineCache) -> CompiledRenderGraph {
+        let mut culled_pass_count = 0;
+        if unsafe { RG_CULL_PASSES } {
+            let live_passes = self.find_live_passes();
+
+            for (pass, is_live) in self.passes.iter_mut().zip(live_passes) {
+                if !is_live {
+                    pass.culled = true;
+                    culled_pass_count += 1;
+                }
+            }
+        }
+
         let resource_info = self.calculate_resource_info();
-        // TODO: alias resources
 
-        /* println!(
-            "Resources: {:#?}",
-            self.resources
-                .iter()
-                .map(|info| info.desc)
-                .zip(resource_lifetimes.iter())
-                .collect::<Vec<_>>()
-        ); */
+        if let Some(dir) = crate::graph_dump::take_dump_request() {
+            let dump = crate::graph_dump::RenderGraphDump::new(&self, &resource_info);
+            match dump.write_to_dir(&dir) {
+                Ok(()) => log::info!("Render graph dumped to {:?}", dir),
+                Err(err) => log::error!("Failed to dump the render graph: {:#}", err),
+            }
+        }
 
         let compute_pipelines = self
             .compute_pipelines
@@ -578,6 +718,12 @@ impl RenderGraph {
             .map(|pipeline| pipeline_cache.register_ray_tracing(&pipeline.shaders, &pipeline.desc))
             .collect::<Vec<_>>();
 
+        let stats = RenderGraphStats {
+            pass_count: self.passes.len(),
+            culled_pass_count,
+            ..Default::default()
+        };
+
         CompiledRenderGraph {
             rg: self,
             resource_info,
@@ -586,6 +732,7 @@ impl RenderGraph {
                 raster: raster_pipelines,
                 rt: rt_pipelines,
             },
+            stats,
         }
     }
 
@@ -713,6 +860,51 @@ impl CompiledRenderGraph {
         dynamic_constants: &'constants mut DynamicConstants,
     ) -> ExecutingRenderGraph<'exec_params, 'constants> {
         let device = params.device;
+
+        let mut stats = self.stats;
+
+        let first_presentation_pass = self.rg.first_presentation_pass();
+        let queue_schedule = self.rg.plan_queue_submissions(
+            first_presentation_pass,
+            unsafe { RG_ASYNC_COMPUTE } && device.async_compute_queue.is_some(),
+        );
+
+        let mut async_compute_resources: HashSet<u32> = HashSet::new();
+        for submission in &queue_schedule.submissions {
+            if submission.queue != QueueAffinity::AsyncCompute {
+                continue;
+            }
+
+            for &pass_idx in &submission.passes {
+                let pass = &self.rg.passes[pass_idx];
+                stats.async_compute_pass_count += 1;
+                async_compute_resources.extend(
+                    pass.read
+                        .iter()
+                        .chain(pass.write.iter())
+                        .map(|res_ref| res_ref.handle.id),
+                );
+            }
+        }
+        stats.queue_submission_count = queue_schedule.submissions.len();
+
+        let aliasing = self
+            .alias_transient_images(
+                device,
+                transient_resource_cache,
+                &async_compute_resources,
+                &mut stats,
+            )
+            .unwrap_or_else(|err| {
+                log::warn!("Transient images will not be aliased: {}", err);
+                TransientImageAliasing {
+                    placements: Default::default(),
+                    memory_blocks: Vec::new(),
+                    predecessors: vec![Vec::new(); self.rg.resources.len()],
+                }
+            });
+        *LAST_FRAME_STATS.lock() = stats;
+
         let resources: Vec<RegistryResource> = self
             .rg
             .resources
@@ -720,9 +912,34 @@ impl CompiledRenderGraph {
             .enumerate()
             .map(|(resource_idx, resource)| match resource {
                 GraphResourceInfo::Created(create_info) => match create_info.desc {
+                    _ if self.resource_info.lifetimes[resource_idx]
+                        .last_access
+                        .is_none() =>
+                    {
+                        RegistryResource {
+                            access_type: vk_sync::AccessType::Nothing,
+                            resource: AnyRenderResource::Unused,
+                        }
+                    }
                     GraphResourceDesc::Image(mut desc) => {
                         desc.usage = self.resource_info.image_usage_flags[resource_idx];
 
+                        if let Some(placement) = aliasing.placements.get(&resource_idx) {
+                            let block = &aliasing.memory_blocks[placement.block];
+                            let image = transient_resource_cache
+                                .get_placed_image(device, &desc, block, placement.offset)
+                                .unwrap();
+
+                            return RegistryResource {
+                                access_type: vk_sync::AccessType::Nothing,
+                                resource: AnyRenderResource::PlacedImage {
+                                    image,
+                                    memory: block.memory(),
+                                    offset: block.offset() + placement.offset,
+                                },
+                            };
+                        }
+
                         let image = transient_resource_cache
                             .get_image(&desc)
                             .unwrap_or_else(|| device.create_image(desc, vec![]).unwrap());
@@ -797,8 +1014,187 @@ impl CompiledRenderGraph {
             passes: self.rg.passes.into(),
             resources: self.rg.resources,
             exported_resources: self.rg.exported_resources,
+            alias_predecessors: aliasing.predecessors,
+            memory_blocks: aliasing.memory_blocks,
+            first_presentation_pass,
+            queue_schedule,
+            barrier_stats: Default::default(),
         }
     }
+
+    /// Packs transient images with non-overlapping lifetimes into shared memory blocks.
+    ///
+    /// Exported images outlive the graph, so they keep dedicated allocations. Images used
+    /// on the async compute queue are not aliased either, as the barriers which hand memory
+    /// over between aliases are only recorded on one queue.
+    ///
+    /// Buffers always get their own allocations. A `Buffer` owns its `gpu_allocator`
+    /// sub-allocation, through which CPU-visible buffers are also mapped, so it can't be bound
+    /// to a shared block without changing every buffer in the backend. Transient buffers are
+    /// small next to the render targets, which is where the savings are.
+    fn alias_transient_images(
+        &self,
+        device: &Device,
+        transient_resource_cache: &mut TransientResourceCache,
+        async_compute_resources: &HashSet<u32>,
+        stats: &mut RenderGraphStats,
+    ) -> Result<TransientImageAliasing, BackendError> {
+        let mut aliased_resource_indices: Vec<usize> = Vec::new();
+        let mut aliasable: Vec<AliasableResource> = Vec::new();
+
+        let exported: HashSet<u32> = self
+            .rg
+            .exported_resources
+            .iter()
+            .map(|(res, _)| res.raw().id)
+            .collect();
+
+        for (resource_idx, resource) in self.rg.resources.iter().enumerate() {
+            let desc = match resource {
+                GraphResourceInfo::Created(GraphResourceCreateInfo {
+                    desc: GraphResourceDesc::Image(desc),
+                }) => desc.usage(self.resource_info.image_usage_flags[resource_idx]),
+                _ => continue,
+            };
+
+            let lifetime = &self.resource_info.lifetimes[resource_idx];
+            let (first_pass, last_pass) = match (lifetime.first_access, lifetime.last_access) {
+                (Some(first), Some(last)) => (first, last),
+                _ => continue,
+            };
+
+            let requirements = transient_resource_cache
+                .image_memory_requirements(device, &desc)
+                .expect("image_memory_requirements");
+
+            stats.transient_image_count += 1;
+            stats.transient_image_bytes += requirements.size;
+
+            if !unsafe { RG_ALIAS_TRANSIENT_RESOURCES }
+                || exported.contains(&(resource_idx as u32))
+                || async_compute_resources.contains(&(resource_idx as u32))
+            {
+                continue;
+            }
+
+            aliased_resource_indices.push(resource_idx);
+            aliasable.push(AliasableResource {
+                size: requirements.size,
+                alignment: requirements.alignment,
+                memory_type_bits: requirements.memory_type_bits,
+                first_pass,
+                last_pass,
+            });
+        }
+
+        let layout = pack_aliased_resources(&aliasable);
+
+        let mut memory_blocks: Vec<TransientMemoryBlock> = Vec::with_capacity(layout.blocks.len());
+        for block in &layout.blocks {
+            let block = transient_resource_cache.get_memory_block(
+                device,
+                vk::MemoryRequirements {
+                    size: block.size,
+                    alignment: block.alignment,
+                    memory_type_bits: block.memory_type_bits,
+                },
+            );
+
+            match block {
+                Ok(block) => memory_blocks.push(block),
+                Err(err) => {
+                    // Back to the pool; nothing has been placed in them yet.
+                    for block in memory_blocks {
+                        transient_resource_cache.insert_memory_block(block);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        // Any resource placed over memory used earlier in the frame must wait for the previous
+        // occupants to be done with it before its first access.
+        let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); self.rg.resources.len()];
+        for (i, res) in aliasable.iter().enumerate() {
+            let place = layout.placements[i];
+
+            for (j, other) in aliasable.iter().enumerate() {
+                let other_place = layout.placements[j];
+
+                if other_place.block == place.block
+                    && other.last_pass < res.first_pass
+                    && other_place.offset < place.offset + res.size
+                    && place.offset < other_place.offset + other.size
+                {
+                    predecessors[aliased_resource_indices[i]].push(aliased_resource_indices[j]);
+                }
+            }
+        }
+
+        stats.aliased_image_count = aliasable.len();
+        stats.aliased_memory_block_count = layout.blocks.len();
+        stats.alias_bytes_saved = layout.bytes_saved();
+
+        Ok(TransientImageAliasing {
+            placements: aliased_resource_indices
+                .into_iter()
+                .zip(layout.placements)
+                .collect(),
+            memory_blocks,
+            predecessors,
+        })
+    }
+}
+
+struct TransientImageAliasing {
+    placements: HashMap<usize, AliasedPlacement>,
+    memory_blocks: Vec<TransientMemoryBlock>,
+    predecessors: Vec<Vec<usize>>,
+}
+
+/// Summary of the most recently executed render graph.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderGraphStats {
+    pub pass_count: usize,
+
+    /// Passes which didn't contribute to any exported or imported resource
+    pub culled_pass_count: usize,
+
+    pub transient_image_count: usize,
+    pub aliased_image_count: usize,
+    pub aliased_memory_block_count: usize,
+
+    /// Size of all transient images if each had its own allocation. Buffers aren't aliased,
+    /// so they aren't counted.
+    pub transient_image_bytes: u64,
+
+    /// Memory which aliasing saved compared to `transient_image_bytes`
+    pub alias_bytes_saved: u64,
+
+    pub async_compute_pass_count: usize,
+
+    /// Number of submissions the main part of the graph was split into
+    pub queue_submission_count: usize,
+
+    /// Pipeline barrier commands recorded; at most one per pass
+    pub barrier_batch_count: usize,
+
+    /// Image and buffer barriers across all batches
+    pub barrier_count: usize,
+
+    /// Transitions skipped because the resource was already in a compatible state
+    pub elided_barrier_count: usize,
+
+    /// Transitions folded into another barrier of the same resource
+    pub merged_barrier_count: usize,
+}
+
+lazy_static::lazy_static! {
+    static ref LAST_FRAME_STATS: Mutex<RenderGraphStats> = Default::default();
+}
+
+pub fn last_frame_stats() -> RenderGraphStats {
+    *LAST_FRAME_STATS.lock()
 }
 
 pub struct ExecutingRenderGraph<'exec_params, 'constants> {
@@ -806,67 +1202,277 @@ pub struct ExecutingRenderGraph<'exec_params, 'constants> {
     resources: Vec<GraphResourceInfo>,
     exported_resources: Vec<(ExportableGraphResource, vk_sync::AccessType)>,
     resource_registry: ResourceRegistry<'exec_params, 'constants>,
+    alias_predecessors: Vec<Vec<usize>>,
+    memory_blocks: Vec<TransientMemoryBlock>,
+    first_presentation_pass: usize,
+    queue_schedule: QueueSchedule,
+    barrier_stats: BarrierStats,
 }
 
 impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
+    /// Whether the main part of the graph needs to be recorded with `record_and_submit_main`,
+    /// as opposed to `record_main_cb`.
+    pub fn is_split_across_queues(&self) -> bool {
+        self.queue_schedule.is_split()
+    }
+
     pub fn record_main_cb(&mut self, cb: &CommandBuffer) {
-        let mut first_presentation_pass: usize = self.passes.len();
+        assert!(
+            !self.queue_schedule.is_split(),
+            "The graph uses multiple queues; use record_and_submit_main"
+        );
 
-        for (pass_idx, pass) in self.passes.iter().enumerate() {
-            for res in &pass.write {
-                let res = &self.resources[res.handle.id as usize];
-                if matches!(
-                    res,
-                    GraphResourceInfo::Imported(GraphResourceImportInfo::SwapchainImage)
-                ) {
-                    first_presentation_pass = pass_idx;
-                    break;
+        let first_presentation_pass = self.first_presentation_pass;
+        let mut passes: Vec<_> = std::mem::take(&mut self.passes).into();
+
+        self.record_initial_transitions(&mut passes[..first_presentation_pass], cb);
+
+        let device = self.resource_registry.execution_params.device;
+
+        for pass in passes.drain(..first_presentation_pass) {
+            Self::record_pass_cb(
+                pass,
+                &mut self.resource_registry,
+                &self.alias_predecessors,
+                &mut self.barrier_stats,
+                cb,
+                device.universal_queue.family.index,
+            );
+        }
+
+        self.passes = passes.into();
+    }
+
+    /// Records the main part of the graph into one command buffer per queue submission,
+    /// and submits them to the graphics and async compute queues.
+    ///
+    /// The first submission is recorded into `main_cb`, which must already be recording.
+    /// The last one signals `main_cb`'s fence, and waits for all the async compute work.
+    pub fn record_and_submit_main(&mut self, main_cb: &CommandBuffer, frame: &DeviceFrame) {
+        let device = self.resource_registry.execution_params.device;
+        let first_presentation_pass = self.first_presentation_pass;
+
+        let mut passes: Vec<_> = std::mem::take(&mut self.passes).into();
+        self.record_initial_transitions(&mut passes[..first_presentation_pass], main_cb);
+
+        let schedule = std::mem::take(&mut self.queue_schedule);
+        self.passes = passes.split_off(first_presentation_pass).into();
+        let mut passes: Vec<Option<RecordedPass>> = passes.into_iter().map(Some).collect();
+
+        let semaphores: Vec<Option<vk::Semaphore>> = schedule
+            .submissions
+            .iter()
+            .map(|submission| submission.signals.then(|| device.frame_semaphore(frame)))
+            .collect();
+
+        // Accesses of resources released on one queue, but not yet acquired on the other.
+        let mut pending_transfers: HashMap<u32, (vk_sync::AccessType, vk_sync::AccessType)> =
+            HashMap::new();
+
+        let last_submission_idx = schedule.submissions.len() - 1;
+        for (submission_idx, submission) in schedule.submissions.iter().enumerate() {
+            let queue = Self::queue(device, submission.queue);
+
+            let cb = if submission_idx == 0 {
+                main_cb.clone()
+            } else {
+                let cb = device.frame_command_buffer(frame, queue);
+                unsafe {
+                    device
+                        .raw
+                        .reset_command_buffer(cb.raw, vk::CommandBufferResetFlags::default())
+                        .unwrap();
+
+                    device
+                        .raw
+                        .begin_command_buffer(
+                            cb.raw,
+                            &vk::CommandBufferBeginInfo::builder()
+                                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+                        )
+                        .unwrap();
+                }
+                cb
+            };
+
+            for transfer in &submission.acquires {
+                if let Some((previous_access, next_access)) =
+                    pending_transfers.remove(&transfer.resource)
+                {
+                    Self::record_ownership_transfer(
+                        device,
+                        &cb,
+                        &mut self.resource_registry.resources[transfer.resource as usize],
+                        previous_access,
+                        next_access,
+                        transfer,
+                        OwnershipTransferHalf::Acquire,
+                    );
                 }
             }
+
+            for &pass_idx in &submission.passes {
+                Self::record_pass_cb(
+                    passes[pass_idx].take().unwrap(),
+                    &mut self.resource_registry,
+                    &self.alias_predecessors,
+                    &mut self.barrier_stats,
+                    &cb,
+                    queue.family.index,
+                );
+            }
+
+            for transfer in &submission.releases {
+                let resource = &mut self.resource_registry.resources[transfer.resource as usize];
+
+                // Nothing to preserve; the other queue can just start using it.
+                let previous_access = resource.access_type;
+                if previous_access == vk_sync::AccessType::Nothing {
+                    continue;
+                }
+
+                let next_access = match transfer.next_pass {
+                    Some(pass_idx) => {
+                        let pass = passes[pass_idx].as_ref().unwrap();
+                        pass.read
+                            .iter()
+                            .chain(pass.write.iter())
+                            .find(|res_ref| res_ref.handle.id == transfer.resource)
+                            .unwrap()
+                            .access
+                            .access_type
+                    }
+                    None => previous_access,
+                };
+
+                Self::record_ownership_transfer(
+                    device,
+                    &cb,
+                    resource,
+                    previous_access,
+                    next_access,
+                    transfer,
+                    OwnershipTransferHalf::Release,
+                );
+                pending_transfers.insert(transfer.resource, (previous_access, next_access));
+            }
+
+            unsafe {
+                device.raw.end_command_buffer(cb.raw).unwrap();
+
+                let wait_semaphores: Vec<vk::Semaphore> = submission
+                    .waits
+                    .iter()
+                    .map(|&idx| semaphores[idx].unwrap())
+                    .collect();
+                let wait_stages = vec![vk::PipelineStageFlags::ALL_COMMANDS; wait_semaphores.len()];
+                let signal_semaphores: Vec<vk::Semaphore> =
+                    semaphores[submission_idx].into_iter().collect();
+
+                let submit_info = [vk::SubmitInfo::builder()
+                    .wait_semaphores(&wait_semaphores)
+                    .wait_dst_stage_mask(&wait_stages)
+                    .signal_semaphores(&signal_semaphores)
+                    .command_buffers(std::slice::from_ref(&cb.raw))
+                    .build()];
+
+                let fence = if submission_idx == last_submission_idx {
+                    device
+                        .raw
+                        .reset_fences(std::slice::from_ref(&main_cb.submit_done_fence))
+                        .expect("reset_fences");
+                    main_cb.submit_done_fence
+                } else {
+                    vk::Fence::null()
+                };
+
+                device
+                    .raw
+                    .queue_submit(queue.raw, &submit_info, fence)
+                    .map_err(|err| device.report_error(err.into()))
+                    .expect("queue_submit failed");
+            }
         }
+    }
 
-        let mut passes: Vec<_> = std::mem::take(&mut self.passes).into();
+    fn queue(device: &Device, queue: QueueAffinity) -> &Queue {
+        match queue {
+            QueueAffinity::Graphics => &device.universal_queue,
+            QueueAffinity::AsyncCompute => device
+                .async_compute_queue
+                .as_ref()
+                .expect("async compute queue"),
+        }
+    }
+
+    fn record_initial_transitions(&mut self, passes: &mut [RecordedPass], cb: &CommandBuffer) {
+        let mut pass_queues = vec![QueueAffinity::Graphics; passes.len()];
+        for submission in &self.queue_schedule.submissions {
+            for &pass_idx in &submission.passes {
+                pass_queues[pass_idx] = submission.queue;
+            }
+        }
 
         // At the start, transition all resources to the access type they're first used with
         // While we don't have split barriers yet, this will remove some bubbles
         // which would otherwise occur with temporal resources.
-        {
-            let mut resource_first_access_states: HashMap<u32, &mut PassResourceAccessType> =
-                HashMap::with_capacity(self.resources.len());
-
-            for pass in &mut passes[0..first_presentation_pass] {
-                for resource_ref in pass.read.iter_mut().chain(pass.write.iter_mut()) {
-                    resource_first_access_states
-                        .entry(resource_ref.handle.id)
-                        .or_insert(&mut resource_ref.access);
-                }
+        let mut resource_first_access_states: HashMap<u32, Option<&mut PassResourceAccessType>> =
+            HashMap::with_capacity(self.resources.len());
+
+        for (pass, queue) in passes.iter_mut().zip(pass_queues) {
+            if pass.culled {
+                continue;
             }
 
-            let params = &self.resource_registry.execution_params;
-            for (resource_idx, access) in resource_first_access_states {
-                let resource = &mut self.resource_registry.resources[resource_idx as usize];
-                Self::transition_resource(
-                    params.device,
-                    cb,
-                    resource,
-                    PassResourceAccessType {
-                        access_type: access.access_type,
-                        sync_type: PassResourceAccessSyncType::SkipSyncIfSameAccessType,
-                    },
-                    false,
-                    "",
-                );
+            for resource_ref in pass.read.iter_mut().chain(pass.write.iter_mut()) {
+                // Resources placed over memory used by earlier passes can't be transitioned
+                // until those passes are done with it.
+                if !self.alias_predecessors[resource_ref.handle.id as usize].is_empty() {
+                    continue;
+                }
 
-                // Skip the sync when this pass is encountered later.
-                access.sync_type = PassResourceAccessSyncType::SkipSyncIfSameAccessType;
+                // Resources first used on the async compute queue are transitioned
+                // as part of the ownership transfer to it.
+                let resource_id = resource_ref.handle.id;
+                let access = (queue == QueueAffinity::Graphics).then_some(&mut resource_ref.access);
+
+                resource_first_access_states
+                    .entry(resource_id)
+                    .or_insert(access);
             }
         }
 
-        for pass in passes.drain(..first_presentation_pass) {
-            Self::record_pass_cb(pass, &mut self.resource_registry, cb);
+        let mut transitions = Vec::with_capacity(resource_first_access_states.len());
+        for (resource_idx, access) in resource_first_access_states {
+            let access = if let Some(access) = access {
+                access
+            } else {
+                continue;
+            };
+
+            transitions.push(Self::resource_transition(
+                &self.resource_registry.resources,
+                &self.alias_predecessors,
+                resource_idx as usize,
+                PassResourceAccessType {
+                    access_type: access.access_type,
+                    sync_type: PassResourceAccessSyncType::SkipSyncIfSameAccessType,
+                },
+            ));
+
+            // Skip the sync when this pass is encountered later.
+            access.sync_type = PassResourceAccessSyncType::SkipSyncIfSameAccessType;
         }
 
-        self.passes = passes.into();
+        let device = self.resource_registry.execution_params.device;
+        Self::record_transitions(
+            device,
+            cb,
+            device.universal_queue.family.index,
+            &mut self.resource_registry.resources,
+            &transitions,
+            &mut self.barrier_stats,
+        );
     }
 
     #[must_use]
@@ -875,26 +1481,33 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
         cb: &CommandBuffer,
         swapchain_image: Arc<Image>,
     ) -> RetiredRenderGraph {
-        let params = &self.resource_registry.execution_params;
-
         // Transition exported images to the requested access types
-        for (resource_idx, access_type) in self.exported_resources {
-            if access_type != vk_sync::AccessType::Nothing {
-                let resource =
-                    &mut self.resource_registry.resources[resource_idx.raw().id as usize];
-                Self::transition_resource(
-                    params.device,
-                    cb,
-                    resource,
+        let transitions: Vec<ResourceTransition> = self
+            .exported_resources
+            .iter()
+            .filter(|(_, access_type)| *access_type != vk_sync::AccessType::Nothing)
+            .map(|(resource, access_type)| {
+                Self::resource_transition(
+                    &self.resource_registry.resources,
+                    &self.alias_predecessors,
+                    resource.raw().id as usize,
                     PassResourceAccessType {
-                        access_type,
+                        access_type: *access_type,
                         sync_type: PassResourceAccessSyncType::AlwaysSync,
                     },
-                    false,
-                    "",
-                );
-            }
-        }
+                )
+            })
+            .collect();
+
+        let device = self.resource_registry.execution_params.device;
+        Self::record_transitions(
+            device,
+            cb,
+            device.universal_queue.family.index,
+            &mut self.resource_registry.resources,
+            &transitions,
+            &mut self.barrier_stats,
+        );
 
         for res in &mut self.resource_registry.resources {
             if let AnyRenderResource::Pending(pending) = &mut res.resource {
@@ -909,21 +1522,53 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
 
         let passes = self.passes;
         for pass in passes {
-            Self::record_pass_cb(pass, &mut self.resource_registry, cb);
+            Self::record_pass_cb(
+                pass,
+                &mut self.resource_registry,
+                &self.alias_predecessors,
+                &mut self.barrier_stats,
+                cb,
+                device.universal_queue.family.index,
+            );
+        }
+
+        {
+            let mut stats = LAST_FRAME_STATS.lock();
+            stats.barrier_batch_count = self.barrier_stats.batch_count;
+            stats.barrier_count = self.barrier_stats.barrier_count;
+            stats.elided_barrier_count = self.barrier_stats.elided_count;
+            stats.merged_barrier_count = self.barrier_stats.merged_count;
         }
 
         RetiredRenderGraph {
             resources: self.resource_registry.resources,
+            memory_blocks: self.memory_blocks,
         }
     }
 
     fn record_pass_cb(
         pass: RecordedPass,
         resource_registry: &mut ResourceRegistry,
+        alias_predecessors: &[Vec<usize>],
+        barrier_stats: &mut BarrierStats,
         cb: &CommandBuffer,
+        queue_family_index: u32,
     ) {
         let params = &resource_registry.execution_params;
 
+        // Culled passes still get an (empty) profiler scope, so that scope indices
+        // keep matching pass indices, which debug hooks rely on.
+        if pass.culled {
+            let query_id = kajiya_backend::gpu_profiler::profiler().create_scope(&pass.name);
+            let vk_scope = params
+                .profiler_data
+                .begin_scope(&params.device.raw, cb.raw, query_id);
+            params
+                .profiler_data
+                .end_scope(&params.device.raw, cb.raw, vk_scope);
+            return;
+        }
+
         // Record a crash marker just before this pass
         params
             .device
@@ -945,43 +1590,32 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
         };
 
         {
-            let params = &resource_registry.execution_params;
-
-            let mut transitions: Vec<(usize, PassResourceAccessType)> = Vec::new();
-            for resource_ref in pass.read.iter() {
-                transitions.push((
-                    resource_ref.handle.id as usize,
-                    resource_ref.access,
-                    //format!("read {i}"),
-                ));
-            }
-
-            for resource_ref in pass.write.iter() {
-                transitions.push((
-                    resource_ref.handle.id as usize,
-                    resource_ref.access,
-                    //format!("write {i}"),
-                ));
-            }
-
-            // TODO: optimize the barriers
-
-            for (resource_idx, access) in transitions {
-                let resource = &mut resource_registry.resources[resource_idx];
-
-                Self::transition_resource(
-                    params.device,
-                    cb,
-                    resource,
-                    access,
-                    //pass.name == "raster simple",
-                    false,
-                    "",
-                );
-            }
+            let transitions: Vec<ResourceTransition> = pass
+                .read
+                .iter()
+                .chain(pass.write.iter())
+                .map(|resource_ref| {
+                    Self::resource_transition(
+                        &resource_registry.resources,
+                        alias_predecessors,
+                        resource_ref.handle.id as usize,
+                        resource_ref.access,
+                    )
+                })
+                .collect();
+
+            Self::record_transitions(
+                resource_registry.execution_params.device,
+                cb,
+                queue_family_index,
+                &mut resource_registry.resources,
+                &transitions,
+                barrier_stats,
+            );
         }
 
         let mut api = RenderPassApi {
+            pass_name: &pass.name,
             cb,
             resources: resource_registry,
         };
@@ -1010,102 +1644,252 @@ impl<'exec_params, 'constants> ExecutingRenderGraph<'exec_params, 'constants> {
             .record_crash_marker(cb, format!("end render pass {:?}", pass.name));
     }
 
-    fn transition_resource(
-        device: &Device,
-        cb: &CommandBuffer,
-        resource: &mut RegistryResource,
+    /// Describes the transition of a resource into `access` for the barrier planner.
+    ///
+    /// The first transition of an image placed over memory which earlier passes used
+    /// for other images waits for all previous occupants, and discards the contents.
+    fn resource_transition(
+        resources: &[RegistryResource],
+        alias_predecessors: &[Vec<usize>],
+        resource_idx: usize,
         access: PassResourceAccessType,
-        debug: bool,
-        dbg_str: &str,
-    ) {
-        if unsafe { RG_ALLOW_PASS_OVERLAP }
-            && resource.access_type == access.access_type
-            && matches!(
+    ) -> ResourceTransition {
+        let resource = &resources[resource_idx];
+        let predecessors = &alias_predecessors[resource_idx];
+        let acquires_aliased_memory =
+            resource.access_type == vk_sync::AccessType::Nothing && !predecessors.is_empty();
+
+        let previous_accesses = if acquires_aliased_memory {
+            predecessors
+                .iter()
+                .map(|&idx| resources[idx].access_type)
+                .filter(|&access_type| access_type != vk_sync::AccessType::Nothing)
+                .collect()
+        } else {
+            vec![resource.access_type]
+        };
+
+        let target = match resource.resource.borrow() {
+            AnyRenderResourceRef::Image(image) => BarrierTarget::Image {
+                aspect_mask: image_aspect_mask_from_access_type_and_format(
+                    access.access_type,
+                    image.desc.format,
+                )
+                .unwrap_or_else(|| {
+                    panic!(
+                        "Invalid image access {:?} :: {:?}",
+                        access.access_type, image.desc
+                    )
+                }),
+                range: SubresourceRange::full(
+                    image.desc.mip_levels as u32,
+                    image.desc.array_elements,
+                ),
+            },
+            AnyRenderResourceRef::Buffer(_) => BarrierTarget::Buffer,
+            // TODO: acceleration structures aren't synchronized yet
+            AnyRenderResourceRef::RayTracingAcceleration(_) => BarrierTarget::Untracked,
+        };
+
+        ResourceTransition {
+            resource: resource_idx,
+            target,
+            previous_accesses,
+            next_access: access.access_type,
+            skip_if_same: matches!(
                 access.sync_type,
                 PassResourceAccessSyncType::SkipSyncIfSameAccessType
-            )
-        {
-            return;
+            ),
+            discard: acquires_aliased_memory,
+        }
+    }
+
+    /// Records all of `transitions` with a single pipeline barrier, leaving out the ones
+    /// which aren't needed. `cb` is to be submitted to a queue of `queue_family_index`.
+    fn record_transitions(
+        device: &Device,
+        cb: &CommandBuffer,
+        queue_family_index: u32,
+        resources: &mut [RegistryResource],
+        transitions: &[ResourceTransition],
+        stats: &mut BarrierStats,
+    ) {
+        let plan = plan_pass_barriers(transitions, unsafe { RG_ALLOW_PASS_OVERLAP });
+        stats.add_plan(&plan);
+
+        let mut image_barriers = Vec::new();
+        let mut buffer_barriers = Vec::new();
+
+        for barrier in &plan.barriers {
+            match (
+                resources[barrier.resource].resource.borrow(),
+                barrier.target,
+            ) {
+                (
+                    AnyRenderResourceRef::Image(image),
+                    BarrierTarget::Image { aspect_mask, range },
+                ) => image_barriers.push(vk_sync::ImageBarrier {
+                    previous_accesses: &barrier.previous_accesses,
+                    next_accesses: &barrier.next_accesses,
+                    previous_layout: vk_sync::ImageLayout::Optimal,
+                    next_layout: vk_sync::ImageLayout::Optimal,
+                    discard_contents: barrier.discard,
+                    src_queue_family_index: queue_family_index,
+                    dst_queue_family_index: queue_family_index,
+                    image: image.raw,
+                    range: vk::ImageSubresourceRange {
+                        aspect_mask,
+                        base_mip_level: range.base_mip_level,
+                        level_count: range.level_count,
+                        base_array_layer: range.base_array_layer,
+                        layer_count: range.layer_count,
+                    },
+                }),
+                (AnyRenderResourceRef::Buffer(buffer), BarrierTarget::Buffer) => buffer_barriers
+                    .push(vk_sync::BufferBarrier {
+                        previous_accesses: &barrier.previous_accesses,
+                        next_accesses: &barrier.next_accesses,
+                        src_queue_family_index: queue_family_index,
+                        dst_queue_family_index: queue_family_index,
+                        buffer: buffer.raw,
+                        offset: 0,
+                        size: buffer.desc.size,
+                    }),
+                _ => unreachable!(),
+            }
         }
 
-        if debug {
-            log::info!(
-                "\t{dbg_str}: {:?} -> {:?}",
-                resource.access_type,
-                access.access_type
+        if !image_barriers.is_empty() || !buffer_barriers.is_empty() {
+            vk_sync::cmd::pipeline_barrier(
+                device.raw.fp_v1_0(),
+                cb.raw,
+                None,
+                &buffer_barriers,
+                &image_barriers,
             );
         }
 
-        match resource.resource.borrow() {
-            AnyRenderResourceRef::Image(image) => {
-                if debug {
-                    log::info!("\t(image {:?})", image.desc);
-                }
+        for (resource_idx, access_type) in plan.final_accesses {
+            resources[resource_idx].access_type = access_type;
+        }
+    }
 
-                record_image_barrier(
-                    device,
-                    cb.raw,
-                    ImageBarrier::new(
-                        image.raw,
-                        resource.access_type,
-                        access.access_type,
-                        image_aspect_mask_from_access_type_and_format(
-                            access.access_type,
-                            image.desc.format,
-                        )
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Invalid image access {:?} :: {:?}",
-                                access.access_type, image.desc
-                            )
-                        }),
-                    ),
-                );
+    /// One half of a queue family ownership transfer. The release half only makes
+    /// `previous_access` available on the source queue, and the acquire half only makes
+    /// the resource visible to `next_access` on the destination one; the semaphore
+    /// between the two submissions orders them.
+    fn record_ownership_transfer(
+        device: &Device,
+        cb: &CommandBuffer,
+        resource: &mut RegistryResource,
+        previous_access: vk_sync::AccessType,
+        next_access: vk_sync::AccessType,
+        transfer: &OwnershipTransfer,
+        half: OwnershipTransferHalf,
+    ) {
+        let src_queue_family_index = Self::queue(device, transfer.src_queue).family.index;
+        let dst_queue_family_index = Self::queue(device, transfer.dst_queue).family.index;
+
+        let buffer_barrier = |buffer: &Buffer| {
+            vk_sync::get_buffer_memory_barrier(&vk_sync::BufferBarrier {
+                previous_accesses: &[previous_access],
+                next_accesses: &[next_access],
+                src_queue_family_index,
+                dst_queue_family_index,
+                buffer: buffer.raw,
+                offset: 0,
+                size: buffer.desc.size,
+            })
+        };
 
-                resource.access_type = access.access_type;
-            }
-            AnyRenderResourceRef::Buffer(buffer) => {
-                if debug {
-                    log::info!("\t(buffer {:?})", buffer.desc);
+        let (mut src_stage_mask, mut dst_stage_mask, mut image_barriers, mut buffer_barriers) =
+            match resource.resource.borrow() {
+                AnyRenderResourceRef::Image(image) => {
+                    let (src_stage_mask, dst_stage_mask, barrier) =
+                        vk_sync::get_image_memory_barrier(&vk_sync::ImageBarrier {
+                            previous_accesses: &[previous_access],
+                            next_accesses: &[next_access],
+                            previous_layout: vk_sync::ImageLayout::Optimal,
+                            next_layout: vk_sync::ImageLayout::Optimal,
+                            discard_contents: false,
+                            src_queue_family_index,
+                            dst_queue_family_index,
+                            image: image.raw,
+                            range: vk::ImageSubresourceRange {
+                                aspect_mask: image_aspect_mask_from_access_type_and_format(
+                                    next_access,
+                                    image.desc.format,
+                                )
+                                .unwrap_or_else(|| {
+                                    panic!(
+                                        "Invalid image access {:?} :: {:?}",
+                                        next_access, image.desc
+                                    )
+                                }),
+                                base_mip_level: 0,
+                                level_count: vk::REMAINING_MIP_LEVELS,
+                                base_array_layer: 0,
+                                layer_count: vk::REMAINING_ARRAY_LAYERS,
+                            },
+                        });
+                    (src_stage_mask, dst_stage_mask, vec![barrier], vec![])
                 }
-                //global_barrier(device, cb, &[resource.access_type], &[access.access_type]);
-
-                vk_sync::cmd::pipeline_barrier(
-                    device.raw.fp_v1_0(),
-                    cb.raw,
-                    None,
-                    &[vk_sync::BufferBarrier {
-                        previous_accesses: &[resource.access_type],
-                        next_accesses: &[access.access_type],
-                        src_queue_family_index: device.universal_queue.family.index,
-                        dst_queue_family_index: device.universal_queue.family.index,
-                        buffer: buffer.raw,
-                        offset: 0,
-                        size: buffer.desc.size,
-                    }],
-                    &[],
-                );
+                AnyRenderResourceRef::Buffer(buffer) => {
+                    let (src_stage_mask, dst_stage_mask, barrier) = buffer_barrier(buffer);
+                    (src_stage_mask, dst_stage_mask, vec![], vec![barrier])
+                }
+                AnyRenderResourceRef::RayTracingAcceleration(acceleration) => {
+                    // Ownership of acceleration structures is that of their backing buffers
+                    let (src_stage_mask, dst_stage_mask, barrier) =
+                        buffer_barrier(acceleration.backing_buffer());
+                    (src_stage_mask, dst_stage_mask, vec![], vec![barrier])
+                }
+            };
 
-                resource.access_type = access.access_type;
+        // Image layout transitions happen in both halves, so the layouts stay as they are.
+        match half {
+            OwnershipTransferHalf::Release => {
+                dst_stage_mask = vk::PipelineStageFlags::BOTTOM_OF_PIPE;
+                for barrier in &mut image_barriers {
+                    barrier.dst_access_mask = vk::AccessFlags::empty();
+                }
+                for barrier in &mut buffer_barriers {
+                    barrier.dst_access_mask = vk::AccessFlags::empty();
+                }
             }
-            AnyRenderResourceRef::RayTracingAcceleration(_) => {
-                if debug {
-                    log::info!("\t(bvh)");
+            OwnershipTransferHalf::Acquire => {
+                src_stage_mask = vk::PipelineStageFlags::TOP_OF_PIPE;
+                for barrier in &mut image_barriers {
+                    barrier.src_access_mask = vk::AccessFlags::empty();
+                }
+                for barrier in &mut buffer_barriers {
+                    barrier.src_access_mask = vk::AccessFlags::empty();
                 }
-                /*global_barrier(
-                    device,
-                    cb,
-                    &[resource.access_type],
-                    &[access_type],
-                );*/
-                // TODO
-
-                resource.access_type = access.access_type;
             }
         }
+
+        unsafe {
+            device.raw.cmd_pipeline_barrier(
+                cb.raw,
+                src_stage_mask,
+                dst_stage_mask,
+                vk::DependencyFlags::empty(),
+                &[],
+                &buffer_barriers,
+                &image_barriers,
+            );
+        }
+
+        resource.access_type = next_access;
     }
 }
 
+#[derive(Clone, Copy)]
+enum OwnershipTransferHalf {
+    Release,
+    Acquire,
+}
+
 #[allow(dead_code)]
 fn global_barrier(
     device: &Device,
@@ -1127,6 +1911,7 @@ fn global_barrier(
 
 pub struct RetiredRenderGraph {
     resources: Vec<RegistryResource>,
+    memory_blocks: Vec<TransientMemoryBlock>,
 }
 
 impl RetiredRenderGraph {
@@ -1150,12 +1935,22 @@ impl RetiredRenderGraph {
                 AnyRenderResource::OwnedBuffer(buffer) => {
                     transient_resource_cache.insert_buffer(buffer)
                 }
+                AnyRenderResource::PlacedImage {
+                    image,
+                    memory,
+                    offset,
+                } => transient_resource_cache.insert_placed_image(image, memory, offset),
                 AnyRenderResource::ImportedImage(_)
                 | AnyRenderResource::ImportedBuffer(_)
-                | AnyRenderResource::ImportedRayTracingAcceleration(_) => {},
+                | AnyRenderResource::ImportedRayTracingAcceleration(_)
+                | AnyRenderResource::Unused => {},
                 AnyRenderResource::Pending { .. } => panic!("RetiredRenderGraph::release_resources called while a resource was in Pending state"),
             }
         }
+
+        for block in self.memory_blocks {
+            transient_resource_cache.insert_memory_block(block);
+        }
     }
 }
 
@@ -1170,7 +1965,7 @@ pub enum PassResourceAccessSyncType {
 #[derive(Copy, Clone)]
 pub struct PassResourceAccessType {
     // TODO: multiple
-    access_type: vk_sync::AccessType,
+    pub(crate) access_type: vk_sync::AccessType,
     sync_type: PassResourceAccessSyncType,
 }
 
@@ -1194,6 +1989,9 @@ pub(crate) struct RecordedPass {
     pub render_fn: Option<Box<DynRenderFn>>,
     pub name: String,
     pub idx: usize,
+    pub never_cull: bool,
+    pub culled: bool,
+    pub queue: QueueAffinity,
 }
 
 impl RecordedPass {
@@ -1204,8 +2002,65 @@ impl RecordedPass {
             render_fn: Default::default(),
             name: name.to_owned(),
             idx,
+            never_cull: false,
+            culled: false,
+            queue: QueueAffinity::Graphics,
         }
     }
 }
 
 pub static mut RG_ALLOW_PASS_OVERLAP: bool = true;
+
+/// Skip passes whose outputs don't reach any exported or imported resource.
+pub static mut RG_CULL_PASSES: bool = true;
+
+/// Place transient images with non-overlapping lifetimes in shared memory.
+pub static mut RG_ALIAS_TRANSIENT_RESOURCES: bool = true;
+
+/// Run passes marked with `async_compute` on the dedicated compute queue, if there is one.
+pub static mut RG_ASYNC_COMPUTE: bool = true;
+
+#[test]
+fn test_find_live_passes() {
+    use vk_sync::AccessType;
+
+    let mut rg = RenderGraph::new();
+    let desc = ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, [4, 4]);
+
+    let mut swapchain = rg.get_swap_chain();
+    let mut presented = rg.create(desc);
+    let mut exported = rg.create(desc);
+    let mut orphaned = rg.create(desc);
+    let mut side_effect = rg.create(desc);
+
+    rg.add_pass("presented")
+        .write(&mut presented, AccessType::ComputeShaderWrite);
+    rg.add_pass("orphaned")
+        .write(&mut orphaned, AccessType::ComputeShaderWrite);
+    rg.add_pass("exported")
+        .write(&mut exported, AccessType::ComputeShaderWrite);
+    {
+        let mut pass = rg.add_pass("side effect");
+        pass.write(&mut side_effect, AccessType::ComputeShaderWrite);
+        pass.never_cull();
+    }
+    {
+        let mut pass = rg.add_pass("present");
+        pass.read(
+            &presented,
+            AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        pass.write(&mut swapchain, AccessType::ComputeShaderWrite);
+    }
+    rg.add_pass("reads orphaned").read(
+        &orphaned,
+        AccessType::ComputeShaderReadSampledImageOrUniformTexelBuffer,
+    );
+
+    rg.export(exported, AccessType::Nothing);
+
+    assert_eq!(
+        rg.find_live_passes(),
+        vec![true, false, true, true, true, false]
+    );
+}
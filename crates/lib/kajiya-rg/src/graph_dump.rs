@@ -0,0 +1,431 @@
+//! Graphviz and JSON dumps of compiled render graphs.
+//!
+//! The output only depends on the structure of the graph (no handles or pointers),
+//! so dumps from two builds can be diffed to spot accidental pipeline changes.
+
+use crate::{
+    graph::{
+        GraphResourceCreateInfo, GraphResourceImportInfo, GraphResourceInfo, PassResourceRef,
+        RenderGraph, ResourceInfo,
+    },
+    queue_schedule::QueueAffinity,
+    resource::GraphResourceDesc,
+    CompiledRenderGraph,
+};
+use kajiya_backend::{
+    ash::vk,
+    vulkan::{buffer::BufferDesc, image::ImageDesc},
+};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize)]
+pub struct RenderGraphDump {
+    pub passes: Vec<PassDump>,
+    pub resources: Vec<ResourceDump>,
+}
+
+#[derive(Serialize)]
+pub struct PassDump {
+    pub idx: usize,
+    pub name: String,
+    pub culled: bool,
+    pub never_cull: bool,
+    pub queue: String,
+    pub reads: Vec<PassResourceAccessDump>,
+    pub writes: Vec<PassResourceAccessDump>,
+}
+
+#[derive(Serialize)]
+pub struct PassResourceAccessDump {
+    pub resource: u32,
+    pub version: u32,
+    pub access_type: String,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceOrigin {
+    Created,
+    Imported,
+    Temporal,
+    Swapchain,
+}
+
+#[derive(Serialize)]
+pub struct ResourceDump {
+    pub id: u32,
+    pub origin: ResourceOrigin,
+
+    /// Key of the temporal resource, if this is one
+    pub temporal_key: Option<String>,
+
+    /// Access type the resource was in when imported into the graph
+    pub import_access_type: Option<String>,
+
+    /// Access type the resource is left in for users of the graph, if exported
+    pub export_access_type: Option<String>,
+
+    pub first_pass: Option<usize>,
+    pub last_pass: Option<usize>,
+
+    pub desc: ResourceDescDump,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum ResourceDescDump {
+    Image {
+        image_type: String,
+        format: String,
+        extent: [u32; 3],
+        mip_levels: u16,
+        array_elements: u32,
+        usage: String,
+    },
+    Buffer {
+        size: usize,
+        memory_location: String,
+        usage: String,
+    },
+    RayTracingAcceleration,
+    Unknown,
+}
+
+impl ResourceDescDump {
+    fn image(desc: &ImageDesc, usage: vk::ImageUsageFlags) -> Self {
+        Self::Image {
+            image_type: format!("{:?}", desc.image_type),
+            format: format!("{:?}", desc.format),
+            extent: desc.extent,
+            mip_levels: desc.mip_levels,
+            array_elements: desc.array_elements,
+            usage: format!("{:?}", usage),
+        }
+    }
+
+    fn buffer(desc: &BufferDesc, usage: vk::BufferUsageFlags) -> Self {
+        Self::Buffer {
+            size: desc.size,
+            memory_location: format!("{:?}", desc.memory_location),
+            usage: format!("{:?}", usage),
+        }
+    }
+
+    fn short_label(&self) -> String {
+        match self {
+            ResourceDescDump::Image {
+                format,
+                extent,
+                mip_levels,
+                ..
+            } => {
+                let mut label = format!("{} {}x{}", format, extent[0], extent[1]);
+                if extent[2] > 1 {
+                    write!(label, "x{}", extent[2]).unwrap();
+                }
+                if *mip_levels > 1 {
+                    write!(label, " ({} mips)", mip_levels).unwrap();
+                }
+                label
+            }
+            ResourceDescDump::Buffer { size, .. } => format!("buffer {} bytes", size),
+            ResourceDescDump::RayTracingAcceleration => "acceleration structure".to_owned(),
+            ResourceDescDump::Unknown => "unknown".to_owned(),
+        }
+    }
+}
+
+fn dump_resource_refs(refs: &[PassResourceRef]) -> Vec<PassResourceAccessDump> {
+    refs.iter()
+        .map(|res_ref| PassResourceAccessDump {
+            resource: res_ref.handle.id,
+            version: res_ref.handle.version,
+            access_type: format!("{:?}", res_ref.access.access_type),
+        })
+        .collect()
+}
+
+impl RenderGraphDump {
+    pub(crate) fn new(rg: &RenderGraph, resource_info: &ResourceInfo) -> Self {
+        let passes = rg
+            .passes
+            .iter()
+            .map(|pass| PassDump {
+                idx: pass.idx,
+                name: pass.name.clone(),
+                culled: pass.culled,
+                never_cull: pass.never_cull,
+                queue: queue_label(pass.queue),
+                reads: dump_resource_refs(&pass.read),
+                writes: dump_resource_refs(&pass.write),
+            })
+            .collect();
+
+        let resources = rg
+            .resources
+            .iter()
+            .enumerate()
+            .map(|(res_idx, res)| {
+                let id = res_idx as u32;
+                let temporal_key = rg.temporal_resource_keys.get(&id).cloned();
+
+                let (origin, import_access_type, desc) = match res {
+                    GraphResourceInfo::Created(GraphResourceCreateInfo { desc }) => {
+                        let desc = match desc {
+                            GraphResourceDesc::Image(desc) => ResourceDescDump::image(
+                                desc,
+                                resource_info.image_usage_flags[res_idx],
+                            ),
+                            GraphResourceDesc::Buffer(desc) => ResourceDescDump::buffer(
+                                desc,
+                                resource_info.buffer_usage_flags[res_idx],
+                            ),
+                            GraphResourceDesc::RayTracingAcceleration(_) => {
+                                ResourceDescDump::RayTracingAcceleration
+                            }
+                        };
+                        (ResourceOrigin::Created, None, desc)
+                    }
+                    GraphResourceInfo::Imported(import) => {
+                        let origin = if temporal_key.is_some() {
+                            ResourceOrigin::Temporal
+                        } else {
+                            ResourceOrigin::Imported
+                        };
+
+                        match import {
+                            GraphResourceImportInfo::Image {
+                                resource,
+                                access_type,
+                            } => (
+                                origin,
+                                Some(format!("{:?}", access_type)),
+                                ResourceDescDump::image(&resource.desc, resource.desc.usage),
+                            ),
+                            GraphResourceImportInfo::Buffer {
+                                resource,
+                                access_type,
+                            } => (
+                                origin,
+                                Some(format!("{:?}", access_type)),
+                                ResourceDescDump::buffer(&resource.desc, resource.desc.usage),
+                            ),
+                            GraphResourceImportInfo::RayTracingAcceleration {
+                                access_type, ..
+                            } => (
+                                origin,
+                                Some(format!("{:?}", access_type)),
+                                ResourceDescDump::RayTracingAcceleration,
+                            ),
+                            GraphResourceImportInfo::SwapchainImage => {
+                                (ResourceOrigin::Swapchain, None, ResourceDescDump::Unknown)
+                            }
+                        }
+                    }
+                };
+
+                let export_access_type = rg
+                    .exported_resources
+                    .iter()
+                    .find(|(res, _)| res.raw().id == id)
+                    .map(|(_, access_type)| format!("{:?}", access_type));
+
+                let lifetime = &resource_info.lifetimes[res_idx];
+
+                ResourceDump {
+                    id,
+                    origin,
+                    temporal_key,
+                    import_access_type,
+                    export_access_type,
+                    first_pass: lifetime.first_access,
+                    last_pass: lifetime.last_access,
+                    desc,
+                }
+            })
+            .collect();
+
+        Self { passes, resources }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Passes are boxes, resources are ellipses. Reads are edges from resources to passes,
+    /// and writes go the other way. Culled passes are dashed and without edges,
+    /// and async compute ones blue.
+    pub fn to_dot(&self) -> String {
+        fn escape(s: &str) -> String {
+            s.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        let mut dot = String::new();
+        writeln!(dot, "digraph render_graph {{").unwrap();
+        writeln!(dot, "    rankdir=LR;").unwrap();
+        writeln!(dot, "    node [fontname=\"monospace\", fontsize=10];").unwrap();
+        writeln!(dot, "    edge [fontname=\"monospace\", fontsize=8];").unwrap();
+
+        // Resources which no live pass touches only add noise
+        let is_resource_shown = |id: u32| {
+            matches!(
+                self.resources.get(id as usize),
+                Some(res) if res.first_pass.is_some() || res.export_access_type.is_some()
+            )
+        };
+
+        for res in &self.resources {
+            if !is_resource_shown(res.id) {
+                continue;
+            }
+
+            let name = match (&res.origin, &res.temporal_key) {
+                (_, Some(key)) => key.clone(),
+                (ResourceOrigin::Swapchain, _) => "swapchain".to_owned(),
+                _ => format!("r{}", res.id),
+            };
+
+            let fill = match res.origin {
+                ResourceOrigin::Created => "white",
+                ResourceOrigin::Imported => "lightgray",
+                ResourceOrigin::Temporal => "lightblue",
+                ResourceOrigin::Swapchain => "palegreen",
+            };
+
+            writeln!(
+                dot,
+                "    r{} [shape=ellipse, style=filled, fillcolor={}, peripheries={}, label=\"{}\\n{}\"];",
+                res.id,
+                fill,
+                if res.export_access_type.is_some() { 2 } else { 1 },
+                escape(&name),
+                escape(&res.desc.short_label()),
+            )
+            .unwrap();
+        }
+
+        for pass in &self.passes {
+            writeln!(
+                dot,
+                "    p{} [shape=box, style=\"{}\", color={}, label=\"{}: {}\"];",
+                pass.idx,
+                if pass.culled { "dashed" } else { "solid" },
+                if pass.queue == queue_label(QueueAffinity::AsyncCompute) {
+                    "blue"
+                } else {
+                    "black"
+                },
+                pass.idx,
+                escape(&pass.name),
+            )
+            .unwrap();
+
+            if pass.culled {
+                continue;
+            }
+
+            for read in pass.reads.iter().filter(|r| is_resource_shown(r.resource)) {
+                writeln!(
+                    dot,
+                    "    r{} -> p{} [label=\"{}\"];",
+                    read.resource, pass.idx, read.access_type
+                )
+                .unwrap();
+            }
+
+            for write in pass.writes.iter().filter(|w| is_resource_shown(w.resource)) {
+                writeln!(
+                    dot,
+                    "    p{} -> r{} [label=\"{}\", color=red];",
+                    pass.idx, write.resource, write.access_type
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    /// Writes `render_graph.dot` and `render_graph.json` into `dir`.
+    pub fn write_to_dir(&self, dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("render_graph.dot"), self.to_dot())?;
+        std::fs::write(dir.join("render_graph.json"), self.to_json())?;
+        Ok(())
+    }
+}
+
+impl CompiledRenderGraph {
+    pub fn dump(&self) -> RenderGraphDump {
+        RenderGraphDump::new(&self.rg, &self.resource_info)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DUMP_REQUEST: Mutex<Option<PathBuf>> = Default::default();
+}
+
+/// Makes the next compiled render graph dump itself into `dir`.
+pub fn request_render_graph_dump(dir: impl Into<PathBuf>) {
+    *DUMP_REQUEST.lock() = Some(dir.into());
+}
+
+pub(crate) fn take_dump_request() -> Option<PathBuf> {
+    DUMP_REQUEST.lock().take()
+}
+
+/// How the queue of a pass is written in dumps
+fn queue_label(queue: QueueAffinity) -> String {
+    format!("{:?}", queue)
+}
+
+#[test]
+fn test_dot_skips_edges_of_hidden_nodes() {
+    let access = |resource| PassResourceAccessDump {
+        resource,
+        version: 0,
+        access_type: "ComputeShaderWrite".to_owned(),
+    };
+
+    let resource = |id, first_pass| ResourceDump {
+        id,
+        origin: ResourceOrigin::Created,
+        temporal_key: None,
+        import_access_type: None,
+        export_access_type: None,
+        first_pass,
+        last_pass: first_pass,
+        desc: ResourceDescDump::Unknown,
+    };
+
+    let pass = |idx, culled, writes| PassDump {
+        idx,
+        name: format!("pass{}", idx),
+        culled,
+        never_cull: false,
+        queue: queue_label(QueueAffinity::Graphics),
+        reads: Vec::new(),
+        writes,
+    };
+
+    // Pass 1 is culled, and r1 is only written by it
+    let dump = RenderGraphDump {
+        passes: vec![
+            pass(0, false, vec![access(0)]),
+            pass(1, true, vec![access(0), access(1)]),
+        ],
+        resources: vec![resource(0, Some(0)), resource(1, None)],
+    };
+
+    let dot = dump.to_dot();
+    assert!(dot.contains("p0 -> r0"), "{}", dot);
+    assert!(dot.contains("p1 [shape=box, style=\"dashed\""), "{}", dot);
+    assert!(!dot.contains("p1 ->"), "{}", dot);
+    assert!(!dot.contains("r1"), "{}", dot);
+}
@@ -5,7 +5,10 @@ use kajiya_backend::{
     vulkan::{
         image::*,
         ray_tracing::{RayTracingAcceleration, RayTracingPipelineDesc},
-        shader::{ComputePipelineDesc, PipelineShaderDesc, ShaderPipelineStage, ShaderSource},
+        shader::{
+            ComputePipelineDesc, PipelineShaderDesc, ShaderDefine, ShaderPipelineStage,
+            ShaderSource,
+        },
     },
 };
 
@@ -130,6 +133,14 @@ impl<'rg> SimpleRenderPass<'rg, RgComputePipelineHandle> {
         }
     }
 
+    /// Compiles the shader with `#define name value`. Passes using the same shader
+    /// with different defines get separate pipelines.
+    pub fn define(mut self, name: &str, value: impl ToString) -> Self {
+        self.pass
+            .add_compute_pipeline_define(self.state.pipeline, ShaderDefine::new(name, value));
+        self
+    }
+
     pub fn dispatch(self, extent: [u32; 3]) {
         let mut state = self.state;
 
@@ -160,12 +171,46 @@ impl<'rg> SimpleRenderPass<'rg, RgComputePipelineHandle> {
     }
 }
 
+/// Shaders of one hit group in the shader binding table of a ray tracing pipeline.
+#[derive(Clone)]
+pub struct RtHitGroup {
+    pub closest_hit: Option<ShaderSource>,
+    pub any_hit: Option<ShaderSource>,
+}
+
+impl RtHitGroup {
+    pub fn closest_hit(source: ShaderSource) -> Self {
+        Self {
+            closest_hit: Some(source),
+            any_hit: None,
+        }
+    }
+
+    pub fn any_hit_only(source: ShaderSource) -> Self {
+        Self {
+            closest_hit: None,
+            any_hit: Some(source),
+        }
+    }
+
+    pub fn any_hit(mut self, source: ShaderSource) -> Self {
+        self.any_hit = Some(source);
+        self
+    }
+}
+
+impl From<ShaderSource> for RtHitGroup {
+    fn from(source: ShaderSource) -> Self {
+        Self::closest_hit(source)
+    }
+}
+
 impl<'rg> SimpleRenderPass<'rg, RgRtPipelineHandle> {
-    pub fn new_rt(
+    pub fn new_rt<H: Into<RtHitGroup>>(
         mut pass: PassBuilder<'rg>,
         rgen: ShaderSource,
         miss: impl IntoIterator<Item = ShaderSource>,
-        hit: impl IntoIterator<Item = ShaderSource>,
+        hit: impl IntoIterator<Item = H>,
     ) -> Self {
         let miss = miss.into_iter();
         let hit = hit.into_iter();
@@ -187,13 +232,35 @@ impl<'rg> SimpleRenderPass<'rg, RgRtPipelineHandle> {
             );
         }
 
-        for source in hit {
-            shaders.push(
-                PipelineShaderDesc::builder(ShaderPipelineStage::RayClosestHit)
-                    .source(source)
-                    .build()
-                    .unwrap(),
+        let mut prev_group_closest_hit_only = false;
+        for group in hit {
+            let group = group.into();
+
+            // The backend puts an any-hit shader in the same group as a closest-hit shader
+            // right before it, so an any-hit-only group can't follow a closest-hit-only one.
+            assert!(
+                !(prev_group_closest_hit_only && group.closest_hit.is_none()),
+                "an any-hit-only hit group can't follow a closest-hit-only one"
             );
+            prev_group_closest_hit_only = group.closest_hit.is_some() && group.any_hit.is_none();
+
+            if let Some(source) = group.closest_hit {
+                shaders.push(
+                    PipelineShaderDesc::builder(ShaderPipelineStage::RayClosestHit)
+                        .source(source)
+                        .build()
+                        .unwrap(),
+                );
+            }
+
+            if let Some(source) = group.any_hit {
+                shaders.push(
+                    PipelineShaderDesc::builder(ShaderPipelineStage::RayAnyHit)
+                        .source(source)
+                        .build()
+                        .unwrap(),
+                );
+            }
         }
 
         let pipeline = pass.register_ray_tracing_pipeline(
@@ -207,6 +274,14 @@ impl<'rg> SimpleRenderPass<'rg, RgRtPipelineHandle> {
         }
     }
 
+    /// Compiles all shaders of the pipeline with `#define name value`. Passes using
+    /// the same shaders with different defines get separate pipelines.
+    pub fn define(mut self, name: &str, value: impl ToString) -> Self {
+        self.pass
+            .add_rt_pipeline_define(self.state.pipeline, ShaderDefine::new(name, value));
+        self
+    }
+
     pub fn trace_rays(mut self, tlas: &Handle<RayTracingAcceleration>, extent: [u32; 3]) {
         let tlas_ref = self.pass.read(tlas, AccessType::AnyShaderReadOther);
         let mut state = self.state;
@@ -385,6 +460,16 @@ impl<'rg, RgPipelineHandle> SimpleRenderPass<'rg, RgPipelineHandle> {
         self
     }
 
+    pub fn never_cull(mut self) -> Self {
+        self.pass.never_cull();
+        self
+    }
+
+    pub fn async_compute(mut self) -> Self {
+        self.pass.async_compute();
+        self
+    }
+
     pub fn raw_descriptor_set(mut self, set_idx: u32, set: vk::DescriptorSet) -> Self {
         self.state.raw_descriptor_sets.push((set_idx, set));
         self
@@ -1,8 +1,12 @@
+mod barrier_batching;
 mod graph;
+mod graph_dump;
 mod hl;
 mod pass_api;
 mod pass_builder;
+mod queue_schedule;
 mod resource;
+mod resource_aliasing;
 mod resource_registry;
 mod temporal;
 
@@ -10,9 +14,11 @@ pub mod imageops;
 pub mod renderer;
 
 pub use graph::*;
+pub use graph_dump::*;
 pub use hl::*;
 pub use pass_api::*;
 pub use pass_builder::*;
+pub use queue_schedule::QueueAffinity;
 pub use resource::*;
 pub use resource_registry::ResourceRegistry;
 pub use temporal::*;
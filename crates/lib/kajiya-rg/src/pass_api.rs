@@ -27,6 +27,7 @@ use kajiya_backend::{
 };
 
 pub struct RenderPassApi<'a, 'exec_params, 'constants> {
+    pub pass_name: &'a str,
     pub cb: &'a CommandBuffer,
     pub resources: &'a mut ResourceRegistry<'exec_params, 'constants>,
 }
@@ -205,6 +206,15 @@ impl<'a, 'exec_params, 'constants> RenderPassApi<'a, 'exec_params, 'constants> {
                 continue;
             }
 
+            // Catch mismatches here rather than as validation layer errors or garbage output
+            pipeline
+                .interface
+                .validate_set_once(set_idx, bindings.iter().map(bound_descriptor))
+                .map_err(|info| BackendError::ShaderBinding {
+                    pass: self.pass_name.to_owned(),
+                    info,
+                })?;
+
             let bindings: Result<Vec<_>, BackendError> = bindings
                 .iter()
                 .map(|binding| {
@@ -634,6 +644,33 @@ impl BindRgRef for Ref<RayTracingAcceleration, GpuSrv> {
     }
 }
 
+fn image_descriptor_type(image_layout: vk::ImageLayout) -> vk::DescriptorType {
+    match image_layout {
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => vk::DescriptorType::SAMPLED_IMAGE,
+        vk::ImageLayout::GENERAL => vk::DescriptorType::STORAGE_IMAGE,
+        _ => unimplemented!("{:?}", image_layout),
+    }
+}
+
+/// The descriptor type and count which `bind_descriptor_set` writes for `binding`
+fn bound_descriptor(binding: &RenderPassBinding) -> (vk::DescriptorType, u32) {
+    match binding {
+        RenderPassBinding::Image(image) => (image_descriptor_type(image.image_layout), 1),
+        RenderPassBinding::ImageArray(images) => (
+            image_descriptor_type(images[0].image_layout),
+            images.len() as u32,
+        ),
+        RenderPassBinding::Buffer(_) => (vk::DescriptorType::STORAGE_BUFFER, 1),
+        RenderPassBinding::RayTracingAcceleration(_) => {
+            (vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, 1)
+        }
+        RenderPassBinding::DynamicConstants(_) => (vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, 1),
+        RenderPassBinding::DynamicConstantsStorageBuffer(_) => {
+            (vk::DescriptorType::STORAGE_BUFFER_DYNAMIC, 1)
+        }
+    }
+}
+
 fn bind_descriptor_set(
     device: &Device,
     cb: &CommandBuffer,
@@ -692,26 +729,14 @@ fn bind_descriptor_set(
 
                     match binding {
                         DescriptorSetBinding::Image(image) => write
-                            .descriptor_type(match image.image_layout {
-                                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => {
-                                    vk::DescriptorType::SAMPLED_IMAGE
-                                }
-                                vk::ImageLayout::GENERAL => vk::DescriptorType::STORAGE_IMAGE,
-                                _ => unimplemented!("{:?}", image.image_layout),
-                            })
+                            .descriptor_type(image_descriptor_type(image.image_layout))
                             .image_info(std::slice::from_ref(image_info.add(*image)))
                             .build(),
                         DescriptorSetBinding::ImageArray(images) => {
                             assert!(!images.is_empty());
 
                             write
-                                .descriptor_type(match images[0].image_layout {
-                                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => {
-                                        vk::DescriptorType::SAMPLED_IMAGE
-                                    }
-                                    vk::ImageLayout::GENERAL => vk::DescriptorType::STORAGE_IMAGE,
-                                    _ => unimplemented!("{:?}", images[0].image_layout),
-                                })
+                                .descriptor_type(image_descriptor_type(images[0].image_layout))
                                 .image_info(images.as_slice())
                                 .build()
                         }
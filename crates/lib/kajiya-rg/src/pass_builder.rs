@@ -1,4 +1,4 @@
-use crate::{PassResourceAccessSyncType, RenderPassApi};
+use crate::{PassResourceAccessSyncType, QueueAffinity, RenderPassApi};
 
 use super::{
     graph::{
@@ -322,6 +322,40 @@ impl<'rg> PassBuilder<'rg> {
         RgRtPipelineHandle { id }
     }
 
+    pub(crate) fn add_compute_pipeline_define(
+        &mut self,
+        pipeline: RgComputePipelineHandle,
+        define: ShaderDefine,
+    ) {
+        self.rg.compute_pipelines[pipeline.id]
+            .desc
+            .defines
+            .push(define);
+    }
+
+    /// Applies to every shader of the pipeline
+    pub(crate) fn add_rt_pipeline_define(
+        &mut self,
+        pipeline: RgRtPipelineHandle,
+        define: ShaderDefine,
+    ) {
+        for shader in &mut self.rg.rt_pipelines[pipeline.id].shaders {
+            shader.defines.push(define.clone());
+        }
+    }
+
+    /// Keep this pass even if nothing reads its outputs. Needed for passes
+    /// with side effects the graph can't see.
+    pub fn never_cull(&mut self) {
+        self.pass.as_mut().unwrap().never_cull = true;
+    }
+
+    /// Run this pass on the async compute queue, if the device has one. Only valid
+    /// for passes which don't use graphics pipelines.
+    pub fn async_compute(&mut self) {
+        self.pass.as_mut().unwrap().queue = QueueAffinity::AsyncCompute;
+    }
+
     pub fn render(
         mut self,
         render: impl (FnOnce(&mut RenderPassApi) -> Result<(), BackendError>) + 'static,
@@ -0,0 +1,435 @@
+//! Splitting of render graph passes into per-queue submissions.
+//!
+//! Passes with `QueueAffinity::AsyncCompute` go to a dedicated compute queue, and everything
+//! else to the universal queue. Resources are owned by one queue at a time; whenever a pass
+//! accesses a resource last touched on the other queue, the previous submission on that queue
+//! releases it, and the pass's submission waits on a semaphore and acquires it.
+//!
+//! The planner here only sees pass queues and resource ids, so it can be tested
+//! without a device.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum QueueAffinity {
+    #[default]
+    Graphics,
+    AsyncCompute,
+}
+
+impl QueueAffinity {
+    fn idx(self) -> usize {
+        match self {
+            QueueAffinity::Graphics => 0,
+            QueueAffinity::AsyncCompute => 1,
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            QueueAffinity::Graphics => QueueAffinity::AsyncCompute,
+            QueueAffinity::AsyncCompute => QueueAffinity::Graphics,
+        }
+    }
+}
+
+pub(crate) struct SchedulePass {
+    pub queue: QueueAffinity,
+
+    /// Ids of all resources the pass reads or writes
+    pub resources: Vec<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct OwnershipTransfer {
+    pub resource: u32,
+    pub src_queue: QueueAffinity,
+    pub dst_queue: QueueAffinity,
+
+    /// First pass to access the resource after the transfer. `None` when the resource
+    /// is just being returned to the graphics queue at the end of the graph.
+    pub next_pass: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct QueueSubmission {
+    pub queue: QueueAffinity,
+    pub passes: Vec<usize>,
+
+    /// Indices of earlier submissions (on the other queue) to wait for
+    pub waits: Vec<usize>,
+
+    /// Whether any later submission waits for this one
+    pub signals: bool,
+
+    /// Recorded before the first pass of the submission
+    pub acquires: Vec<OwnershipTransfer>,
+
+    /// Recorded after the last pass of the submission
+    pub releases: Vec<OwnershipTransfer>,
+}
+
+/// Submissions in the order they must be submitted in. The first and last ones
+/// are always on the graphics queue, and the last one waits for all async compute work.
+#[derive(Debug, Default)]
+pub(crate) struct QueueSchedule {
+    pub submissions: Vec<QueueSubmission>,
+}
+
+impl QueueSchedule {
+    pub fn is_split(&self) -> bool {
+        self.submissions.len() > 1
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PassLocation {
+    Open(QueueAffinity),
+    Closed(usize),
+}
+
+struct Planner {
+    submissions: Vec<QueueSubmission>,
+    open: [QueueSubmission; 2],
+    pass_location: Vec<PassLocation>,
+
+    /// Index of the latest submission on the other queue which each queue waits for
+    waited: [Option<usize>; 2],
+}
+
+impl Planner {
+    fn close(&mut self, queue: QueueAffinity) -> usize {
+        let submission = std::mem::replace(
+            &mut self.open[queue.idx()],
+            QueueSubmission {
+                queue,
+                ..Default::default()
+            },
+        );
+
+        let idx = self.submissions.len();
+        for &pass in &submission.passes {
+            self.pass_location[pass] = PassLocation::Closed(idx);
+        }
+
+        self.submissions.push(submission);
+        idx
+    }
+
+    /// Closes the submission containing `pass` if it's still open.
+    fn close_submission_of(&mut self, pass: usize) -> usize {
+        match self.pass_location[pass] {
+            PassLocation::Closed(idx) => idx,
+            PassLocation::Open(queue) => self.close(queue),
+        }
+    }
+
+    fn last_closed_on(&self, queue: QueueAffinity) -> Option<usize> {
+        self.submissions.iter().rposition(|s| s.queue == queue)
+    }
+
+    fn wait(&mut self, queue: QueueAffinity, submission: usize) {
+        if self.waited[queue.idx()] < Some(submission) {
+            self.waited[queue.idx()] = Some(submission);
+            self.open[queue.idx()].waits.push(submission);
+        }
+    }
+}
+
+/// * `initial_owners` - queue owning each resource at the start of the graph; `None` for
+///   transient resources, whose contents don't need to be preserved.
+/// * `return_to_graphics` - resources which need to end up on the graphics queue,
+///   e.g. imported ones, or those used by passes outside of the schedule.
+pub(crate) fn plan_queue_submissions(
+    passes: &[SchedulePass],
+    initial_owners: &[Option<QueueAffinity>],
+    return_to_graphics: &[bool],
+) -> QueueSchedule {
+    if passes
+        .iter()
+        .all(|pass| pass.queue == QueueAffinity::Graphics)
+    {
+        return QueueSchedule {
+            submissions: vec![QueueSubmission {
+                queue: QueueAffinity::Graphics,
+                passes: (0..passes.len()).collect(),
+                ..Default::default()
+            }],
+        };
+    }
+
+    let mut planner = Planner {
+        submissions: Vec::new(),
+        open: [
+            QueueSubmission {
+                queue: QueueAffinity::Graphics,
+                ..Default::default()
+            },
+            QueueSubmission {
+                queue: QueueAffinity::AsyncCompute,
+                ..Default::default()
+            },
+        ],
+        pass_location: vec![PassLocation::Open(QueueAffinity::Graphics); passes.len()],
+        waited: [None, None],
+    };
+
+    // Queue owning each resource, and the last pass to access it. Initial owners
+    // don't have a pass; they're handled by the first graphics submission.
+    let mut owners: Vec<Option<(QueueAffinity, Option<usize>)>> = initial_owners
+        .iter()
+        .map(|owner| owner.map(|queue| (queue, None)))
+        .collect();
+
+    for (pass_idx, pass) in passes.iter().enumerate() {
+        let queue = pass.queue;
+
+        // The first graphics submission resets profiler queries and performs the initial
+        // resource transitions, so all async work must start after it.
+        if queue == QueueAffinity::AsyncCompute
+            && planner.waited[queue.idx()].is_none()
+            && planner.open[queue.idx()].passes.is_empty()
+        {
+            let first_graphics = planner
+                .submissions
+                .iter()
+                .position(|s| s.queue == QueueAffinity::Graphics);
+            let first_graphics =
+                first_graphics.unwrap_or_else(|| planner.close(QueueAffinity::Graphics));
+            planner.wait(queue, first_graphics);
+        }
+
+        let mut transfers: Vec<(u32, usize)> = Vec::new();
+        for &resource in &pass.resources {
+            let owner = &mut owners[resource as usize];
+
+            if let Some((owner_queue, last_pass)) = *owner {
+                if owner_queue != queue && !transfers.iter().any(|(res, _)| *res == resource) {
+                    let src = match last_pass {
+                        Some(last_pass) => planner.close_submission_of(last_pass),
+                        None => {
+                            // Initially owned by the graphics queue; released by
+                            // the first graphics submission.
+                            debug_assert_eq!(owner_queue, QueueAffinity::Graphics);
+                            planner
+                                .submissions
+                                .iter()
+                                .position(|s| s.queue == owner_queue)
+                                .unwrap()
+                        }
+                    };
+                    transfers.push((resource, src));
+                }
+            }
+
+            *owner = Some((queue, Some(pass_idx)));
+        }
+
+        if !transfers.is_empty() {
+            // Let earlier passes on this queue start without waiting for the other queue.
+            if !planner.open[queue.idx()].passes.is_empty() {
+                planner.close(queue);
+            }
+
+            for (resource, src) in transfers {
+                planner.wait(queue, src);
+
+                let transfer = OwnershipTransfer {
+                    resource,
+                    src_queue: queue.other(),
+                    dst_queue: queue,
+                    next_pass: Some(pass_idx),
+                };
+                planner.submissions[src].releases.push(transfer);
+                planner.open[queue.idx()].acquires.push(transfer);
+            }
+        }
+
+        planner.open[queue.idx()].passes.push(pass_idx);
+        planner.pass_location[pass_idx] = PassLocation::Open(queue);
+    }
+
+    // Join: return resources to the graphics queue, and wait for all async work.
+    for (resource, owner) in owners.iter().enumerate() {
+        if let Some((QueueAffinity::AsyncCompute, Some(last_pass))) = *owner {
+            if return_to_graphics[resource] {
+                let src = planner.close_submission_of(last_pass);
+                let transfer = OwnershipTransfer {
+                    resource: resource as u32,
+                    src_queue: QueueAffinity::AsyncCompute,
+                    dst_queue: QueueAffinity::Graphics,
+                    next_pass: None,
+                };
+                planner.submissions[src].releases.push(transfer);
+                planner.open[QueueAffinity::Graphics.idx()]
+                    .acquires
+                    .push(transfer);
+            }
+        }
+    }
+
+    if !planner.open[QueueAffinity::AsyncCompute.idx()]
+        .passes
+        .is_empty()
+    {
+        planner.close(QueueAffinity::AsyncCompute);
+    }
+
+    if let Some(last_async) = planner.last_closed_on(QueueAffinity::AsyncCompute) {
+        planner.wait(QueueAffinity::Graphics, last_async);
+    }
+
+    let graphics_tail = &planner.open[QueueAffinity::Graphics.idx()];
+    if !graphics_tail.passes.is_empty()
+        || !graphics_tail.waits.is_empty()
+        || !graphics_tail.acquires.is_empty()
+    {
+        planner.close(QueueAffinity::Graphics);
+    }
+
+    let mut submissions = planner.submissions;
+    for idx in 0..submissions.len() {
+        for wait_idx in submissions[idx].waits.clone() {
+            submissions[wait_idx].signals = true;
+        }
+    }
+
+    QueueSchedule { submissions }
+}
+
+#[cfg(test)]
+fn pass(queue: QueueAffinity, resources: &[u32]) -> SchedulePass {
+    SchedulePass {
+        queue,
+        resources: resources.to_vec(),
+    }
+}
+
+#[cfg(test)]
+fn assert_valid_schedule(passes: &[SchedulePass], schedule: &QueueSchedule) {
+    let submissions = &schedule.submissions;
+    assert_eq!(submissions.first().unwrap().queue, QueueAffinity::Graphics);
+    assert_eq!(submissions.last().unwrap().queue, QueueAffinity::Graphics);
+
+    // Every pass is scheduled exactly once, on its own queue, and passes on one queue
+    // keep their relative order.
+    let mut seen = vec![false; passes.len()];
+    let mut last_on_queue = [None; 2];
+    for submission in submissions {
+        for &pass_idx in &submission.passes {
+            assert!(!seen[pass_idx]);
+            seen[pass_idx] = true;
+            assert_eq!(passes[pass_idx].queue, submission.queue);
+
+            let last = &mut last_on_queue[submission.queue.idx()];
+            assert!(*last < Some(pass_idx));
+            *last = Some(pass_idx);
+        }
+    }
+    assert!(seen.iter().all(|&seen| seen));
+
+    // Semaphores are signaled by earlier submissions on the other queue.
+    for (idx, submission) in submissions.iter().enumerate() {
+        for &wait in &submission.waits {
+            assert!(wait < idx);
+            assert_ne!(submissions[wait].queue, submission.queue);
+            assert!(submissions[wait].signals);
+        }
+    }
+}
+
+#[test]
+fn test_graphics_only_is_a_single_submission() {
+    let passes = [
+        pass(QueueAffinity::Graphics, &[0]),
+        pass(QueueAffinity::Graphics, &[0, 1]),
+    ];
+    let schedule = plan_queue_submissions(&passes, &[None, None], &[false, false]);
+
+    assert!(!schedule.is_split());
+    assert_eq!(schedule.submissions[0].passes, vec![0, 1]);
+}
+
+#[test]
+fn test_async_pass_waits_for_its_inputs() {
+    // 0: gfx writes r0
+    // 1: async reads r0, writes r1
+    // 2: gfx writes r2 (independent; overlaps with 1)
+    // 3: gfx reads r1 and r2
+    let passes = [
+        pass(QueueAffinity::Graphics, &[0]),
+        pass(QueueAffinity::AsyncCompute, &[0, 1]),
+        pass(QueueAffinity::Graphics, &[2]),
+        pass(QueueAffinity::Graphics, &[1, 2]),
+    ];
+    let schedule = plan_queue_submissions(&passes, &[None; 3], &[false; 3]);
+    assert_valid_schedule(&passes, &schedule);
+
+    let s = &schedule.submissions;
+    assert_eq!(s.len(), 4);
+    assert_eq!(s[0].passes, vec![0]);
+    assert_eq!(s[1].passes, vec![1]);
+    assert_eq!(s[1].waits, vec![0]);
+    assert_eq!(s[2].passes, vec![2]);
+    assert!(s[2].waits.is_empty());
+    assert_eq!(s[3].passes, vec![3]);
+    assert_eq!(s[3].waits, vec![1]);
+
+    // r0 moves to the compute queue, and r1 back to graphics.
+    assert_eq!(s[0].releases.len(), 1);
+    assert_eq!(s[0].releases[0].resource, 0);
+    assert_eq!(s[1].acquires, s[0].releases);
+    assert_eq!(s[1].releases.len(), 1);
+    assert_eq!(s[1].releases[0].resource, 1);
+    assert_eq!(s[1].releases[0].next_pass, Some(3));
+}
+
+#[test]
+fn test_async_work_is_joined_and_returned() {
+    // The async pass writes an imported resource which nothing reads afterwards.
+    let passes = [
+        pass(QueueAffinity::Graphics, &[0]),
+        pass(QueueAffinity::AsyncCompute, &[1]),
+        pass(QueueAffinity::Graphics, &[0]),
+    ];
+    let schedule = plan_queue_submissions(
+        &passes,
+        &[None, Some(QueueAffinity::Graphics)],
+        &[false, true],
+    );
+    assert_valid_schedule(&passes, &schedule);
+
+    let s = &schedule.submissions;
+    let last = s.last().unwrap();
+    let async_idx = s
+        .iter()
+        .position(|s| s.queue == QueueAffinity::AsyncCompute)
+        .unwrap();
+
+    assert!(last.waits.contains(&async_idx));
+
+    // Acquired from the initial graphics submission, and returned at the end.
+    assert_eq!(s[0].releases[0].resource, 1);
+    assert_eq!(s[async_idx].releases[0].resource, 1);
+    assert_eq!(s[async_idx].releases[0].next_pass, None);
+    assert_eq!(last.acquires, s[async_idx].releases);
+}
+
+#[test]
+fn test_ping_pong_between_queues() {
+    let passes = [
+        pass(QueueAffinity::AsyncCompute, &[0]),
+        pass(QueueAffinity::Graphics, &[0]),
+        pass(QueueAffinity::AsyncCompute, &[0]),
+        pass(QueueAffinity::Graphics, &[0, 1]),
+        pass(QueueAffinity::AsyncCompute, &[2]),
+    ];
+    let schedule = plan_queue_submissions(&passes, &[None; 3], &[false; 3]);
+    assert_valid_schedule(&passes, &schedule);
+
+    // Each hand-over of r0 needs its own pair of submissions.
+    let transfers: usize = schedule
+        .submissions
+        .iter()
+        .map(|s| s.acquires.len())
+        .sum();
+    assert_eq!(transfers, 3);
+}
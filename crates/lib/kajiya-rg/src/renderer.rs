@@ -8,9 +8,10 @@ use kajiya_backend::{
     dynamic_constants::*,
     pipeline_cache::*,
     rspirv_reflect,
+    shader_compiler::ShaderDiagnostic,
     transient_resource_cache::TransientResourceCache,
     vk_sync,
-    vulkan::{self, swapchain::Swapchain, RenderBackend},
+    vulkan::{self, offscreen::OffscreenTarget, swapchain::Swapchain, RenderBackend},
     Device,
 };
 #[allow(unused_imports)]
@@ -30,6 +31,12 @@ impl Default for TemporalRg {
     }
 }
 
+/// Where the frame ends up: presented via a swapchain, or read back from an offscreen image.
+enum FrameTarget<'a> {
+    Swapchain(&'a mut Swapchain),
+    Offscreen(&'a OffscreenTarget),
+}
+
 pub struct Renderer {
     device: Arc<Device>,
 
@@ -119,6 +126,28 @@ impl Renderer {
         swapchain: &mut Swapchain,
     ) where
         PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
+    {
+        self.draw_frame_to(prepare_frame_constants, FrameTarget::Swapchain(swapchain));
+    }
+
+    /// Like `draw_frame`, but renders into `target` instead of a swapchain image,
+    /// and records a copy of the result for `OffscreenTarget::read_pixels`.
+    pub fn draw_frame_offscreen<PrepareFrameConstantsFn>(
+        &mut self,
+        prepare_frame_constants: PrepareFrameConstantsFn,
+        target: &OffscreenTarget,
+    ) where
+        PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
+    {
+        self.draw_frame_to(prepare_frame_constants, FrameTarget::Offscreen(target));
+    }
+
+    fn draw_frame_to<PrepareFrameConstantsFn>(
+        &mut self,
+        prepare_frame_constants: PrepareFrameConstantsFn,
+        mut target: FrameTarget,
+    ) where
+        PrepareFrameConstantsFn: FnOnce(&mut DynamicConstants) -> FrameConstantsLayout,
     {
         let rg = if let Some(rg) = self.compiled_rg.take() {
             rg
@@ -180,46 +209,68 @@ impl Renderer {
                 )
             };
 
-            // Record and submit the main command buffer
-            unsafe {
-                puffin::profile_scope!("main cb");
-
-                {
-                    puffin::profile_scope!("rg::record_main_cb");
-                    executing_rg.record_main_cb(main_cb)
-                }
+            // With async compute, the graph splits the main work into multiple submissions,
+            // and submits them itself.
+            if executing_rg.is_split_across_queues() {
+                puffin::profile_scope!("rg::record_and_submit_main");
+                executing_rg.record_and_submit_main(main_cb, &current_frame);
+            } else {
+                // Record and submit the main command buffer
+                unsafe {
+                    puffin::profile_scope!("main cb");
+
+                    {
+                        puffin::profile_scope!("rg::record_main_cb");
+                        executing_rg.record_main_cb(main_cb)
+                    }
 
-                raw_device.end_command_buffer(main_cb.raw).unwrap();
+                    raw_device.end_command_buffer(main_cb.raw).unwrap();
 
-                let submit_info = [vk::SubmitInfo::builder()
-                    .command_buffers(std::slice::from_ref(&main_cb.raw))
-                    .build()];
+                    let submit_info = [vk::SubmitInfo::builder()
+                        .command_buffers(std::slice::from_ref(&main_cb.raw))
+                        .build()];
 
-                raw_device
-                    .reset_fences(std::slice::from_ref(&main_cb.submit_done_fence))
-                    .expect("reset_fences");
+                    raw_device
+                        .reset_fences(std::slice::from_ref(&main_cb.submit_done_fence))
+                        .expect("reset_fences");
 
-                puffin::profile_scope!("submit main cb");
+                    puffin::profile_scope!("submit main cb");
 
-                // Try to submit the command buffer to the GPU. We might encounter a GPU crash.
-                raw_device
-                    .queue_submit(
-                        self.device.universal_queue.raw,
-                        &submit_info,
-                        main_cb.submit_done_fence,
-                    )
-                    .map_err(|err| device.report_error(err.into()))
-                    .expect("main queue_submit failed");
-            };
+                    // Try to submit the command buffer to the GPU. We might encounter a GPU crash.
+                    raw_device
+                        .queue_submit(
+                            self.device.universal_queue.raw,
+                            &submit_info,
+                            main_cb.submit_done_fence,
+                        )
+                        .map_err(|err| device.report_error(err.into()))
+                        .expect("main queue_submit failed");
+                };
+            }
         }
 
         // Now that we've done the main submission and the GPU is busy, acquire the presentation image.
         // This can block, so we're doing it as late as possible.
 
-        let swapchain_image = swapchain
-            .acquire_next_image()
-            .ok()
-            .expect("swapchain image");
+        let (output_image, output_access, swapchain_image) = match &mut target {
+            FrameTarget::Swapchain(swapchain) => {
+                let swapchain_image = swapchain
+                    .acquire_next_image()
+                    .ok()
+                    .expect("swapchain image");
+
+                (
+                    swapchain_image.image.clone(),
+                    vk_sync::AccessType::Present,
+                    Some(swapchain_image),
+                )
+            }
+            FrameTarget::Offscreen(target) => (
+                target.image.clone(),
+                OffscreenTarget::ACCESS_AFTER_READBACK,
+                None,
+            ),
+        };
 
         // Execute the rest of the render graph, and submit the presentation command buffer.
         let retired_rg = {
@@ -227,13 +278,13 @@ impl Renderer {
 
             let presentation_cb = &current_frame.presentation_command_buffer;
 
-            // Transition the swapchain to CS write
+            // Transition the output image to CS write
             vulkan::barrier::record_image_barrier(
                 device,
                 presentation_cb.raw,
                 vulkan::barrier::ImageBarrier::new(
-                    swapchain_image.image.raw,
-                    vk_sync::AccessType::Present,
+                    output_image.raw,
+                    output_access,
                     vk_sync::AccessType::ComputeShaderWrite,
                     vk::ImageAspectFlags::COLOR,
                 )
@@ -241,19 +292,22 @@ impl Renderer {
             );
 
             let retired_rg =
-                executing_rg.record_presentation_cb(presentation_cb, swapchain_image.image.clone());
-
-            // Transition the swapchain to present
-            vulkan::barrier::record_image_barrier(
-                device,
-                presentation_cb.raw,
-                vulkan::barrier::ImageBarrier::new(
-                    swapchain_image.image.raw,
-                    vk_sync::AccessType::ComputeShaderWrite,
-                    vk_sync::AccessType::Present,
-                    vk::ImageAspectFlags::COLOR,
+                executing_rg.record_presentation_cb(presentation_cb, output_image.clone());
+
+            match &target {
+                // Transition the swapchain to present
+                FrameTarget::Swapchain(_) => vulkan::barrier::record_image_barrier(
+                    device,
+                    presentation_cb.raw,
+                    vulkan::barrier::ImageBarrier::new(
+                        output_image.raw,
+                        vk_sync::AccessType::ComputeShaderWrite,
+                        vk_sync::AccessType::Present,
+                        vk::ImageAspectFlags::COLOR,
+                    ),
                 ),
-            );
+                FrameTarget::Offscreen(target) => target.record_readback(presentation_cb),
+            }
 
             current_frame
                 .profiler_data
@@ -263,12 +317,20 @@ impl Renderer {
             unsafe {
                 raw_device.end_command_buffer(presentation_cb.raw).unwrap();
 
+                let (wait_semaphores, signal_semaphores) = match &swapchain_image {
+                    Some(swapchain_image) => (
+                        vec![swapchain_image.acquire_semaphore],
+                        vec![swapchain_image.rendering_finished_semaphore],
+                    ),
+                    None => (vec![], vec![]),
+                };
+                let wait_stages =
+                    vec![vk::PipelineStageFlags::COMPUTE_SHADER; wait_semaphores.len()];
+
                 let submit_info = [vk::SubmitInfo::builder()
-                    .wait_semaphores(std::slice::from_ref(&swapchain_image.acquire_semaphore))
-                    .signal_semaphores(std::slice::from_ref(
-                        &swapchain_image.rendering_finished_semaphore,
-                    ))
-                    .wait_dst_stage_mask(&[vk::PipelineStageFlags::COMPUTE_SHADER])
+                    .wait_semaphores(&wait_semaphores)
+                    .signal_semaphores(&signal_semaphores)
+                    .wait_dst_stage_mask(&wait_stages)
                     .command_buffers(std::slice::from_ref(&presentation_cb.raw))
                     .build()];
                 raw_device
@@ -286,7 +348,11 @@ impl Renderer {
                     .expect("presentation queue_submit failed");
             }
 
-            swapchain.present_image(swapchain_image);
+            if let (FrameTarget::Swapchain(swapchain), Some(swapchain_image)) =
+                (target, swapchain_image)
+            {
+                swapchain.present_image(swapchain_image);
+            }
 
             retired_rg
         };
@@ -299,6 +365,7 @@ impl Renderer {
         };
 
         retired_rg.release_resources(&mut self.transient_resource_cache);
+        self.transient_resource_cache.advance_frame(&self.device);
 
         self.dynamic_constants.advance_frame();
         self.device.finish_frame(current_frame);
@@ -501,4 +568,9 @@ impl Renderer {
     pub fn device(&self) -> &Arc<Device> {
         &self.device
     }
+
+    /// Pipelines whose latest build failed; see `PipelineCache::failures`
+    pub fn pipeline_failures(&self) -> Vec<ShaderDiagnostic> {
+        self.pipeline_cache.failures()
+    }
 }
@@ -0,0 +1,231 @@
+//! Packing of transient render graph images into shared memory blocks.
+//!
+//! Resources whose pass lifetimes don't overlap can occupy the same memory. The packer
+//! here is oblivious to Vulkan objects; it only sees sizes, alignments, memory type masks
+//! and the `[first_pass, last_pass]` intervals computed by the graph compiler.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AliasableResource {
+    pub size: u64,
+    pub alignment: u64,
+    pub memory_type_bits: u32,
+    pub first_pass: usize,
+    pub last_pass: usize,
+}
+
+impl AliasableResource {
+    fn overlaps_in_time(&self, other: &Self) -> bool {
+        self.first_pass <= other.last_pass && other.first_pass <= self.last_pass
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AliasedPlacement {
+    pub block: usize,
+    pub offset: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AliasedMemoryBlock {
+    pub size: u64,
+    pub alignment: u64,
+    pub memory_type_bits: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AliasingLayout {
+    pub blocks: Vec<AliasedMemoryBlock>,
+
+    /// One entry per input resource, in the input order.
+    pub placements: Vec<AliasedPlacement>,
+
+    /// Sum of the sizes of all packed resources; what dedicated allocations would cost.
+    pub resource_bytes: u64,
+}
+
+impl AliasingLayout {
+    pub fn allocated_bytes(&self) -> u64 {
+        self.blocks.iter().map(|block| block.size).sum()
+    }
+
+    pub fn bytes_saved(&self) -> u64 {
+        self.resource_bytes.saturating_sub(self.allocated_bytes())
+    }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    let alignment = alignment.max(1);
+    value.div_ceil(alignment) * alignment
+}
+
+/// Greedy first-fit packing. Resources are visited from largest to smallest; each one is placed
+/// at the lowest offset of the first compatible block where it doesn't collide with any
+/// resource that is alive at the same time. Blocks never grow after creation, so a block is
+/// as large as the first (largest) resource placed in it.
+pub fn pack_aliased_resources(resources: &[AliasableResource]) -> AliasingLayout {
+    let mut order: Vec<usize> = (0..resources.len()).collect();
+    order.sort_by(|&a, &b| {
+        resources[b]
+            .size
+            .cmp(&resources[a].size)
+            .then(resources[a].first_pass.cmp(&resources[b].first_pass))
+    });
+
+    let mut blocks: Vec<AliasedMemoryBlock> = Vec::new();
+    let mut block_residents: Vec<Vec<usize>> = Vec::new();
+    let mut placements: Vec<Option<AliasedPlacement>> = vec![None; resources.len()];
+
+    for res_idx in order {
+        let res = &resources[res_idx];
+        let mut found: Option<AliasedPlacement> = None;
+
+        for (block_idx, block) in blocks.iter().enumerate() {
+            if block.memory_type_bits & res.memory_type_bits == 0 || block.size < res.size {
+                continue;
+            }
+
+            // Address ranges of everything in this block that is alive at the same time.
+            let mut occupied: Vec<(u64, u64)> = block_residents[block_idx]
+                .iter()
+                .copied()
+                .filter(|&other| resources[other].overlaps_in_time(res))
+                .map(|other| {
+                    let offset = placements[other].unwrap().offset;
+                    (offset, offset + resources[other].size)
+                })
+                .collect();
+            occupied.sort_unstable();
+
+            let mut candidate = 0u64;
+            for (begin, end) in occupied {
+                if candidate + res.size <= begin {
+                    break;
+                }
+                candidate = align_up(candidate.max(end), res.alignment);
+            }
+
+            if candidate + res.size <= block.size {
+                found = Some(AliasedPlacement {
+                    block: block_idx,
+                    offset: candidate,
+                });
+                break;
+            }
+        }
+
+        let placement = found.unwrap_or_else(|| {
+            blocks.push(AliasedMemoryBlock {
+                size: res.size,
+                alignment: res.alignment,
+                memory_type_bits: res.memory_type_bits,
+            });
+            block_residents.push(Vec::new());
+
+            AliasedPlacement {
+                block: blocks.len() - 1,
+                offset: 0,
+            }
+        });
+
+        let block = &mut blocks[placement.block];
+        block.memory_type_bits &= res.memory_type_bits;
+        block.alignment = block.alignment.max(res.alignment);
+
+        block_residents[placement.block].push(res_idx);
+        placements[res_idx] = Some(placement);
+    }
+
+    AliasingLayout {
+        blocks,
+        placements: placements.into_iter().map(Option::unwrap).collect(),
+        resource_bytes: resources.iter().map(|res| res.size).sum(),
+    }
+}
+
+#[cfg(test)]
+fn res(size: u64, first_pass: usize, last_pass: usize) -> AliasableResource {
+    AliasableResource {
+        size,
+        alignment: 256,
+        memory_type_bits: !0,
+        first_pass,
+        last_pass,
+    }
+}
+
+#[cfg(test)]
+fn assert_no_collisions(resources: &[AliasableResource], layout: &AliasingLayout) {
+    for (a_idx, a) in resources.iter().enumerate() {
+        let a_place = layout.placements[a_idx];
+        assert_eq!(a_place.offset % a.alignment, 0);
+        assert!(a_place.offset + a.size <= layout.blocks[a_place.block].size);
+
+        for (b_idx, b) in resources.iter().enumerate().skip(a_idx + 1) {
+            let b_place = layout.placements[b_idx];
+            if a_place.block != b_place.block || !a.overlaps_in_time(b) {
+                continue;
+            }
+
+            let disjoint = a_place.offset + a.size <= b_place.offset
+                || b_place.offset + b.size <= a_place.offset;
+            assert!(disjoint, "resources {} and {} collide", a_idx, b_idx);
+        }
+    }
+}
+
+#[test]
+fn test_disjoint_lifetimes_share_memory() {
+    let resources = [res(1024, 0, 1), res(1024, 2, 3), res(512, 4, 5)];
+    let layout = pack_aliased_resources(&resources);
+
+    assert_no_collisions(&resources, &layout);
+    assert_eq!(layout.blocks.len(), 1);
+    assert_eq!(layout.allocated_bytes(), 1024);
+    assert_eq!(layout.bytes_saved(), 1536);
+}
+
+#[test]
+fn test_overlapping_lifetimes_get_separate_ranges() {
+    let resources = [res(1024, 0, 2), res(1024, 1, 3), res(256, 3, 4)];
+    let layout = pack_aliased_resources(&resources);
+
+    assert_no_collisions(&resources, &layout);
+    assert_eq!(layout.allocated_bytes(), 2048);
+    assert_eq!(layout.bytes_saved(), 256);
+}
+
+#[test]
+fn test_small_resources_fill_gaps_in_larger_blocks() {
+    let resources = [
+        res(4096, 0, 1),
+        res(1024, 2, 5),
+        res(1024, 3, 6),
+        res(1024, 4, 7),
+    ];
+    let layout = pack_aliased_resources(&resources);
+
+    assert_no_collisions(&resources, &layout);
+    assert_eq!(layout.blocks.len(), 1);
+    assert_eq!(layout.allocated_bytes(), 4096);
+}
+
+#[test]
+fn test_incompatible_memory_types_are_not_aliased() {
+    let mut resources = [res(1024, 0, 1), res(1024, 2, 3)];
+    resources[0].memory_type_bits = 0b01;
+    resources[1].memory_type_bits = 0b10;
+    let layout = pack_aliased_resources(&resources);
+
+    assert_eq!(layout.blocks.len(), 2);
+    assert_eq!(layout.bytes_saved(), 0);
+}
+
+#[test]
+fn test_alignment_is_respected() {
+    let mut resources = [res(1000, 0, 3), res(1000, 1, 2), res(1000, 2, 3)];
+    resources[1].alignment = 4096;
+    let layout = pack_aliased_resources(&resources);
+
+    assert_no_collisions(&resources, &layout);
+    assert_eq!(layout.blocks[layout.placements[1].block].alignment, 4096);
+}
@@ -22,6 +22,12 @@ pub struct PendingRenderResourceInfo {
 
 pub enum AnyRenderResource {
     OwnedImage(Image),
+    // Owned image bound to memory shared with other transient images.
+    PlacedImage {
+        image: Image,
+        memory: vk::DeviceMemory,
+        offset: u64,
+    },
     ImportedImage(Arc<Image>),
     OwnedBuffer(Buffer),
     ImportedBuffer(Arc<Buffer>),
@@ -29,6 +35,9 @@ pub enum AnyRenderResource {
 
     // Must be replaced before access. Used to late-update swapchain resources.
     Pending(PendingRenderResourceInfo),
+
+    // Created by the graph, but only accessed by culled passes.
+    Unused,
 }
 
 impl AnyRenderResource {
@@ -36,6 +45,7 @@ impl AnyRenderResource {
     pub fn borrow(&self) -> AnyRenderResourceRef {
         match self {
             AnyRenderResource::OwnedImage(inner) => AnyRenderResourceRef::Image(inner),
+            AnyRenderResource::PlacedImage { image, .. } => AnyRenderResourceRef::Image(image),
             AnyRenderResource::ImportedImage(inner) => AnyRenderResourceRef::Image(inner.as_ref()),
             AnyRenderResource::OwnedBuffer(inner) => AnyRenderResourceRef::Buffer(inner),
             AnyRenderResource::ImportedBuffer(inner) => {
@@ -47,6 +57,9 @@ impl AnyRenderResource {
             AnyRenderResource::Pending { .. } => {
                 panic!("AnyRenderResource::borrow called while the resource was in Pending state")
             }
+            AnyRenderResource::Unused => {
+                panic!("AnyRenderResource::borrow called on a resource only used by culled passes")
+            }
         }
     }
 }
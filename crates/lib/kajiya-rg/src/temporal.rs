@@ -171,6 +171,9 @@ impl GetOrCreateTemporal<ImageDesc> for TemporalRenderGraph {
                         match &resource {
                             TemporalResource::Image(image) => {
                                 let handle = self.rg.import(image.clone(), *access_type);
+                                self.rg
+                                    .temporal_resource_keys
+                                    .insert(handle.raw.id, key.0.clone());
 
                                 *state = TemporalResourceState::Imported {
                                     resource,
@@ -206,6 +209,9 @@ impl GetOrCreateTemporal<ImageDesc> for TemporalRenderGraph {
                         .with_context(|| format!("Creating image {:?}", desc))?,
                 );
                 let handle = self.rg.import(resource.clone(), AccessType::Nothing);
+                self.rg
+                    .temporal_resource_keys
+                    .insert(handle.raw.id, key.0.clone());
                 entry.insert(TemporalResourceState::Imported {
                     resource: TemporalResource::Image(resource),
                     handle: ExportableGraphResource::Image(handle.clone_unchecked()),
@@ -239,6 +245,9 @@ impl GetOrCreateTemporal<BufferDesc> for TemporalRenderGraph {
                         match &resource {
                             TemporalResource::Buffer(buffer) => {
                                 let handle = self.rg.import(buffer.clone(), *access_type);
+                                self.rg
+                                    .temporal_resource_keys
+                                    .insert(handle.raw.id, key.0.clone());
 
                                 *state = TemporalResourceState::Imported {
                                     resource,
@@ -274,6 +283,9 @@ impl GetOrCreateTemporal<BufferDesc> for TemporalRenderGraph {
                     Some(vec![0; desc.size].as_slice()),
                 )?);
                 let handle = self.rg.import(resource.clone(), AccessType::Nothing);
+                self.rg
+                    .temporal_resource_keys
+                    .insert(handle.raw.id, key.0.clone());
                 entry.insert(TemporalResourceState::Imported {
                     resource: TemporalResource::Buffer(resource),
                     handle: ExportableGraphResource::Buffer(handle.clone_unchecked()),
@@ -0,0 +1,175 @@
+use kajiya::{
+    backend::{
+        vulkan::{offscreen::OffscreenTarget, RenderBackendConfig},
+        *,
+    },
+    frame_desc::WorldFrameDesc,
+    ui_renderer::UiRenderer,
+    world_renderer::WorldRenderer,
+};
+use turbosloth::*;
+
+use crate::{main_loop::prepare_frame_graph, SimpleMainLoopBuilder};
+
+pub struct HeadlessFrameContext<'a> {
+    pub frame_index: usize,
+
+    /// Fixed time step; see `HeadlessMainLoop::frame_dt`
+    pub dt: f32,
+    pub render_extent: [u32; 2],
+    pub world_renderer: &'a mut WorldRenderer,
+}
+
+impl<'a> HeadlessFrameContext<'a> {
+    pub fn aspect_ratio(&self) -> f32 {
+        self.render_extent[0] as f32 / self.render_extent[1] as f32
+    }
+}
+
+/// A rendered frame, read back to the CPU.
+pub struct HeadlessFrame {
+    pub frame_index: usize,
+    pub extent: [u32; 2],
+
+    /// Tightly packed RGBA8 pixels, top row first
+    pub pixels: Vec<u8>,
+}
+
+/// Renders without a window or display, into an offscreen image which is read back
+/// after every frame. Meant for batch jobs, CI, and render farm nodes.
+pub struct HeadlessMainLoop {
+    pub world_renderer: WorldRenderer,
+    ui_renderer: UiRenderer,
+
+    render_backend: RenderBackend,
+    rg_renderer: kajiya::rg::renderer::Renderer,
+    output: OffscreenTarget,
+    render_extent: [u32; 2],
+    frame_dt: f32,
+}
+
+impl HeadlessMainLoop {
+    pub(crate) fn build(builder: SimpleMainLoopBuilder) -> anyhow::Result<Self> {
+        kajiya::logging::set_up_logging(builder.default_log_level)?;
+        std::env::set_var("SMOL_THREADS", "64"); // HACK; TODO: get a real executor
+
+        let render_extent = builder.render_extent();
+        let output_extent = builder.resolution;
+
+        log::info!(
+            "Headless rendering extent: {}x{}, output extent: {}x{}",
+            render_extent[0],
+            render_extent[1],
+            output_extent[0],
+            output_extent[1]
+        );
+
+        let render_backend = RenderBackend::new_headless(RenderBackendConfig {
+            swapchain_extent: output_extent,
+            vsync: false,
+            graphics_debugging: builder.graphics_debugging,
+            device_index: builder.physical_device_index,
+        })?;
+
+        let output = OffscreenTarget::new(&render_backend.device, output_extent)?;
+
+        let lazy_cache = LazyCache::create();
+        let world_renderer =
+            WorldRenderer::new(render_extent, output_extent, &render_backend, &lazy_cache)?;
+        let rg_renderer = kajiya::rg::renderer::Renderer::new(&render_backend)?;
+
+        Ok(Self {
+            world_renderer,
+            ui_renderer: UiRenderer::default(),
+            render_backend,
+            rg_renderer,
+            output,
+            render_extent,
+            frame_dt: 1.0 / 60.0,
+        })
+    }
+
+    /// Time step passed to the renderer and to `HeadlessFrameContext`.
+    /// Frames are not paced in real time, so this is what drives animation. Defaults to 1/60s.
+    pub fn frame_dt(mut self, frame_dt: f32) -> Self {
+        self.frame_dt = frame_dt;
+        self
+    }
+
+    pub fn output_extent(&self) -> [u32; 2] {
+        self.output.extent()
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        let [width, height] = self.output_extent();
+        width as f32 / height as f32
+    }
+
+    /// Renders `frame_count` frames. `frame_fn` describes each frame, and `output_fn`
+    /// receives the resulting pixels.
+    ///
+    /// Unlike the windowed loop, errors (such as shader compilation failures) end the run.
+    pub fn run<FrameFn, OutputFn>(
+        self,
+        frame_count: usize,
+        mut frame_fn: FrameFn,
+        mut output_fn: OutputFn,
+    ) -> anyhow::Result<()>
+    where
+        FrameFn: FnMut(HeadlessFrameContext) -> WorldFrameDesc,
+        OutputFn: FnMut(HeadlessFrame) -> anyhow::Result<()>,
+    {
+        let HeadlessMainLoop {
+            mut world_renderer,
+            mut ui_renderer,
+            render_backend: _render_backend,
+            mut rg_renderer,
+            output,
+            render_extent,
+            frame_dt,
+        } = self;
+
+        let output_extent = output.extent();
+
+        for frame_index in 0..frame_count {
+            gpu_profiler::profiler().begin_frame();
+
+            let frame_desc = frame_fn(HeadlessFrameContext {
+                frame_index,
+                dt: frame_dt,
+                render_extent,
+                world_renderer: &mut world_renderer,
+            });
+
+            rg_renderer.prepare_frame(|rg| {
+                prepare_frame_graph(
+                    rg,
+                    &mut world_renderer,
+                    &mut ui_renderer,
+                    &frame_desc,
+                    output_extent,
+                )
+            })?;
+
+            rg_renderer.draw_frame_offscreen(
+                |dynamic_constants| {
+                    world_renderer.prepare_frame_constants(dynamic_constants, &frame_desc, frame_dt)
+                },
+                &output,
+            );
+            world_renderer.retire_frame();
+
+            gpu_profiler::profiler().end_frame();
+
+            output_fn(HeadlessFrame {
+                frame_index,
+                extent: output_extent,
+                pixels: output.read_pixels()?,
+            })?;
+        }
+
+        world_renderer.finish_frame_captures();
+
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::VecDeque;
 
+use crate::HeadlessMainLoop;
 use kajiya::{
     backend::{vulkan::RenderBackendConfig, *},
     frame_desc::WorldFrameDesc,
@@ -26,6 +27,9 @@ pub struct FrameContext<'a> {
     pub events: &'a [Event<'static, ()>],
     pub world_renderer: &'a mut WorldRenderer,
     pub window: &'a winit::window::Window,
+    /// Pipelines whose latest build failed, as of the previous frame.
+    /// Rendering continues with their last good versions.
+    pub pipeline_failures: &'a [shader_compiler::ShaderDiagnostic],
 
     #[cfg(feature = "dear-imgui")]
     pub imgui: Option<ImguiContext<'a>>,
@@ -84,12 +88,12 @@ pub enum FullscreenMode {
 }
 
 pub struct SimpleMainLoopBuilder {
-    resolution: [u32; 2],
+    pub(crate) resolution: [u32; 2],
     vsync: bool,
     fullscreen: Option<FullscreenMode>,
-    graphics_debugging: bool,
-    physical_device_index: Option<usize>,
-    default_log_level: log::LevelFilter,
+    pub(crate) graphics_debugging: bool,
+    pub(crate) physical_device_index: Option<usize>,
+    pub(crate) default_log_level: log::LevelFilter,
     window_scale: WindowScale,
     temporal_upsampling: f32,
 }
@@ -161,6 +165,20 @@ impl SimpleMainLoopBuilder {
     pub fn build(self, window_builder: WindowBuilder) -> anyhow::Result<SimpleMainLoop> {
         SimpleMainLoop::build(self, window_builder)
     }
+
+    /// Builds a main loop which renders offscreen at `resolution`, without a window
+    /// or a swapchain. Window-related options are ignored.
+    pub fn build_headless(self) -> anyhow::Result<HeadlessMainLoop> {
+        HeadlessMainLoop::build(self)
+    }
+
+    // The internal rendering resolution
+    pub(crate) fn render_extent(&self) -> [u32; 2] {
+        [
+            (self.resolution[0] as f32 / self.temporal_upsampling) as u32,
+            (self.resolution[1] as f32 / self.temporal_upsampling) as u32,
+        ]
+    }
 }
 
 pub struct SimpleMainLoop {
@@ -219,10 +237,7 @@ impl SimpleMainLoop {
         let swapchain_extent = [window.inner_size().width, window.inner_size().height];
 
         // Find the internal rendering resolution
-        let render_extent = [
-            (builder.resolution[0] as f32 / builder.temporal_upsampling) as u32,
-            (builder.resolution[1] as f32 / builder.temporal_upsampling) as u32,
-        ];
+        let render_extent = builder.render_extent();
 
         log::info!(
             "Internal rendering extent: {}x{}",
@@ -419,12 +434,15 @@ impl SimpleMainLoop {
                 }
             };
 
+            let pipeline_failures = rg_renderer.pipeline_failures();
+
             let frame_desc = frame_fn(FrameContext {
                 dt_filtered,
                 render_extent,
                 events: &events,
                 world_renderer: &mut world_renderer,
                 window: &window,
+                pipeline_failures: &pipeline_failures,
 
                 #[cfg(feature = "dear-imgui")]
                 imgui: Some(ImguiContext {
@@ -444,28 +462,13 @@ impl SimpleMainLoop {
             let prepared_frame = {
                 puffin::profile_scope!("prepare_frame");
                 rg_renderer.prepare_frame(|rg| {
-                    rg.debug_hook = world_renderer.rg_debug_hook.take();
-                    let main_img = world_renderer.prepare_render_graph(rg, &frame_desc);
-                    let ui_img = ui_renderer.prepare_render_graph(rg);
-
-                    let mut swap_chain = rg.get_swap_chain();
-                    rg::SimpleRenderPass::new_compute(
-                        rg.add_pass("final blit"),
-                        "/shaders/final_blit.hlsl",
+                    prepare_frame_graph(
+                        rg,
+                        &mut world_renderer,
+                        &mut ui_renderer,
+                        &frame_desc,
+                        swapchain_extent,
                     )
-                    .read(&main_img)
-                    .read(&ui_img)
-                    .write(&mut swap_chain)
-                    .constants((
-                        main_img.desc().extent_inv_extent_2d(),
-                        [
-                            swapchain_extent[0] as f32,
-                            swapchain_extent[1] as f32,
-                            1.0 / swapchain_extent[0] as f32,
-                            1.0 / swapchain_extent[1] as f32,
-                        ],
-                    ))
-                    .dispatch([swapchain_extent[0], swapchain_extent[1], 1]);
                 })
             };
 
@@ -480,7 +483,10 @@ impl SimpleMainLoop {
                                 dt_filtered,
                             )
                         },
-                        &mut render_backend.swapchain,
+                        render_backend
+                            .swapchain
+                            .as_mut()
+                            .expect("windowed backend must have a swapchain"),
                     );
                     world_renderer.retire_frame();
                     last_error_text = None;
@@ -503,3 +509,32 @@ impl SimpleMainLoop {
         Ok(())
     }
 }
+
+/// Renders the world and the UI, and composites them into the swapchain image of the graph.
+pub(crate) fn prepare_frame_graph(
+    rg: &mut rg::TemporalRenderGraph,
+    world_renderer: &mut WorldRenderer,
+    ui_renderer: &mut UiRenderer,
+    frame_desc: &WorldFrameDesc,
+    output_extent: [u32; 2],
+) {
+    rg.debug_hook = world_renderer.rg_debug_hook.take();
+    let main_img = world_renderer.prepare_render_graph(rg, frame_desc);
+    let ui_img = ui_renderer.prepare_render_graph(rg);
+
+    let mut swap_chain = rg.get_swap_chain();
+    rg::SimpleRenderPass::new_compute(rg.add_pass("final blit"), "/shaders/final_blit.hlsl")
+        .read(&main_img)
+        .read(&ui_img)
+        .write(&mut swap_chain)
+        .constants((
+            main_img.desc().extent_inv_extent_2d(),
+            [
+                output_extent[0] as f32,
+                output_extent[1] as f32,
+                1.0 / output_extent[0] as f32,
+                1.0 / output_extent[1] as f32,
+            ],
+        ))
+        .dispatch([output_extent[0], output_extent[1], 1]);
+}
@@ -33,6 +33,7 @@ impl WorldRenderer {
                         use_mips: false,
                         compression: kajiya_asset::mesh::TexCompressionMode::None,
                         channel_swizzle: None,
+                        max_size: kajiya_asset::mesh::DEFAULT_TEX_MAX_SIZE,
                     },
                     device: backend.device.clone(),
                 }
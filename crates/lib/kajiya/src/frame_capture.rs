@@ -0,0 +1,365 @@
+use anyhow::Context as _;
+use kajiya_backend::{
+    ash::vk,
+    vk_sync::AccessType,
+    vulkan::{
+        buffer::{Buffer, BufferDesc},
+        device::Device,
+        image::*,
+    },
+};
+use kajiya_rg::{self as rg, SimpleRenderPass};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// The render graph resource a frame capture reads from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureSource {
+    /// Post-processed, tone-mapped image, before the UI is composited over it
+    Final,
+
+    /// The HDR lighting accumulation target (`root.accum`, or `refpt.accum` in reference mode)
+    HdrAccumulation,
+
+    /// Whatever is currently selected by `WorldRenderer::rg_debug_hook`
+    DebugTarget,
+}
+
+impl CaptureSource {
+    pub fn default_format(&self) -> CaptureFileFormat {
+        match self {
+            CaptureSource::Final => CaptureFileFormat::Png,
+            CaptureSource::HdrAccumulation | CaptureSource::DebugTarget => CaptureFileFormat::Exr,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureFileFormat {
+    /// 8-bit sRGB; values are clamped to [0, 1]
+    Png,
+
+    /// 32-bit float linear RGBA
+    Exr,
+}
+
+impl CaptureFileFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            CaptureFileFormat::Png => "png",
+            CaptureFileFormat::Exr => "exr",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FrameCaptureRequest {
+    pub source: CaptureSource,
+
+    /// Output path; the extension is replaced with one matching the format
+    pub path: PathBuf,
+
+    /// Appended to the file name as `_00042` when set, for capturing sequences
+    pub frame_number: Option<u64>,
+
+    /// Defaults to `CaptureSource::default_format` when `None`
+    pub format: Option<CaptureFileFormat>,
+}
+
+impl FrameCaptureRequest {
+    pub fn new(source: CaptureSource, path: impl Into<PathBuf>) -> Self {
+        Self {
+            source,
+            path: path.into(),
+            frame_number: None,
+            format: None,
+        }
+    }
+
+    pub fn frame_number(mut self, frame_number: u64) -> Self {
+        self.frame_number = Some(frame_number);
+        self
+    }
+
+    pub fn format(mut self, format: CaptureFileFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn resolved_format(&self) -> CaptureFileFormat {
+        self.format.unwrap_or_else(|| self.source.default_format())
+    }
+
+    pub fn output_path(&self) -> PathBuf {
+        let mut file_name = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "capture".to_owned());
+
+        if let Some(frame_number) = self.frame_number {
+            file_name += &format!("_{:05}", frame_number);
+        }
+
+        file_name += ".";
+        file_name += self.resolved_format().extension();
+
+        self.path.with_file_name(file_name)
+    }
+}
+
+/// Images a frame capture can be sourced from, as produced by the world renderer's graph.
+pub(crate) struct CaptureSources<'a> {
+    pub final_image: &'a rg::Handle<Image>,
+    pub hdr_accumulation: &'a rg::Handle<Image>,
+    pub debug_target: Option<&'a rg::Handle<Image>>,
+}
+
+struct RecordedCapture {
+    request: FrameCaptureRequest,
+    extent: [u32; 2],
+    buffer: Arc<Buffer>,
+}
+
+struct InFlightCapture {
+    capture: RecordedCapture,
+    submitted_frame: u64,
+}
+
+// Matches the number of frames the device keeps in flight; once `Device::begin_frame`
+// has been called this many times after a submission, the GPU is done with it.
+const FRAMES_IN_FLIGHT: u64 = 2;
+
+/// Copies render graph images into host-visible buffers, and writes them to disk
+/// once the GPU is done with the frame they were captured in.
+pub(crate) struct FrameCapture {
+    device: Arc<Device>,
+    pending: Vec<FrameCaptureRequest>,
+    recorded: Vec<RecordedCapture>,
+    in_flight: Vec<InFlightCapture>,
+    buffers_to_release: Vec<Arc<Buffer>>,
+    frame_counter: u64,
+}
+
+impl FrameCapture {
+    pub fn new(device: Arc<Device>) -> Self {
+        Self {
+            device,
+            pending: Default::default(),
+            recorded: Default::default(),
+            in_flight: Default::default(),
+            buffers_to_release: Default::default(),
+            frame_counter: 0,
+        }
+    }
+
+    pub fn request(&mut self, request: FrameCaptureRequest) {
+        self.pending.push(request);
+    }
+
+    pub fn has_outstanding_captures(&self) -> bool {
+        !self.pending.is_empty() || !self.recorded.is_empty() || !self.in_flight.is_empty()
+    }
+
+    pub fn record_captures(&mut self, rg: &mut rg::RenderGraph, sources: CaptureSources) {
+        // If the previous graph was never submitted, its captures didn't happen either.
+        self.pending
+            .extend(self.recorded.drain(..).map(|capture| capture.request));
+
+        for request in std::mem::take(&mut self.pending) {
+            let image = match request.source {
+                CaptureSource::Final => sources.final_image,
+                CaptureSource::HdrAccumulation => sources.hdr_accumulation,
+                CaptureSource::DebugTarget => {
+                    if let Some(image) = sources.debug_target {
+                        image
+                    } else {
+                        log::warn!(
+                            "Frame capture of {:?} skipped: no debug target is selected",
+                            request.path
+                        );
+                        continue;
+                    }
+                }
+            };
+
+            match self.record_capture(rg, image, &request) {
+                Ok((extent, buffer)) => self.recorded.push(RecordedCapture {
+                    request,
+                    extent,
+                    buffer,
+                }),
+                Err(err) => log::error!("Frame capture of {:?} failed: {:#}", request.path, err),
+            }
+        }
+    }
+
+    fn record_capture(
+        &self,
+        rg: &mut rg::RenderGraph,
+        image: &rg::Handle<Image>,
+        request: &FrameCaptureRequest,
+    ) -> anyhow::Result<([u32; 2], Arc<Buffer>)> {
+        let desc = *image.desc();
+        let extent = [desc.extent[0], desc.extent[1]];
+
+        let buffer = Arc::new(self.device.create_buffer(
+            BufferDesc::new_gpu_to_cpu(
+                extent[0] as usize * extent[1] as usize * std::mem::size_of::<[f32; 4]>(),
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            ),
+            "frame capture",
+            None,
+        )?);
+
+        let mut output = rg.import(buffer.clone(), AccessType::Nothing);
+
+        SimpleRenderPass::new_compute(
+            rg.add_pass(&format!("capture {:?}", request.source)),
+            "/shaders/capture/image_to_buffer.hlsl",
+        )
+        .read(image)
+        .write(&mut output)
+        .constants((desc.extent_inv_extent_2d(),))
+        .dispatch(desc.extent);
+
+        rg.export(output, AccessType::HostRead);
+
+        Ok((extent, buffer))
+    }
+
+    /// Call once the frame with the captures has been submitted.
+    pub fn retire_frame(&mut self) {
+        let submitted_frame = self.frame_counter;
+        self.frame_counter += 1;
+
+        self.in_flight
+            .extend(self.recorded.drain(..).map(|capture| InFlightCapture {
+                capture,
+                submitted_frame,
+            }));
+
+        let frame_counter = self.frame_counter;
+        let (done, in_flight): (Vec<_>, Vec<_>) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition(|capture| frame_counter > capture.submitted_frame + FRAMES_IN_FLIGHT);
+        self.in_flight = in_flight;
+
+        for capture in done {
+            self.write_capture(capture.capture);
+        }
+        self.release_buffers();
+    }
+
+    /// Blocks until all submitted captures are written to disk.
+    pub fn finish(&mut self) {
+        if self.in_flight.is_empty() {
+            return;
+        }
+
+        unsafe { self.device.raw.device_wait_idle() }.expect("device_wait_idle");
+
+        for capture in std::mem::take(&mut self.in_flight) {
+            self.write_capture(capture.capture);
+        }
+        self.release_buffers();
+    }
+
+    fn write_capture(&mut self, capture: RecordedCapture) {
+        let RecordedCapture {
+            request,
+            extent,
+            buffer,
+        } = capture;
+
+        let path = request.output_path();
+        let pixels: &[[f32; 4]] = bytemuck::cast_slice(
+            buffer
+                .allocation
+                .mapped_slice()
+                .expect("frame capture buffer must be host-visible"),
+        );
+
+        let result = match request.resolved_format() {
+            CaptureFileFormat::Png => write_png(&path, extent, pixels),
+            CaptureFileFormat::Exr => write_exr(&path, extent, pixels),
+        };
+
+        match result {
+            Ok(()) => log::info!("Captured {:?} to {:?}", request.source, path),
+            Err(err) => log::error!("Failed to write frame capture {:?}: {:#}", path, err),
+        }
+
+        self.buffers_to_release.push(buffer);
+    }
+
+    // The render graph holds on to imported resources until it's dropped,
+    // so buffers can outlive their captures by a frame.
+    fn release_buffers(&mut self) {
+        for buffer in std::mem::take(&mut self.buffers_to_release) {
+            match Arc::try_unwrap(buffer) {
+                Ok(buffer) => self.device.immediate_destroy_buffer(buffer),
+                Err(buffer) => self.buffers_to_release.push(buffer),
+            }
+        }
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn to_srgb8(pixels: &[[f32; 4]]) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|px| {
+            let [r, g, b, _] = *px;
+            [r, g, b].map(|v| (linear_to_srgb(v) * 255.0 + 0.5) as u8)
+        })
+        .collect()
+}
+
+fn write_png(path: &Path, extent: [u32; 2], pixels: &[[f32; 4]]) -> anyhow::Result<()> {
+    let image = image::RgbImage::from_raw(extent[0], extent[1], to_srgb8(pixels))
+        .context("pixel count mismatch")?;
+    image.save_with_format(path, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+fn write_exr(path: &Path, extent: [u32; 2], pixels: &[[f32; 4]]) -> anyhow::Result<()> {
+    let width = extent[0] as usize;
+    exr::prelude::write_rgba_file(path, width, extent[1] as usize, |x, y| {
+        let [r, g, b, a] = pixels[y * width + x];
+        (r, g, b, a)
+    })?;
+    Ok(())
+}
+
+#[test]
+fn test_capture_output_path() {
+    let request = FrameCaptureRequest::new(CaptureSource::Final, "out/shot.png");
+    assert_eq!(request.output_path(), Path::new("out/shot.png"));
+
+    let request =
+        FrameCaptureRequest::new(CaptureSource::HdrAccumulation, "out/shot.png").frame_number(42);
+    assert_eq!(request.output_path(), Path::new("out/shot_00042.exr"));
+
+    let request =
+        FrameCaptureRequest::new(CaptureSource::DebugTarget, "shot").format(CaptureFileFormat::Png);
+    assert_eq!(request.output_path(), Path::new("shot.png"));
+}
+
+#[test]
+fn test_srgb_encoding() {
+    assert_eq!(
+        to_srgb8(&[[0.0, 1.0, 2.0, 1.0], [0.5, -1.0, 0.002, 0.0]]),
+        vec![0, 255, 255, 188, 0, 7]
+    );
+}
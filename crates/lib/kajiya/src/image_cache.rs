@@ -1,7 +1,11 @@
 use std::{hash::Hash, sync::Arc};
 
+use half::f16;
 use image::{imageops::FilterType, DynamicImage, GenericImageView};
-use kajiya_asset::{image::RawImage, mesh::TexParams};
+use kajiya_asset::{
+    image::{RawImage, RawRgba8Image, RawRgbaF32Image},
+    mesh::TexParams,
+};
 use kajiya_backend::{ash::vk, Device, Image, ImageDesc, ImageSubResourceData};
 use turbosloth::*;
 
@@ -25,13 +29,16 @@ impl LazyWorker for UploadGpuImage {
 
     async fn run(self, ctx: RunContext) -> Self::Output {
         let src = self.image.eval(&ctx).await?;
-        let src = match &*src {
-            RawImage::Rgba8(src) => src,
-            RawImage::Dds(_) => {
-                return Err(anyhow::anyhow!("UploadGpuImage does not support Dds yet"));
-            }
-        };
+        match &*src {
+            RawImage::Rgba8(src) => self.upload_rgba8(src),
+            RawImage::RgbaF32(src) => self.upload_rgba_f32(src),
+            RawImage::Dds(_) => Err(anyhow::anyhow!("UploadGpuImage does not support Dds yet")),
+        }
+    }
+}
 
+impl UploadGpuImage {
+    fn upload_rgba8(&self, src: &RawRgba8Image) -> anyhow::Result<Image> {
         let format = match self.params.gamma {
             kajiya_asset::mesh::TexGamma::Linear => vk::Format::R8G8B8A8_UNORM,
             kajiya_asset::mesh::TexGamma::Srgb => vk::Format::R8G8B8A8_SRGB,
@@ -90,4 +97,76 @@ impl LazyWorker for UploadGpuImage {
 
         Ok(self.device.create_image(desc, initial_data)?)
     }
+
+    /// As half floats, like uncompressed HDR textures of baked meshes
+    fn upload_rgba_f32(&self, src: &RawRgbaF32Image) -> anyhow::Result<Image> {
+        let mut desc = ImageDesc::new_2d(vk::Format::R16G16B16A16_SFLOAT, src.dimensions)
+            .usage(vk::ImageUsageFlags::SAMPLED);
+
+        let mut mips = vec![(src.dimensions, src.data.clone())];
+        if self.params.use_mips {
+            desc = desc.all_mip_levels();
+
+            for _ in 1..desc.mip_levels {
+                let (dimensions, data) = mips.last().unwrap();
+                mips.push(downsample_rgba_f32(*dimensions, data));
+            }
+        }
+
+        let mips: Vec<(u32, Vec<f16>)> = mips
+            .into_iter()
+            .map(|(dimensions, data)| {
+                (dimensions[0], data.into_iter().map(f16::from_f32).collect())
+            })
+            .collect();
+
+        let initial_data = mips
+            .iter()
+            .map(|(width, data)| ImageSubResourceData {
+                data: bytemuck::cast_slice(data.as_slice()),
+                row_pitch: *width as usize * 8,
+                slice_pitch: 0,
+            })
+            .collect();
+
+        Ok(self.device.create_image(desc, initial_data)?)
+    }
+}
+
+/// Halves both dimensions with a box filter, clamping at odd edges.
+fn downsample_rgba_f32([width, height]: [u32; 2], data: &[f32]) -> ([u32; 2], Vec<f32>) {
+    let dst_dimensions = [(width / 2).max(1), (height / 2).max(1)];
+    let texel = |x: u32, y: u32| {
+        let offset = ((y.min(height - 1) * width + x.min(width - 1)) * 4) as usize;
+        &data[offset..offset + 4]
+    };
+
+    let mut dst = Vec::with_capacity((dst_dimensions[0] * dst_dimensions[1] * 4) as usize);
+    for y in 0..dst_dimensions[1] {
+        for x in 0..dst_dimensions[0] {
+            let (x, y) = (x * 2, y * 2);
+            for c in 0..4 {
+                let sum = texel(x, y)[c]
+                    + texel(x + 1, y)[c]
+                    + texel(x, y + 1)[c]
+                    + texel(x + 1, y + 1)[c];
+                dst.push(sum * 0.25);
+            }
+        }
+    }
+
+    (dst_dimensions, dst)
+}
+
+#[test]
+fn test_downsample_rgba_f32() {
+    let texels: Vec<f32> = (0..3 * 2).flat_map(|i| [i as f32, 0.0, 0.0, 1.0]).collect();
+
+    let (dimensions, data) = downsample_rgba_f32([3, 2], &texels);
+    assert_eq!(dimensions, [1, 1]);
+    assert_eq!(data, vec![2.0, 0.0, 0.0, 1.0]);
+
+    let (dimensions, data) = downsample_rgba_f32(dimensions, &data);
+    assert_eq!(dimensions, [1, 1]);
+    assert_eq!(data, vec![2.0, 0.0, 0.0, 1.0]);
 }
@@ -1,5 +1,6 @@
 pub mod camera;
 pub mod default_world_renderer;
+pub mod frame_capture;
 pub mod frame_desc;
 pub mod image_cache;
 pub mod image_lut;
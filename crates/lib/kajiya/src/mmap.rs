@@ -1,23 +1,42 @@
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    path::PathBuf,
+};
 
 use anyhow::Context;
+use kajiya_asset::mesh::FlatAsset;
 use parking_lot::Mutex;
 
 lazy_static::lazy_static! {
     static ref ASSET_MMAPS: Mutex<HashMap<PathBuf, memmap2::Mmap>> = Mutex::new(HashMap::new());
 }
 
-pub fn mmapped_asset<T, P: Into<std::path::PathBuf>>(path: P) -> anyhow::Result<&'static T> {
+/// Maps a baked asset file into memory. The file is validated the first time it's mapped,
+/// and the mapping is kept alive for the rest of the program.
+pub fn mmapped_asset<T: FlatAsset, P: Into<std::path::PathBuf>>(
+    path: P,
+) -> anyhow::Result<&'static T> {
     let path = path.into();
     let path = kajiya_backend::canonical_path_from_vfs(&path)
         .with_context(|| format!("Can't mmap asset: file doesn't exist: {:?}", path))?;
 
     let mut mmaps = ASSET_MMAPS.lock();
-    let data: &[u8] = mmaps.entry(path.clone()).or_insert_with(|| {
-        let file =
-            File::open(&path).unwrap_or_else(|e| panic!("Could not mmap {:?}: {:?}", path, e));
-        unsafe { memmap2::MmapOptions::new().map(&file).unwrap() }
-    });
-    let asset: &T = unsafe { (data.as_ptr() as *const T).as_ref() }.unwrap();
-    Ok(asset)
+    let data: &[u8] = match mmaps.entry(path.clone()) {
+        Entry::Occupied(entry) => &entry.into_mut()[..],
+        Entry::Vacant(entry) => {
+            let file = File::open(&path).with_context(|| format!("Could not mmap {:?}", path))?;
+            let mmap = unsafe { memmap2::MmapOptions::new().map(&file) }
+                .with_context(|| format!("Could not mmap {:?}", path))?;
+
+            T::validate(&mmap).with_context(|| format!("Invalid asset file {:?}", path))?;
+
+            &entry.insert(mmap)[..]
+        }
+    };
+
+    // Mappings are never removed, so the data stays valid.
+    let data: &'static [u8] = unsafe { std::slice::from_raw_parts(data.as_ptr(), data.len()) };
+
+    Ok(unsafe { T::from_validated_bytes(data) })
 }
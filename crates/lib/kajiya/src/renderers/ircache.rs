@@ -394,13 +394,10 @@ impl IrcacheRenderState {
         SimpleRenderPass::new_rt(
             rg.add_pass("ircache trace access"),
             ShaderSource::hlsl("/shaders/ircache/trace_accessibility.rgen.hlsl"),
-            [
-                // Duplicated because `rt.hlsl` hardcodes miss index to 1
-                ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
-                ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
-            ],
-            std::iter::empty(),
+            [ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl")],
+            super::rt_shadow_hit_groups(),
         )
+        .define("RT_SHADOW_RAY_INDEX", 0)
         .read(&self.ircache_spatial_buf)
         .read(&self.ircache_life_buf)
         .write_no_sync(&mut self.ircache_reposition_proposal_buf)
@@ -418,7 +415,7 @@ impl IrcacheRenderState {
                 ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                 ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
             ],
-            [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+            super::rt_hit_groups(),
         )
         .read(&self.ircache_spatial_buf)
         .read(sky_cube)
@@ -451,7 +448,7 @@ impl IrcacheRenderState {
                 ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                 ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
             ],
-            [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+            super::rt_hit_groups(),
         )
         .read(&self.ircache_spatial_buf)
         .read(sky_cube)
@@ -1,7 +1,7 @@
 use std::cell::{Ref, RefCell};
 
-use kajiya_backend::Image;
-use kajiya_rg::{self as rg, GetOrCreateTemporal};
+use kajiya_backend::{vulkan::shader::ShaderSource, Image};
+use kajiya_rg::{self as rg, GetOrCreateTemporal, RtHitGroup};
 
 pub mod deferred;
 pub mod dof;
@@ -28,6 +28,24 @@ pub mod wrc;
 #[cfg(feature = "dlss")]
 pub mod dlss;
 
+/// Hit groups of ray tracing passes which trace `GbufferRaytrace` rays (group 0)
+/// and `rt_is_shadowed` rays (group 1). The any-hit shaders alpha-test non-opaque geometry.
+pub fn rt_hit_groups() -> [RtHitGroup; 2] {
+    [
+        RtHitGroup::closest_hit(ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl"))
+            .any_hit(ShaderSource::hlsl("/shaders/rt/gbuffer.rahit.hlsl")),
+        RtHitGroup::any_hit_only(ShaderSource::hlsl("/shaders/rt/shadow.rahit.hlsl")),
+    ]
+}
+
+/// Hit groups of ray tracing passes which only trace `rt_is_shadowed` rays.
+/// Such passes must also define `RT_SHADOW_RAY_INDEX` as 0; see `rt.hlsl`.
+pub fn rt_shadow_hit_groups() -> [RtHitGroup; 1] {
+    [RtHitGroup::any_hit_only(ShaderSource::hlsl(
+        "/shaders/rt/shadow.rahit.hlsl",
+    ))]
+}
+
 pub struct GbufferDepth {
     pub geometric_normal: rg::Handle<Image>,
     pub gbuffer: rg::Handle<Image>,
@@ -1,5 +1,6 @@
 use std::sync::Arc;
 
+use glam::{Affine3A, Vec3};
 use kajiya_backend::{
     ash::vk,
     vk_sync::AccessType,
@@ -12,15 +13,71 @@ use crate::world_renderer::MeshInstance;
 
 use super::GbufferDepth;
 
-#[derive(Clone)]
-pub struct UploadedTriMesh {
+#[derive(Clone, Copy)]
+pub struct UploadedMeshLod {
     pub index_buffer_offset: u64,
     pub index_count: u32,
+
+    /// Object-space distance from the full-detail surface
+    pub error: f32,
+}
+
+#[derive(Clone)]
+pub struct UploadedTriMesh {
+    /// Full detail first, then progressively coarser
+    pub lods: Vec<UploadedMeshLod>,
+
+    // Object-space bounding sphere
+    pub bounds_center: Vec3,
+    pub bounds_radius: f32,
+}
+
+/// Picks the coarsest level of detail of each instance whose error is
+/// no bigger than `error_threshold_px` on screen.
+#[derive(Clone, Copy)]
+pub struct MeshLodSelection {
+    pub eye_position: Vec3,
+
+    /// Size on screen of something one unit large, one unit away from the camera
+    pub pixels_per_unit: f32,
+
+    /// Zero always selects full detail
+    pub error_threshold_px: f32,
+}
+
+impl MeshLodSelection {
+    pub fn select(&self, mesh: &UploadedTriMesh, transform: &Affine3A) -> usize {
+        if self.error_threshold_px <= 0.0 || mesh.lods.len() < 2 {
+            return 0;
+        }
+
+        let scale = transform
+            .x_axis
+            .length()
+            .max(transform.y_axis.length())
+            .max(transform.z_axis.length());
+
+        // Distance to the nearest point of the bounds; full detail when inside of them.
+        let distance = (transform.transform_point3(mesh.bounds_center) - self.eye_position)
+            .length()
+            - mesh.bounds_radius * scale;
+        if distance <= 0.0 {
+            return 0;
+        }
+
+        let max_error = self.error_threshold_px * distance / (self.pixels_per_unit * scale);
+
+        mesh.lods
+            .iter()
+            .rposition(|lod| lod.error <= max_error)
+            .unwrap_or(0)
+    }
 }
 
 pub struct RasterMeshesData<'a> {
     pub meshes: &'a [UploadedTriMesh],
     pub instances: &'a [MeshInstance],
+    pub lod_selection: MeshLodSelection,
     pub vertex_buffer: Arc<Buffer>,
     pub bindless_descriptor_set: vk::DescriptorSet,
 }
@@ -53,8 +110,14 @@ pub fn raster_meshes(
             .push_constants_bytes(2 * std::mem::size_of::<u32>()),
     );
 
-    let meshes: Vec<UploadedTriMesh> = mesh_data.meshes.to_vec();
     let instances: Vec<MeshInstance> = mesh_data.instances.to_vec();
+    let instance_lods: Vec<UploadedMeshLod> = instances
+        .iter()
+        .map(|inst| {
+            let mesh = &mesh_data.meshes[inst.mesh.0];
+            mesh.lods[mesh_data.lod_selection.select(mesh, &inst.transform)]
+        })
+        .collect();
 
     let depth_ref = pass.raster(
         &mut gbuffer_depth.depth,
@@ -145,13 +208,11 @@ pub fn raster_meshes(
             let raw_device = &api.device().raw;
             let cb = api.cb;
 
-            for (draw_idx, instance) in instances.into_iter().enumerate() {
-                let mesh = &meshes[instance.mesh.0];
-
+            for (draw_idx, (instance, lod)) in instances.iter().zip(&instance_lods).enumerate() {
                 raw_device.cmd_bind_index_buffer(
                     cb.raw,
                     vertex_buffer.raw,
-                    mesh.index_buffer_offset,
+                    lod.index_buffer_offset,
                     vk::IndexType::UINT32,
                 );
 
@@ -167,7 +228,7 @@ pub fn raster_meshes(
                     ),
                 );
 
-                raw_device.cmd_draw_indexed(cb.raw, mesh.index_count, 1, 0, 0, 0);
+                raw_device.cmd_draw_indexed(cb.raw, lod.index_count, 1, 0, 0, 0);
             }
         }
 
@@ -18,8 +18,9 @@ pub fn reference_path_trace(
             ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
             ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
         ],
-        [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+        super::rt_hit_groups(),
     )
+    .define("RT_PAYLOAD_MATERIAL_EXTENSIONS", 1)
     .write(output_img)
     .raw_descriptor_set(1, bindless_descriptor_set)
     .trace_rays(tlas, output_img.desc().extent);
@@ -297,7 +297,7 @@ impl RtdgiRenderer {
                     ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                     ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
                 ],
-                [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+                super::rt_hit_groups(),
             )
             .read(&*half_view_normal_tex)
             .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
@@ -325,7 +325,7 @@ impl RtdgiRenderer {
                     ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                     ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
                 ],
-                [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+                super::rt_hit_groups(),
             )
             .read(&*half_view_normal_tex)
             .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
@@ -446,6 +446,7 @@ impl RtdgiRenderer {
                     rg.add_pass("restir spatial"),
                     "/shaders/rtdgi/restir_spatial.hlsl",
                 )
+                .async_compute()
                 .read(reservoir_input_tex)
                 .read(bounced_radiance_input_tex)
                 .read(&*half_view_normal_tex)
@@ -483,7 +484,7 @@ impl RtdgiRenderer {
                         ShaderSource::hlsl("/shaders/rt/gbuffer.rmiss.hlsl"),
                         ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
                     ],
-                    [ShaderSource::hlsl("/shaders/rt/gbuffer.rchit.hlsl")],
+                    super::rt_hit_groups(),
                 )
                 .read(&*half_depth_tex)
                 .read(&temporal_reservoir_packed_tex)
@@ -18,13 +18,10 @@ pub fn trace_sun_shadow_mask(
     SimpleRenderPass::new_rt(
         rg.add_pass("trace shadow mask"),
         ShaderSource::hlsl("/shaders/rt/trace_sun_shadow_mask.rgen.hlsl"),
-        [
-            // Duplicated because `rt.hlsl` hardcodes miss index to 1
-            ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
-            ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl"),
-        ],
-        std::iter::empty(),
+        [ShaderSource::hlsl("/shaders/rt/shadow.rmiss.hlsl")],
+        super::rt_shadow_hit_groups(),
     )
+    .define("RT_SHADOW_RAY_INDEX", 0)
     .read_aspect(&gbuffer_depth.depth, vk::ImageAspectFlags::DEPTH)
     .read(&gbuffer_depth.geometric_normal)
     .write(&mut output_img)
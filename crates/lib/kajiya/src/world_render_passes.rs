@@ -1,4 +1,5 @@
 use crate::{
+    frame_capture::CaptureSources,
     frame_desc::WorldFrameDesc,
     renderers::{
         deferred::light_gbuffer, motion_blur::motion_blur, raster_meshes::*,
@@ -6,10 +7,74 @@ use crate::{
     },
     world_renderer::{RenderDebugMode, WorldRenderer},
 };
-use kajiya_backend::{ash::vk, vulkan::image::*};
-use kajiya_rg::{self as rg, GetOrCreateTemporal};
+use kajiya_backend::{ash::vk, vk_sync::AccessType, vulkan::image::*};
+use kajiya_rg::{self as rg, GetOrCreateTemporal, SimpleRenderPass};
 
 impl WorldRenderer {
+    /// Deforms the vertices of skinned instances, and refits their bottom level
+    /// acceleration structures to match.
+    pub(super) fn prepare_skinning(&mut self, rg: &mut rg::TemporalRenderGraph) {
+        if self.skinned_instances.is_empty() {
+            return;
+        }
+
+        let mut vertex_buffer = rg.import(
+            self.vertex_buffer.lock().clone(),
+            AccessType::AnyShaderReadOther,
+        );
+
+        let mut blas_updates = Vec::new();
+
+        for (i, skinned) in self.skinned_instances.values().enumerate() {
+            let [vertex_count, src_core_offset, src_tangent_offset, joint_offset, weight_offset] =
+                self.skinned_instance_source(skinned);
+
+            let pass =
+                SimpleRenderPass::new_compute(rg.add_pass("skinning"), "/shaders/skinning.hlsl");
+
+            // Instances write disjoint ranges of the buffer
+            let pass = if i == 0 {
+                pass.write(&mut vertex_buffer)
+            } else {
+                pass.write_no_sync(&mut vertex_buffer)
+            };
+
+            pass.dynamic_storage_buffer_vec(self.instance_skinning_matrices(skinned))
+                .constants([
+                    vertex_count,
+                    src_core_offset,
+                    src_tangent_offset,
+                    joint_offset,
+                    weight_offset,
+                    skinned.vertex_core_offset,
+                    skinned.vertex_tangent_offset,
+                ])
+                .dispatch([vertex_count, 1, 1]);
+
+            if let Some(blas_desc) = skinned.blas_desc.as_ref() {
+                blas_updates.push((
+                    self.mesh_blas[skinned.deformed_mesh.0].clone(),
+                    blas_desc.clone(),
+                ));
+            }
+        }
+
+        // The vertex buffer is read through bindless descriptors, so this pass
+        // doubles as the barrier making the deformed vertices visible to everything after it.
+        let mut pass = rg.add_pass("skinned blas refit");
+        pass.write(&mut vertex_buffer, AccessType::General);
+
+        pass.render(move |api| {
+            let cb = api.cb.raw;
+            for (blas, blas_desc) in &blas_updates {
+                api.device()
+                    .update_ray_tracing_bottom_acceleration(cb, blas_desc, blas);
+            }
+
+            Ok(())
+        });
+    }
+
     pub(super) fn prepare_render_graph_standard(
         &mut self,
         rg: &mut rg::TemporalRenderGraph,
@@ -73,6 +138,13 @@ impl WorldRenderer {
                 RasterMeshesData {
                     meshes: self.meshes.as_slice(),
                     instances: self.instances.as_slice(),
+                    lod_selection: MeshLodSelection {
+                        eye_position: frame_desc.camera_matrices.eye_position(),
+                        pixels_per_unit: frame_desc.camera_matrices.view_to_clip.y_axis.y
+                            * frame_desc.render_extent[1] as f32
+                            * 0.5,
+                        error_threshold_px: self.lod_error_threshold_px,
+                    },
                     vertex_buffer: self.vertex_buffer.lock().clone(),
                     bindless_descriptor_set: self.bindless_descriptor_set,
                 },
@@ -288,7 +360,18 @@ impl WorldRenderer {
             self.dynamic_exposure.histogram_clipping,
         );
 
-        rg.debugged_resource.take().unwrap_or(post_processed)
+        let debugged_resource = rg.debugged_resource.take();
+
+        self.frame_capture.record_captures(
+            rg,
+            CaptureSources {
+                final_image: &post_processed,
+                hdr_accumulation: &accum_img,
+                debug_target: debugged_resource.as_ref(),
+            },
+        );
+
+        debugged_resource.unwrap_or(post_processed)
     }
 
     pub(super) fn prepare_render_graph_reference(
@@ -318,7 +401,7 @@ impl WorldRenderer {
             reference_path_trace(rg, &mut accum_img, self.bindless_descriptor_set, &tlas);
         }
 
-        self.post.render(
+        let post_processed = self.post.render(
             rg,
             &accum_img,
             //&accum_img, // hack
@@ -326,6 +409,19 @@ impl WorldRenderer {
             self.exposure_state().post_mult,
             self.contrast,
             self.dynamic_exposure.histogram_clipping,
-        )
+        );
+
+        let debugged_resource = rg.debugged_resource.take();
+
+        self.frame_capture.record_captures(
+            rg,
+            CaptureSources {
+                final_image: &post_processed,
+                hdr_accumulation: &accum_img,
+                debug_target: debugged_resource.as_ref(),
+            },
+        );
+
+        post_processed
     }
 }
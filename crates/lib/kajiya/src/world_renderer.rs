@@ -4,6 +4,7 @@ use crate::{
         BINDLESS_TEXURES_BINDING_INDEX,
     },
     buffer_builder::BufferBuilder,
+    frame_capture::{FrameCapture, FrameCaptureRequest},
     frame_desc::WorldFrameDesc,
     image_lut::{ComputeImageLut, ImageLut},
     renderers::{
@@ -12,7 +13,7 @@ use crate::{
         shadow_denoise::ShadowDenoiseRenderer, ssgi::*, taa::TaaRenderer,
     },
 };
-use glam::{Affine3A, Vec2, Vec3};
+use glam::{Affine3A, Mat4, Vec2, Vec3};
 use kajiya_asset::mesh::{AssetRef, GpuImage, MeshMaterialFlags, PackedTriMesh, PackedVertex};
 use kajiya_backend::{
     ash::vk::{self, ImageView},
@@ -51,6 +52,7 @@ struct GpuMesh {
 
     mat_data_offset: u32,
     index_offset: u32,
+    alpha_tested_first_triangle: u32,
 }
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
@@ -98,6 +100,32 @@ pub struct MeshInstance {
     pub dynamic_parameters: InstanceDynamicParameters,
 }
 
+struct SkinnedMesh {
+    asset: &'static PackedTriMesh::Flat,
+    blas_triangles: BlasTriangles,
+    vertex_count: u32,
+
+    // Bind pose data in `vertex_buffer`
+    vertex_core_offset: u32,
+    vertex_tangent_offset: u32,
+    joint_offset: u32,
+    weight_offset: u32,
+
+    inverse_bind_matrices: Vec<Mat4>,
+    joint_rest_transforms: Vec<Mat4>,
+}
+
+/// Every instance of a skinned mesh gets its own copy of the mesh, with the deformed
+/// vertices written to `vertex_core_offset` and `vertex_tangent_offset` by the skinning pass.
+pub(super) struct SkinnedInstance {
+    pub source_mesh: MeshHandle,
+    pub deformed_mesh: MeshHandle,
+    pub vertex_core_offset: u32,
+    pub vertex_tangent_offset: u32,
+    pub blas_desc: Option<RayTracingBottomAccelerationDesc>,
+    pub joint_matrices: Vec<Mat4>,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum RenderDebugMode {
     None,
@@ -162,6 +190,13 @@ pub struct WorldRenderer {
 
     mesh_buffer: Mutex<Arc<Buffer>>,
 
+    skinned_meshes: HashMap<MeshHandle, SkinnedMesh>,
+    pub(super) skinned_instances: HashMap<InstanceHandle, SkinnedInstance>,
+
+    // Mesh slots, BLASes and vertex buffer ranges are never freed, so the deformed copies
+    // of removed instances are kept for new instances of the same source mesh.
+    free_skinned_instances: HashMap<MeshHandle, Vec<SkinnedInstance>>,
+
     mesh_blas: Vec<Arc<RayTracingAcceleration>>,
     tlas: Option<Arc<RayTracingAcceleration>>,
     accel_scratch: RayTracingAccelerationScratchBuffer,
@@ -179,6 +214,7 @@ pub struct WorldRenderer {
     supersample_offsets: Vec<Vec2>,
 
     pub rg_debug_hook: Option<rg::GraphDebugHook>,
+    pub(crate) frame_capture: FrameCapture,
     pub render_mode: RenderMode,
     pub reset_reference_accumulation: bool,
 
@@ -210,6 +246,12 @@ pub struct WorldRenderer {
 
     pub render_overrides: RenderOverrides,
 
+    /// Rasterized meshes use the coarsest level of detail whose error is at most this many pixels.
+    /// Opt-in, as it's zero by default. Acceleration structures are built from a fixed level
+    /// (`AddMeshOptions::blas_lod`, full detail unless set), so rays leaving a coarser
+    /// rasterized surface can hit different geometry, e.g. in self-shadowing.
+    pub lod_error_threshold_px: f32,
+
     // One for each render mode
     pub(crate) exposure_state: [ExposureState; 2],
 }
@@ -325,6 +367,11 @@ fn load_gpu_image_asset(
 #[derive(Default)]
 pub struct AddMeshOptions {
     pub use_lights: bool,
+
+    /// Level of detail to build the ray tracing acceleration structure from, clamped to the
+    /// coarsest one in the mesh. Coarser geometry is cheaper to trace, but all rays see it,
+    /// including primary rays of the reference path tracer.
+    pub blas_lod: usize,
 }
 
 impl AddMeshOptions {
@@ -336,6 +383,11 @@ impl AddMeshOptions {
         self.use_lights = v;
         self
     }
+
+    pub fn blas_lod(mut self, v: usize) -> Self {
+        self.blas_lod = v;
+        self
+    }
 }
 
 impl WorldRenderer {
@@ -461,6 +513,9 @@ impl WorldRenderer {
             mesh_buffer: Mutex::new(Arc::new(mesh_buffer)),
             vertex_buffer: Mutex::new(Arc::new(vertex_buffer)),
             vertex_buffer_written: 0,
+            skinned_meshes: Default::default(),
+            skinned_instances: Default::default(),
+            free_skinned_instances: Default::default(),
             bindless_descriptor_set,
             bindless_images: Default::default(),
             image_luts: Default::default(),
@@ -470,6 +525,7 @@ impl WorldRenderer {
             bindless_texture_sizes,
 
             rg_debug_hook: None,
+            frame_capture: FrameCapture::new(backend.device.clone()),
             render_mode: RenderMode::Standard,
             frame_idx: 0u32,
             prev_camera_matrices: None,
@@ -510,6 +566,7 @@ impl WorldRenderer {
             sky_ambient: Vec3::ZERO,
 
             render_overrides: Default::default(),
+            lod_error_threshold_px: 0.0,
 
             exposure_state: Default::default(),
         })
@@ -659,6 +716,36 @@ impl WorldRenderer {
         let mut buffer_builder = BufferBuilder::new();
         let vertex_index_offset =
             buffer_builder.append(mesh.indices.as_slice()) as u32 + vertex_data_offset;
+
+        let mut lods = vec![UploadedMeshLod {
+            index_buffer_offset: vertex_index_offset as u64,
+            index_count: mesh.indices.len() as _,
+            error: 0.0,
+        }];
+        for (indices, &error) in mesh.lod_indices.iter().zip(mesh.lod_errors.iter()) {
+            lods.push(UploadedMeshLod {
+                index_buffer_offset: (buffer_builder.append(indices.as_slice()) as u32
+                    + vertex_data_offset) as u64,
+                index_count: indices.len() as _,
+                error,
+            });
+        }
+
+        // The index offset in `GpuMesh` is only used by ray tracing, so it points to the BLAS's indices.
+        // Those need their own copy if opaque and alpha-tested triangles have to be regrouped.
+        let blas_lod = opts.blas_lod.min(lods.len() - 1);
+        let blas_indices: &'static [u32] = if blas_lod == 0 {
+            mesh.indices.as_slice()
+        } else {
+            mesh.lod_indices[blas_lod - 1].as_slice()
+        };
+        let (sorted_blas_indices, blas_triangles) = sort_blas_triangles(mesh, blas_indices);
+        let blas_index_offset = if sorted_blas_indices.as_slice() == blas_indices {
+            lods[blas_lod].index_buffer_offset as u32
+        } else {
+            buffer_builder.append(sorted_blas_indices) as u32 + vertex_data_offset
+        };
+
         let vertex_core_offset =
             buffer_builder.append(mesh.verts.as_slice()) as u32 + vertex_data_offset;
         let vertex_uv_offset =
@@ -671,6 +758,13 @@ impl WorldRenderer {
             buffer_builder.append(mesh.tangents.as_slice()) as u32 + vertex_data_offset;
         let mat_data_offset = buffer_builder.append(materials) as u32 + vertex_data_offset;
 
+        let skinning_offsets = (!mesh.joint_nodes.is_empty()).then(|| {
+            (
+                buffer_builder.append(mesh.joints.as_slice()) as u32 + vertex_data_offset,
+                buffer_builder.append(mesh.weights.as_slice()) as u32 + vertex_data_offset,
+            )
+        });
+
         let total_buffer_size = buffer_builder.current_offset();
         let mut vertex_buffer = self.vertex_buffer.lock();
         buffer_builder
@@ -692,32 +786,15 @@ impl WorldRenderer {
         };
 
         if self.device.ray_tracing_enabled() {
-            let base_da = vertex_buffer.device_address(&self.device);
-            let vertex_buffer_da = base_da + vertex_core_offset as u64;
-            let index_buffer_da = base_da + vertex_index_offset as u64;
-
             let blas = self
                 .device
-                .create_ray_tracing_bottom_acceleration(&RayTracingBottomAccelerationDesc {
-                    geometries: vec![RayTracingGeometryDesc {
-                        geometry_type: RayTracingGeometryType::Triangle,
-                        vertex_buffer: vertex_buffer_da,
-                        index_buffer: index_buffer_da,
-                        vertex_format: vk::Format::R32G32B32_SFLOAT,
-                        vertex_stride: size_of::<PackedVertex>(),
-                        parts: vec![RayTracingGeometryPart {
-                            index_count: mesh.indices.len(),
-                            index_offset: 0,
-                            max_vertex: mesh
-                                .indices
-                                .as_slice()
-                                .iter()
-                                .copied()
-                                .max()
-                                .expect("mesh must not be empty"),
-                        }],
-                    }],
-                })
+                .create_ray_tracing_bottom_acceleration(&mesh_blas_desc(
+                    vertex_buffer.device_address(&self.device),
+                    vertex_core_offset,
+                    blas_index_offset,
+                    blas_triangles,
+                    false,
+                ))
                 .expect("blas");
 
             self.mesh_blas.push(Arc::new(blas));
@@ -730,12 +807,15 @@ impl WorldRenderer {
             vertex_aux_offset,
             vertex_tangent_offset,
             mat_data_offset,
-            index_offset: vertex_index_offset,
+            index_offset: blas_index_offset,
+            alpha_tested_first_triangle: blas_triangles.alpha_tested_first,
         };
 
+        let (bounds_center, bounds_radius) = mesh_bounding_sphere(mesh);
         self.meshes.push(UploadedTriMesh {
-            index_buffer_offset: vertex_index_offset as u64,
-            index_count: mesh.indices.len() as _,
+            lods,
+            bounds_center,
+            bounds_radius,
         });
 
         let mesh_lights = if opts.use_lights {
@@ -772,14 +852,191 @@ impl WorldRenderer {
             lights: mesh_lights,
         });
 
+        if let Some((joint_offset, weight_offset)) = skinning_offsets {
+            self.skinned_meshes.insert(
+                MeshHandle(mesh_idx),
+                SkinnedMesh {
+                    asset: mesh,
+                    blas_triangles,
+                    vertex_count: mesh.verts.len() as u32,
+                    vertex_core_offset,
+                    vertex_tangent_offset,
+                    joint_offset,
+                    weight_offset,
+                    inverse_bind_matrices: mesh
+                        .inverse_bind_matrices
+                        .iter()
+                        .map(Mat4::from_cols_array)
+                        .collect(),
+                    joint_rest_transforms: mesh
+                        .joint_rest_transforms
+                        .iter()
+                        .map(Mat4::from_cols_array)
+                        .collect(),
+                },
+            );
+        }
+
         MeshHandle(mesh_idx)
     }
 
+    /// Creates a copy of a skinned mesh for a new instance to deform,
+    /// or reuses one left over by a removed instance.
+    fn add_skinned_instance(&mut self, source_mesh: MeshHandle) -> SkinnedInstance {
+        let skinned_mesh = &self.skinned_meshes[&source_mesh];
+
+        if let Some(mut skinned) = self
+            .free_skinned_instances
+            .get_mut(&source_mesh)
+            .and_then(Vec::pop)
+        {
+            skinned
+                .joint_matrices
+                .clone_from(&skinned_mesh.joint_rest_transforms);
+            return skinned;
+        }
+
+        let mesh = skinned_mesh.asset;
+        let blas_triangles = skinned_mesh.blas_triangles;
+        let mesh_idx = self.meshes.len();
+        assert!(mesh_idx < MAX_GPU_MESHES, "too many meshes");
+
+        // Start off with the bind pose, so that the BLAS is built from sensible geometry.
+        let vertex_data_offset = self.vertex_buffer_written as u32;
+        let mut buffer_builder = BufferBuilder::new();
+        let vertex_core_offset =
+            buffer_builder.append(mesh.verts.as_slice()) as u32 + vertex_data_offset;
+        let vertex_tangent_offset =
+            buffer_builder.append(mesh.tangents.as_slice()) as u32 + vertex_data_offset;
+
+        let total_buffer_size = buffer_builder.current_offset();
+        let mut vertex_buffer = self.vertex_buffer.lock();
+        buffer_builder
+            .upload(
+                self.device.as_ref(),
+                Arc::get_mut(&mut *vertex_buffer).expect("refs may not be retained"),
+                self.vertex_buffer_written,
+            )
+            .map_err(|err| self.device.report_error(err))
+            .unwrap();
+        self.vertex_buffer_written += total_buffer_size;
+
+        let mesh_buffer_dst = unsafe {
+            let mut mesh_buffer = self.mesh_buffer.lock();
+            let mesh_buffer = Arc::get_mut(&mut *mesh_buffer).expect("refs may not be retained");
+            let mesh_buffer_dst =
+                mesh_buffer.allocation.mapped_ptr().unwrap().as_ptr() as *mut GpuMesh;
+            std::slice::from_raw_parts_mut(mesh_buffer_dst, MAX_GPU_MESHES)
+        };
+
+        // Everything other than the vertices is shared with the source mesh
+        mesh_buffer_dst[mesh_idx] = GpuMesh {
+            vertex_core_offset,
+            vertex_tangent_offset,
+            ..mesh_buffer_dst[source_mesh.0]
+        };
+
+        let blas_desc = self.device.ray_tracing_enabled().then(|| {
+            mesh_blas_desc(
+                vertex_buffer.device_address(&self.device),
+                vertex_core_offset,
+                mesh_buffer_dst[mesh_idx].index_offset,
+                blas_triangles,
+                true,
+            )
+        });
+
+        if let Some(blas_desc) = blas_desc.as_ref() {
+            let blas = self
+                .device
+                .create_ray_tracing_bottom_acceleration(blas_desc)
+                .expect("blas");
+            self.mesh_blas.push(Arc::new(blas));
+        }
+
+        self.meshes.push(self.meshes[source_mesh.0].clone());
+
+        // Emissive triangles of skinned meshes aren't used as lights, since they move.
+        self.mesh_lights.push(MeshLightSet { lights: Vec::new() });
+
+        SkinnedInstance {
+            source_mesh,
+            deformed_mesh: MeshHandle(mesh_idx),
+            vertex_core_offset,
+            vertex_tangent_offset,
+            blas_desc,
+            joint_matrices: skinned_mesh.joint_rest_transforms.clone(),
+        }
+    }
+
+    /// Joint transforms of the scene the skinned `mesh` was imported from, in its default pose.
+    /// Returns `None` if the mesh is not skinned.
+    pub fn mesh_joint_rest_transforms(&self, mesh: MeshHandle) -> Option<&[Mat4]> {
+        self.skinned_meshes
+            .get(&mesh)
+            .map(|mesh| mesh.joint_rest_transforms.as_slice())
+    }
+
+    /// Poses a skinned instance. `joint_matrices` transform each joint to the mesh's space,
+    /// in the order of `PackedTriMesh::joint_nodes`; see `mesh_joint_rest_transforms`.
+    pub fn set_instance_joint_matrices(&mut self, inst: InstanceHandle, joint_matrices: &[Mat4]) {
+        let skinned = self
+            .skinned_instances
+            .get_mut(&inst)
+            .expect("not a skinned instance");
+
+        assert_eq!(
+            joint_matrices.len(),
+            skinned.joint_matrices.len(),
+            "joint count mismatch"
+        );
+
+        skinned.joint_matrices.copy_from_slice(joint_matrices);
+    }
+
+    /// Skinning matrices of an instance, as rows of 3x4 affine transforms.
+    pub(super) fn instance_skinning_matrices(&self, skinned: &SkinnedInstance) -> Vec<[f32; 12]> {
+        let skinned_mesh = &self.skinned_meshes[&skinned.source_mesh];
+
+        skinned
+            .joint_matrices
+            .iter()
+            .zip(&skinned_mesh.inverse_bind_matrices)
+            .map(|(joint, inverse_bind)| {
+                let rows = (*joint * *inverse_bind).transpose().to_cols_array();
+                let mut res = [0.0; 12];
+                res.copy_from_slice(&rows[..12]);
+                res
+            })
+            .collect()
+    }
+
+    /// Bind pose vertex data of the mesh `skinned` is an instance of; see `SkinnedMesh`.
+    pub(super) fn skinned_instance_source(&self, skinned: &SkinnedInstance) -> [u32; 5] {
+        let mesh = &self.skinned_meshes[&skinned.source_mesh];
+        [
+            mesh.vertex_count,
+            mesh.vertex_core_offset,
+            mesh.vertex_tangent_offset,
+            mesh.joint_offset,
+            mesh.weight_offset,
+        ]
+    }
+
     pub fn add_instance(&mut self, mesh: MeshHandle, transform: Affine3A) -> InstanceHandle {
         let handle = self.next_instance_handle;
         self.next_instance_handle += 1;
         let handle = InstanceHandle(handle);
 
+        let mesh = if self.skinned_meshes.contains_key(&mesh) {
+            let skinned = self.add_skinned_instance(mesh);
+            let deformed_mesh = skinned.deformed_mesh;
+            self.skinned_instances.insert(handle, skinned);
+            deformed_mesh
+        } else {
+            mesh
+        };
+
         let index = self.instances.len();
 
         self.instances.push(MeshInstance {
@@ -802,6 +1059,13 @@ impl WorldRenderer {
             .instance_handle_to_index
             .remove(&inst)
             .expect("no such instance");
+        if let Some(skinned) = self.skinned_instances.remove(&inst) {
+            self.free_skinned_instances
+                .entry(skinned.source_mesh)
+                .or_default()
+                .push(skinned);
+        }
+
         self.instances.swap_remove(index);
         self.instance_handles.swap_remove(index);
 
@@ -969,6 +1233,8 @@ impl WorldRenderer {
             image_lut.compute_if_needed(rg);
         }
 
+        self.prepare_skinning(rg);
+
         match self.render_mode {
             RenderMode::Standard => {
                 if USE_TAA_JITTER {
@@ -1110,6 +1376,116 @@ impl WorldRenderer {
     pub fn retire_frame(&mut self) {
         self.frame_idx = self.frame_idx.overflowing_add(1).0;
         self.store_prev_mesh_transforms();
+        self.frame_capture.retire_frame();
+    }
+
+    /// Captures a render graph image to disk. The copy is recorded the next time
+    /// the render graph is prepared, and the file is written a few frames later,
+    /// once the GPU is done with it; see `finish_frame_captures`.
+    pub fn request_frame_capture(&mut self, request: FrameCaptureRequest) {
+        self.frame_capture.request(request);
+    }
+
+    pub fn has_outstanding_frame_captures(&self) -> bool {
+        self.frame_capture.has_outstanding_captures()
+    }
+
+    /// Waits for the GPU, and writes out all captures from submitted frames.
+    pub fn finish_frame_captures(&mut self) {
+        self.frame_capture.finish();
+    }
+}
+
+fn mesh_bounding_sphere(mesh: &PackedTriMesh::Flat) -> (Vec3, f32) {
+    let positions = || mesh.verts.iter().map(|v| Vec3::from(v.pos));
+
+    let (min, max) = positions().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(min, max), p| (min.min(p), max.max(p)),
+    );
+    let center = (min + max) * 0.5;
+    let radius = positions()
+        .map(|p| p.distance(center))
+        .fold(0.0f32, f32::max);
+
+    (center, radius)
+}
+
+/// Triangles of a BLAS, in `sort_blas_triangles` order.
+#[derive(Clone, Copy)]
+struct BlasTriangles {
+    count: u32,
+    alpha_tested_first: u32,
+    max_vertex: u32,
+}
+
+/// Moves the triangles with alpha-tested materials after the opaque ones, so that they can
+/// go in separate geometries, and the opaque one can skip any-hit shaders.
+/// `indices` are one of the levels of detail of `mesh`.
+fn sort_blas_triangles(mesh: &PackedTriMesh::Flat, indices: &[u32]) -> (Vec<u32>, BlasTriangles) {
+    let materials = mesh.materials.as_slice();
+    let (opaque, alpha_tested): (Vec<&[u32]>, Vec<&[u32]>) =
+        indices.chunks_exact(3).partition(|triangle| {
+            let material_id = mesh.material_ids[triangle[0] as usize];
+            !materials[material_id as usize].is_alpha_tested()
+        });
+
+    let triangles = BlasTriangles {
+        count: (indices.len() / 3) as u32,
+        alpha_tested_first: opaque.len() as u32,
+        max_vertex: indices
+            .iter()
+            .copied()
+            .max()
+            .expect("mesh must not be empty"),
+    };
+
+    (
+        opaque
+            .into_iter()
+            .chain(alpha_tested)
+            .flatten()
+            .copied()
+            .collect(),
+        triangles,
+    )
+}
+
+fn mesh_blas_desc(
+    vertex_buffer_da: vk::DeviceAddress,
+    vertex_core_offset: u32,
+    vertex_index_offset: u32,
+    triangles: BlasTriangles,
+    allow_update: bool,
+) -> RayTracingBottomAccelerationDesc {
+    // Alpha-tested materials need any-hit shaders, which don't run for opaque geometry.
+    // The order of geometries must match `rt_mesh_triangle_index` in `rt.hlsl`.
+    let geometries = [
+        (0, triangles.alpha_tested_first, true),
+        (triangles.alpha_tested_first, triangles.count, false),
+    ]
+    .into_iter()
+    .filter(|(first, end, _)| first < end)
+    .map(|(first, end, opaque)| RayTracingGeometryDesc {
+        geometry_type: RayTracingGeometryType::Triangle,
+        vertex_buffer: vertex_buffer_da + vertex_core_offset as u64,
+        index_buffer: vertex_buffer_da
+            + vertex_index_offset as u64
+            + (first as usize * 3 * size_of::<u32>()) as u64,
+        vertex_format: vk::Format::R32G32B32_SFLOAT,
+        vertex_stride: size_of::<PackedVertex>(),
+        opaque,
+        parts: vec![RayTracingGeometryPart {
+            index_count: (end - first) as usize * 3,
+            index_offset: 0,
+            max_vertex: triangles.max_vertex,
+        }],
+    })
+    .collect();
+
+    RayTracingBottomAccelerationDesc {
+        geometries,
+        allow_update,
     }
 }
 
@@ -11,6 +11,7 @@ pub struct MeshDescriptor {
     pub vertex_tangent_offset: u32,
     pub mat_data_offset: u32,
     pub index_offset: u32,
+    pub alpha_tested_first_triangle: u32,
 }
 
 #[repr(C, align(16))]